@@ -216,9 +216,9 @@ impl ReshardingManager {
             let mode = TrackingMode::RefcountsAndAccesses(&mut trie_recorder);
             let memtrie_update = memtries.update(*parent_chunk_extra.state_root(), mode)?;
 
-            let trie_changes = memtrie_update.retain_split_shard(&boundary_account, retain_mode);
+            let trie_changes = memtrie_update.retain_split_shard(&boundary_account, retain_mode)?;
             let memtrie_changes = trie_changes.memtrie_changes.as_ref().unwrap();
-            let new_state_root = memtries.apply_memtrie_changes(block_height, memtrie_changes);
+            let new_state_root = memtries.apply_memtrie_changes(block_height, memtrie_changes)?;
             drop(memtries);
 
             // Get the congestion info for the child.
@@ -0,0 +1,191 @@
+//! Benchmarks for updates of the in-memory trie, as a baseline for its
+//! performance work.
+//!
+//! Each benchmark applies `NUM_KEYS` keys of one of the distributions below
+//! to a trie and reports the time per iteration; keys per second follow as
+//! `NUM_KEYS / time`, and bytes per second, counting keys and values, are
+//! reported directly.
+//!
+//! - `insert`: inserting the keys into a trie holding the other half of the
+//!   distribution.
+//! - `delete`: deleting the keys from a trie holding all of them.
+//! - `finalize`: the same insertions followed by the conversion of the update
+//!   into `TrieChanges`, which includes hashing and serializing the new
+//!   nodes. The cost of the finalization alone is the difference with
+//!   `insert`, as the benchmark harness can't exclude the setup of each
+//!   iteration.
+
+#[macro_use]
+extern crate bencher;
+
+use bencher::{black_box, Bencher};
+use near_primitives::hash::CryptoHash;
+use near_primitives::shard_layout::ShardUId;
+use near_primitives::state::FlatStateValue;
+use near_primitives::types::StateRoot;
+use near_store::trie::mem::memtrie_update::TrackingMode;
+use near_store::trie::mem::memtries::MemTries;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+const NUM_KEYS: usize = 10_000;
+const VALUE_LEN: usize = 32;
+/// Iterations run before measuring, so that the arena and the allocator are
+/// in a steady state.
+const NUM_WARMUP_ITERATIONS: usize = 3;
+
+#[derive(Clone, Copy)]
+enum KeyDistribution {
+    /// Uniformly random 32-byte keys, like hashed keys.
+    Random,
+    /// Big-endian counters, so that consecutive keys share long prefixes.
+    Sequential,
+    /// Random keys under a few short prefixes, like accounts with many
+    /// contract storage keys.
+    ClusteredPrefix,
+}
+
+impl KeyDistribution {
+    /// Returns `2 * NUM_KEYS` distinct keys: the first half to be present in
+    /// the trie before the update, the second half to be changed by it.
+    fn keys(self) -> Vec<Vec<u8>> {
+        let mut rng = ChaCha20Rng::seed_from_u64(0);
+        (0..2 * NUM_KEYS as u64)
+            .map(|i| match self {
+                Self::Random => rng.gen::<[u8; 32]>().to_vec(),
+                Self::Sequential => i.to_be_bytes().to_vec(),
+                Self::ClusteredPrefix => {
+                    let mut key = format!("account{}.near", i % 16).into_bytes();
+                    key.extend(i.to_be_bytes());
+                    key.extend(rng.gen::<[u8; 8]>());
+                    key
+                }
+            })
+            .collect()
+    }
+}
+
+fn value(key: &[u8]) -> Vec<u8> {
+    key.iter().copied().cycle().take(VALUE_LEN).collect()
+}
+
+/// Builds a trie holding the given keys.
+fn build_trie(keys: &[Vec<u8>]) -> (MemTries, StateRoot) {
+    let mut tries = MemTries::new(ShardUId::single_shard());
+    let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+    for key in keys {
+        update.insert_memtrie_only(key, FlatStateValue::Inlined(value(key))).unwrap();
+    }
+    let root = tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+    (tries, root)
+}
+
+fn num_bytes(keys: &[Vec<u8>]) -> u64 {
+    keys.iter().map(|key| (key.len() + VALUE_LEN) as u64).sum()
+}
+
+fn warmup(mut f: impl FnMut()) {
+    for _ in 0..NUM_WARMUP_ITERATIONS {
+        f();
+    }
+}
+
+fn bench_insert(bench: &mut Bencher, distribution: KeyDistribution) {
+    let keys = distribution.keys();
+    let (initial_keys, keys) = keys.split_at(NUM_KEYS);
+    let (tries, root) = build_trie(initial_keys);
+    let values: Vec<_> = keys.iter().map(|key| value(key)).collect();
+    let mut run = || {
+        let mut update = tries.update(root, TrackingMode::Refcounts).unwrap();
+        for (key, value) in keys.iter().zip(&values) {
+            update.insert(key, value.clone()).unwrap();
+        }
+        black_box(update);
+    };
+    warmup(&mut run);
+    bench.bytes = num_bytes(keys);
+    bench.iter(run);
+}
+
+fn bench_delete(bench: &mut Bencher, distribution: KeyDistribution) {
+    let keys = distribution.keys();
+    let (tries, root) = build_trie(&keys);
+    let keys = &keys[NUM_KEYS..];
+    let mut run = || {
+        let mut update = tries.update(root, TrackingMode::Refcounts).unwrap();
+        for key in keys {
+            update.delete(key).unwrap();
+        }
+        black_box(update);
+    };
+    warmup(&mut run);
+    bench.bytes = num_bytes(keys);
+    bench.iter(run);
+}
+
+fn bench_finalize(bench: &mut Bencher, distribution: KeyDistribution) {
+    let keys = distribution.keys();
+    let (initial_keys, keys) = keys.split_at(NUM_KEYS);
+    let (tries, root) = build_trie(initial_keys);
+    let values: Vec<_> = keys.iter().map(|key| value(key)).collect();
+    let mut run = || {
+        let mut update = tries.update(root, TrackingMode::Refcounts).unwrap();
+        for (key, value) in keys.iter().zip(&values) {
+            update.insert(key, value.clone()).unwrap();
+        }
+        black_box(update.to_trie_changes().unwrap());
+    };
+    warmup(&mut run);
+    bench.bytes = num_bytes(keys);
+    bench.iter(run);
+}
+
+fn memtrie_insert_random(bench: &mut Bencher) {
+    bench_insert(bench, KeyDistribution::Random);
+}
+
+fn memtrie_insert_sequential(bench: &mut Bencher) {
+    bench_insert(bench, KeyDistribution::Sequential);
+}
+
+fn memtrie_insert_clustered_prefix(bench: &mut Bencher) {
+    bench_insert(bench, KeyDistribution::ClusteredPrefix);
+}
+
+fn memtrie_delete_random(bench: &mut Bencher) {
+    bench_delete(bench, KeyDistribution::Random);
+}
+
+fn memtrie_delete_sequential(bench: &mut Bencher) {
+    bench_delete(bench, KeyDistribution::Sequential);
+}
+
+fn memtrie_delete_clustered_prefix(bench: &mut Bencher) {
+    bench_delete(bench, KeyDistribution::ClusteredPrefix);
+}
+
+fn memtrie_finalize_random(bench: &mut Bencher) {
+    bench_finalize(bench, KeyDistribution::Random);
+}
+
+fn memtrie_finalize_sequential(bench: &mut Bencher) {
+    bench_finalize(bench, KeyDistribution::Sequential);
+}
+
+fn memtrie_finalize_clustered_prefix(bench: &mut Bencher) {
+    bench_finalize(bench, KeyDistribution::ClusteredPrefix);
+}
+
+benchmark_group!(
+    benches,
+    memtrie_insert_random,
+    memtrie_insert_sequential,
+    memtrie_insert_clustered_prefix,
+    memtrie_delete_random,
+    memtrie_delete_sequential,
+    memtrie_delete_clustered_prefix,
+    memtrie_finalize_random,
+    memtrie_finalize_sequential,
+    memtrie_finalize_clustered_prefix
+);
+benchmark_main!(benches);
@@ -8,6 +8,53 @@ use crate::trie::mem::arena::ArenaMemoryMut;
 use crate::trie::mem::flexible_data::encoding::BorshFixedSize;
 use near_o11y::metrics::IntGauge;
 
+/// Allocations and deallocations made in arenas, with their requested sizes,
+/// see `AllocCounter`.
+#[cfg(test)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct AllocCounts {
+    pub allocs: usize,
+    pub alloc_bytes: usize,
+    pub deallocs: usize,
+    pub dealloc_bytes: usize,
+}
+
+#[cfg(test)]
+thread_local! {
+    /// Allocations and deallocations made by allocators on this thread so
+    /// far.
+    static ALLOC_COUNTS: std::cell::Cell<AllocCounts> =
+        std::cell::Cell::new(AllocCounts::default());
+}
+
+/// Counts the allocations and deallocations made in arenas, so that tests can
+/// assert how many each operation makes. Counts are kept per thread, so they
+/// also cover arenas owned by other structures, e.g. `MemTries`.
+#[cfg(test)]
+pub(crate) struct AllocCounter {
+    last: AllocCounts,
+}
+
+#[cfg(test)]
+impl AllocCounter {
+    pub fn start() -> Self {
+        Self { last: ALLOC_COUNTS.with(|counts| counts.get()) }
+    }
+
+    /// Returns the counts since the counter was started or since the previous
+    /// call.
+    pub fn take(&mut self) -> AllocCounts {
+        let counts = ALLOC_COUNTS.with(|counts| counts.get());
+        let last = std::mem::replace(&mut self.last, counts);
+        AllocCounts {
+            allocs: counts.allocs - last.allocs,
+            alloc_bytes: counts.alloc_bytes - last.alloc_bytes,
+            deallocs: counts.deallocs - last.deallocs,
+            dealloc_bytes: counts.dealloc_bytes - last.dealloc_bytes,
+        }
+    }
+}
+
 /// Simple bump allocator with freelists.
 ///
 /// Allocations are rounded up to its allocation class, so that deallocated
@@ -123,6 +170,13 @@ impl Allocator {
         size: usize,
     ) -> ArenaSliceMut<'a, STArenaMemory> {
         assert!(size <= MAX_ALLOC_SIZE, "Cannot allocate {} bytes", size);
+        #[cfg(test)]
+        ALLOC_COUNTS.with(|counts| {
+            let mut new_counts = counts.get();
+            new_counts.allocs += 1;
+            new_counts.alloc_bytes += size;
+            counts.set(new_counts);
+        });
         self.active_allocs_bytes += size;
         self.active_allocs_count += 1;
         self.active_allocs_bytes_gauge.set(self.active_allocs_bytes as i64);
@@ -149,6 +203,13 @@ impl Allocator {
     /// Deallocates the given slice from the arena; the slice's `pos` and `len`
     /// must be the same as an allocation that was returned earlier.
     pub fn deallocate(&mut self, memory: &mut STArenaMemory, pos: ArenaPos, len: usize) {
+        #[cfg(test)]
+        ALLOC_COUNTS.with(|counts| {
+            let mut new_counts = counts.get();
+            new_counts.deallocs += 1;
+            new_counts.dealloc_bytes += len;
+            counts.set(new_counts);
+        });
         self.active_allocs_bytes -= len;
         self.active_allocs_count -= 1;
         self.active_allocs_bytes_gauge.set(self.active_allocs_bytes as i64);
@@ -82,6 +82,8 @@ impl ArenaMemoryMut for HybridArenaMemory {
 pub struct HybridArena {
     memory: HybridArenaMemory,
     allocator: Allocator,
+    /// Optional limit on the total size of active allocations, in bytes.
+    capacity_limit: Option<usize>,
 }
 
 /// Conversion from STArena to HybridArena. We set shared memory as empty.
@@ -93,6 +95,7 @@ impl From<STArena> for HybridArena {
                 shared_memory: Arc::new(Default::default()),
             },
             allocator: arena.allocator,
+            capacity_limit: None,
         }
     }
 }
@@ -105,7 +108,7 @@ impl HybridArena {
             frozen_arena.active_allocs_bytes,
             frozen_arena.active_allocs_count,
         );
-        Self { memory: frozen_arena.memory.into(), allocator }
+        Self { memory: frozen_arena.memory.into(), allocator, capacity_limit: None }
     }
 
     /// HybridArena with an empty shared memory can be frozen. Freezing HybridArena with shared memory will panic.
@@ -122,6 +125,13 @@ impl HybridArena {
         }
     }
 
+    /// Limits the total size of active allocations. Allocating beyond the
+    /// limit panics, so callers are expected to check `remaining_capacity`
+    /// first.
+    pub fn set_capacity_limit(&mut self, capacity_limit: Option<usize>) {
+        self.capacity_limit = capacity_limit;
+    }
+
     #[inline]
     pub fn has_shared_memory(&self) -> bool {
         self.memory.chunks_offset() > 0
@@ -155,11 +165,18 @@ impl ArenaMut for HybridArena {
     }
 
     fn alloc(&mut self, size: usize) -> ArenaSliceMut<Self::Memory> {
+        if let Some(remaining_capacity) = self.remaining_capacity() {
+            assert!(size <= remaining_capacity, "Arena capacity exceeded");
+        }
         let ArenaSliceMut { mut pos, len, .. } =
             self.allocator.allocate(&mut self.memory.owned_memory, size);
         pos.chunk = pos.chunk + self.memory.chunks_offset();
         ArenaSliceMut::new(&mut self.memory, pos, len)
     }
+
+    fn remaining_capacity(&self) -> Option<usize> {
+        self.capacity_limit.map(|limit| limit.saturating_sub(self.allocator.active_allocs_bytes()))
+    }
 }
 
 impl ArenaWithDealloc for HybridArena {
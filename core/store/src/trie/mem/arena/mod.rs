@@ -14,6 +14,8 @@ mod frozen;
 pub mod hybrid;
 mod metrics;
 pub mod single_thread;
+#[cfg(test)]
+pub(crate) use alloc::{AllocCounter, AllocCounts};
 pub use frozen::FrozenArena;
 
 /// An abstraction of a read-only arena.
@@ -29,6 +31,11 @@ pub trait ArenaMut: Arena {
     fn memory_mut(&mut self) -> &mut Self::MemoryMut;
     /// Allocates a slice of the given size in the arena.
     fn alloc(&mut self, size: usize) -> ArenaSliceMut<Self::MemoryMut>;
+    /// Returns how many more bytes can be allocated, or None if the arena
+    /// capacity is not limited.
+    fn remaining_capacity(&self) -> Option<usize> {
+        None
+    }
 }
 
 /// The deallocation part of the arena interface; separated from the `Arena`
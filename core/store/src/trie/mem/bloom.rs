@@ -0,0 +1,42 @@
+use near_primitives::hash::CryptoHash;
+
+/// Number of bits set in the filter for each key.
+const NUM_HASHES: u64 = 4;
+
+/// Bloom filter over trie keys, see `MemTries::build_key_bloom`. Allows
+/// cheap negative lookups: `might_contain` never returns false for an
+/// inserted key, but may return true for a key which was never inserted.
+/// Not used for consensus.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter {
+    words: Vec<u64>,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter of the given number of bits, rounded up to a
+    /// multiple of 64.
+    pub fn new(bits: usize) -> Self {
+        Self { words: vec![0; bits.max(1).div_ceil(64)] }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in self.bit_positions(key) {
+            self.words[bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn might_contain(&self, key: &[u8]) -> bool {
+        self.bit_positions(key).all(|bit| self.words[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    /// Positions of the bits of the key, derived from a single hash by
+    /// double hashing.
+    fn bit_positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let hash = CryptoHash::hash_bytes(key);
+        let bytes = hash.as_bytes();
+        let h1 = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+        let h2 = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        let num_bits = self.words.len() as u64 * 64;
+        (0..NUM_HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+}
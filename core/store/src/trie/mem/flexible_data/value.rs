@@ -1,6 +1,7 @@
 use super::encoding::BorshFixedSize;
 use super::FlexibleDataHeader;
 use crate::trie::mem::arena::{ArenaMemory, ArenaMemoryMut, ArenaSlice, ArenaSliceMut};
+use crate::trie::ops::interface::HasValueLength;
 use crate::trie::OptimizedValueRef;
 use borsh::{BorshDeserialize, BorshSerialize};
 use near_primitives::hash::CryptoHash;
@@ -92,6 +93,13 @@ impl FlexibleDataHeader for EncodedValueHeader {
     }
 }
 
+#[cfg(test)]
+thread_local! {
+    /// Number of values materialized from their views, to check that walks
+    /// which only need keys don't read values.
+    pub(crate) static NUM_VALUE_DEREFS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
 // Efficient view of the encoded value.
 #[derive(Debug, Clone)]
 pub enum ValueView<'a> {
@@ -101,6 +109,8 @@ pub enum ValueView<'a> {
 
 impl<'a> ValueView<'a> {
     pub fn to_flat_value(&self) -> FlatStateValue {
+        #[cfg(test)]
+        NUM_VALUE_DEREFS.with(|num_derefs| num_derefs.set(num_derefs.get() + 1));
         match self {
             Self::Ref { length, hash } => {
                 FlatStateValue::Ref(ValueRef { length: *length, hash: *hash })
@@ -120,3 +130,9 @@ impl<'a> ValueView<'a> {
         }
     }
 }
+
+impl HasValueLength for ValueView<'_> {
+    fn len(&self) -> u64 {
+        ValueView::len(self) as u64
+    }
+}
@@ -0,0 +1,40 @@
+use near_primitives::hash::{hash, CryptoHash};
+
+/// Computes hashes of serialized memtrie nodes.
+///
+/// Consensus tries must use `Sha256Hasher`. Tries which never leave the node,
+/// e.g. local indexes, may plug in a faster non-cryptographic hash instead.
+/// Leaf nodes don't store their hashes and recompute them on access, so all
+/// nodes of a trie must be hashed with the same hasher, and the trie must be
+/// accessed only via methods taking the hasher explicitly.
+pub trait Hasher {
+    fn hash(data: &[u8]) -> CryptoHash;
+}
+
+/// The sha256-based hasher used for consensus tries.
+pub struct Sha256Hasher;
+
+impl Hasher for Sha256Hasher {
+    fn hash(data: &[u8]) -> CryptoHash {
+        hash(data)
+    }
+}
+
+/// A fast non-cryptographic hasher, for testing tries with a custom hasher.
+#[cfg(test)]
+pub(crate) struct FnvHasher;
+
+#[cfg(test)]
+impl Hasher for FnvHasher {
+    fn hash(data: &[u8]) -> CryptoHash {
+        let mut result = [0u8; 32];
+        for (chunk, seed) in result.chunks_mut(8).zip(0u64..) {
+            let mut state = 0xcbf29ce484222325 ^ seed;
+            for byte in data {
+                state = (state ^ *byte as u64).wrapping_mul(0x100000001b3);
+            }
+            chunk.copy_from_slice(&state.to_le_bytes());
+        }
+        CryptoHash(result)
+    }
+}
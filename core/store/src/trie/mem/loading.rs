@@ -5,7 +5,6 @@ use crate::adapter::StoreAdapter;
 use crate::flat::FlatStorageStatus;
 use crate::trie::mem::arena::Arena;
 use crate::trie::mem::construction::TrieConstructor;
-use crate::trie::mem::memtrie_update::TrackingMode;
 use crate::trie::mem::parallel_loader::load_memtrie_in_parallel;
 use crate::trie::ops::insert_delete::GenericTrieUpdateInsertDelete;
 use crate::{DBCol, NibbleSlice, Store};
@@ -156,7 +155,7 @@ pub fn load_trie_from_flat_state_and_delta(
             let old_state_root = get_state_root(store, prev_hash, shard_uid)?;
             let new_state_root = get_state_root(store, hash, shard_uid)?;
 
-            let mut trie_update = memtries.update(old_state_root, TrackingMode::None)?;
+            let mut trie_update = memtries.update_memtrie_only(old_state_root)?;
             for (key, value) in changes.0 {
                 match value {
                     Some(value) => {
@@ -166,8 +165,8 @@ pub fn load_trie_from_flat_state_and_delta(
                 };
             }
 
-            let memtrie_changes = trie_update.to_memtrie_changes_only();
-            let new_root_after_apply = memtries.apply_memtrie_changes(height, &memtrie_changes);
+            let memtrie_changes = trie_update.to_memtrie_changes_only()?;
+            let new_root_after_apply = memtries.apply_memtrie_changes(height, &memtrie_changes)?;
             assert_eq!(new_root_after_apply, new_state_root);
         }
         debug!(target: "memtrie", %shard_uid, "Applied memtrie changes for height {}", height);
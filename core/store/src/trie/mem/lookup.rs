@@ -1,9 +1,14 @@
 use super::arena::ArenaMemory;
+use super::flexible_data::children::ChildrenView;
 use super::flexible_data::value::ValueView;
 use super::metrics::MEMTRIE_NUM_LOOKUPS;
 use super::node::{MemTrieNodePtr, MemTrieNodeView};
-use crate::NibbleSlice;
-use near_primitives::hash::CryptoHash;
+use crate::{NibbleSlice, RawTrieNode, RawTrieNodeWithSize};
+use borsh::{BorshDeserialize, BorshSerialize};
+use near_primitives::hash::{hash, CryptoHash};
+use near_primitives::state::{FlatStateValue, ValueRef};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
 use std::sync::Arc;
 
 /// If `nodes_accessed` is provided, each trie node along the lookup path
@@ -13,10 +18,19 @@ use std::sync::Arc;
 pub fn memtrie_lookup<'a, M: ArenaMemory>(
     root: MemTrieNodePtr<'a, M>,
     key: &[u8],
-    mut nodes_accessed: Option<&mut Vec<(CryptoHash, Arc<[u8]>)>>,
+    nodes_accessed: Option<&mut Vec<(CryptoHash, Arc<[u8]>)>>,
 ) -> Option<ValueView<'a>> {
     MEMTRIE_NUM_LOOKUPS.inc();
-    let mut nibbles = NibbleSlice::new(key);
+    memtrie_lookup_nibbles(root, NibbleSlice::new(key), nodes_accessed)
+}
+
+/// Same as `memtrie_lookup`, but starts at an arbitrary node, with the
+/// nibbles of the key which remain after reaching it.
+fn memtrie_lookup_nibbles<'a, M: ArenaMemory>(
+    root: MemTrieNodePtr<'a, M>,
+    mut nibbles: NibbleSlice<'_>,
+    mut nodes_accessed: Option<&mut Vec<(CryptoHash, Arc<[u8]>)>>,
+) -> Option<ValueView<'a>> {
     let mut node = root;
 
     loop {
@@ -67,3 +81,757 @@ pub fn memtrie_lookup<'a, M: ArenaMemory>(
         }
     }
 }
+
+/// Returns the value of the key only if it's inlined into the trie, so that
+/// callers can skip the storage read. Returns None both if the key is absent
+/// and if its value is stored on disk as a reference.
+pub fn memtrie_get_inlined<M: ArenaMemory>(root: MemTrieNodePtr<M>, key: &[u8]) -> Option<Vec<u8>> {
+    match memtrie_lookup(root, key, None)? {
+        ValueView::Inlined(data) => Some(data.to_vec()),
+        ValueView::Ref { .. } => None,
+    }
+}
+
+/// Serialized nodes on the path from a root to the subtree covering a key
+/// prefix, see `MemTries::cache_subtree_hashes`. Proofs for keys under the
+/// prefix all start with these nodes, so `memtrie_prove` takes them from the
+/// cache and resumes the descent at the subtree. The subtree starts at the
+/// last node fully covered by the prefix; if the prefix ends in the middle
+/// of an extension, that extension is the subtree root.
+pub struct SubtreeHashCache<'a, M: ArenaMemory> {
+    root: MemTrieNodePtr<'a, M>,
+    /// Nibbles of the key leading to `subtree_root`.
+    nibbles: Vec<u8>,
+    subtree_root: MemTrieNodePtr<'a, M>,
+    /// Hashes and serialized `RawTrieNodeWithSize` of the nodes above
+    /// `subtree_root`, starting from the root.
+    path: Vec<(CryptoHash, Arc<[u8]>)>,
+}
+
+impl<'a, M: ArenaMemory> SubtreeHashCache<'a, M> {
+    pub fn new(root: MemTrieNodePtr<'a, M>, prefix: &[u8]) -> Self {
+        let mut remaining = NibbleSlice::new(prefix);
+        let mut nibbles = Vec::new();
+        let mut path = Vec::new();
+        let mut node = root;
+        loop {
+            let view = node.view();
+            let next = match &view {
+                MemTrieNodeView::Leaf { .. } => None,
+                MemTrieNodeView::Extension { extension, child, .. } => {
+                    let extension_nibbles = NibbleSlice::from_encoded(extension).0;
+                    remaining.starts_with(&extension_nibbles).then(|| {
+                        nibbles.extend(extension_nibbles.iter());
+                        (extension_nibbles.len(), *child)
+                    })
+                }
+                MemTrieNodeView::Branch { children, .. }
+                | MemTrieNodeView::BranchWithValue { children, .. } => {
+                    if remaining.is_empty() {
+                        None
+                    } else {
+                        children.get(remaining.at(0) as usize).map(|child| {
+                            nibbles.push(remaining.at(0));
+                            (1, child)
+                        })
+                    }
+                }
+            };
+            let Some((num_nibbles, child)) = next else {
+                break;
+            };
+            let raw_node_serialized = borsh::to_vec(&view.to_raw_trie_node_with_size()).unwrap();
+            path.push((view.node_hash(), raw_node_serialized.into()));
+            remaining = remaining.mid(num_nibbles);
+            node = child;
+        }
+        Self { root, nibbles, subtree_root: node, path }
+    }
+
+    /// Whether proofs for the key can use the cache.
+    fn covers(&self, key: &[u8]) -> bool {
+        let key_nibbles = NibbleSlice::new(key);
+        key_nibbles.len() >= self.nibbles.len()
+            && key_nibbles.iter().zip(&self.nibbles).all(|(nibble, cached)| nibble == *cached)
+    }
+}
+
+/// Looks up a key, returning its value together with the proof: the hash
+/// and the serialized `RawTrieNodeWithSize` of every node accessed, as
+/// `memtrie_lookup` records them. If `cache` is given for the same root and
+/// covers the key, the nodes above the cached subtree are taken from it
+/// rather than visited again. The proof is the same either way.
+pub fn memtrie_prove<'a, M: ArenaMemory>(
+    root: MemTrieNodePtr<'a, M>,
+    key: &[u8],
+    cache: Option<&SubtreeHashCache<'a, M>>,
+) -> (Option<ValueView<'a>>, Vec<(CryptoHash, Arc<[u8]>)>) {
+    MEMTRIE_NUM_LOOKUPS.inc();
+    match cache {
+        Some(cache) if cache.root == root && cache.covers(key) => {
+            let mut nodes_accessed = cache.path.clone();
+            let nibbles = NibbleSlice::new(key).mid(cache.nibbles.len());
+            let value =
+                memtrie_lookup_nibbles(cache.subtree_root, nibbles, Some(&mut nodes_accessed));
+            (value, nodes_accessed)
+        }
+        _ => {
+            let mut nodes_accessed = Vec::new();
+            let nibbles = NibbleSlice::new(key);
+            let value = memtrie_lookup_nibbles(root, nibbles, Some(&mut nodes_accessed));
+            (value, nodes_accessed)
+        }
+    }
+}
+
+/// Returns the hash of the node at which the given key terminates: the leaf
+/// with exactly this key, or the branch located at the key, whether or not
+/// it has a value. Returns None if there is no such node. Unlike the value
+/// hash, this identifies the whole subtree under the key.
+pub fn memtrie_terminal_hash<M: ArenaMemory>(
+    root: MemTrieNodePtr<'_, M>,
+    key: &[u8],
+) -> Option<CryptoHash> {
+    let mut nibbles = NibbleSlice::new(key);
+    let mut node = root;
+
+    loop {
+        let view = node.view();
+        match &view {
+            MemTrieNodeView::Leaf { extension, .. } => {
+                return (nibbles == NibbleSlice::from_encoded(extension).0)
+                    .then(|| view.node_hash());
+            }
+            MemTrieNodeView::Extension { extension, child, .. } => {
+                let extension_nibbles = NibbleSlice::from_encoded(extension).0;
+                if !nibbles.starts_with(&extension_nibbles) {
+                    return None;
+                }
+                nibbles = nibbles.mid(extension_nibbles.len());
+                node = *child;
+            }
+            MemTrieNodeView::Branch { children, .. }
+            | MemTrieNodeView::BranchWithValue { children, .. } => {
+                if nibbles.is_empty() {
+                    return Some(view.node_hash());
+                }
+                node = children.get(nibbles.at(0) as usize)?;
+                nibbles = nibbles.mid(1);
+            }
+        }
+    }
+}
+
+/// Looks up multiple keys at once, returning their values together with a
+/// single proof for all of them: the serialized `RawTrieNodeWithSize` of
+/// every node accessed during the lookups. Nodes on common paths of the keys
+/// appear in the proof only once, so it is smaller than separate proofs for
+/// each key.
+pub fn memtrie_multiprove<'a, M: ArenaMemory>(
+    root: MemTrieNodePtr<'a, M>,
+    keys: &[Vec<u8>],
+) -> (Vec<Option<FlatStateValue>>, Vec<Arc<[u8]>>) {
+    let mut nodes_accessed = Vec::new();
+    let values = keys
+        .iter()
+        .map(|key| {
+            memtrie_lookup(root, key, Some(&mut nodes_accessed)).map(|value| value.to_flat_value())
+        })
+        .collect();
+    let mut seen_hashes = HashSet::new();
+    let nodes = nodes_accessed
+        .into_iter()
+        .filter(|(hash, _)| seen_hashes.insert(*hash))
+        .map(|(_, node)| node)
+        .collect();
+    (values, nodes)
+}
+
+/// Neighbor of a key found while descending to it in `memtrie_neighbors`:
+/// either a key, or a subtree whose last or first key is the neighbor. The
+/// nibbles lead to the key or to the subtree root.
+enum Neighbor<'a, M: ArenaMemory> {
+    Key(Vec<u8>),
+    Subtree(Vec<u8>, MemTrieNodePtr<'a, M>),
+}
+
+/// Returns the largest key smaller than `key` and the smallest key larger
+/// than `key`, whether `key` is present or not, in a single descent. Either
+/// is None if there is no such key.
+pub fn memtrie_neighbors<M: ArenaMemory>(
+    root: MemTrieNodePtr<'_, M>,
+    key: &[u8],
+) -> (Option<Vec<u8>>, Option<Vec<u8>>) {
+    let mut nibbles = NibbleSlice::new(key);
+    let mut path = Vec::new();
+    let mut node = root;
+    // Neighbors found deeper along the path are closer to the key, so they
+    // replace the ones found before.
+    let mut prev = None;
+    let mut next = None;
+
+    loop {
+        let (children, has_value) = match node.view() {
+            MemTrieNodeView::Leaf { extension, .. } => {
+                let extension: Vec<u8> = NibbleSlice::from_encoded(extension).0.iter().collect();
+                let remaining: Vec<u8> = nibbles.iter().collect();
+                path.extend(&extension);
+                match extension.cmp(&remaining) {
+                    Ordering::Less => prev = Some(Neighbor::Key(path)),
+                    Ordering::Greater => next = Some(Neighbor::Key(path)),
+                    Ordering::Equal => {}
+                }
+                break;
+            }
+            MemTrieNodeView::Extension { extension, child, .. } => {
+                let extension_nibbles = NibbleSlice::from_encoded(extension).0;
+                if nibbles.starts_with(&extension_nibbles) {
+                    path.extend(extension_nibbles.iter());
+                    nibbles = nibbles.mid(extension_nibbles.len());
+                    node = child;
+                    continue;
+                }
+                let extension: Vec<u8> = extension_nibbles.iter().collect();
+                let remaining: Vec<u8> = nibbles.iter().collect();
+                path.extend(&extension);
+                if extension < remaining {
+                    prev = Some(Neighbor::Subtree(path, child));
+                } else {
+                    next = Some(Neighbor::Subtree(path, child));
+                }
+                break;
+            }
+            MemTrieNodeView::Branch { children, .. } => (children, false),
+            MemTrieNodeView::BranchWithValue { children, .. } => (children, true),
+        };
+        let child_at = |i: usize| Some(Neighbor::Subtree(child_path(&path, i), children.get(i)?));
+        if nibbles.is_empty() {
+            if let Some(neighbor) = (0..16).find_map(child_at) {
+                next = Some(neighbor);
+            }
+            break;
+        }
+        let nibble = nibbles.at(0) as usize;
+        if let Some(neighbor) = (0..nibble).rev().find_map(child_at) {
+            prev = Some(neighbor);
+        } else if has_value {
+            prev = Some(Neighbor::Key(path.clone()));
+        }
+        if let Some(neighbor) = (nibble + 1..16).find_map(child_at) {
+            next = Some(neighbor);
+        }
+        let Some(child) = children.get(nibble) else {
+            break;
+        };
+        path.push(nibble as u8);
+        nibbles = nibbles.mid(1);
+        node = child;
+    }
+
+    let to_key = |neighbor: Neighbor<M>, last: bool| {
+        let nibbles = match neighbor {
+            Neighbor::Key(nibbles) => nibbles,
+            Neighbor::Subtree(nibbles, node) => subtree_end_nibbles(nibbles, node, last),
+        };
+        NibbleSlice::nibbles_to_bytes(&nibbles)
+    };
+    (prev.map(|neighbor| to_key(neighbor, true)), next.map(|neighbor| to_key(neighbor, false)))
+}
+
+fn child_path(path: &[u8], nibble: usize) -> Vec<u8> {
+    let mut child_path = path.to_vec();
+    child_path.push(nibble as u8);
+    child_path
+}
+
+/// Returns the nibbles of the last key or of the first key of the subtree
+/// located at `path`.
+fn subtree_end_nibbles<M: ArenaMemory>(
+    mut path: Vec<u8>,
+    mut node: MemTrieNodePtr<'_, M>,
+    last: bool,
+) -> Vec<u8> {
+    loop {
+        match node.view() {
+            MemTrieNodeView::Leaf { extension, .. } => {
+                path.extend(NibbleSlice::from_encoded(extension).0.iter());
+                return path;
+            }
+            MemTrieNodeView::Extension { extension, child, .. } => {
+                path.extend(NibbleSlice::from_encoded(extension).0.iter());
+                node = child;
+            }
+            MemTrieNodeView::BranchWithValue { .. } if !last => return path,
+            MemTrieNodeView::Branch { children, .. }
+            | MemTrieNodeView::BranchWithValue { children, .. } => {
+                let mut indices = 0..16;
+                let index = if last {
+                    indices.rev().find(|i| children.get(*i).is_some())
+                } else {
+                    indices.find(|i| children.get(*i).is_some())
+                };
+                let Some(index) = index else {
+                    // Only a branch with a value and no children, which is
+                    // not canonical, may get here.
+                    return path;
+                };
+                path.push(index as u8);
+                node = children.get(index).unwrap();
+            }
+        }
+    }
+}
+
+/// Position of a resumable scan of the trie, see `memtrie_scan_from_token`.
+/// It holds the nibble path of the next node to visit, as encoded in trie
+/// nodes, so it may point into the middle of a byte of the key. Tokens are
+/// plain data and may be persisted, e.g. across restarts of state sync.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ScanToken {
+    encoded_nibbles: Vec<u8>,
+}
+
+impl ScanToken {
+    /// Token of the first entry of the trie.
+    pub fn start() -> Self {
+        Self::from_nibbles(&[])
+    }
+
+    fn from_nibbles(nibbles: &[u8]) -> Self {
+        Self { encoded_nibbles: NibbleSlice::encode_nibbles(nibbles, false).into_vec() }
+    }
+
+    fn nibbles(&self) -> Vec<u8> {
+        if self.encoded_nibbles.is_empty() {
+            return Vec::new();
+        }
+        NibbleSlice::from_encoded(&self.encoded_nibbles).0.iter().collect()
+    }
+}
+
+/// Returns up to `limit` entries of the trie at the position of the token or
+/// after it, in key order, together with the token to continue from, or None
+/// if the scan is finished. The pages only depend on the trie and the token.
+pub fn memtrie_scan_from_token<M: ArenaMemory>(
+    root: MemTrieNodePtr<'_, M>,
+    token: &ScanToken,
+    limit: usize,
+) -> (Vec<(Vec<u8>, FlatStateValue)>, Option<ScanToken>) {
+    let start = token.nibbles();
+    let mut entries = Vec::new();
+    // Nodes to visit along with their key nibbles, the next one on top.
+    let mut stack = vec![(Vec::new(), root)];
+    while let Some((mut nibbles, node)) = stack.pop() {
+        // Skip subtrees with all keys less than the start.
+        let len = nibbles.len().min(start.len());
+        if nibbles[..len] < start[..len] {
+            continue;
+        }
+        // Keys less than `nibbles` were visited already, so the next scan
+        // can start right at the node.
+        if entries.len() == limit {
+            return (entries, Some(ScanToken::from_nibbles(&nibbles.max(start))));
+        }
+        match node.view() {
+            MemTrieNodeView::Leaf { extension, value } => {
+                nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+                if nibbles >= start {
+                    entries.push((NibbleSlice::nibbles_to_bytes(&nibbles), value.to_flat_value()));
+                }
+            }
+            MemTrieNodeView::Extension { extension, child, .. } => {
+                nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+                stack.push((nibbles, child));
+            }
+            MemTrieNodeView::Branch { children, .. } => {
+                push_children_with_nibbles(&mut stack, &nibbles, children);
+            }
+            MemTrieNodeView::BranchWithValue { children, value, .. } => {
+                if nibbles >= start {
+                    entries.push((NibbleSlice::nibbles_to_bytes(&nibbles), value.to_flat_value()));
+                }
+                push_children_with_nibbles(&mut stack, &nibbles, children);
+            }
+        }
+    }
+    (entries, None)
+}
+
+/// Pushes the children of a branch at `nibbles` to the stack of a walk in key
+/// order, so that the first child ends up on top.
+pub(super) fn push_children_with_nibbles<'a, M: ArenaMemory>(
+    stack: &mut Vec<(Vec<u8>, MemTrieNodePtr<'a, M>)>,
+    nibbles: &[u8],
+    children: ChildrenView<'a, M>,
+) {
+    for i in (0..16).rev() {
+        if let Some(child) = children.get(i) {
+            let mut child_nibbles = nibbles.to_vec();
+            child_nibbles.push(i as u8);
+            stack.push((child_nibbles, child));
+        }
+    }
+}
+
+/// Checks that the proof, a set of serialized `RawTrieNodeWithSize` as
+/// produced by `memtrie_lookup` or `memtrie_multiprove`, shows that the key
+/// has the given value under the given state root, or is absent if `value`
+/// is None. Returns false if the proof is invalid or lacks nodes on the path
+/// to the key.
+pub fn verify_memtrie_proof(
+    root: CryptoHash,
+    key: &[u8],
+    value: Option<&[u8]>,
+    proof: &[Arc<[u8]>],
+) -> bool {
+    let expected = value.map(ValueRef::new);
+    if root == CryptoHash::default() {
+        return expected.is_none();
+    }
+    let nodes: HashMap<CryptoHash, &[u8]> =
+        proof.iter().map(|node| (hash(node), &**node)).collect();
+    let mut nibbles = NibbleSlice::new(key);
+    let mut node_hash = root;
+
+    loop {
+        let Some(Ok(node)) =
+            nodes.get(&node_hash).map(|node| borsh::from_slice::<RawTrieNodeWithSize>(node))
+        else {
+            return false;
+        };
+        let (children, branch_value) = match node.node {
+            RawTrieNode::Leaf(extension, value) => {
+                let found = nibbles == NibbleSlice::from_encoded(&extension).0;
+                return found.then_some(value) == expected;
+            }
+            RawTrieNode::Extension(extension, child) => {
+                let extension_nibbles = NibbleSlice::from_encoded(&extension).0;
+                if !nibbles.starts_with(&extension_nibbles) {
+                    return expected.is_none();
+                }
+                nibbles = nibbles.mid(extension_nibbles.len());
+                node_hash = child;
+                continue;
+            }
+            RawTrieNode::BranchNoValue(children) => (children, None),
+            RawTrieNode::BranchWithValue(value, children) => (children, Some(value)),
+        };
+        if nibbles.is_empty() {
+            return branch_value == expected;
+        }
+        let Some(child) = children[nibbles.at(0)] else {
+            return expected.is_none();
+        };
+        nibbles = nibbles.mid(1);
+        node_hash = child;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        memtrie_get_inlined, memtrie_lookup, memtrie_multiprove, memtrie_neighbors, memtrie_prove,
+        memtrie_scan_from_token, memtrie_terminal_hash, verify_memtrie_proof, ScanToken,
+    };
+    use crate::trie::mem::memtrie_update::UpdatedMemTrieNode;
+    use crate::trie::mem::memtries::MemTries;
+    use crate::{KeyLookupMode, PartialStorage, Trie};
+    use near_primitives::challenge::PartialState;
+    use near_primitives::hash::{hash, CryptoHash};
+    use near_primitives::shard_layout::ShardUId;
+    use near_primitives::state::FlatStateValue;
+    use rand::Rng;
+    use std::collections::{BTreeSet, HashSet};
+
+    #[test]
+    fn test_memtrie_multiprove() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for key in [vec![0x12, 0x34], vec![0x12, 0x35], vec![0x12, 0x46], vec![0x90]] {
+            let value = FlatStateValue::Inlined(key.iter().rev().copied().collect());
+            update.insert_memtrie_only(&key, value).unwrap();
+        }
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let state_root = tries.apply_memtrie_changes(0, &changes).unwrap();
+        let root = tries.get_root(&state_root).unwrap();
+
+        // Keys sharing a prefix, plus one missing key.
+        let keys = vec![vec![0x12, 0x34], vec![0x12, 0x35], vec![0x12, 0x46], vec![0x12, 0x47]];
+        let (values, nodes) = memtrie_multiprove(root, &keys);
+        assert_eq!(
+            values,
+            vec![
+                Some(FlatStateValue::Inlined(vec![0x34, 0x12])),
+                Some(FlatStateValue::Inlined(vec![0x35, 0x12])),
+                Some(FlatStateValue::Inlined(vec![0x46, 0x12])),
+                None,
+            ]
+        );
+
+        // Shared nodes appear once, so the proof is smaller than separate ones.
+        let hashes: HashSet<_> = nodes.iter().map(|node| hash(node)).collect();
+        assert_eq!(hashes.len(), nodes.len());
+        assert!(hashes.contains(&state_root));
+        let mut num_nodes_in_single_proofs = 0;
+        for key in &keys {
+            let mut nodes_accessed = Vec::new();
+            memtrie_lookup(root, key, Some(&mut nodes_accessed));
+            num_nodes_in_single_proofs += nodes_accessed.len();
+        }
+        assert!(nodes.len() < num_nodes_in_single_proofs);
+
+        // The proof is enough to look up all keys from the state root.
+        let trie = Trie::from_recorded_storage(
+            PartialStorage { nodes: PartialState::TrieValues(nodes) },
+            state_root,
+            false,
+        );
+        for (key, value) in keys.iter().zip(values) {
+            let value_ref = trie
+                .get_optimized_ref(key, KeyLookupMode::Trie)
+                .unwrap()
+                .map(|value_ref| value_ref.into_value_ref());
+            assert_eq!(value_ref, value.map(|value| value.to_value_ref()));
+        }
+    }
+
+    #[test]
+    fn test_verify_memtrie_proof() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for key in [vec![0x12, 0x34], vec![0x12, 0x35], vec![0x12], vec![0x90]] {
+            let value = FlatStateValue::on_disk(&key.iter().rev().copied().collect::<Vec<_>>());
+            update.insert_memtrie_only(&key, value).unwrap();
+        }
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let state_root = tries.apply_memtrie_changes(0, &changes).unwrap();
+        let root = tries.get_root(&state_root).unwrap();
+        let prove = |key: &[u8]| {
+            let mut nodes_accessed = Vec::new();
+            memtrie_lookup(root, key, Some(&mut nodes_accessed));
+            nodes_accessed.into_iter().map(|(_, node)| node).collect::<Vec<_>>()
+        };
+
+        // Inclusion, for values in a leaf and in a branch.
+        let proof = prove(&[0x12, 0x34]);
+        assert!(verify_memtrie_proof(state_root, &[0x12, 0x34], Some(&[0x34, 0x12]), &proof));
+        assert!(!verify_memtrie_proof(state_root, &[0x12, 0x34], Some(&[0x35, 0x12]), &proof));
+        assert!(!verify_memtrie_proof(state_root, &[0x12, 0x34], None, &proof));
+        let proof = prove(&[0x12]);
+        assert!(verify_memtrie_proof(state_root, &[0x12], Some(&[0x12]), &proof));
+
+        // Exclusion, ending at a missing child, inside an extension or a leaf.
+        for key in [vec![0x12, 0x36], vec![0x10], vec![0x90, 0x00], vec![0x91]] {
+            let proof = prove(&key);
+            assert!(verify_memtrie_proof(state_root, &key, None, &proof));
+            assert!(!verify_memtrie_proof(state_root, &key, Some(&[0x00]), &proof));
+        }
+
+        // A multiproof verifies each of its keys.
+        let keys = vec![vec![0x12, 0x35], vec![0x90]];
+        let (_, proof) = memtrie_multiprove(root, &keys);
+        assert!(verify_memtrie_proof(state_root, &keys[0], Some(&[0x35, 0x12]), &proof));
+        assert!(verify_memtrie_proof(state_root, &keys[1], Some(&[0x90]), &proof));
+
+        // Tampered, incomplete and mismatching proofs.
+        let mut proof = prove(&[0x12, 0x34]);
+        let mut tampered = proof.last().unwrap().to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        *proof.last_mut().unwrap() = tampered.into();
+        assert!(!verify_memtrie_proof(state_root, &[0x12, 0x34], Some(&[0x34, 0x12]), &proof));
+        let mut proof = prove(&[0x12, 0x34]);
+        proof.remove(0);
+        assert!(!verify_memtrie_proof(state_root, &[0x12, 0x34], Some(&[0x34, 0x12]), &proof));
+        let proof = prove(&[0x90]);
+        assert!(!verify_memtrie_proof(state_root, &[0x12, 0x34], Some(&[0x34, 0x12]), &proof));
+        let other_root = CryptoHash::hash_bytes(&[1]);
+        assert!(!verify_memtrie_proof(other_root, &[0x90], Some(&[0x90]), &proof));
+
+        // The empty trie has no keys.
+        assert!(verify_memtrie_proof(CryptoHash::default(), &[0x90], None, &[]));
+        assert!(!verify_memtrie_proof(CryptoHash::default(), &[0x90], Some(&[0x90]), &[]));
+    }
+
+    #[test]
+    fn test_memtrie_terminal_hash() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for key in [vec![0x12, 0x34], vec![0x12, 0x35], vec![0x12], vec![0x90]] {
+            let value = FlatStateValue::Inlined(key.iter().rev().copied().collect());
+            update.insert_memtrie_only(&key, value).unwrap();
+        }
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let state_root = tries.apply_memtrie_changes(0, &changes).unwrap();
+        let root = tries.get_root(&state_root).unwrap();
+
+        // The only leaf with value [0x90] is the one for key [0x90].
+        let leaf_hash = changes
+            .node_ids_with_hashes
+            .iter()
+            .find_map(|(node_id, node_hash)| {
+                match &changes.updated_nodes[*node_id].as_ref().unwrap().node {
+                    UpdatedMemTrieNode::Leaf { value, .. }
+                        if value == &FlatStateValue::Inlined(vec![0x90]) =>
+                    {
+                        Some(*node_hash)
+                    }
+                    _ => None,
+                }
+            })
+            .unwrap();
+        assert_eq!(memtrie_terminal_hash(root, &[0x90]), Some(leaf_hash));
+        assert_ne!(leaf_hash, FlatStateValue::Inlined(vec![0x90]).to_value_ref().hash);
+
+        // Key [0x12] ends at a branch with a value, the root is a branch too.
+        let branch_hash = memtrie_terminal_hash(root, &[0x12]).unwrap();
+        assert_ne!(branch_hash, state_root);
+        assert_eq!(memtrie_terminal_hash(root, &[]), Some(state_root));
+
+        // Missing keys, including the ones ending inside an extension or a leaf.
+        assert_eq!(memtrie_terminal_hash(root, &[0x12, 0x36]), None);
+        assert_eq!(memtrie_terminal_hash(root, &[0x12, 0x30]), None);
+        assert_eq!(memtrie_terminal_hash(root, &[0x10]), None);
+        assert_eq!(memtrie_terminal_hash(root, &[0x90, 0x00]), None);
+        assert_eq!(memtrie_terminal_hash(root, &[0x91]), None);
+    }
+
+    #[test]
+    fn test_memtrie_prove_with_subtree_cache() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        let mut keys = Vec::new();
+        for i in 0..100u8 {
+            for prefix in [vec![0x12, 0x34], vec![0x12, 0x35], vec![0x56]] {
+                let mut key = prefix;
+                key.push(i.wrapping_mul(37));
+                update.insert_memtrie_only(&key, FlatStateValue::Inlined(vec![i])).unwrap();
+                keys.push(key);
+            }
+        }
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let state_root = tries.apply_memtrie_changes(0, &changes).unwrap();
+        let root = tries.get_root(&state_root).unwrap();
+
+        // Prefixes ending at a branch, inside an extension, at a leaf and
+        // outside of the trie, plus a missing key under the prefix.
+        for prefix in [vec![0x12, 0x34], vec![0x12], vec![0x12, 0x34, 0x00], vec![0x99]] {
+            let cache = tries.cache_subtree_hashes(&state_root, &prefix).unwrap();
+            let mut missing_key = prefix.clone();
+            missing_key.extend([0xff, 0xff]);
+            for key in keys.iter().chain([&missing_key, &vec![0x56, 0x00]]) {
+                let (value, proof) = memtrie_prove(root, key, None);
+                let (cached_value, cached_proof) = memtrie_prove(root, key, Some(&cache));
+                assert_eq!(
+                    cached_value.map(|value| value.to_flat_value()),
+                    value.map(|value| value.to_flat_value())
+                );
+                assert_eq!(cached_proof, proof);
+            }
+        }
+    }
+
+    #[test]
+    fn test_memtrie_neighbors() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..20 {
+            let mut tries = MemTries::new(ShardUId::single_shard());
+            let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+            let mut keys = BTreeSet::new();
+            // Few distinct bytes, so that keys are often prefixes of others.
+            let random_key = |rng: &mut rand::rngs::ThreadRng| -> Vec<u8> {
+                let key_len = rng.gen_range(0..4);
+                (0..key_len).map(|_| rng.gen_range(0..3) * 0x11).collect()
+            };
+            for _ in 0..rng.gen_range(1..30) {
+                let key = random_key(&mut rng);
+                update.insert_memtrie_only(&key, FlatStateValue::Inlined(vec![1])).unwrap();
+                keys.insert(key);
+            }
+            let state_root =
+                tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+            let root = tries.get_root(&state_root).unwrap();
+
+            let first = keys.first().unwrap().clone();
+            let last = keys.last().unwrap().clone();
+            assert_eq!(memtrie_neighbors(root, &first).0, None);
+            assert_eq!(memtrie_neighbors(root, &last).1, None);
+            // Present and absent keys, including ones outside of the range of
+            // keys.
+            let probes: Vec<Vec<u8>> = keys
+                .iter()
+                .cloned()
+                .chain((0..50).map(|_| random_key(&mut rng)))
+                .chain([vec![0xff], vec![0x00, 0x00, 0x00, 0x00]])
+                .collect();
+            for key in probes {
+                let expected_prev = keys.range(..key.clone()).next_back().cloned();
+                let mut greater = keys.range(key.clone()..).skip_while(|other| **other == key);
+                let expected_next = greater.next().cloned();
+                assert_eq!(
+                    memtrie_neighbors(root, &key),
+                    (expected_prev, expected_next),
+                    "key {:?} in {:?}",
+                    key,
+                    keys
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_memtrie_scan_from_token() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        let mut entries = Vec::new();
+        // Keys which are prefixes of others are stored at branches.
+        for i in 0..200u32 {
+            let key = (i * 7919 % 1000).to_be_bytes()[(i % 3) as usize + 1..].to_vec();
+            let value = FlatStateValue::Inlined(key.iter().rev().copied().collect());
+            update.insert_memtrie_only(&key, value.clone()).unwrap();
+            entries.push((key, value));
+        }
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries.dedup_by(|a, b| a.0 == b.0);
+        let state_root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+        let root = tries.get_root(&state_root).unwrap();
+
+        for limit in [1, 7, entries.len(), entries.len() + 1] {
+            let mut scanned = Vec::new();
+            let mut token = Some(ScanToken::start());
+            while let Some(current) = token {
+                let page = memtrie_scan_from_token(root, &current, limit);
+                assert_eq!(memtrie_scan_from_token(root, &current, limit), page);
+                let (page_entries, next) = page;
+                assert!(page_entries.len() <= limit);
+                assert!(next.is_none() || page_entries.len() == limit);
+                scanned.extend(page_entries);
+                token = next.map(|next| {
+                    let bytes = borsh::to_vec(&next).unwrap();
+                    let decoded: ScanToken = borsh::from_slice(&bytes).unwrap();
+                    assert_eq!(decoded, next);
+                    decoded
+                });
+            }
+            assert_eq!(scanned, entries);
+        }
+    }
+
+    #[test]
+    fn test_memtrie_get_inlined() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        let inlined_value = vec![1, 2, 3];
+        let on_disk_value = vec![4; FlatStateValue::INLINE_DISK_VALUE_THRESHOLD + 1];
+        update.insert_memtrie_only(&[0x10], FlatStateValue::on_disk(&inlined_value)).unwrap();
+        update.insert_memtrie_only(&[0x10, 0x00], FlatStateValue::on_disk(&on_disk_value)).unwrap();
+        let state_root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+        let root = tries.get_root(&state_root).unwrap();
+
+        assert_eq!(memtrie_get_inlined(root, &[0x10]), Some(inlined_value));
+        assert_eq!(memtrie_get_inlined(root, &[0x10, 0x00]), None);
+        assert!(memtrie_lookup(root, &[0x10, 0x00], None).is_some());
+        assert_eq!(memtrie_get_inlined(root, &[0x20]), None);
+    }
+}
@@ -1,8 +1,20 @@
-use std::collections::{BTreeMap, HashMap};
+use std::borrow::Cow;
+#[cfg(feature = "memtrie_timing")]
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::sync::Arc;
+#[cfg(feature = "memtrie_timing")]
+use std::time::{Duration, Instant};
 
+use borsh::{BorshDeserialize, BorshSerialize};
+use near_primitives::challenge::PartialState;
 use near_primitives::errors::StorageError;
 use near_primitives::hash::{hash, CryptoHash};
-use near_primitives::state::FlatStateValue;
+use near_primitives::shard_layout::ShardUId;
+use near_primitives::state::{FlatStateValue, ValueRef};
 use near_primitives::types::AccountId;
 
 use crate::trie::ops::insert_delete::GenericTrieUpdateInsertDelete;
@@ -11,14 +23,20 @@ use crate::trie::ops::interface::{
     GenericTrieValue, GenericUpdatedTrieNode, GenericUpdatedTrieNodeWithSize, UpdatedNodeId,
 };
 use crate::trie::ops::resharding::{GenericTrieUpdateRetain, RetainMode};
+use crate::trie::ops::squash::GenericTrieUpdateSquash;
 use crate::trie::trie_recording::TrieRecorder;
-use crate::trie::{Children, MemTrieChanges, TrieRefcountDeltaMap};
-use crate::{RawTrieNode, RawTrieNodeWithSize, TrieChanges};
+use crate::trie::trie_storage_update::TrieStorageUpdate;
+use crate::trie::{Children, MemTrieChanges, TrieRefcountDeltaMap, ValueHandle};
+use crate::{NibbleSlice, RawTrieNode, RawTrieNodeWithSize, TrieChanges};
 
-use super::arena::{ArenaMemory, ArenaMut};
+use super::arena::{ArenaMemory, ArenaMut, ArenaPos};
 use super::flexible_data::children::ChildrenView;
-use super::metrics::MEMTRIE_NUM_NODES_CREATED_FROM_UPDATES;
-use super::node::{InputMemTrieNode, MemTrieNodeId, MemTrieNodeView};
+use super::hasher::{Hasher, Sha256Hasher};
+use super::lookup::memtrie_lookup;
+#[cfg(feature = "memtrie_timing")]
+use super::metrics::MEM_TRIE_UPDATE_PHASE_SECONDS;
+use super::metrics::{MEMTRIE_NUM_NODES_CREATED_FROM_UPDATES, MEM_TRIE_SQUASH_OPS};
+use super::node::{InputMemTrieNode, MemTrieNodeId, MemTrieNodePtr, MemTrieNodeView, NodeKind};
 
 pub type OldOrUpdatedNodeId = GenericNodeOrIndex<MemTrieNodeId>;
 
@@ -30,6 +48,17 @@ pub type MemTrieNodeWithSize = GenericTrieNodeWithSize<MemTrieNodeId, FlatStateV
 
 pub type UpdatedMemTrieNodeWithSize = GenericUpdatedTrieNodeWithSize<MemTrieNodeId, FlatStateValue>;
 
+/// Hashes and serialized forms of nodes computed by previous updates, see
+/// `MemTrieUpdate::compute_hashes_and_serialized_nodes`.
+pub type NodeHashCache = HashMap<RawTrieNodeWithSize, (CryptoHash, Vec<u8>)>;
+
+#[cfg(test)]
+thread_local! {
+    /// Number of nodes serialized and hashed when computing hashes of updated
+    /// nodes, to check that cached nodes are skipped.
+    static NUM_NODES_HASHED: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
 impl MemTrieNodeWithSize {
     /// Converts an existing in-memory trie node into an updated one that is
     /// equivalent.
@@ -37,21 +66,26 @@ impl MemTrieNodeWithSize {
         let memory_usage = view.memory_usage();
         let node = match view {
             MemTrieNodeView::Leaf { extension, value } => MemTrieNode::Leaf {
-                extension: extension.to_vec().into_boxed_slice(),
+                extension: extension.into(),
                 value: value.to_flat_value(),
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value: None,
             },
             MemTrieNodeView::Branch { children, .. } => MemTrieNode::Branch {
                 children: Box::new(Self::convert_children_to_updated(children)),
                 value: None,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value: None,
             },
             MemTrieNodeView::BranchWithValue { children, value, .. } => MemTrieNode::Branch {
                 children: Box::new(Self::convert_children_to_updated(children)),
                 value: Some(value.to_flat_value()),
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value: None,
             },
-            MemTrieNodeView::Extension { extension, child, .. } => MemTrieNode::Extension {
-                extension: extension.to_vec().into_boxed_slice(),
-                child: child.id(),
-            },
+            MemTrieNodeView::Extension { extension, child, .. } => {
+                MemTrieNode::Extension { extension: extension.into(), child: child.id() }
+            }
         };
         Self { node, memory_usage }
     }
@@ -69,11 +103,48 @@ impl MemTrieNodeWithSize {
     }
 }
 
+impl UpdatedMemTrieNode {
+    /// Converts the node to its on-disk form. Children may be either old or
+    /// updated nodes, so their hashes are resolved by `child_hash`.
+    ///
+    /// Panics if the node is empty, as empty nodes have no on-disk form.
+    pub fn to_raw_trie_node(
+        &self,
+        mut child_hash: impl FnMut(OldOrUpdatedNodeId) -> CryptoHash,
+    ) -> RawTrieNode {
+        match self {
+            UpdatedMemTrieNode::Empty => unreachable!("Empty node has no on-disk form"),
+            UpdatedMemTrieNode::Branch { children, value, .. } => {
+                let mut child_hashes = vec![];
+                for child in children.iter() {
+                    match child {
+                        Some(child) => {
+                            child_hashes.push(Some(child_hash(*child)));
+                        }
+                        None => {
+                            child_hashes.push(None);
+                        }
+                    }
+                }
+                let children = Children(child_hashes.as_slice().try_into().unwrap());
+                let value_ref = value.as_ref().map(|value| value.to_value_ref());
+                RawTrieNode::branch(children, value_ref)
+            }
+            UpdatedMemTrieNode::Extension { extension, child } => {
+                RawTrieNode::Extension(extension.to_vec(), child_hash(*child))
+            }
+            UpdatedMemTrieNode::Leaf { extension, value, .. } => {
+                RawTrieNode::Leaf(extension.to_vec(), value.to_value_ref())
+            }
+        }
+    }
+}
+
 /// Allows using in-memory tries to construct the trie node changes entirely
 /// (for both in-memory and on-disk updates) because it's much faster.
+/// Updates which don't need on-disk changes don't track anything, see
+/// `MemOnly`.
 pub enum TrackingMode<'a> {
-    /// Don't track any nodes.
-    None,
     /// Track disk refcount changes for trie nodes.
     Refcounts,
     /// Track disk refcount changes and record all accessed trie nodes.
@@ -85,6 +156,26 @@ pub enum TrackingMode<'a> {
     RefcountsAndAccesses(&'a mut TrieRecorder),
 }
 
+/// Trie nodes and values accessed by an operation, keyed by their hashes.
+/// These are what a state witness must contain for the operation to be
+/// replayed on it.
+#[derive(Default, Debug, PartialEq, Eq)]
+pub struct TrieAccesses {
+    /// Serialized accessed nodes.
+    pub nodes: HashMap<CryptoHash, Arc<[u8]>>,
+    /// Accessed values. Values which are not inlined in the memtrie are only
+    /// known by reference, so their bytes must be read from the disk.
+    pub values: HashMap<CryptoHash, FlatStateValue>,
+}
+
+/// Type state of a `MemTrieUpdate` which produces changes for the in-memory
+/// trie only.
+pub struct MemOnly;
+
+/// Type state of a `MemTrieUpdate` which also tracks on-disk changes, so
+/// that it can produce full `TrieChanges`.
+pub struct Tracking;
+
 /// Tracks intermediate trie changes, final version of which is to be committed
 /// to disk after finishing trie update.
 struct TrieChangesTracker<'a> {
@@ -100,14 +191,138 @@ struct TrieChangesTracker<'a> {
     /// as node or value of the same hash can be removed and inserted for the
     /// same update in different parts of trie!
     recorder: Option<&'a mut TrieRecorder>,
+    /// Hashes of values resolved by lookups, as opposed to the values which
+    /// were written.
+    read_value_hashes: BTreeSet<CryptoHash>,
+    /// References to values whose bytes are owned by another shard. They are
+    /// not part of `refcount_inserted_values`, as their bytes are not stored
+    /// by this shard, see `ShardTries::apply_shared_value_refcounts`.
+    shared_value_refcounts: BTreeMap<CryptoHash, SharedValueRefcount>,
+}
+
+/// Number of references added by an update to a value whose bytes are owned
+/// by another shard, see `MemTrieUpdate::insert_shared_value`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedValueRefcount {
+    pub origin_shard: ShardUId,
+    pub rc: u32,
+}
+
+/// Value whose disk refcount reached zero during an update, see
+/// `MemTrieUpdateBuilder::with_zero_crossings`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZeroCrossing {
+    /// Key of the operation which removed the last reference, or None if it
+    /// is not known, e.g. for `clear`.
+    pub key: Option<Vec<u8>>,
+    pub value_hash: CryptoHash,
+}
+
+/// For debugging only: records the values whose disk refcount reached zero
+/// during an update, even if they were re-added later.
+#[derive(Default)]
+struct ZeroCrossingTracker {
+    /// Key of the operation being performed, if known.
+    current_key: Option<Vec<u8>>,
+    /// Net refcount change of each value so far.
+    value_deltas: HashMap<CryptoHash, i64>,
+    zero_crossings: Vec<ZeroCrossing>,
+}
+
+/// Original nodes recorded by a `MemTrieUpdate` in witness mode, see
+/// `MemTrieUpdateBuilder::with_witness_mode`.
+#[derive(Default)]
+struct WitnessNodes {
+    hashes: HashSet<CryptoHash>,
+    /// Serialized nodes, in the order of their first access.
+    nodes: Vec<Arc<[u8]>>,
+}
+
+impl WitnessNodes {
+    fn record<M: ArenaMemory>(&mut self, node: &MemTrieNodeView<'_, M>) {
+        if self.hashes.insert(node.node_hash()) {
+            self.nodes.push(borsh::to_vec(&node.to_raw_trie_node_with_size()).unwrap().into());
+        }
+    }
+}
+
+impl ZeroCrossingTracker {
+    fn add_delta(&mut self, value_hash: CryptoHash, delta: i64) {
+        let rc = self.value_deltas.entry(value_hash).or_default();
+        let was_referenced = *rc >= 0;
+        *rc += delta;
+        if was_referenced && *rc < 0 {
+            self.zero_crossings.push(ZeroCrossing { key: self.current_key.clone(), value_hash });
+        }
+    }
+}
+
+/// Cost category of reading a value, see `MemTrieUpdate::get_ref_with_cost`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReadCostClass {
+    /// The value is inlined into the trie, so reading it is cheap.
+    Inlined,
+    /// Only a reference is stored in the trie, so the value has to be read
+    /// from disk separately.
+    Referenced,
+}
+
+impl ReadCostClass {
+    pub fn of(value: &FlatStateValue) -> Self {
+        match value {
+            FlatStateValue::Inlined(_) => Self::Inlined,
+            FlatStateValue::Ref(_) => Self::Referenced,
+        }
+    }
+}
+
+/// Key-value operation performed on a `MemTrieUpdate`, as logged when
+/// enabled by `MemTrieUpdateBuilder::with_recorded_ops`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemTrieUpdateOp {
+    Insert {
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    InsertMemtrieOnly {
+        key: Vec<u8>,
+        value: FlatStateValue,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    DeleteRange {
+        start: Vec<u8>,
+        end: Vec<u8>,
+    },
+    Clear,
+    /// Moves the value of `old_key` to `new_key`, see `MemTrieUpdate::rekey`.
+    Rekey {
+        old_key: Vec<u8>,
+        new_key: Vec<u8>,
+    },
+    /// Adds disk refcounts to existing values without changing the trie,
+    /// see `MemTrieUpdate::touch_values`.
+    TouchValues {
+        keys: Vec<Vec<u8>>,
+    },
 }
 
 impl<'a> TrieChangesTracker<'a> {
+    fn new(mode: TrackingMode<'a>) -> Self {
+        match mode {
+            TrackingMode::Refcounts => Self::with_recorder(None),
+            TrackingMode::RefcountsAndAccesses(recorder) => Self::with_recorder(Some(recorder)),
+        }
+    }
+
     fn with_recorder(recorder: Option<&'a mut TrieRecorder>) -> Self {
         Self {
             refcount_deleted_hashes: BTreeMap::new(),
             refcount_inserted_values: BTreeMap::new(),
             recorder,
+            read_value_hashes: BTreeSet::new(),
+            shared_value_refcounts: BTreeMap::new(),
         }
     }
 
@@ -134,7 +349,10 @@ impl<'a> TrieChangesTracker<'a> {
 }
 
 /// Structure to build an update to the in-memory trie.
-pub struct MemTrieUpdate<'a, M: ArenaMemory> {
+///
+/// The type state `S` says whether on-disk changes are tracked: `MemOnly`
+/// updates can't be converted to `TrieChanges`. All the mutations are shared.
+pub struct MemTrieUpdate<'a, M: ArenaMemory, S> {
     /// The original root before updates. It is None iff the original trie had no keys.
     root: Option<MemTrieNodeId>,
     memory: &'a M,
@@ -146,10 +364,526 @@ pub struct MemTrieUpdate<'a, M: ArenaMemory> {
     /// Tracks trie changes necessary to make on-disk updates and recorded
     /// storage.
     nodes_tracker: Option<TrieChangesTracker<'a>>,
+    /// If set, `delete` fails on keys which are absent from the trie instead
+    /// of doing nothing.
+    strict_deletes: bool,
+    /// Nodes left without a value by `prepare_split`. They are squashed when
+    /// the update is finalized, unless a value was inserted there since.
+    prepared_splits: Vec<UpdatedNodeId>,
+    /// Original nodes which updated nodes were converted from. An original
+    /// node may be referenced from several positions when identical subtrees
+    /// share it, so each reference gets its own conversion.
+    converted_from: HashMap<UpdatedNodeId, MemTrieNodeId>,
+    /// Optional features enabled by `MemTrieUpdateBuilder`. None if none is,
+    /// so that plain updates don't pay for them.
+    instrumentation: Option<Box<UpdateInstrumentation>>,
+    /// Taken nodes which are degenerate branches. Only these, and nodes
+    /// placed by `place_unsquashed_node_at`, may be placed as degenerate
+    /// branches, so that buggy operations are caught where they create one.
+    #[cfg(debug_assertions)]
+    taken_degenerate_branches: HashSet<UpdatedNodeId>,
+    _state: PhantomData<S>,
+}
+
+/// Debugging, profiling and memory-bounding state of a `MemTrieUpdate`,
+/// each part of which is enabled separately by `MemTrieUpdateBuilder`.
+#[derive(Default)]
+struct UpdateInstrumentation {
+    /// For debugging only: hashes of the original nodes which updated nodes
+    /// were converted from. None unless enabled by `with_original_hashes`.
+    original_hashes: Option<HashMap<UpdatedNodeId, CryptoHash>>,
+    /// If set, identical extensions of updated nodes share a single
+    /// allocation from this pool, see `with_intern_extensions`.
+    extension_pool: Option<HashSet<Arc<[u8]>>>,
+    /// Log of the operations performed so far. None unless enabled by
+    /// `with_recorded_ops`.
+    recorded_ops: Option<Vec<MemTrieUpdateOp>>,
+    /// For debugging only: values whose refcount reached zero. None unless
+    /// enabled by `with_zero_crossings`.
+    zero_crossings: Option<ZeroCrossingTracker>,
+    /// Original nodes needed to verify the update. None unless enabled by
+    /// `with_witness_mode`.
+    witness: Option<WitnessNodes>,
+    /// Time spent in the phases of the update so far, see
+    /// `MemTrieUpdate::timings`. Always collected when the feature is on.
+    #[cfg(feature = "memtrie_timing")]
+    timings: Cell<UpdateTimings>,
+    /// Updated nodes moved out of memory. None unless enabled by
+    /// `with_updated_nodes_cap`.
+    spilled_nodes: Option<SpilledNodes>,
+    /// When to prune unreachable updated nodes. None unless enabled by
+    /// `with_compaction_interval`.
+    compaction: Option<CompactionSchedule>,
+}
+
+/// Number of operations between compactions of updated nodes, see
+/// `MemTrieUpdateBuilder::with_compaction_interval`.
+struct CompactionSchedule {
+    interval: usize,
+    num_ops_since_compaction: usize,
+}
+
+/// Cumulative time spent by a `MemTrieUpdate` in each phase of the update,
+/// for profiling.
+#[cfg(feature = "memtrie_timing")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UpdateTimings {
+    /// Descending the trie in inserts and deletes, excluding squashing.
+    pub descent: Duration,
+    /// Squashing nodes after deletions.
+    pub squash: Duration,
+    /// Ordering the updated nodes for hashing.
+    pub traversal: Duration,
+    /// Computing hashes and serializing the updated nodes.
+    pub hashing: Duration,
+}
+
+/// Value slot targeted by slot-aware operations of `MemTrieUpdate`.
+///
+/// EXPERIMENTAL, NON-CONSENSUS: meant for A/B state testing only. Each key
+/// with a value may have a shadow value besides its primary one. It is kept
+/// in the updated node holding the primary value, so it follows the value
+/// through restructuring of the trie, but it is not part of the node hash:
+/// shadow values never affect the state root and are dropped when the
+/// update is finalized.
+#[cfg(feature = "memtrie_shadow_values")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueSlot {
+    /// The regular value stored in the trie.
+    Primary,
+    /// The experimental secondary value.
+    Shadow,
+}
+
+/// Updated nodes serialized to a temporary file by a `MemTrieUpdate` in
+/// memory-bounded mode, see `MemTrieUpdateBuilder::with_updated_nodes_cap`.
+struct SpilledNodes {
+    /// Maximum number of updated node slots kept in memory.
+    cap: usize,
+    file: File,
+    file_len: u64,
+    /// Offset and length of each spilled node in the file.
+    positions: HashMap<UpdatedNodeId, (u64, usize)>,
+}
+
+/// Serialized form of a child of a spilled or serialized node.
+#[derive(BorshSerialize, BorshDeserialize)]
+enum SpilledChild {
+    Old(ArenaPos),
+    Updated(u64),
+}
+
+/// Child of a node in `MemTrieChanges::canonical_nodes`, in which updated
+/// nodes are referred to by hash rather than by ID.
+#[derive(BorshSerialize)]
+enum CanonicalChild {
+    Old(ArenaPos),
+    Updated(CryptoHash),
+}
+
+/// Serialized form of a spilled node.
+#[derive(BorshSerialize, BorshDeserialize)]
+enum SpilledNode {
+    Empty,
+    Leaf {
+        extension: Vec<u8>,
+        value: FlatStateValue,
+        #[cfg(feature = "memtrie_shadow_values")]
+        shadow_value: Option<FlatStateValue>,
+    },
+    Extension {
+        extension: Vec<u8>,
+        child: SpilledChild,
+    },
+    Branch {
+        children: [Option<SpilledChild>; 16],
+        value: Option<FlatStateValue>,
+        #[cfg(feature = "memtrie_shadow_values")]
+        shadow_value: Option<FlatStateValue>,
+    },
+}
+
+/// Serialized form of a node in `MemTrieChanges::to_versioned_bytes`.
+/// Unlike `SpilledNode`, it has no feature-dependent fields, so that the
+/// layout of a version is the same in all builds. Shadow values are not
+/// serialized: they are dropped when the update is finalized.
+#[derive(BorshSerialize, BorshDeserialize)]
+enum SerializedNode<C = SpilledChild> {
+    Empty,
+    Leaf { extension: Vec<u8>, value: FlatStateValue },
+    Extension { extension: Vec<u8>, child: C },
+    Branch { children: [Option<C>; 16], value: Option<FlatStateValue> },
+}
+
+impl SpilledChild {
+    fn new(child: OldOrUpdatedNodeId) -> Self {
+        match child {
+            OldOrUpdatedNodeId::Old(node_id) => Self::Old(node_id.pos),
+            OldOrUpdatedNodeId::Updated(node_id) => Self::Updated(node_id as u64),
+        }
+    }
+
+    fn into_child(self) -> OldOrUpdatedNodeId {
+        match self {
+            Self::Old(pos) => OldOrUpdatedNodeId::Old(MemTrieNodeId { pos }),
+            Self::Updated(node_id) => OldOrUpdatedNodeId::Updated(node_id as UpdatedNodeId),
+        }
+    }
+}
+
+impl SpilledNode {
+    fn new(node: UpdatedMemTrieNode) -> Self {
+        match node {
+            UpdatedMemTrieNode::Empty => Self::Empty,
+            UpdatedMemTrieNode::Leaf {
+                extension,
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            } => Self::Leaf {
+                extension: extension.to_vec(),
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            },
+            UpdatedMemTrieNode::Extension { extension, child } => {
+                Self::Extension { extension: extension.to_vec(), child: SpilledChild::new(child) }
+            }
+            UpdatedMemTrieNode::Branch {
+                children,
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            } => Self::Branch {
+                children: (*children).map(|child| child.map(SpilledChild::new)),
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            },
+        }
+    }
+
+    fn into_node(self) -> UpdatedMemTrieNode {
+        match self {
+            Self::Empty => UpdatedMemTrieNode::Empty,
+            Self::Leaf {
+                extension,
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            } => UpdatedMemTrieNode::Leaf {
+                extension: extension.into(),
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            },
+            Self::Extension { extension, child } => UpdatedMemTrieNode::Extension {
+                extension: extension.into(),
+                child: child.into_child(),
+            },
+            Self::Branch {
+                children,
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            } => UpdatedMemTrieNode::Branch {
+                children: Box::new(children.map(|child| child.map(SpilledChild::into_child))),
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            },
+        }
+    }
+}
+
+impl SerializedNode {
+    fn new(node: UpdatedMemTrieNode) -> Self {
+        match node {
+            UpdatedMemTrieNode::Empty => Self::Empty,
+            UpdatedMemTrieNode::Leaf { extension, value, .. } => {
+                Self::Leaf { extension: extension.to_vec(), value }
+            }
+            UpdatedMemTrieNode::Extension { extension, child } => {
+                Self::Extension { extension: extension.to_vec(), child: SpilledChild::new(child) }
+            }
+            UpdatedMemTrieNode::Branch { children, value, .. } => Self::Branch {
+                children: (*children).map(|child| child.map(SpilledChild::new)),
+                value,
+            },
+        }
+    }
+
+    fn map_children<D>(self, mut f: impl FnMut(SpilledChild) -> D) -> SerializedNode<D> {
+        match self {
+            Self::Empty => SerializedNode::Empty,
+            Self::Leaf { extension, value } => SerializedNode::Leaf { extension, value },
+            Self::Extension { extension, child } => {
+                SerializedNode::Extension { extension, child: f(child) }
+            }
+            Self::Branch { children, value } => {
+                SerializedNode::Branch { children: children.map(|child| child.map(&mut f)), value }
+            }
+        }
+    }
+
+    fn into_node(self) -> UpdatedMemTrieNode {
+        match self {
+            Self::Empty => UpdatedMemTrieNode::Empty,
+            Self::Leaf { extension, value } => UpdatedMemTrieNode::Leaf {
+                extension: extension.into(),
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value: None,
+            },
+            Self::Extension { extension, child } => UpdatedMemTrieNode::Extension {
+                extension: extension.into(),
+                child: child.into_child(),
+            },
+            Self::Branch { children, value } => UpdatedMemTrieNode::Branch {
+                children: Box::new(children.map(|child| child.map(SpilledChild::into_child))),
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value: None,
+            },
+        }
+    }
+}
+
+/// Version of the serialized form of `MemTrieChanges`, written as its first
+/// byte by `MemTrieChanges::to_versioned_bytes`.
+///
+/// - 0: generic layout, in which a branch has an optional value.
+/// - 1: version 0 followed by the unchanged root, if any.
+pub const MEM_TRIE_CHANGES_VERSION: u8 = 1;
+
+/// Serialized form of `MemTrieChanges` in version 0, following the version
+/// byte.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct SerializedMemTrieChangesV0 {
+    node_ids_with_hashes: Vec<(u64, CryptoHash)>,
+    updated_nodes: Vec<Option<(SerializedNode, u64)>>,
+}
+
+/// Serialized form of `MemTrieChanges` in the current version, following
+/// the version byte.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct SerializedMemTrieChanges {
+    changes: SerializedMemTrieChangesV0,
+    unchanged_root: Option<(ArenaPos, CryptoHash)>,
+}
+
+impl SerializedMemTrieChangesV0 {
+    fn into_changes(self) -> MemTrieChanges {
+        MemTrieChanges {
+            node_ids_with_hashes: self
+                .node_ids_with_hashes
+                .into_iter()
+                .map(|(node_id, node_hash)| (node_id as UpdatedNodeId, node_hash))
+                .collect(),
+            updated_nodes: self
+                .updated_nodes
+                .into_iter()
+                .map(|node| {
+                    node.map(|(node, memory_usage)| UpdatedMemTrieNodeWithSize {
+                        node: node.into_node(),
+                        memory_usage,
+                    })
+                })
+                .collect(),
+            unchanged_root: None,
+        }
+    }
+}
+
+impl SerializedMemTrieChanges {
+    fn into_changes(self) -> MemTrieChanges {
+        MemTrieChanges {
+            unchanged_root: self
+                .unchanged_root
+                .map(|(pos, root_hash)| (MemTrieNodeId { pos }, root_hash)),
+            ..self.changes.into_changes()
+        }
+    }
+}
+
+impl MemTrieChanges {
+    /// Serializes the changes, prefixed by `MEM_TRIE_CHANGES_VERSION`.
+    ///
+    /// Existing nodes, including the unchanged root, are referred to by their
+    /// position in the arena, so the changes can only be applied to the
+    /// memtrie they were computed on.
+    pub fn to_versioned_bytes(&self) -> Vec<u8> {
+        let serialized = SerializedMemTrieChanges {
+            changes: SerializedMemTrieChangesV0 {
+                node_ids_with_hashes: self
+                    .node_ids_with_hashes
+                    .iter()
+                    .map(|(node_id, node_hash)| (*node_id as u64, *node_hash))
+                    .collect(),
+                updated_nodes: self
+                    .updated_nodes
+                    .iter()
+                    .map(|node| {
+                        node.as_ref()
+                            .map(|node| (SerializedNode::new(node.node.clone()), node.memory_usage))
+                    })
+                    .collect(),
+            },
+            unchanged_root: self.unchanged_root.map(|(root, root_hash)| (root.pos, root_hash)),
+        };
+        let mut bytes = vec![MEM_TRIE_CHANGES_VERSION];
+        borsh::to_writer(&mut bytes, &serialized).unwrap();
+        bytes
+    }
+
+    /// Returns the hash of the root resulting from the changes, or None if the
+    /// resulting trie is empty.
+    pub fn root_hash(&self) -> Option<CryptoHash> {
+        match self.unchanged_root {
+            Some((_, root_hash)) => Some(root_hash),
+            None => self.node_ids_with_hashes.last().map(|(_, node_hash)| *node_hash),
+        }
+    }
+
+    /// Returns whether both changes construct the same nodes, regardless of
+    /// the IDs assigned to them, which depend on the order of the operations
+    /// leading to the changes. Unlike `==`, this holds for equivalent changes
+    /// computed along different paths, e.g. on forks being merged.
+    pub fn canonical_eq(&self, other: &MemTrieChanges) -> bool {
+        self.root_hash() == other.root_hash()
+            && match (self.canonical_nodes(), other.canonical_nodes()) {
+                (Some(nodes), Some(other_nodes)) => nodes == other_nodes,
+                _ => false,
+            }
+    }
+
+    /// Returns the hashes and serialized forms of the constructed nodes, in
+    /// which updated children are referred to by hash. Returns `None` if a
+    /// child is not among the constructed nodes.
+    fn canonical_nodes(&self) -> Option<BTreeSet<(CryptoHash, Vec<u8>)>> {
+        let hashes: HashMap<UpdatedNodeId, CryptoHash> =
+            self.node_ids_with_hashes.iter().copied().collect();
+        let mut nodes = BTreeSet::new();
+        for (node_id, node_hash) in &self.node_ids_with_hashes {
+            let node = self.updated_nodes.get(*node_id)?.as_ref()?;
+            let mut missing_child = false;
+            let canonical_node =
+                SerializedNode::new(node.node.clone()).map_children(|child| match child {
+                    SpilledChild::Old(pos) => CanonicalChild::Old(pos),
+                    SpilledChild::Updated(child_id) => {
+                        let child_hash = hashes.get(&(child_id as UpdatedNodeId));
+                        missing_child |= child_hash.is_none();
+                        CanonicalChild::Updated(child_hash.copied().unwrap_or_default())
+                    }
+                });
+            if missing_child {
+                return None;
+            }
+            let serialized = borsh::to_vec(&(canonical_node, node.memory_usage)).unwrap();
+            nodes.insert((*node_hash, serialized));
+        }
+        Some(nodes)
+    }
+
+    /// Deserializes changes written by `to_versioned_bytes` in the current
+    /// or any prior version.
+    pub fn from_versioned_bytes(bytes: &[u8]) -> Result<Self, StorageError> {
+        let decode_error = |err: std::io::Error| {
+            StorageError::StorageInconsistentState(format!(
+                "Failed to decode memtrie changes: {}",
+                err
+            ))
+        };
+        let Some((&version, serialized)) = bytes.split_first() else {
+            return Err(StorageError::StorageInconsistentState(
+                "Missing version of memtrie changes".to_string(),
+            ));
+        };
+        match version {
+            0 => Ok(borsh::from_slice::<SerializedMemTrieChangesV0>(serialized)
+                .map_err(decode_error)?
+                .into_changes()),
+            1 => Ok(borsh::from_slice::<SerializedMemTrieChanges>(serialized)
+                .map_err(decode_error)?
+                .into_changes()),
+            _ => Err(StorageError::StorageInconsistentState(format!(
+                "Unknown version of memtrie changes: {}",
+                version
+            ))),
+        }
+    }
+}
+
+impl SpilledNodes {
+    fn new(cap: usize) -> Result<Self, StorageError> {
+        let file = tempfile::tempfile().map_err(|err| {
+            StorageError::StorageInconsistentState(format!(
+                "Failed to create file for spilled nodes: {}",
+                err
+            ))
+        })?;
+        Ok(Self { cap, file, file_len: 0, positions: HashMap::new() })
+    }
+
+    fn spill(
+        &mut self,
+        node_id: UpdatedNodeId,
+        node: UpdatedMemTrieNodeWithSize,
+    ) -> Result<(), StorageError> {
+        let spilled_node = SpilledNode::new(node.node);
+        let serialized = borsh::to_vec(&(spilled_node, node.memory_usage)).unwrap();
+        self.file
+            .seek(SeekFrom::Start(self.file_len))
+            .and_then(|_| self.file.write_all(&serialized))
+            .map_err(|err| {
+                StorageError::StorageInconsistentState(format!("Failed to spill node: {}", err))
+            })?;
+        self.positions.insert(node_id, (self.file_len, serialized.len()));
+        self.file_len += serialized.len() as u64;
+        Ok(())
+    }
+
+    /// Reads back the spilled node, if the node with given ID is spilled.
+    fn reload(
+        &mut self,
+        node_id: UpdatedNodeId,
+    ) -> Result<Option<UpdatedMemTrieNodeWithSize>, StorageError> {
+        let node = self.read(node_id)?;
+        self.positions.remove(&node_id);
+        Ok(node)
+    }
+
+    /// Like `reload`, but the node stays spilled.
+    fn read(
+        &self,
+        node_id: UpdatedNodeId,
+    ) -> Result<Option<UpdatedMemTrieNodeWithSize>, StorageError> {
+        let Some(&(offset, len)) = self.positions.get(&node_id) else {
+            return Ok(None);
+        };
+        let mut serialized = vec![0; len];
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset)).and_then(|_| file.read_exact(&mut serialized)).map_err(
+            |err| {
+                StorageError::StorageInconsistentState(format!(
+                    "Failed to reload spilled node: {}",
+                    err
+                ))
+            },
+        )?;
+        let (spilled_node, memory_usage) = borsh::from_slice::<(SpilledNode, u64)>(&serialized)
+            .map_err(|err| {
+                StorageError::StorageInconsistentState(format!(
+                    "Failed to decode spilled node: {}",
+                    err
+                ))
+            })?;
+        Ok(Some(UpdatedMemTrieNodeWithSize { node: spilled_node.into_node(), memory_usage }))
+    }
 }
 
-impl<'a, M: ArenaMemory> GenericTrieUpdate<'a, MemTrieNodeId, FlatStateValue>
-    for MemTrieUpdate<'a, M>
+impl<'a, M: ArenaMemory, S> GenericTrieUpdate<'a, MemTrieNodeId, FlatStateValue>
+    for MemTrieUpdate<'a, M, S>
 {
     fn ensure_updated(
         &mut self,
@@ -161,12 +895,48 @@ impl<'a, M: ArenaMemory> GenericTrieUpdate<'a, MemTrieNodeId, FlatStateValue>
         })
     }
 
-    fn take_node(&mut self, index: UpdatedNodeId) -> UpdatedMemTrieNodeWithSize {
-        self.updated_nodes.get_mut(index).unwrap().take().expect("Node taken twice")
+    fn take_node(
+        &mut self,
+        index: UpdatedNodeId,
+    ) -> Result<UpdatedMemTrieNodeWithSize, StorageError> {
+        let node = match self.updated_nodes.get_mut(index).unwrap().take() {
+            Some(node) => node,
+            None => {
+                let spilled_node =
+                    match self.instrumentation.as_mut().and_then(|i| i.spilled_nodes.as_mut()) {
+                        Some(spilled_nodes) => spilled_nodes.reload(index)?,
+                        None => None,
+                    };
+                spilled_node.expect("Node taken twice")
+            }
+        };
+        #[cfg(debug_assertions)]
+        if node.node.is_degenerate_branch() {
+            self.taken_degenerate_branches.insert(index);
+        }
+        Ok(node)
     }
 
     fn place_node_at(&mut self, index: UpdatedNodeId, node: UpdatedMemTrieNodeWithSize) {
+        #[cfg(debug_assertions)]
+        assert!(
+            !node.node.is_degenerate_branch() || self.taken_degenerate_branches.contains(&index),
+            "Degenerate branch {}: {:?}",
+            index,
+            node.node
+        );
+        self.place_unsquashed_node_at(index, node);
+    }
+
+    fn place_unsquashed_node_at(
+        &mut self,
+        index: UpdatedNodeId,
+        mut node: UpdatedMemTrieNodeWithSize,
+    ) {
         assert!(self.updated_nodes[index].is_none(), "Node placed twice");
+        #[cfg(debug_assertions)]
+        self.taken_degenerate_branches.remove(&index);
+        self.intern_extension(&mut node.node);
         self.updated_nodes[index] = Some(node);
     }
 
@@ -175,9 +945,8 @@ impl<'a, M: ArenaMemory> GenericTrieUpdate<'a, MemTrieNodeId, FlatStateValue>
     }
 
     fn place_node(&mut self, node: UpdatedMemTrieNodeWithSize) -> UpdatedNodeId {
-        let index = self.updated_nodes.len();
-        self.updated_nodes.push(Some(node));
-        index
+        debug_assert!(!node.node.is_degenerate_branch(), "Degenerate branch: {:?}", node.node);
+        self.new_updated_node(node)
     }
 
     fn store_value(&mut self, value: GenericTrieValue) -> FlatStateValue {
@@ -194,11 +963,7 @@ impl<'a, M: ArenaMemory> GenericTrieUpdate<'a, MemTrieNodeId, FlatStateValue>
         };
 
         // Otherwise, record disk changes if needed.
-        let Some(nodes_tracker) = self.nodes_tracker.as_mut() else {
-            return flat_value;
-        };
-        *nodes_tracker.refcount_inserted_values.entry(full_value).or_default() += 1;
-
+        self.add_refcount_to_value(full_value);
         flat_value
     }
 
@@ -209,248 +974,552 @@ impl<'a, M: ArenaMemory> GenericTrieUpdate<'a, MemTrieNodeId, FlatStateValue>
 
         let hash = value.to_value_ref().hash;
         *nodes_tracker.refcount_deleted_hashes.entry(hash).or_default() += 1;
+        if let Some(zero_crossings) =
+            self.instrumentation.as_mut().and_then(|i| i.zero_crossings.as_mut())
+        {
+            zero_crossings.add_delta(hash, -1);
+        }
         Ok(())
     }
-}
 
-impl<'a, M: ArenaMemory> MemTrieUpdate<'a, M> {
-    pub fn new(
-        root: Option<MemTrieNodeId>,
-        memory: &'a M,
-        shard_uid: String,
-        mode: TrackingMode<'a>,
-    ) -> Self {
-        let nodes_tracker = match mode {
-            TrackingMode::None => None,
-            TrackingMode::Refcounts => Some(TrieChangesTracker::with_recorder(None)),
-            TrackingMode::RefcountsAndAccesses(recorder) => {
-                Some(TrieChangesTracker::with_recorder(Some(recorder)))
-            }
-        };
-        let mut trie_update =
-            Self { root, memory, shard_uid, updated_nodes: vec![], nodes_tracker };
-        assert_eq!(trie_update.convert_existing_to_updated(root), 0usize);
-        trie_update
+    fn record_squash_op(&self, kind: &'static str) {
+        MEM_TRIE_SQUASH_OPS.with_label_values(&[&self.shard_uid, kind]).inc();
     }
 
-    /// Creates a new updated node, assigning it a new ID.
-    fn new_updated_node(&mut self, node: UpdatedMemTrieNodeWithSize) -> UpdatedNodeId {
-        let index = self.updated_nodes.len();
-        self.updated_nodes.push(Some(node));
-        index
+    #[cfg(feature = "memtrie_timing")]
+    fn record_squash_time(&self, elapsed: Duration) {
+        self.add_timings(|timings| timings.squash += elapsed);
     }
+}
 
-    /// This is called when we need to mutate a subtree of the original trie.
-    /// It decrements the refcount of the original trie node (since logically
-    /// we are removing it), and creates a new node that is equivalent to the
-    /// original node. The ID of the new node is returned.
-    ///
-    /// If the original node is None, it is a marker for the root of an empty
-    /// trie.
-    fn convert_existing_to_updated(&mut self, node: Option<MemTrieNodeId>) -> UpdatedNodeId {
-        let Some(node) = node else {
-            return self.new_updated_node(UpdatedMemTrieNodeWithSize::empty());
-        };
-        let node_view = node.as_ptr(self.memory).view();
-        if let Some(tracked_trie_changes) = self.nodes_tracker.as_mut() {
-            tracked_trie_changes.record(&node_view);
+/// Configures a `MemTrieUpdate` with options beyond the defaults of
+/// `MemTrieUpdate::new` and `MemTrieUpdate::new_tracking`, which are
+/// validated together when building the update. They can't be changed
+/// once the update is built.
+pub struct MemTrieUpdateBuilder<'a, S> {
+    shard_uid: String,
+    nodes_tracker: Option<TrieChangesTracker<'a>>,
+    strict_deletes: bool,
+    intern_extensions: bool,
+    updated_nodes_cap: Option<usize>,
+    compaction_interval: Option<usize>,
+    original_hashes: bool,
+    recorded_ops: bool,
+    zero_crossings: bool,
+    witness_mode: bool,
+    _state: PhantomData<S>,
+}
+
+impl<'a> MemTrieUpdateBuilder<'a, MemOnly> {
+    /// `shard_uid` only labels the metrics of the update.
+    pub fn new(shard_uid: String) -> Self {
+        Self {
+            shard_uid,
+            nodes_tracker: None,
+            strict_deletes: false,
+            intern_extensions: false,
+            updated_nodes_cap: None,
+            compaction_interval: None,
+            original_hashes: false,
+            recorded_ops: false,
+            zero_crossings: false,
+            witness_mode: false,
+            _state: PhantomData,
         }
-        self.new_updated_node(MemTrieNodeWithSize::from_existing_node_view(node_view).into())
     }
 
-    /// Inserts the given key value pair into the trie.
-    pub fn insert(&mut self, key: &[u8], value: Vec<u8>) -> Result<(), StorageError> {
-        self.generic_insert(0, key, GenericTrieValue::MemtrieAndDisk(value))
+    /// Makes the update track on-disk changes, see `MemTrieUpdate::new_tracking`.
+    pub fn with_tracking(self, mode: TrackingMode<'a>) -> MemTrieUpdateBuilder<'a, Tracking> {
+        MemTrieUpdateBuilder {
+            shard_uid: self.shard_uid,
+            nodes_tracker: Some(TrieChangesTracker::new(mode)),
+            strict_deletes: self.strict_deletes,
+            intern_extensions: self.intern_extensions,
+            updated_nodes_cap: self.updated_nodes_cap,
+            compaction_interval: self.compaction_interval,
+            original_hashes: self.original_hashes,
+            recorded_ops: self.recorded_ops,
+            zero_crossings: self.zero_crossings,
+            witness_mode: self.witness_mode,
+            _state: PhantomData,
+        }
     }
+}
 
-    /// Inserts the given key value pair into the trie, but the value may be a reference.
-    /// This is used to update the in-memory trie only, without caring about on-disk changes.
-    pub fn insert_memtrie_only(
-        &mut self,
-        key: &[u8],
-        value: FlatStateValue,
-    ) -> Result<(), StorageError> {
-        self.generic_insert(0, key, GenericTrieValue::MemtrieOnly(value))
+impl<'a> MemTrieUpdateBuilder<'a, Tracking> {
+    /// Records the witness of the update: the original nodes on the paths it
+    /// changes, including the ones merged into their parents when squashing,
+    /// which suffice to apply the same operations to a partial trie and
+    /// recompute the new root, see `MemTrieUpdate::into_witness`. Unlike the
+    /// accesses recorded by `TrackingMode::RefcountsAndAccesses`, it excludes
+    /// read-only lookups such as `get_ref_with_proof`. Only available with
+    /// tracking, as only the on-disk changes of an update can be verified
+    /// with its witness. Disabled by default.
+    pub fn with_witness_mode(mut self, enable: bool) -> Self {
+        self.witness_mode = enable;
+        self
     }
 }
 
-impl<'a, M: ArenaMemory> MemTrieUpdate<'a, M> {
-    /// To construct the new trie nodes, we need to create the new nodes in an
-    /// order such that children are created before their parents - essentially
-    /// a topological sort. We do this via a post-order traversal of the
-    /// updated nodes. After this function, `ordered_nodes` contains the IDs of
-    /// the updated nodes in the order they should be created.
-    fn post_order_traverse_updated_nodes(
-        node_id: UpdatedNodeId,
-        updated_nodes: &Vec<Option<UpdatedMemTrieNodeWithSize>>,
-        ordered_nodes: &mut Vec<UpdatedNodeId>,
-    ) {
-        let node = updated_nodes[node_id].as_ref().unwrap();
-        match &node.node {
-            UpdatedMemTrieNode::Empty => {
-                assert_eq!(node_id, 0); // only root can be empty
-                return;
-            }
-            UpdatedMemTrieNode::Branch { children, .. } => {
-                for child in children.iter() {
-                    if let Some(OldOrUpdatedNodeId::Updated(child_node_id)) = child {
-                        Self::post_order_traverse_updated_nodes(
-                            *child_node_id,
-                            updated_nodes,
-                            ordered_nodes,
-                        );
-                    }
-                }
-            }
-            UpdatedMemTrieNode::Extension { child, .. } => {
-                if let OldOrUpdatedNodeId::Updated(child_node_id) = child {
-                    Self::post_order_traverse_updated_nodes(
-                        *child_node_id,
-                        updated_nodes,
-                        ordered_nodes,
-                    );
-                }
-            }
-            _ => {}
-        }
-        ordered_nodes.push(node_id);
+impl<'a, S> MemTrieUpdateBuilder<'a, S> {
+    /// Makes `delete` fail on keys which are absent from the trie instead of
+    /// doing nothing.
+    pub fn with_strict_deletes(mut self, strict: bool) -> Self {
+        self.strict_deletes = strict;
+        self
     }
 
-    /// For each node in `ordered_nodes`, computes its hash and serialized data.
-    /// `ordered_nodes` is expected to follow the post-order traversal of the
-    /// updated nodes.
-    /// `updated_nodes` must be indexed by the node IDs in `ordered_nodes`.
-    pub(crate) fn compute_hashes_and_serialized_nodes(
-        &self,
-        ordered_nodes: &Vec<UpdatedNodeId>,
-        updated_nodes: &Vec<Option<UpdatedMemTrieNodeWithSize>>,
-    ) -> Vec<(UpdatedNodeId, CryptoHash, Vec<u8>)> {
-        let memory = self.memory;
-        let mut result = Vec::<(CryptoHash, Vec<u8>)>::new();
-        for _ in 0..updated_nodes.len() {
-            result.push((CryptoHash::default(), Vec::new()));
-        }
-        let get_hash =
-            |node: OldOrUpdatedNodeId, result: &Vec<(CryptoHash, Vec<u8>)>| -> CryptoHash {
-                match node {
-                    OldOrUpdatedNodeId::Updated(node_id) => result[node_id].0,
-                    // IMPORTANT: getting a node hash for a child doesn't
-                    // record a new node read. In recorded storage, child node
-                    // is referenced by its hash, and we don't need to need the
-                    // whole node to verify parent hash.
-                    // TODO(#12361): consider fixing it, perhaps by taking this
-                    // hash from old version of the parent node.
-                    OldOrUpdatedNodeId::Old(node_id) => node_id.as_ptr(memory).view().node_hash(),
-                }
-            };
+    /// Makes identical extensions of updated nodes share a single allocation,
+    /// which reduces memory usage of updates with many keys sharing long
+    /// prefixes. Disabled by default; the resulting changes are the same
+    /// either way.
+    pub fn with_intern_extensions(mut self, intern: bool) -> Self {
+        self.intern_extensions = intern;
+        self
+    }
 
-        for node_id in ordered_nodes.iter() {
-            let node = updated_nodes[*node_id].as_ref().unwrap();
-            let raw_node = match &node.node {
-                UpdatedMemTrieNode::Empty => unreachable!(),
-                UpdatedMemTrieNode::Branch { children, value } => {
-                    let mut child_hashes = vec![];
-                    for child in children.iter() {
-                        match child {
-                            Some(child) => {
-                                let child_hash = get_hash(*child, &result);
-                                child_hashes.push(Some(child_hash));
-                            }
-                            None => {
-                                child_hashes.push(None);
-                            }
-                        }
-                    }
-                    let children = Children(child_hashes.as_slice().try_into().unwrap());
-                    let value_ref = value.as_ref().map(|value| value.to_value_ref());
-                    RawTrieNode::branch(children, value_ref)
+    /// Enables a memory-bounded mode for huge updates, e.g. genesis. Once
+    /// more than `cap` updated nodes are in memory, inserts move the nodes
+    /// off the path to the inserted key to a temporary file. Inserts reload
+    /// the spilled nodes they descend into, so keys inserted in ascending
+    /// order never need reloading. Deletions and finalization reload all
+    /// spilled nodes first; lookups don't, so they may only be done for keys
+    /// on the path to the last inserted one.
+    pub fn with_updated_nodes_cap(mut self, cap: usize) -> Self {
+        self.updated_nodes_cap = Some(cap);
+        self
+    }
+
+    /// Enables pruning of the updated nodes which are no longer reachable
+    /// from the root, e.g. left over by deletions, once every `interval`
+    /// inserts or deletes, which bounds the memory used by huge batches. The
+    /// remaining nodes are renumbered, so node IDs obtained from the update
+    /// before a compaction must not be used after it. The resulting changes
+    /// are the same either way. Not done while `with_updated_nodes_cap` is
+    /// in effect.
+    pub fn with_compaction_interval(mut self, interval: usize) -> Self {
+        self.compaction_interval = Some(interval);
+        self
+    }
+
+    /// Remembers the original node hash of every node converted from the
+    /// original trie, to help correlating updated nodes with the nodes they
+    /// replaced, e.g. when debugging reorgs, see
+    /// `MemTrieUpdate::original_hash_of`. Disabled by default to avoid the
+    /// overhead.
+    pub fn with_original_hashes(mut self, enable: bool) -> Self {
+        self.original_hashes = enable;
+        self
+    }
+
+    /// Logs the key-value operations performed by the update, so that they
+    /// can be replayed against another root with `MemTrieUpdate::replay`.
+    /// Operations which are combinations of others are logged as such, e.g.
+    /// `update_values` as inserts, `insert_shared_value` as a memtrie-only
+    /// insert of the reference, and `set_value_at` as an insert or a delete
+    /// of the key of the node. Only operations which don't change any value,
+    /// like `prepare_split`, are not logged. Disabled by default.
+    pub fn with_recorded_ops(mut self, enable: bool) -> Self {
+        self.recorded_ops = enable;
+        self
+    }
+
+    /// Records every value whose disk refcount reaches zero during the
+    /// update, with the key involved, even if the value is re-added later,
+    /// to help debugging garbage collection issues, see
+    /// `MemTrieUpdate::zero_crossing_values`. Disabled by default to avoid
+    /// the overhead.
+    ///
+    /// Refcounts of values before the update are unknown, so a value is
+    /// considered to reach zero whenever the update has removed more
+    /// references to it than it added, as for a value referenced once by the
+    /// original trie. Only updates which track disk changes have refcounts.
+    pub fn with_zero_crossings(mut self, enable: bool) -> Self {
+        self.zero_crossings = enable;
+        self
+    }
+
+    /// Creates the update of the trie at `root`.
+    pub fn build<M: ArenaMemory>(
+        self,
+        memory: &'a M,
+        root: Option<MemTrieNodeId>,
+    ) -> Result<MemTrieUpdate<'a, M, S>, StorageError> {
+        let enabled = self.intern_extensions
+            || self.original_hashes
+            || self.recorded_ops
+            || self.zero_crossings
+            || self.witness_mode
+            || self.compaction_interval.is_some()
+            || self.updated_nodes_cap.is_some();
+        let instrumentation = UpdateInstrumentation {
+            original_hashes: self.original_hashes.then(HashMap::new),
+            extension_pool: self.intern_extensions.then(HashSet::new),
+            recorded_ops: self.recorded_ops.then(Vec::new),
+            zero_crossings: self.zero_crossings.then(ZeroCrossingTracker::default),
+            witness: self.witness_mode.then(WitnessNodes::default),
+            #[cfg(feature = "memtrie_timing")]
+            timings: Cell::default(),
+            spilled_nodes: self.updated_nodes_cap.map(SpilledNodes::new).transpose()?,
+            compaction: self.compaction_interval.map(|interval| {
+                assert!(interval > 0, "Compaction interval must be positive");
+                CompactionSchedule { interval, num_ops_since_compaction: 0 }
+            }),
+        };
+        let instrumentation = enabled.then(|| Box::new(instrumentation));
+        let mut update = MemTrieUpdate::new_impl(
+            root,
+            memory,
+            self.shard_uid,
+            self.nodes_tracker,
+            instrumentation,
+        );
+        update.strict_deletes = self.strict_deletes;
+        Ok(update)
+    }
+}
+
+impl<'a, M: ArenaMemory> MemTrieUpdate<'a, M, MemOnly> {
+    pub fn new(root: Option<MemTrieNodeId>, memory: &'a M, shard_uid: String) -> Self {
+        Self::new_impl(root, memory, shard_uid, None, None)
+    }
+
+    /// Builds an update equivalent to the nodes of a disk trie update
+    /// reachable from `storage_root`, e.g. to finish with memtrie changes an
+    /// update which was started on disk. Old nodes and values of the disk
+    /// update are resolved by descending the memtrie at `root` along their
+    /// paths, so it must have the same state root as the disk trie.
+    pub(crate) fn from_nodes_storage(
+        storage: &TrieStorageUpdate,
+        storage_root: UpdatedNodeId,
+        root: Option<MemTrieNodeId>,
+        memory: &'a M,
+        shard_uid: String,
+    ) -> Result<Self, StorageError> {
+        let mut update = Self::new(root, memory, shard_uid);
+        update.updated_nodes.clear();
+        update.converted_from.clear();
+        let new_root = update.bridge_storage_node(storage, storage_root, &mut Vec::new())?;
+        debug_assert_eq!(new_root, 0);
+        Ok(update)
+    }
+
+    /// Converts the storage node at `index`, which starts at the nibble
+    /// `path`.
+    fn bridge_storage_node(
+        &mut self,
+        storage: &TrieStorageUpdate,
+        index: UpdatedNodeId,
+        path: &mut Vec<u8>,
+    ) -> Result<UpdatedNodeId, StorageError> {
+        let storage_node = storage.nodes.get(index).and_then(Option::as_ref).ok_or_else(|| {
+            StorageError::StorageInconsistentState(format!("Missing storage node {}", index))
+        })?;
+        let new_index = self.updated_nodes.len();
+        self.updated_nodes.push(None);
+        let node = match &storage_node.node {
+            GenericTrieNode::Empty => UpdatedMemTrieNode::Empty,
+            GenericTrieNode::Leaf { extension, value, .. } => {
+                let mut key = path.clone();
+                key.extend(NibbleSlice::from_encoded(extension).0.iter());
+                UpdatedMemTrieNode::Leaf {
+                    extension: extension.clone(),
+                    value: self.bridge_storage_value(storage, value, &key)?,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value: None,
                 }
-                UpdatedMemTrieNode::Extension { extension, child } => {
-                    let child_hash = get_hash(*child, &result);
-                    RawTrieNode::Extension(extension.to_vec(), child_hash)
+            }
+            GenericTrieNode::Extension { extension, child } => {
+                let path_len = path.len();
+                path.extend(NibbleSlice::from_encoded(extension).0.iter());
+                let child = self.bridge_storage_child(storage, *child, path)?;
+                path.truncate(path_len);
+                UpdatedMemTrieNode::Extension { extension: extension.clone(), child }
+            }
+            GenericTrieNode::Branch { children, value, .. } => {
+                let mut new_children = Box::<[Option<OldOrUpdatedNodeId>; 16]>::default();
+                for (nibble, (new_child, child)) in
+                    new_children.iter_mut().zip(children.iter()).enumerate()
+                {
+                    if let Some(child) = child {
+                        path.push(nibble as u8);
+                        *new_child = Some(self.bridge_storage_child(storage, *child, path)?);
+                        path.pop();
+                    }
                 }
-                UpdatedMemTrieNode::Leaf { extension, value } => {
-                    RawTrieNode::Leaf(extension.to_vec(), value.to_value_ref())
+                let value = match value {
+                    Some(value) => Some(self.bridge_storage_value(storage, value, path)?),
+                    None => None,
+                };
+                UpdatedMemTrieNode::Branch {
+                    children: new_children,
+                    value,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value: None,
                 }
-            };
+            }
+        };
+        self.updated_nodes[new_index] =
+            Some(UpdatedMemTrieNodeWithSize { node, memory_usage: storage_node.memory_usage });
+        Ok(new_index)
+    }
 
-            let memory_usage = node.memory_usage;
-            let raw_node_with_size = RawTrieNodeWithSize { node: raw_node, memory_usage };
-            let node_serialized = borsh::to_vec(&raw_node_with_size).unwrap();
-            let node_hash = hash(&node_serialized);
-            result[*node_id] = (node_hash, node_serialized);
+    fn bridge_storage_child(
+        &mut self,
+        storage: &TrieStorageUpdate,
+        child: GenericNodeOrIndex<CryptoHash>,
+        path: &mut Vec<u8>,
+    ) -> Result<OldOrUpdatedNodeId, StorageError> {
+        match child {
+            GenericNodeOrIndex::Old(hash) => {
+                // Unchanged nodes stay at the same path in the original trie.
+                let node = self
+                    .root
+                    .and_then(|root| memtrie_node_at_path(root.as_ptr(self.memory), path))
+                    .filter(|node| node.view().node_hash() == hash)
+                    .ok_or_else(|| {
+                        StorageError::StorageInconsistentState(format!(
+                            "Node {} of the storage update is not in the memtrie",
+                            hash
+                        ))
+                    })?;
+                Ok(OldOrUpdatedNodeId::Old(node.id()))
+            }
+            GenericNodeOrIndex::Updated(index) => {
+                Ok(OldOrUpdatedNodeId::Updated(self.bridge_storage_node(storage, index, path)?))
+            }
         }
+    }
 
-        ordered_nodes
-            .iter()
-            .map(|node_id| {
-                let (hash, serialized) = &mut result[*node_id];
-                (*node_id, *hash, std::mem::take(serialized))
+    /// Converts the value stored at the nibble `key`. Values known only by
+    /// reference are the original values of the key, which are read from
+    /// the memtrie, as short values are inlined there.
+    fn bridge_storage_value(
+        &self,
+        storage: &TrieStorageUpdate,
+        value: &ValueHandle,
+        key: &[u8],
+    ) -> Result<FlatStateValue, StorageError> {
+        let value_ref = match value {
+            ValueHandle::InMemory(handle) => {
+                return Ok(FlatStateValue::on_disk(storage.value_ref(*handle)));
+            }
+            ValueHandle::HashAndSize(value_ref) => value_ref,
+        };
+        let old_value = self
+            .root
+            .filter(|_| key.len() % 2 == 0)
+            .and_then(|root| {
+                memtrie_lookup(root.as_ptr(self.memory), &NibbleSlice::nibbles_to_bytes(key), None)
             })
-            .collect()
+            .map(|value| value.to_flat_value())
+            .filter(|value| value.to_value_ref().hash == value_ref.hash);
+        match old_value {
+            Some(value) => Ok(value),
+            None if value_ref.len() > FlatStateValue::INLINE_DISK_VALUE_THRESHOLD => {
+                Ok(FlatStateValue::Ref(*value_ref))
+            }
+            None => Err(StorageError::StorageInconsistentState(format!(
+                "Value {} of the storage update is not in the memtrie",
+                value_ref.hash
+            ))),
+        }
     }
+}
 
-    /// Converts the changes to memtrie changes. Also returns the list of new nodes inserted,
-    /// in hash and serialized form.
-    fn to_memtrie_changes_internal(self) -> (MemTrieChanges, Vec<(CryptoHash, Vec<u8>)>) {
-        MEMTRIE_NUM_NODES_CREATED_FROM_UPDATES
-            .with_label_values(&[&self.shard_uid])
-            .inc_by(self.updated_nodes.len() as u64);
-        let mut ordered_nodes = Vec::new();
-        Self::post_order_traverse_updated_nodes(0, &self.updated_nodes, &mut ordered_nodes);
-
-        let hashes_and_serialized_nodes =
-            self.compute_hashes_and_serialized_nodes(&ordered_nodes, &self.updated_nodes);
-
-        let node_ids_with_hashes = hashes_and_serialized_nodes
-            .iter()
-            .map(|(node_id, hash, _)| (*node_id, *hash))
-            .collect();
-        (
-            MemTrieChanges { node_ids_with_hashes, updated_nodes: self.updated_nodes },
-            hashes_and_serialized_nodes
-                .into_iter()
-                .map(|(_, hash, serialized)| (hash, serialized))
-                .collect(),
-        )
+/// Returns the node of the memtrie at `root` which starts at the nibble
+/// `path`, if any.
+fn memtrie_node_at_path<'a, M: ArenaMemory>(
+    root: MemTrieNodePtr<'a, M>,
+    mut path: &[u8],
+) -> Option<MemTrieNodePtr<'a, M>> {
+    let mut node = root;
+    while let Some(&nibble) = path.first() {
+        match node.view() {
+            MemTrieNodeView::Leaf { .. } => return None,
+            MemTrieNodeView::Extension { extension, child, .. } => {
+                let extension = NibbleSlice::from_encoded(extension).0;
+                if path.len() < extension.len()
+                    || !extension.iter().eq(path[..extension.len()].iter().copied())
+                {
+                    return None;
+                }
+                path = &path[extension.len()..];
+                node = child;
+            }
+            MemTrieNodeView::Branch { children, .. }
+            | MemTrieNodeView::BranchWithValue { children, .. } => {
+                node = children.get(nibble as usize)?;
+                path = &path[1..];
+            }
+        }
     }
+    Some(node)
+}
 
-    /// Converts the updates to memtrie changes only.
-    pub fn to_memtrie_changes_only(self) -> MemTrieChanges {
-        let (memtrie_changes, _) = self.to_memtrie_changes_internal();
-        memtrie_changes
+impl<'a, M: ArenaMemory> MemTrieUpdate<'a, M, Tracking> {
+    pub fn new_tracking(
+        root: Option<MemTrieNodeId>,
+        memory: &'a M,
+        shard_uid: String,
+        mode: TrackingMode<'a>,
+    ) -> Self {
+        Self::new_impl(root, memory, shard_uid, Some(TrieChangesTracker::new(mode)), None)
     }
 
     /// Converts the updates to trie changes as well as memtrie changes.
-    pub(crate) fn to_trie_changes(mut self) -> TrieChanges {
+    pub fn to_trie_changes(mut self) -> Result<TrieChanges, StorageError> {
         let old_root =
             self.root.map(|root| root.as_ptr(self.memory).view().node_hash()).unwrap_or_default();
-        let mut refcount_changes = self
-            .nodes_tracker
-            .take()
-            .expect("Cannot to_trie_changes for memtrie changes only")
-            .finalize();
-        let (memtrie_changes, hashes_and_serialized) = self.to_memtrie_changes_internal();
-
+        let mut refcount_changes =
+            self.nodes_tracker.take().expect("Tracking update must have a tracker").finalize();
         // We've accounted for the dereferenced nodes, as well as value addition/subtractions.
         // The only thing left is to increment refcount for all new nodes.
-        for (node_hash, node_serialized) in hashes_and_serialized {
-            refcount_changes.add(node_hash, node_serialized, 1);
-        }
+        let memtrie_changes =
+            self.to_memtrie_changes_internal::<Sha256Hasher>(|node_hash, node_serialized| {
+                refcount_changes.add(node_hash, node_serialized, 1);
+            })?;
         let (insertions, deletions) = refcount_changes.into_changes();
 
-        TrieChanges {
+        Ok(TrieChanges {
             old_root,
-            new_root: memtrie_changes
-                .node_ids_with_hashes
-                .last()
-                .map(|(_, hash)| *hash)
-                .unwrap_or_default(),
+            new_root: memtrie_changes.root_hash().unwrap_or_default(),
             insertions,
             deletions,
             memtrie_changes: Some(memtrie_changes),
             children_memtrie_changes: Default::default(),
+        })
+    }
+
+    /// Returns the number of insertions `to_trie_changes` would produce, i.e.
+    /// the number of new nodes and values with positive refcount delta.
+    /// Like `checkpoint_root`, hashes all updated nodes, but leaves the update
+    /// untouched: the finalization is done on a copy of the updated nodes.
+    pub fn disk_insertion_count(&self) -> Result<usize, StorageError> {
+        let finished = self.finished_copy()?;
+        let mut ordered_nodes = Vec::new();
+        Self::post_order_traverse_updated_nodes(0, &finished.updated_nodes, &mut ordered_nodes);
+        let nodes_tracker =
+            self.nodes_tracker.as_ref().expect("Tracking update must have a tracker");
+        let squash_tracker = finished.nodes_tracker.as_ref().unwrap();
+        let mut refcount_deltas = HashMap::<CryptoHash, i64>::new();
+        for (value, rc) in &nodes_tracker.refcount_inserted_values {
+            *refcount_deltas.entry(hash(value)).or_default() += i64::from(*rc);
+        }
+        for (node_hash, rc) in nodes_tracker
+            .refcount_deleted_hashes
+            .iter()
+            .chain(&squash_tracker.refcount_deleted_hashes)
+        {
+            *refcount_deltas.entry(*node_hash).or_default() -= i64::from(*rc);
+        }
+        for (_, node_hash, _) in finished.compute_hashes_and_serialized_nodes(
+            &ordered_nodes,
+            &finished.updated_nodes,
+            None,
+        ) {
+            *refcount_deltas.entry(node_hash).or_default() += 1;
+        }
+        Ok(refcount_deltas.values().filter(|rc| **rc > 0).count())
+    }
+
+    /// Looks up the value reference of the key, taking into account the
+    /// changes made so far in this update. The hash of the found value is
+    /// recorded as read, see `read_values`.
+    pub fn get_ref(&mut self, key: &[u8]) -> Result<Option<ValueRef>, StorageError> {
+        Ok(self.get_ref_with_cost(key)?.map(|(value, _)| value.to_value_ref()))
+    }
+
+    /// Like `get_ref`, but returns the value as stored in the trie together
+    /// with the cost category of reading it.
+    pub fn get_ref_with_cost(
+        &mut self,
+        key: &[u8],
+    ) -> Result<Option<(FlatStateValue, ReadCostClass)>, StorageError> {
+        let Some(value) = self.get_value(key)? else {
+            return Ok(None);
+        };
+        self.nodes_tracker
+            .as_mut()
+            .expect("Tracking update must have a tracker")
+            .read_value_hashes
+            .insert(value.to_value_ref().hash);
+        let cost_class = ReadCostClass::of(&value);
+        Ok(Some((value, cost_class)))
+    }
+
+    /// Like `get_ref`, but also returns the serialized nodes on the path to
+    /// the key in the original trie, which prove the value read against the
+    /// root this update started from, or its absence. The nodes are also
+    /// recorded like the ones accessed by the changes, so that the witness
+    /// can be produced later without descending again. The value takes into
+    /// account the changes made so far, so it's only proven by the nodes if
+    /// the key wasn't changed by this update.
+    pub fn get_ref_with_proof(
+        &mut self,
+        key: &[u8],
+    ) -> Result<(Option<FlatStateValue>, Vec<Arc<[u8]>>), StorageError> {
+        let value = self.get_ref_with_cost(key)?.map(|(value, _)| value);
+        let mut nodes_accessed = Vec::new();
+        if let Some(root) = self.root {
+            memtrie_lookup(root.as_ptr(self.memory), key, Some(&mut nodes_accessed));
+        }
+        let nodes_tracker =
+            self.nodes_tracker.as_mut().expect("Tracking update must have a tracker");
+        if let Some(recorder) = nodes_tracker.recorder.as_mut() {
+            for (node_hash, node) in &nodes_accessed {
+                recorder.record(node_hash, node.clone());
+            }
+        }
+        Ok((value, nodes_accessed.into_iter().map(|(_, node)| node).collect()))
+    }
+
+    /// Inserts a reference to a value whose bytes are owned by
+    /// `origin_shard`, e.g. a parent shard during resharding. The value bytes
+    /// are not part of the trie changes: only the added references are
+    /// counted, see `shared_value_refcounts`.
+    pub fn insert_shared_value(
+        &mut self,
+        key: &[u8],
+        value_ref: ValueRef,
+        origin_shard: ShardUId,
+    ) -> Result<(), StorageError> {
+        let nodes_tracker =
+            self.nodes_tracker.as_mut().expect("Tracking update must have a tracker");
+        let refcount = nodes_tracker
+            .shared_value_refcounts
+            .entry(value_ref.hash)
+            .or_insert(SharedValueRefcount { origin_shard, rc: 0 });
+        if refcount.origin_shard != origin_shard {
+            return Err(StorageError::StorageInconsistentState(format!(
+                "Value {} is shared from both {} and {}",
+                value_ref.hash, refcount.origin_shard, origin_shard
+            )));
         }
+        refcount.rc += 1;
+        let value = FlatStateValue::Ref(value_ref);
+        self.record_op(|| MemTrieUpdateOp::InsertMemtrieOnly {
+            key: key.to_vec(),
+            value: value.clone(),
+        });
+        self.generic_insert(0, key, GenericTrieValue::MemtrieOnly(value))
+    }
+
+    /// References added so far to values owned by other shards, by value
+    /// hash. They are persisted separately from the trie changes, by
+    /// `ShardTries::apply_shared_value_refcounts`.
+    pub fn shared_value_refcounts(&self) -> &BTreeMap<CryptoHash, SharedValueRefcount> {
+        &self
+            .nodes_tracker
+            .as_ref()
+            .expect("Tracking update must have a tracker")
+            .shared_value_refcounts
+    }
+
+    /// Hashes of all values read by lookups of this update so far. Values
+    /// which were only written are not included.
+    pub fn read_values(&self) -> &BTreeSet<CryptoHash> {
+        &self.nodes_tracker.as_ref().expect("Tracking update must have a tracker").read_value_hashes
     }
 
     /// Splits the trie, separating entries by the boundary account.
@@ -463,562 +1532,5699 @@ impl<'a, M: ArenaMemory> MemTrieUpdate<'a, M> {
         mut self,
         boundary_account: &AccountId,
         retain_mode: RetainMode,
-    ) -> TrieChanges {
-        GenericTrieUpdateRetain::retain_split_shard(&mut self, boundary_account, retain_mode);
+    ) -> Result<TrieChanges, StorageError> {
+        GenericTrieUpdateRetain::retain_split_shard(&mut self, boundary_account, retain_mode)?;
         self.to_trie_changes()
     }
 }
 
-/// Applies the given memtrie changes to the in-memory trie data structure.
-/// Returns the new root hash.
-pub(super) fn construct_root_from_changes<A: ArenaMut>(
-    arena: &mut A,
-    changes: &MemTrieChanges,
-) -> Option<MemTrieNodeId> {
-    let mut last_node_id: Option<MemTrieNodeId> = None;
-    let map_to_new_node_id = |node_id: OldOrUpdatedNodeId,
-                              old_to_new_map: &HashMap<UpdatedNodeId, MemTrieNodeId>|
-     -> MemTrieNodeId {
-        match node_id {
-            OldOrUpdatedNodeId::Updated(node_id) => *old_to_new_map.get(&node_id).unwrap(),
-            OldOrUpdatedNodeId::Old(node_id) => node_id,
-        }
-    };
-
-    let mut updated_to_new_map = HashMap::<UpdatedNodeId, MemTrieNodeId>::new();
-    let updated_nodes = &changes.updated_nodes;
-    let node_ids_with_hashes = &changes.node_ids_with_hashes;
-    for (node_id, node_hash) in node_ids_with_hashes.iter() {
-        let node = updated_nodes.get(*node_id).unwrap().clone().unwrap();
-        let node = match &node.node {
-            UpdatedMemTrieNode::Empty => unreachable!(),
-            UpdatedMemTrieNode::Branch { children, value } => {
-                let mut new_children = [None; 16];
-                for i in 0..16 {
-                    if let Some(child) = children[i] {
-                        new_children[i] = Some(map_to_new_node_id(child, &updated_to_new_map));
-                    }
-                }
-                match value {
-                    Some(value) => {
-                        InputMemTrieNode::BranchWithValue { children: new_children, value }
-                    }
-                    None => InputMemTrieNode::Branch { children: new_children },
-                }
-            }
-            UpdatedMemTrieNode::Extension { extension, child } => InputMemTrieNode::Extension {
-                extension,
-                child: map_to_new_node_id(*child, &updated_to_new_map),
-            },
-            UpdatedMemTrieNode::Leaf { extension, value } => {
-                InputMemTrieNode::Leaf { value, extension }
-            }
+impl<'a, M: ArenaMemory, S> MemTrieUpdate<'a, M, S> {
+    fn new_impl(
+        root: Option<MemTrieNodeId>,
+        memory: &'a M,
+        shard_uid: String,
+        nodes_tracker: Option<TrieChangesTracker<'a>>,
+        instrumentation: Option<Box<UpdateInstrumentation>>,
+    ) -> Self {
+        // Timings are collected by all updates when the feature is on.
+        #[cfg(feature = "memtrie_timing")]
+        let instrumentation = instrumentation.or_else(|| Some(Box::default()));
+        let mut trie_update = Self {
+            root,
+            memory,
+            shard_uid,
+            updated_nodes: vec![],
+            nodes_tracker,
+            strict_deletes: false,
+            prepared_splits: Vec::new(),
+            converted_from: HashMap::new(),
+            instrumentation,
+            #[cfg(debug_assertions)]
+            taken_degenerate_branches: HashSet::new(),
+            _state: PhantomData,
         };
-        let mem_node_id = MemTrieNodeId::new_with_hash(arena, node, *node_hash);
-        updated_to_new_map.insert(*node_id, mem_node_id);
-        last_node_id = Some(mem_node_id);
+        assert_eq!(trie_update.convert_existing_to_updated(root), 0usize);
+        trie_update
     }
 
-    last_node_id
-}
-
-#[cfg(test)]
-mod tests {
-    use crate::test_utils::TestTriesBuilder;
-    use crate::trie::mem::arena::hybrid::HybridArena;
-    use crate::trie::mem::lookup::memtrie_lookup;
-    use crate::trie::mem::memtrie_update::GenericTrieUpdateInsertDelete;
-    use crate::trie::mem::memtries::MemTries;
-    use crate::trie::MemTrieChanges;
-    use crate::{KeyLookupMode, ShardTries, TrieChanges};
-    use near_primitives::hash::CryptoHash;
-    use near_primitives::shard_layout::ShardUId;
-    use near_primitives::state::{FlatStateValue, ValueRef};
-    use near_primitives::types::{BlockHeight, StateRoot};
-    use rand::Rng;
-    use std::collections::{HashMap, HashSet};
+    /// Creates a new updated node, assigning it a new ID.
+    fn new_updated_node(&mut self, mut node: UpdatedMemTrieNodeWithSize) -> UpdatedNodeId {
+        self.intern_extension(&mut node.node);
+        let index = self.updated_nodes.len();
+        self.updated_nodes.push(Some(node));
+        index
+    }
 
-    use super::TrackingMode;
+    /// Replaces the extension of the node with the interned one, if
+    /// interning is enabled.
+    fn intern_extension(&mut self, node: &mut UpdatedMemTrieNode) {
+        let Some(pool) = self.instrumentation.as_mut().and_then(|i| i.extension_pool.as_mut())
+        else {
+            return;
+        };
+        let extension = match node {
+            UpdatedMemTrieNode::Leaf { extension, .. }
+            | UpdatedMemTrieNode::Extension { extension, .. } => extension,
+            UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Branch { .. } => return,
+        };
+        match pool.get(&**extension) {
+            Some(interned) => *extension = interned.clone(),
+            None => {
+                pool.insert(extension.clone());
+            }
+        }
+    }
 
-    struct TestTries {
-        mem: MemTries,
-        disk: ShardTries,
-        truth: HashMap<Vec<u8>, Option<ValueRef>>,
-        state_root: StateRoot,
-        check_deleted_keys: bool,
+    /// This is called when we need to mutate a subtree of the original trie.
+    /// It decrements the refcount of the original trie node (since logically
+    /// we are removing it), and creates a new node that is equivalent to the
+    /// original node. The ID of the new node is returned.
+    ///
+    /// If the original node is None, it is a marker for the root of an empty
+    /// trie.
+    fn convert_existing_to_updated(&mut self, node: Option<MemTrieNodeId>) -> UpdatedNodeId {
+        let Some(node) = node else {
+            return self.new_updated_node(UpdatedMemTrieNodeWithSize::empty());
+        };
+        let node_view = node.as_ptr(self.memory).view();
+        if let Some(tracked_trie_changes) = self.nodes_tracker.as_mut() {
+            tracked_trie_changes.record(&node_view);
+        }
+        if let Some(witness) = self.instrumentation.as_mut().and_then(|i| i.witness.as_mut()) {
+            witness.record(&node_view);
+        }
+        if let Some(original_hashes) =
+            self.instrumentation.as_mut().and_then(|i| i.original_hashes.as_mut())
+        {
+            original_hashes.insert(self.updated_nodes.len(), node_view.node_hash());
+        }
+        let node_id =
+            self.new_updated_node(MemTrieNodeWithSize::from_existing_node_view(node_view).into());
+        self.converted_from.insert(node_id, node);
+        node_id
     }
 
-    impl TestTries {
-        fn new(check_deleted_keys: bool) -> Self {
-            let mem = MemTries::new(ShardUId::single_shard());
-            let disk = TestTriesBuilder::new().build();
-            Self {
-                mem,
-                disk,
-                truth: HashMap::new(),
-                state_root: StateRoot::default(),
-                check_deleted_keys,
+    /// Returns a copy of the update with the updated nodes as they are when
+    /// the update is finalized: spilled nodes are read back and prepared
+    /// splits are squashed. The update itself is left untouched. Only the
+    /// refcount changes made by squashing are tracked by the copy.
+    fn finished_copy(&self) -> Result<MemTrieUpdate<'a, M, MemOnly>, StorageError> {
+        let mut finished = MemTrieUpdate::new(self.root, self.memory, self.shard_uid.clone());
+        finished.updated_nodes = self.updated_nodes.clone();
+        if let Some(spilled_nodes) =
+            self.instrumentation.as_ref().and_then(|i| i.spilled_nodes.as_ref())
+        {
+            for node_id in spilled_nodes.positions.keys() {
+                finished.updated_nodes[*node_id] = spilled_nodes.read(*node_id)?;
             }
         }
+        // Squashing may convert original nodes, which are then deleted.
+        finished.nodes_tracker = Some(TrieChangesTracker::with_recorder(None));
+        finished.prepared_splits = self.prepared_splits.clone();
+        finished.squash_prepared_splits()?;
+        Ok(finished)
+    }
 
-        fn make_all_changes(&mut self, changes: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> TrieChanges {
-            let mut update =
-                self.mem.update(self.state_root, TrackingMode::Refcounts).unwrap_or_else(|_| {
-                    panic!("Trying to update root {:?} but it's not in memtries", self.state_root)
-                });
-            for (key, value) in changes {
-                if let Some(value) = value {
-                    update.insert(&key, value).unwrap();
-                } else {
-                    update.generic_delete(0, &key).unwrap();
+    /// Number of updated nodes currently spilled out of memory.
+    pub fn num_spilled_nodes(&self) -> usize {
+        self.instrumentation
+            .as_ref()
+            .and_then(|i| i.spilled_nodes.as_ref())
+            .map_or(0, |spilled_nodes| spilled_nodes.positions.len())
+    }
+
+    /// Moves all spilled nodes back to memory.
+    pub fn reload_spilled_nodes(&mut self) -> Result<(), StorageError> {
+        let Some(spilled_nodes) =
+            self.instrumentation.as_mut().and_then(|i| i.spilled_nodes.as_mut())
+        else {
+            return Ok(());
+        };
+        let node_ids: Vec<_> = spilled_nodes.positions.keys().copied().collect();
+        for node_id in node_ids {
+            self.updated_nodes[node_id] = spilled_nodes.reload(node_id)?;
+        }
+        spilled_nodes.file_len = 0;
+        Ok(())
+    }
+
+    /// Spills the nodes off the path to the key if there are too many
+    /// updated nodes in memory.
+    fn spill_updated_nodes(&mut self, key: &[u8]) -> Result<(), StorageError> {
+        let Some(spilled_nodes) =
+            self.instrumentation.as_ref().and_then(|i| i.spilled_nodes.as_ref())
+        else {
+            return Ok(());
+        };
+        if self.updated_nodes.len() - spilled_nodes.positions.len() <= spilled_nodes.cap {
+            return Ok(());
+        }
+        let path: HashSet<_> = self.updated_path_to_key(key).into_iter().collect();
+        let spilled_nodes =
+            self.instrumentation.as_mut().and_then(|i| i.spilled_nodes.as_mut()).unwrap();
+        for (node_id, node) in self.updated_nodes.iter_mut().enumerate() {
+            if !path.contains(&node_id) {
+                if let Some(node) = node.take() {
+                    spilled_nodes.spill(node_id, node)?;
                 }
             }
-            update.to_trie_changes()
         }
+        Ok(())
+    }
 
-        fn make_memtrie_changes_only(
-            &mut self,
-            changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
-        ) -> MemTrieChanges {
-            let mut update =
-                self.mem.update(self.state_root, TrackingMode::None).unwrap_or_else(|_| {
-                    panic!("Trying to update root {:?} but it's not in memtries", self.state_root)
-                });
-            for (key, value) in changes {
-                if let Some(value) = value {
-                    update.insert_memtrie_only(&key, FlatStateValue::on_disk(&value)).unwrap();
-                } else {
-                    update.generic_delete(0, &key).unwrap();
+    /// Counts an insert or delete, compacting the updated nodes if it's time.
+    fn after_op(&mut self) {
+        let Some(instrumentation) = self.instrumentation.as_mut() else {
+            return;
+        };
+        let Some(compaction) = instrumentation.compaction.as_mut() else {
+            return;
+        };
+        compaction.num_ops_since_compaction += 1;
+        if compaction.num_ops_since_compaction < compaction.interval {
+            return;
+        }
+        compaction.num_ops_since_compaction = 0;
+        if instrumentation.spilled_nodes.is_none() {
+            self.compact_updated_nodes();
+        }
+    }
+
+    /// Drops the updated nodes unreachable from the root and renumbers the
+    /// other ones, keeping their order, so that the root stays at 0.
+    fn compact_updated_nodes(&mut self) {
+        let mut new_ids: Vec<Option<UpdatedNodeId>> = vec![None; self.updated_nodes.len()];
+        let mut stack = if self.updated_nodes.is_empty() { vec![] } else { vec![0] };
+        while let Some(node_id) = stack.pop() {
+            new_ids[node_id] = Some(0);
+            let Some(node) = &self.updated_nodes[node_id] else {
+                continue;
+            };
+            let children: Vec<OldOrUpdatedNodeId> = match &node.node {
+                UpdatedMemTrieNode::Branch { children, .. } => {
+                    children.iter().flatten().copied().collect()
+                }
+                UpdatedMemTrieNode::Extension { child, .. } => vec![*child],
+                UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Leaf { .. } => vec![],
+            };
+            for child in children {
+                if let OldOrUpdatedNodeId::Updated(child_id) = child {
+                    stack.push(child_id);
                 }
             }
-            update.to_memtrie_changes_only()
+        }
+        let mut num_reachable = 0;
+        for new_id in new_ids.iter_mut().flatten() {
+            *new_id = num_reachable;
+            num_reachable += 1;
+        }
+        if num_reachable == self.updated_nodes.len() {
+            return;
         }
 
-        fn make_disk_changes_only(
-            &mut self,
-            changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
-        ) -> TrieChanges {
-            let trie = self.disk.get_trie_for_shard(ShardUId::single_shard(), self.state_root);
-            trie.update(changes).unwrap()
+        let updated_nodes = std::mem::take(&mut self.updated_nodes);
+        self.updated_nodes = updated_nodes
+            .into_iter()
+            .zip(&new_ids)
+            .filter_map(|(node, new_id)| new_id.map(|_| node))
+            .collect();
+        let renumber = |child: &mut OldOrUpdatedNodeId| {
+            if let OldOrUpdatedNodeId::Updated(child_id) = child {
+                *child_id = new_ids[*child_id].unwrap();
+            }
+        };
+        for node in self.updated_nodes.iter_mut().flatten() {
+            match &mut node.node {
+                UpdatedMemTrieNode::Branch { children, .. } => {
+                    for child in children.iter_mut().flatten() {
+                        renumber(child);
+                    }
+                }
+                UpdatedMemTrieNode::Extension { child, .. } => renumber(child),
+                UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Leaf { .. } => {}
+            }
         }
+        self.converted_from = self
+            .converted_from
+            .drain()
+            .filter_map(|(node_id, old_id)| Some((new_ids[node_id]?, old_id)))
+            .collect();
+        if let Some(original_hashes) =
+            self.instrumentation.as_mut().and_then(|i| i.original_hashes.as_mut())
+        {
+            *original_hashes = original_hashes
+                .drain()
+                .filter_map(|(node_id, hash)| Some((new_ids[node_id]?, hash)))
+                .collect();
+        }
+        self.prepared_splits =
+            self.prepared_splits.iter().filter_map(|node_id| new_ids[*node_id]).collect();
+    }
 
-        fn check_consistency_across_all_changes_and_apply(
-            &mut self,
-            changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
-        ) {
-            // First check consistency between the changes.
-            let memtrie_changes = self.make_memtrie_changes_only(changes.clone());
-            let disk_changes = self.make_disk_changes_only(changes.clone());
-            let mut all_changes = self.make_all_changes(changes.clone());
+    /// Returns the updated nodes on the path from the root to the key.
+    fn updated_path_to_key(&self, key: &[u8]) -> Vec<UpdatedNodeId> {
+        let mut nibbles = NibbleSlice::new(key);
+        let mut path = vec![0];
+        loop {
+            let child = match &self.get_node_ref(*path.last().unwrap()).node {
+                UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Leaf { .. } => None,
+                UpdatedMemTrieNode::Extension { extension, child } => {
+                    let extension_nibbles = NibbleSlice::from_encoded(extension).0;
+                    nibbles.starts_with(&extension_nibbles).then(|| {
+                        nibbles = nibbles.mid(extension_nibbles.len());
+                        *child
+                    })
+                }
+                UpdatedMemTrieNode::Branch { children, .. } => {
+                    if nibbles.is_empty() {
+                        None
+                    } else {
+                        let child = children[nibbles.at(0) as usize];
+                        nibbles = nibbles.mid(1);
+                        child
+                    }
+                }
+            };
+            match child {
+                Some(OldOrUpdatedNodeId::Updated(child_id)) => path.push(child_id),
+                _ => return path,
+            }
+        }
+    }
 
-            let memtrie_changes_from_all_changes = all_changes.memtrie_changes.take().unwrap();
-            assert_eq!(memtrie_changes, memtrie_changes_from_all_changes);
-            assert_eq!(disk_changes, all_changes);
+    /// Returns the hash of the original node which the given updated node
+    /// was converted from. Returns None for nodes created from scratch, or if
+    /// it's not enabled by `MemTrieUpdateBuilder::with_original_hashes`.
+    pub fn original_hash_of(&self, node_id: UpdatedNodeId) -> Option<CryptoHash> {
+        self.instrumentation.as_ref()?.original_hashes.as_ref()?.get(&node_id).copied()
+    }
 
-            // Then apply the changes and check consistency of new state roots.
-            let new_state_root_from_mem = self.mem.apply_memtrie_changes(0, &memtrie_changes);
-            let mut store_update = self.disk.store_update();
-            let new_state_root_from_disk =
-                self.disk.apply_all(&disk_changes, ShardUId::single_shard(), &mut store_update);
-            assert_eq!(new_state_root_from_mem, new_state_root_from_disk);
-            store_update.commit().unwrap();
-            self.state_root = new_state_root_from_mem;
+    /// Operations logged so far, or None if not enabled by
+    /// `MemTrieUpdateBuilder::with_recorded_ops`.
+    pub fn recorded_ops(&self) -> Option<&[MemTrieUpdateOp]> {
+        self.instrumentation.as_ref()?.recorded_ops.as_deref()
+    }
 
-            // Update our truth.
-            for (key, value) in changes {
-                if let Some(value) = value {
-                    self.truth.insert(key, Some(ValueRef::new(&value)));
-                } else {
-                    if self.check_deleted_keys {
-                        self.truth.insert(key, None);
+    fn record_op(&mut self, op: impl FnOnce() -> MemTrieUpdateOp) {
+        let Some(instrumentation) = self.instrumentation.as_mut() else {
+            return;
+        };
+        if instrumentation.recorded_ops.is_none() && instrumentation.zero_crossings.is_none() {
+            return;
+        }
+        let op = op();
+        if let Some(zero_crossings) = instrumentation.zero_crossings.as_mut() {
+            zero_crossings.current_key = match &op {
+                MemTrieUpdateOp::Insert { key, .. }
+                | MemTrieUpdateOp::InsertMemtrieOnly { key, .. }
+                | MemTrieUpdateOp::Delete { key }
+                | MemTrieUpdateOp::Rekey { old_key: key, .. } => Some(key.clone()),
+                MemTrieUpdateOp::DeleteRange { .. }
+                | MemTrieUpdateOp::Clear
+                | MemTrieUpdateOp::TouchValues { .. } => None,
+            };
+        }
+        if let Some(recorded_ops) = instrumentation.recorded_ops.as_mut() {
+            recorded_ops.push(op);
+        }
+    }
+
+    /// Values whose refcount reached zero so far, in order, or None if not
+    /// enabled by `MemTrieUpdateBuilder::with_zero_crossings`.
+    pub fn zero_crossing_values(&self) -> Option<&[ZeroCrossing]> {
+        let tracker = self.instrumentation.as_ref()?.zero_crossings.as_ref()?;
+        Some(tracker.zero_crossings.as_slice())
+    }
+
+    /// Returns the nodes recorded as the witness of the update, without
+    /// duplicates and in the order of their first access. Panics if not
+    /// enabled by `MemTrieUpdateBuilder::with_witness_mode`.
+    pub fn into_witness(self) -> PartialState {
+        let witness = self
+            .instrumentation
+            .and_then(|i| i.witness)
+            .expect("into_witness requires witness mode");
+        PartialState::TrieValues(witness.nodes)
+    }
+
+    /// Applies the given operations to a fresh update of `new_root`, e.g. to
+    /// check that an update recorded on one root produces the same relative
+    /// changes on another one. The new update doesn't track on-disk changes.
+    pub fn replay(
+        &self,
+        ops: &[MemTrieUpdateOp],
+        new_root: Option<MemTrieNodeId>,
+    ) -> Result<MemTrieUpdate<'a, M, MemOnly>, StorageError> {
+        let mut update = MemTrieUpdate::new(new_root, self.memory, self.shard_uid.clone());
+        for op in ops {
+            match op {
+                MemTrieUpdateOp::Insert { key, value } => update.insert(key, value.clone())?,
+                MemTrieUpdateOp::InsertMemtrieOnly { key, value } => {
+                    update.insert_memtrie_only(key, value.clone())?
+                }
+                MemTrieUpdateOp::Delete { key } => update.delete(key)?,
+                MemTrieUpdateOp::DeleteRange { start, end } => {
+                    update.delete_range(start, end)?;
+                }
+                MemTrieUpdateOp::Clear => update.clear()?,
+                MemTrieUpdateOp::Rekey { old_key, new_key } => {
+                    update.rekey(std::iter::once((old_key.clone(), new_key.clone())))?
+                }
+                MemTrieUpdateOp::TouchValues { keys } => update.touch_values(keys)?,
+            }
+        }
+        Ok(update)
+    }
+
+    /// Returns the net key-level value changes of the operations recorded so
+    /// far, sorted by key, which are needed to keep flat storage consistent
+    /// with the updated trie: the new value of each changed key, or None if
+    /// it was deleted. Keys which end up with their original value are
+    /// omitted. Panics if operations are not recorded, see
+    /// `MemTrieUpdateBuilder::with_recorded_ops`.
+    pub fn to_flat_state_changes(&self) -> Vec<(Vec<u8>, Option<FlatStateValue>)> {
+        let ops = self.recorded_ops().expect("to_flat_state_changes requires recorded ops");
+        let mut changes = BTreeMap::new();
+        for op in ops {
+            match op {
+                MemTrieUpdateOp::Insert { key, value } => {
+                    changes.insert(key.clone(), Some(FlatStateValue::on_disk(value)));
+                }
+                MemTrieUpdateOp::InsertMemtrieOnly { key, value } => {
+                    changes.insert(key.clone(), Some(value.clone()));
+                }
+                MemTrieUpdateOp::Delete { key } => {
+                    changes.insert(key.clone(), None);
+                }
+                MemTrieUpdateOp::DeleteRange { start, end } => {
+                    let range = start.clone()..end.clone();
+                    for (key, value) in changes.iter_mut() {
+                        if range.contains(key) {
+                            *value = None;
+                        }
+                    }
+                    let common_prefix_len =
+                        start.iter().zip(end).take_while(|(a, b)| a == b).count();
+                    let original = MemTrieUpdate::new(self.root, self.memory, String::new());
+                    changes.extend(
+                        original
+                            .iter_prefix(&start[..common_prefix_len])
+                            .filter(|(key, _)| range.contains(key))
+                            .map(|(key, _)| (key, None)),
+                    );
+                }
+                MemTrieUpdateOp::Clear => {
+                    changes.values_mut().for_each(|value| *value = None);
+                    let original = MemTrieUpdate::new(self.root, self.memory, String::new());
+                    changes.extend(original.iter_prefix(&[]).map(|(key, _)| (key, None)));
+                }
+                MemTrieUpdateOp::Rekey { old_key, new_key } => {
+                    let value = match changes.get(old_key) {
+                        Some(value) => value.clone(),
+                        None => self.root.and_then(|root| {
+                            memtrie_lookup(root.as_ptr(self.memory), old_key, None)
+                                .map(|value| value.to_flat_value())
+                        }),
+                    };
+                    changes.insert(old_key.clone(), None);
+                    changes.insert(new_key.clone(), value);
+                }
+                MemTrieUpdateOp::TouchValues { .. } => {}
+            }
+        }
+        let root = self.root.map(|root| root.as_ptr(self.memory));
+        changes
+            .into_iter()
+            .filter(|(key, value)| {
+                let original = root.and_then(|root| memtrie_lookup(root, key, None));
+                original.map(|original| original.to_flat_value()) != *value
+            })
+            .collect()
+    }
+
+    /// Returns, for each key changed by the operations recorded so far, the
+    /// hashes of its original and new values, None meaning that the key is
+    /// absent, sorted by key. E.g. a deletion is `(key, Some(old), None)`.
+    /// Requires recorded operations, like `to_flat_state_changes`.
+    pub fn value_transitions(&self) -> Vec<(Vec<u8>, Option<CryptoHash>, Option<CryptoHash>)> {
+        let root = self.root.map(|root| root.as_ptr(self.memory));
+        self.to_flat_state_changes()
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let original = root.and_then(|root| memtrie_lookup(root, &key, None));
+                let old_hash =
+                    original.map(|original| original.to_flat_value().to_value_ref().hash);
+                let new_hash = value.map(|value| value.to_value_ref().hash);
+                // A value may change from inlined to a reference while
+                // staying the same.
+                (old_hash != new_hash).then_some((key, old_hash, new_hash))
+            })
+            .collect()
+    }
+
+    /// Time spent in each phase of the update so far.
+    #[cfg(feature = "memtrie_timing")]
+    pub fn timings(&self) -> UpdateTimings {
+        self.instrumentation.as_ref().map_or_else(UpdateTimings::default, |i| i.timings.get())
+    }
+
+    #[cfg(feature = "memtrie_timing")]
+    fn add_timings(&self, f: impl FnOnce(&mut UpdateTimings)) {
+        let Some(instrumentation) = self.instrumentation.as_ref() else {
+            return;
+        };
+        let mut timings = instrumentation.timings.get();
+        f(&mut timings);
+        instrumentation.timings.set(timings);
+    }
+
+    /// Records the time spent in each phase of the update to
+    /// `MEM_TRIE_UPDATE_PHASE_SECONDS`, once per update, as the time of
+    /// single operations is too short to be measured on its own.
+    #[cfg(feature = "memtrie_timing")]
+    fn observe_phases(&self) {
+        let timings = self.timings();
+        for (phase, elapsed) in [
+            ("apply", timings.descent + timings.squash),
+            ("traverse", timings.traversal),
+            ("hash", timings.hashing),
+        ] {
+            MEM_TRIE_UPDATE_PHASE_SECONDS
+                .with_label_values(&[&self.shard_uid, phase])
+                .observe(elapsed.as_secs_f64());
+        }
+    }
+
+    /// Runs an insert or a delete, accounting the time spent outside of
+    /// squashing as descent when timing is enabled.
+    fn timed_descent<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> T {
+        #[cfg(feature = "memtrie_timing")]
+        let (start, squash_before) = (Instant::now(), self.timings().squash);
+        let result = f(self);
+        #[cfg(feature = "memtrie_timing")]
+        {
+            let elapsed = start.elapsed();
+            self.add_timings(|timings| {
+                let squash = timings.squash - squash_before;
+                timings.descent += elapsed.saturating_sub(squash);
+            });
+        }
+        result
+    }
+
+    /// Inserts the given key value pair into the trie.
+    pub fn insert(&mut self, key: &[u8], value: Vec<u8>) -> Result<(), StorageError> {
+        self.record_op(|| MemTrieUpdateOp::Insert { key: key.to_vec(), value: value.clone() });
+        self.timed_descent(|update| {
+            update.generic_insert(0, key, GenericTrieValue::MemtrieAndDisk(value))
+        })?;
+        self.spill_updated_nodes(key)?;
+        self.after_op();
+        Ok(())
+    }
+
+    /// Like `insert`, but returns the value as it ended up stored at the key,
+    /// i.e. whether it was inlined or stored as a reference, which tells the
+    /// caller if the value bytes have to be persisted separately. The stored
+    /// form is read back from the trie rather than derived from the input.
+    pub fn insert_returning_stored(
+        &mut self,
+        key: &[u8],
+        value: Vec<u8>,
+    ) -> Result<FlatStateValue, StorageError> {
+        self.insert(key, value)?;
+        self.get_value(key)?.ok_or_else(|| {
+            StorageError::StorageInconsistentState(format!(
+                "Inserted key {} is missing from the trie",
+                hex::encode(key)
+            ))
+        })
+    }
+
+    /// Inserts the given key value pair into the trie, but the value may be a reference.
+    /// This is used to update the in-memory trie only, without caring about on-disk changes.
+    pub fn insert_memtrie_only(
+        &mut self,
+        key: &[u8],
+        value: FlatStateValue,
+    ) -> Result<(), StorageError> {
+        self.record_op(|| MemTrieUpdateOp::InsertMemtrieOnly {
+            key: key.to_vec(),
+            value: value.clone(),
+        });
+        self.timed_descent(|update| {
+            update.generic_insert(0, key, GenericTrieValue::MemtrieOnly(value))
+        })?;
+        self.spill_updated_nodes(key)?;
+        self.after_op();
+        Ok(())
+    }
+
+    /// Inserts the given entries, like calling `insert` for each of them,
+    /// e.g. to build a trie from a sorted map. Values repeated across keys
+    /// are hashed once; as for `insert`, each distinct value becomes a single
+    /// insertion on disk, referenced once per key holding it.
+    pub fn insert_many(
+        &mut self,
+        entries: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), StorageError> {
+        let mut flat_values = HashMap::<Vec<u8>, FlatStateValue>::new();
+        for (key, value) in entries {
+            self.record_op(|| MemTrieUpdateOp::Insert { key: key.clone(), value: value.clone() });
+            let flat_value = match flat_values.get(value.as_slice()) {
+                Some(flat_value) => flat_value.clone(),
+                None => {
+                    let flat_value = FlatStateValue::on_disk(&value);
+                    flat_values.insert(value.clone(), flat_value.clone());
+                    flat_value
+                }
+            };
+            self.add_refcount_to_value(value);
+            self.timed_descent(|update| {
+                update.generic_insert(0, &key, GenericTrieValue::MemtrieOnly(flat_value))
+            })?;
+            self.spill_updated_nodes(&key)?;
+            self.after_op();
+        }
+        Ok(())
+    }
+
+    /// Inserts at `key` the value currently stored at `existing_key`, taking
+    /// into account the changes made so far, without the caller supplying
+    /// the bytes. Returns false if `existing_key` is absent. The value gets
+    /// one more reference on disk, which needs its bytes; so for values
+    /// stored as references this fails if disk changes are tracked.
+    pub fn insert_alias(&mut self, key: &[u8], existing_key: &[u8]) -> Result<bool, StorageError> {
+        let Some(value) = self.get_value(existing_key)? else {
+            return Ok(false);
+        };
+        match value {
+            FlatStateValue::Inlined(value) => self.insert(key, value)?,
+            FlatStateValue::Ref(value_ref) if self.nodes_tracker.is_some() => {
+                return Err(StorageError::StorageInconsistentState(format!(
+                    "Cannot alias value {} of key {} without its bytes",
+                    value_ref.hash,
+                    hex::encode(existing_key)
+                )));
+            }
+            value @ FlatStateValue::Ref(_) => self.insert_memtrie_only(key, value)?,
+        }
+        Ok(true)
+    }
+
+    /// Replaces the values of existing keys. As the trie structure can't
+    /// change, this only descends to each key and rewrites the value in
+    /// place. Each descent resumes from the deepest node on the path to the
+    /// previous key that is shared with the current one, so sorted keys
+    /// need little descending. Disk changes are tracked as for `insert`.
+    /// Fails at the first absent key, after which the update should be
+    /// discarded.
+    pub fn update_values(
+        &mut self,
+        updates: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), StorageError> {
+        let mut previous: Option<(Vec<u8>, Vec<(UpdatedNodeId, usize)>)> = None;
+        for (key, value) in updates {
+            self.record_op(|| MemTrieUpdateOp::Insert { key: key.clone(), value: value.clone() });
+            let mut path = match previous.take() {
+                Some((previous_key, mut path)) => {
+                    let common_prefix =
+                        NibbleSlice::new(&previous_key).common_prefix(&NibbleSlice::new(&key));
+                    let num_shared =
+                        path.iter().take_while(|(_, depth)| *depth <= common_prefix).count();
+                    path.truncate(num_shared);
+                    path
+                }
+                None => vec![(0, 0)],
+            };
+            self.timed_descent(|update| update.update_value(&key, value, &mut path))?;
+            self.spill_updated_nodes(&key)?;
+            let num_updated_nodes = self.updated_nodes.len();
+            self.after_op();
+            // A compaction renumbers the nodes, so the path can't be reused.
+            if self.updated_nodes.len() == num_updated_nodes {
+                previous = Some((key, path));
+            }
+        }
+        Ok(())
+    }
+
+    fn update_value(
+        &mut self,
+        key: &[u8],
+        value: Vec<u8>,
+        path: &mut Vec<(UpdatedNodeId, usize)>,
+    ) -> Result<(), StorageError> {
+        self.extend_updated_path_to_value(key, path)?;
+        let path: Vec<UpdatedNodeId> = path.iter().map(|(node_id, _)| *node_id).collect();
+        let node_id = *path.last().unwrap();
+        let old_memory_usages: Vec<u64> =
+            path.iter().map(|node_id| self.get_node_ref(*node_id).memory_usage).collect();
+        let GenericUpdatedTrieNodeWithSize { node, memory_usage } = self.take_node(node_id)?;
+        let children_memory_usage = memory_usage.saturating_sub(node.memory_usage_direct());
+        let node = match node {
+            UpdatedMemTrieNode::Branch {
+                children,
+                value: Some(old_value),
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            } => {
+                self.delete_value(old_value)?;
+                let value = self.store_value(GenericTrieValue::MemtrieAndDisk(value));
+                UpdatedMemTrieNode::Branch {
+                    children,
+                    value: Some(value),
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value,
+                }
+            }
+            UpdatedMemTrieNode::Leaf {
+                extension,
+                value: old_value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            } => {
+                self.delete_value(old_value)?;
+                let value = self.store_value(GenericTrieValue::MemtrieAndDisk(value));
+                UpdatedMemTrieNode::Leaf {
+                    extension,
+                    value,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value,
+                }
+            }
+            node => unreachable!("Unexpected node at key {}: {:?}", hex::encode(key), node),
+        };
+        let memory_usage = children_memory_usage + node.memory_usage_direct();
+        self.place_node_at(node_id, UpdatedMemTrieNodeWithSize { node, memory_usage });
+        self.update_path_memory_usage(path, old_memory_usages, false)
+    }
+
+    /// Extends the path to `key`, given as pairs of node ID and number of
+    /// key nibbles above the node, down to the node holding the value of
+    /// `key`, converting the nodes on the way to updated ones. The path must
+    /// start at the root. Fails if the key is absent.
+    fn extend_updated_path_to_value(
+        &mut self,
+        key: &[u8],
+        path: &mut Vec<(UpdatedNodeId, usize)>,
+    ) -> Result<(), StorageError> {
+        let key_nibbles = NibbleSlice::new(key);
+        let (mut node_id, depth) = *path.last().unwrap();
+        let mut partial = key_nibbles.mid(depth);
+        loop {
+            let (child, nibble) = match &self.get_node_ref(node_id).node {
+                UpdatedMemTrieNode::Branch { value: Some(_), .. } if partial.is_empty() => break,
+                UpdatedMemTrieNode::Leaf { extension, .. }
+                    if NibbleSlice::from_encoded(extension).0 == partial =>
+                {
+                    break
+                }
+                UpdatedMemTrieNode::Branch { children, .. } if !partial.is_empty() => {
+                    let nibble = partial.at(0);
+                    partial = partial.mid(1);
+                    (children[nibble as usize], Some(nibble))
+                }
+                UpdatedMemTrieNode::Extension { extension, child } => {
+                    let extension = NibbleSlice::from_encoded(extension).0;
+                    if partial.starts_with(&extension) {
+                        partial = partial.mid(extension.len());
+                        (Some(*child), None)
                     } else {
-                        self.truth.remove(&key);
+                        (None, None)
+                    }
+                }
+                _ => (None, None),
+            };
+            let Some(child) = child else {
+                return Err(StorageError::StorageInconsistentState(format!(
+                    "Updating value of absent key {}",
+                    hex::encode(key)
+                )));
+            };
+            let child_id = self.ensure_updated(child)?;
+            if let OldOrUpdatedNodeId::Old(_) = child {
+                let mut parent = self.take_node(node_id)?;
+                match (&mut parent.node, nibble) {
+                    (UpdatedMemTrieNode::Branch { children, .. }, Some(nibble)) => {
+                        children[nibble as usize] = Some(OldOrUpdatedNodeId::Updated(child_id));
+                    }
+                    (UpdatedMemTrieNode::Extension { child, .. }, None) => {
+                        *child = OldOrUpdatedNodeId::Updated(child_id);
+                    }
+                    (node, _) => unreachable!("Unexpected parent node {:?}", node),
+                }
+                self.place_node_at(node_id, parent);
+            }
+            node_id = child_id;
+            path.push((node_id, key_nibbles.len() - partial.len()));
+        }
+        Ok(())
+    }
+
+    /// Deletes the given key from the trie. By default, deleting an absent
+    /// key is a no-op; in strict mode it fails instead, after which the
+    /// update should be discarded.
+    pub fn delete(&mut self, key: &[u8]) -> Result<(), StorageError> {
+        self.record_op(|| MemTrieUpdateOp::Delete { key: key.to_vec() });
+        self.reload_spilled_nodes()?;
+        self.check_strict_delete(key)?;
+        self.timed_descent(|update| update.generic_delete(0, key))?;
+        self.after_op();
+        Ok(())
+    }
+
+    /// Deletes the value of the branch located at `key`, keeping its
+    /// children. The branch stays a branch if it has at least two children,
+    /// and is squashed with its only child otherwise. Returns false without
+    /// changing anything if there is no branch with a value at `key`, e.g.
+    /// if the key is stored in a leaf.
+    pub fn delete_branch_value(&mut self, key: &[u8]) -> Result<bool, StorageError> {
+        self.reload_spilled_nodes()?;
+        if !self.is_branch_value(key) {
+            return Ok(false);
+        }
+        self.delete(key)?;
+        Ok(true)
+    }
+
+    /// Whether the value of `key` is stored in a branch rather than a leaf.
+    fn is_branch_value(&self, key: &[u8]) -> bool {
+        let mut partial = NibbleSlice::new(key);
+        let mut node_id = OldOrUpdatedNodeId::Updated(0);
+        loop {
+            let (extension, child) = match node_id {
+                OldOrUpdatedNodeId::Updated(node_id) => match &self.get_node_ref(node_id).node {
+                    UpdatedMemTrieNode::Branch { value, .. } if partial.is_empty() => {
+                        return value.is_some();
+                    }
+                    UpdatedMemTrieNode::Branch { children, .. } => {
+                        (None, children[partial.at(0) as usize])
+                    }
+                    UpdatedMemTrieNode::Extension { extension, child } => {
+                        (Some(NibbleSlice::from_encoded(extension).0), Some(*child))
                     }
+                    UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Leaf { .. } => return false,
+                },
+                OldOrUpdatedNodeId::Old(node_id) => match node_id.as_ptr(self.memory).view() {
+                    MemTrieNodeView::BranchWithValue { .. } if partial.is_empty() => return true,
+                    MemTrieNodeView::Branch { children, .. }
+                    | MemTrieNodeView::BranchWithValue { children, .. } => {
+                        if partial.is_empty() {
+                            return false;
+                        }
+                        let child = children.get(partial.at(0) as usize);
+                        (None, child.map(|child| OldOrUpdatedNodeId::Old(child.id())))
+                    }
+                    MemTrieNodeView::Extension { extension, child, .. } => (
+                        Some(NibbleSlice::from_encoded(extension).0),
+                        Some(OldOrUpdatedNodeId::Old(child.id())),
+                    ),
+                    MemTrieNodeView::Leaf { .. } => return false,
+                },
+            };
+            match extension {
+                Some(extension) if !partial.starts_with(&extension) => return false,
+                Some(extension) => partial = partial.mid(extension.len()),
+                None => partial = partial.mid(1),
+            }
+            let Some(child) = child else {
+                return false;
+            };
+            node_id = child;
+        }
+    }
+
+    /// Deletes multiple keys, like calling `delete` for each of them, but
+    /// squashes the affected nodes only once in the end, so that nodes
+    /// shared by the paths to the keys are not restructured repeatedly.
+    pub fn delete_many<'k>(
+        &mut self,
+        keys: impl IntoIterator<Item = &'k [u8]>,
+    ) -> Result<(), StorageError> {
+        self.reload_spilled_nodes()?;
+        self.timed_descent(|update| {
+            let mut modified = Vec::new();
+            for key in keys {
+                update.record_op(|| MemTrieUpdateOp::Delete { key: key.to_vec() });
+                update.check_strict_delete(key)?;
+                if let Some(path) = update.generic_delete_without_squash(0, key)? {
+                    modified.extend(path);
+                }
+            }
+            update.squash_all(&modified)
+        })?;
+        self.after_op();
+        Ok(())
+    }
+
+    /// Deletes all keys in `[start, end)` in a single walk over the nodes
+    /// which may contain them, squashing each affected node once after its
+    /// children. Returns the number of deleted values. When tracking,
+    /// refcounts of the deleted values and of the visited nodes are
+    /// decremented, as for `delete`.
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) -> Result<u64, StorageError> {
+        self.record_op(|| MemTrieUpdateOp::DeleteRange {
+            start: start.to_vec(),
+            end: end.to_vec(),
+        });
+        self.reload_spilled_nodes()?;
+        let num_deleted = self.timed_descent(|update| {
+            update.generic_delete_range(0, &NibbleSlice::new(start), &NibbleSlice::new(end))
+        })?;
+        self.after_op();
+        Ok(num_deleted)
+    }
+
+    /// Deletes all keys from the trie in a single walk over it, without
+    /// restructuring nodes key by key. When tracking, refcounts of all nodes
+    /// and values of the original trie are decremented, and values inserted
+    /// by this update are cancelled out.
+    pub fn clear(&mut self) -> Result<(), StorageError> {
+        self.record_op(|| MemTrieUpdateOp::Clear);
+        self.reload_spilled_nodes()?;
+        let memory = self.memory;
+        let mut updated_nodes = vec![0];
+        let mut old_nodes = vec![];
+        while let Some(node_id) = updated_nodes.pop() {
+            let UpdatedMemTrieNodeWithSize { node, .. } = self.take_node(node_id)?;
+            let (children, value) = match node {
+                UpdatedMemTrieNode::Empty => (vec![], None),
+                UpdatedMemTrieNode::Leaf { value, .. } => (vec![], Some(value)),
+                UpdatedMemTrieNode::Branch { children, value, .. } => {
+                    (children.into_iter().flatten().collect(), value)
+                }
+                UpdatedMemTrieNode::Extension { child, .. } => (vec![child], None),
+            };
+            if let Some(value) = value {
+                self.delete_value(value)?;
+            }
+            for child in children {
+                match child {
+                    OldOrUpdatedNodeId::Updated(child_id) => updated_nodes.push(child_id),
+                    OldOrUpdatedNodeId::Old(child_id) => old_nodes.push(child_id),
+                }
+            }
+        }
+        // Without tracking or a witness, there is nothing to do for the
+        // original nodes.
+        if self.nodes_tracker.is_some()
+            || self.instrumentation.as_ref().is_some_and(|i| i.witness.is_some())
+        {
+            while let Some(node_id) = old_nodes.pop() {
+                let view = node_id.as_ptr(memory).view();
+                if let Some(nodes_tracker) = self.nodes_tracker.as_mut() {
+                    nodes_tracker.record(&view);
+                }
+                if let Some(witness) =
+                    self.instrumentation.as_mut().and_then(|i| i.witness.as_mut())
+                {
+                    witness.record(&view);
                 }
+                if let MemTrieNodeView::Leaf { value, .. }
+                | MemTrieNodeView::BranchWithValue { value, .. } = &view
+                {
+                    self.delete_value(value.to_flat_value())?;
+                }
+                old_nodes.extend(view.iter_children().map(|child| child.id()));
             }
+        }
+        self.prepared_splits.clear();
+        self.place_node_at(0, UpdatedMemTrieNodeWithSize::empty());
+        Ok(())
+    }
+
+    fn check_strict_delete(&self, key: &[u8]) -> Result<(), StorageError> {
+        if self.strict_deletes && self.get_value(key)?.is_none() {
+            return Err(StorageError::StorageInconsistentState(format!(
+                "Deleting absent key {} in strict mode",
+                hex::encode(key)
+            )));
+        }
+        Ok(())
+    }
+
+    /// Looks up the value of the key, taking into account the changes made so
+    /// far in this update. Descends through the updated nodes, reading back
+    /// spilled ones, and continues in the original trie below them.
+    fn get_value(&self, key: &[u8]) -> Result<Option<FlatStateValue>, StorageError> {
+        let mut partial = NibbleSlice::new(key);
+        let mut node_id = OldOrUpdatedNodeId::Updated(0);
+        loop {
+            node_id = match node_id {
+                OldOrUpdatedNodeId::Updated(node_id) => {
+                    let node = self.read_updated_node(node_id)?;
+                    match &node.node {
+                        UpdatedMemTrieNode::Empty => return Ok(None),
+                        UpdatedMemTrieNode::Leaf { extension, value, .. } => {
+                            return Ok((NibbleSlice::from_encoded(extension).0 == partial)
+                                .then(|| value.clone()));
+                        }
+                        UpdatedMemTrieNode::Branch { value, .. } if partial.is_empty() => {
+                            return Ok(value.clone());
+                        }
+                        UpdatedMemTrieNode::Branch { children, .. } => {
+                            let Some(child) = children[partial.at(0) as usize] else {
+                                return Ok(None);
+                            };
+                            partial = partial.mid(1);
+                            child
+                        }
+                        UpdatedMemTrieNode::Extension { extension, child } => {
+                            let extension = NibbleSlice::from_encoded(extension).0;
+                            if !partial.starts_with(&extension) {
+                                return Ok(None);
+                            }
+                            partial = partial.mid(extension.len());
+                            *child
+                        }
+                    }
+                }
+                OldOrUpdatedNodeId::Old(node_id) => match node_id.as_ptr(self.memory).view() {
+                    MemTrieNodeView::Leaf { extension, value } => {
+                        return Ok((NibbleSlice::from_encoded(extension).0 == partial)
+                            .then(|| value.to_flat_value()));
+                    }
+                    MemTrieNodeView::BranchWithValue { value, .. } if partial.is_empty() => {
+                        return Ok(Some(value.to_flat_value()));
+                    }
+                    MemTrieNodeView::Branch { .. } if partial.is_empty() => return Ok(None),
+                    MemTrieNodeView::Branch { children, .. }
+                    | MemTrieNodeView::BranchWithValue { children, .. } => {
+                        let Some(child) = children.get(partial.at(0) as usize) else {
+                            return Ok(None);
+                        };
+                        partial = partial.mid(1);
+                        OldOrUpdatedNodeId::Old(child.id())
+                    }
+                    MemTrieNodeView::Extension { extension, child, .. } => {
+                        let extension = NibbleSlice::from_encoded(extension).0;
+                        if !partial.starts_with(&extension) {
+                            return Ok(None);
+                        }
+                        partial = partial.mid(extension.len());
+                        OldOrUpdatedNodeId::Old(child.id())
+                    }
+                },
+            };
+        }
+    }
+
+    /// Returns the updated node with given ID, reading it back if it was
+    /// spilled. Unlike `take_node`, the node stays where it is.
+    fn read_updated_node(
+        &self,
+        node_id: UpdatedNodeId,
+    ) -> Result<Cow<'_, UpdatedMemTrieNodeWithSize>, StorageError> {
+        if let Some(node) = self.updated_nodes[node_id].as_ref() {
+            return Ok(Cow::Borrowed(node));
+        }
+        let spilled_node =
+            match self.instrumentation.as_ref().and_then(|i| i.spilled_nodes.as_ref()) {
+                Some(spilled_nodes) => spilled_nodes.read(node_id)?,
+                None => None,
+            };
+        Ok(Cow::Owned(spilled_node.expect("Node is taken")))
+    }
+
+    /// Sets the value of `key` in the given slot. Like `insert_memtrie_only`
+    /// for the primary slot, which keeps the shadow value of an existing key.
+    /// A shadow value can only be set for a key which has a primary value.
+    #[cfg(feature = "memtrie_shadow_values")]
+    pub fn insert_to_slot(
+        &mut self,
+        slot: ValueSlot,
+        key: &[u8],
+        value: FlatStateValue,
+    ) -> Result<(), StorageError> {
+        match slot {
+            ValueSlot::Primary => self.insert_memtrie_only(key, value),
+            ValueSlot::Shadow => self.set_shadow_value(key, Some(value)),
+        }
+    }
+
+    /// Looks up the value of `key` in the given slot.
+    #[cfg(feature = "memtrie_shadow_values")]
+    pub fn get_from_slot(
+        &self,
+        slot: ValueSlot,
+        key: &[u8],
+    ) -> Result<Option<FlatStateValue>, StorageError> {
+        match slot {
+            ValueSlot::Primary => self.get_value(key),
+            ValueSlot::Shadow => self.get_shadow_value(key),
+        }
+    }
+
+    /// Removes the value of `key` from the given slot. Deleting the primary
+    /// value deletes the shadow value as well.
+    #[cfg(feature = "memtrie_shadow_values")]
+    pub fn delete_from_slot(&mut self, slot: ValueSlot, key: &[u8]) -> Result<(), StorageError> {
+        match slot {
+            ValueSlot::Primary => self.delete(key),
+            ValueSlot::Shadow if self.get_shadow_value(key)?.is_none() => Ok(()),
+            ValueSlot::Shadow => self.set_shadow_value(key, None),
+        }
+    }
+
+    /// Looks up the shadow value of the key. Shadow values are only stored
+    /// in updated nodes, so the descent stops at the original trie.
+    #[cfg(feature = "memtrie_shadow_values")]
+    fn get_shadow_value(&self, key: &[u8]) -> Result<Option<FlatStateValue>, StorageError> {
+        let mut partial = NibbleSlice::new(key);
+        let mut node_id = 0;
+        loop {
+            let node = self.read_updated_node(node_id)?;
+            let child = match &node.node {
+                UpdatedMemTrieNode::Empty => return Ok(None),
+                UpdatedMemTrieNode::Leaf { extension, shadow_value, .. } => {
+                    return Ok(shadow_value
+                        .clone()
+                        .filter(|_| NibbleSlice::from_encoded(extension).0 == partial));
+                }
+                UpdatedMemTrieNode::Branch { shadow_value, .. } if partial.is_empty() => {
+                    return Ok(shadow_value.clone());
+                }
+                UpdatedMemTrieNode::Branch { children, .. } => {
+                    let Some(child) = children[partial.at(0) as usize] else {
+                        return Ok(None);
+                    };
+                    partial = partial.mid(1);
+                    child
+                }
+                UpdatedMemTrieNode::Extension { extension, child } => {
+                    let extension = NibbleSlice::from_encoded(extension).0;
+                    if !partial.starts_with(&extension) {
+                        return Ok(None);
+                    }
+                    partial = partial.mid(extension.len());
+                    *child
+                }
+            };
+            match child {
+                OldOrUpdatedNodeId::Updated(child_id) => node_id = child_id,
+                OldOrUpdatedNodeId::Old(_) => return Ok(None),
+            }
+        }
+    }
+
+    /// Sets or clears the shadow value of the key, which must have a primary
+    /// value. Shadow values don't count towards the memory usage of nodes,
+    /// so no memory usages change.
+    #[cfg(feature = "memtrie_shadow_values")]
+    fn set_shadow_value(
+        &mut self,
+        key: &[u8],
+        value: Option<FlatStateValue>,
+    ) -> Result<(), StorageError> {
+        self.reload_spilled_nodes()?;
+        let mut path = vec![(0, 0)];
+        self.extend_updated_path_to_value(key, &mut path)?;
+        let (node_id, _) = *path.last().unwrap();
+        let mut node = self.take_node(node_id)?;
+        match &mut node.node {
+            UpdatedMemTrieNode::Leaf { shadow_value, .. }
+            | UpdatedMemTrieNode::Branch { shadow_value, .. } => *shadow_value = value,
+            node => unreachable!("Unexpected node at key {}: {:?}", hex::encode(key), node),
+        }
+        self.place_node_at(node_id, node);
+        Ok(())
+    }
+
+    /// Drops all shadow values, which are not part of the state and so must
+    /// not end up in the changes.
+    #[cfg(feature = "memtrie_shadow_values")]
+    fn drop_shadow_values(&mut self) {
+        for node in self.updated_nodes.iter_mut().flatten() {
+            if let UpdatedMemTrieNode::Leaf { shadow_value, .. }
+            | UpdatedMemTrieNode::Branch { shadow_value, .. } = &mut node.node
+            {
+                *shadow_value = None;
+            }
+        }
+    }
+
+    /// Moves values from old keys to new keys, applying the mapping pairs one
+    /// after another. Relocated values keep their disk refcounts, as the
+    /// value removed from the old key is the one added at the new key.
+    pub fn rekey(
+        &mut self,
+        mapping: impl Iterator<Item = (Vec<u8>, Vec<u8>)>,
+    ) -> Result<(), StorageError> {
+        for (old_key, new_key) in mapping {
+            self.record_op(|| MemTrieUpdateOp::Rekey {
+                old_key: old_key.clone(),
+                new_key: new_key.clone(),
+            });
+            let value = self.get_value(&old_key)?.ok_or_else(|| {
+                StorageError::StorageInconsistentState(format!(
+                    "Rekeying absent key {}",
+                    hex::encode(&old_key)
+                ))
+            })?;
+            let value_hash = value.to_value_ref().hash;
+            // The value is moved, so its refcount doesn't reach zero.
+            if self.nodes_tracker.is_some() {
+                if let Some(zero_crossings) =
+                    self.instrumentation.as_mut().and_then(|i| i.zero_crossings.as_mut())
+                {
+                    zero_crossings.add_delta(value_hash, 1);
+                }
+            }
+            self.generic_delete(0, &old_key)?;
+            self.generic_insert(0, &new_key, GenericTrieValue::MemtrieOnly(value))?;
+            // Cancel out the value removal recorded by the delete.
+            if let Some(nodes_tracker) = self.nodes_tracker.as_mut() {
+                let rc = nodes_tracker.refcount_deleted_hashes.get_mut(&value_hash).unwrap();
+                *rc -= 1;
+                if *rc == 0 {
+                    nodes_tracker.refcount_deleted_hashes.remove(&value_hash);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a disk refcount to the values of the given existing keys, without
+    /// changing the trie itself, e.g. to hand inlined values over to the
+    /// refcounted storage. The values must be inlined, as the full value is
+    /// needed to insert it.
+    pub fn touch_values(&mut self, keys: &[Vec<u8>]) -> Result<(), StorageError> {
+        self.record_op(|| MemTrieUpdateOp::TouchValues { keys: keys.to_vec() });
+        for key in keys {
+            match self.get_value(key)? {
+                Some(FlatStateValue::Inlined(value)) => self.add_refcount_to_value(value),
+                Some(FlatStateValue::Ref(_)) => {
+                    return Err(StorageError::StorageInconsistentState(format!(
+                        "Touching value of key {} which is not inlined",
+                        hex::encode(key)
+                    )));
+                }
+                None => {
+                    return Err(StorageError::StorageInconsistentState(format!(
+                        "Touching absent key {}",
+                        hex::encode(key)
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records an additional disk refcount for the given value, if disk
+    /// changes are tracked.
+    fn add_refcount_to_value(&mut self, value: Vec<u8>) {
+        if let Some(nodes_tracker) = self.nodes_tracker.as_mut() {
+            if let Some(zero_crossings) =
+                self.instrumentation.as_mut().and_then(|i| i.zero_crossings.as_mut())
+            {
+                zero_crossings.add_delta(hash(&value), 1);
+            }
+            *nodes_tracker.refcount_inserted_values.entry(value).or_default() += 1;
+        }
+    }
+
+    /// Sets or clears the value stored directly at the given updated node,
+    /// which must be a branch or a leaf reachable from the root. Meant for
+    /// callers which already located the node, e.g. when editing a subtree.
+    ///
+    /// Like `generic_insert`, the new value is tracked for disk changes only
+    /// if it's given in full with `GenericTrieValue::MemtrieAndDisk`, while
+    /// the removal of the old value is always tracked. Memory usages of all
+    /// ancestors are recomputed, and if the value is cleared, the path from
+    /// the root is squashed to keep the trie structure unique.
+    pub fn set_value_at(
+        &mut self,
+        node_id: UpdatedNodeId,
+        value: Option<GenericTrieValue>,
+    ) -> Result<(), StorageError> {
+        let mut path = Vec::new();
+        if !self.find_path_to_updated_node(0, node_id, &mut path) {
+            return Err(StorageError::StorageInconsistentState(format!(
+                "Node {} is not reachable from the root",
+                node_id
+            )));
+        }
+        if let UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Extension { .. } =
+            &self.get_node_ref(node_id).node
+        {
+            return Err(StorageError::StorageInconsistentState(format!(
+                "Cannot set value at node {}: {:?}",
+                node_id,
+                self.get_node_ref(node_id).node
+            )));
+        }
+        let key = self.key_at_path(&path).ok_or_else(|| {
+            StorageError::StorageInconsistentState(format!(
+                "Node {} doesn't store the value of a key",
+                node_id
+            ))
+        })?;
+        self.record_op(|| match &value {
+            Some(GenericTrieValue::MemtrieAndDisk(value)) => {
+                MemTrieUpdateOp::Insert { key, value: value.clone() }
+            }
+            Some(GenericTrieValue::MemtrieOnly(value)) => {
+                MemTrieUpdateOp::InsertMemtrieOnly { key, value: value.clone() }
+            }
+            None => MemTrieUpdateOp::Delete { key },
+        });
+        let old_memory_usages: Vec<u64> =
+            path.iter().map(|node_id| self.get_node_ref(*node_id).memory_usage).collect();
+
+        let value_cleared = value.is_none();
+        let GenericUpdatedTrieNodeWithSize { node, memory_usage } = self.take_node(node_id)?;
+        let children_memory_usage = memory_usage.saturating_sub(node.memory_usage_direct());
+        let new_node = match node {
+            UpdatedMemTrieNode::Branch {
+                children,
+                value: old_value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            } => {
+                if let Some(old_value) = old_value {
+                    self.delete_value(old_value)?;
+                }
+                let value = value.map(|value| self.store_value(value));
+                // The shadow value goes away together with the primary one.
+                #[cfg(feature = "memtrie_shadow_values")]
+                let shadow_value = shadow_value.filter(|_| value.is_some());
+                let node = UpdatedMemTrieNode::Branch {
+                    children,
+                    value,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value,
+                };
+                let memory_usage = children_memory_usage + node.memory_usage_direct();
+                UpdatedMemTrieNodeWithSize { node, memory_usage }
+            }
+            UpdatedMemTrieNode::Leaf {
+                extension,
+                value: old_value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            } => {
+                self.delete_value(old_value)?;
+                match value {
+                    Some(value) => {
+                        let value = self.store_value(value);
+                        let node = UpdatedMemTrieNode::Leaf {
+                            extension,
+                            value,
+                            #[cfg(feature = "memtrie_shadow_values")]
+                            shadow_value,
+                        };
+                        let memory_usage = node.memory_usage_direct();
+                        UpdatedMemTrieNodeWithSize { node, memory_usage }
+                    }
+                    None => UpdatedMemTrieNodeWithSize::empty(),
+                }
+            }
+            UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Extension { .. } => {
+                unreachable!("Checked above")
+            }
+        };
+        // Squashed below if the value was cleared.
+        self.place_unsquashed_node_at(node_id, new_node);
+        // Squash nodes on the way up if the value was removed.
+        self.update_path_memory_usage(path, old_memory_usages, value_cleared)
+    }
+
+    /// Propagates the change of memory usage of the last node on the path
+    /// from the root up to the root, given the memory usages of the path
+    /// nodes before the change. If `squash` is set, also squashes each node
+    /// of the path, bottom-up.
+    fn update_path_memory_usage(
+        &mut self,
+        path: Vec<UpdatedNodeId>,
+        old_memory_usages: Vec<u64>,
+        squash: bool,
+    ) -> Result<(), StorageError> {
+        let mut child = None;
+        for (i, node_id) in path.into_iter().enumerate().rev() {
+            if let Some((old_child_memory_usage, new_child_memory_usage)) = child {
+                let mut node = self.take_node(node_id)?;
+                node.memory_usage =
+                    node.memory_usage + new_child_memory_usage - old_child_memory_usage;
+                self.place_node_at(node_id, node);
+            }
+            if squash {
+                self.squash_node(node_id)?;
+            }
+            child = Some((old_memory_usages[i], self.get_node_ref(node_id).memory_usage));
+        }
+        Ok(())
+    }
+
+    /// Splits the nodes on the path to `key` as if it was inserted, but
+    /// without adding the value, so that a following `insert` of `key` only
+    /// has to fill the prepared slot. Useful to move the restructuring off
+    /// latency-sensitive paths when the key is known in advance.
+    ///
+    /// If `key` isn't inserted afterwards, the prepared nodes are squashed
+    /// back when the update is finalized, so the trie stays canonical.
+    pub fn prepare_split(&mut self, key: &[u8]) -> Result<(), StorageError> {
+        if self.get_value(key)?.is_some() {
+            return Ok(());
+        }
+        // Insert a placeholder value and remove it right away, without
+        // squashing anything. The placeholder is memtrie only, so it's not
+        // tracked as a disk change.
+        let placeholder = FlatStateValue::Inlined(vec![]);
+        self.generic_insert(0, key, GenericTrieValue::MemtrieOnly(placeholder))?;
+        let node_id = self.find_updated_node_for_key(key);
+        let mut path = Vec::new();
+        assert!(self.find_path_to_updated_node(0, node_id, &mut path));
+        let old_memory_usages: Vec<u64> =
+            path.iter().map(|node_id| self.get_node_ref(*node_id).memory_usage).collect();
+        let GenericUpdatedTrieNodeWithSize { node, memory_usage } = self.take_node(node_id)?;
+        let children_memory_usage = memory_usage.saturating_sub(node.memory_usage_direct());
+        let node = match node {
+            UpdatedMemTrieNode::Branch { children, value: Some(_), .. } => {
+                UpdatedMemTrieNode::Branch {
+                    children,
+                    value: None,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value: None,
+                }
+            }
+            UpdatedMemTrieNode::Leaf { .. } => UpdatedMemTrieNode::Empty,
+            node => unreachable!("Unexpected node at inserted key: {:?}", node),
+        };
+        let memory_usage = children_memory_usage + node.memory_usage_direct();
+        self.place_unsquashed_node_at(node_id, UpdatedMemTrieNodeWithSize { node, memory_usage });
+        self.update_path_memory_usage(path, old_memory_usages, false)?;
+        self.prepared_splits.push(node_id);
+        Ok(())
+    }
+
+    /// Finds the updated node where the value of `key` is stored, assuming
+    /// that all nodes on the path to it are updated, e.g. right after `key`
+    /// was inserted.
+    fn find_updated_node_for_key(&self, key: &[u8]) -> UpdatedNodeId {
+        let mut partial = NibbleSlice::new(key);
+        let mut node_id = 0;
+        loop {
+            let child = match &self.get_node_ref(node_id).node {
+                UpdatedMemTrieNode::Branch { .. } if partial.is_empty() => return node_id,
+                UpdatedMemTrieNode::Leaf { .. } => return node_id,
+                UpdatedMemTrieNode::Branch { children, .. } => {
+                    let child = children[partial.at(0) as usize];
+                    partial = partial.mid(1);
+                    child
+                }
+                UpdatedMemTrieNode::Extension { extension, child } => {
+                    partial = partial.mid(NibbleSlice::from_encoded(extension).0.len());
+                    Some(*child)
+                }
+                UpdatedMemTrieNode::Empty => None,
+            };
+            match child {
+                Some(OldOrUpdatedNodeId::Updated(child_id)) => node_id = child_id,
+                _ => panic!("Key {} is not on a path of updated nodes", hex::encode(key)),
+            }
+        }
+    }
+
+    /// Squashes the nodes left without a value by `prepare_split`, together
+    /// with their ancestors, unless a value was inserted there since.
+    fn squash_prepared_splits(&mut self) -> Result<(), StorageError> {
+        for node_id in std::mem::take(&mut self.prepared_splits) {
+            let needs_squash = match &self.get_node_ref(node_id).node {
+                UpdatedMemTrieNode::Empty => true,
+                UpdatedMemTrieNode::Branch { value, .. } => value.is_none(),
+                UpdatedMemTrieNode::Leaf { .. } | UpdatedMemTrieNode::Extension { .. } => false,
+            };
+            let mut path = Vec::new();
+            // The node may have been detached from the trie by later changes.
+            if !needs_squash || !self.find_path_to_updated_node(0, node_id, &mut path) {
+                continue;
+            }
+            let old_memory_usages =
+                path.iter().map(|node_id| self.get_node_ref(*node_id).memory_usage).collect();
+            self.update_path_memory_usage(path, old_memory_usages, true)?;
+        }
+        Ok(())
+    }
+
+    /// Squashes all the given modified nodes exactly once, children before
+    /// parents, recomputing memory usages on the way. All ancestors of the
+    /// modified nodes must be modified too, as it happens for the paths
+    /// returned by `generic_delete_without_squash`.
+    fn squash_all(&mut self, modified: &[UpdatedNodeId]) -> Result<(), StorageError> {
+        let modified: HashSet<UpdatedNodeId> = modified.iter().copied().collect();
+        let mut ordered_nodes = Vec::new();
+        if modified.contains(&0) {
+            self.post_order_traverse_modified_nodes(0, &modified, &mut ordered_nodes);
+        }
+        for node_id in ordered_nodes {
+            let UpdatedMemTrieNodeWithSize { node, .. } = self.take_node(node_id)?;
+            let memory_usage = node.memory_usage_direct() + self.children_memory_usage(&node);
+            self.place_node_at(node_id, UpdatedMemTrieNodeWithSize { node, memory_usage });
+            self.squash_node(node_id)?;
+        }
+        Ok(())
+    }
+
+    /// Appends the nodes from `modified` in the subtree of `node_id` to
+    /// `ordered_nodes`, in post-order. Unlike
+    /// `post_order_traverse_updated_nodes`, allows empty nodes anywhere.
+    fn post_order_traverse_modified_nodes(
+        &self,
+        node_id: UpdatedNodeId,
+        modified: &HashSet<UpdatedNodeId>,
+        ordered_nodes: &mut Vec<UpdatedNodeId>,
+    ) {
+        let children: Vec<OldOrUpdatedNodeId> = match &self.get_node_ref(node_id).node {
+            UpdatedMemTrieNode::Branch { children, .. } => {
+                children.iter().flatten().copied().collect()
+            }
+            UpdatedMemTrieNode::Extension { child, .. } => vec![*child],
+            UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Leaf { .. } => vec![],
+        };
+        for child in children {
+            if let OldOrUpdatedNodeId::Updated(child_id) = child {
+                if modified.contains(&child_id) {
+                    self.post_order_traverse_modified_nodes(child_id, modified, ordered_nodes);
+                }
+            }
+        }
+        ordered_nodes.push(node_id);
+    }
+
+    /// Returns the total memory usage of the children of the node.
+    fn children_memory_usage(&self, node: &UpdatedMemTrieNode) -> u64 {
+        let child_memory_usage = |child: &OldOrUpdatedNodeId| match child {
+            OldOrUpdatedNodeId::Updated(child_id) => self.get_node_ref(*child_id).memory_usage,
+            OldOrUpdatedNodeId::Old(child_id) => child_id.as_ptr(self.memory).view().memory_usage(),
+        };
+        match node {
+            UpdatedMemTrieNode::Branch { children, .. } => {
+                children.iter().flatten().map(child_memory_usage).sum()
+            }
+            UpdatedMemTrieNode::Extension { child, .. } => child_memory_usage(child),
+            UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Leaf { .. } => 0,
+        }
+    }
+
+    /// Finds the path of updated nodes from `node_id` down to `target`,
+    /// appending it to `path`. Returns false if `target` is not reachable.
+    fn find_path_to_updated_node(
+        &self,
+        node_id: UpdatedNodeId,
+        target: UpdatedNodeId,
+        path: &mut Vec<UpdatedNodeId>,
+    ) -> bool {
+        path.push(node_id);
+        if node_id == target {
+            return true;
+        }
+        let children: Vec<OldOrUpdatedNodeId> = match &self.get_node_ref(node_id).node {
+            UpdatedMemTrieNode::Branch { children, .. } => {
+                children.iter().flatten().copied().collect()
+            }
+            UpdatedMemTrieNode::Extension { child, .. } => vec![*child],
+            UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Leaf { .. } => vec![],
+        };
+        for child in children {
+            if let OldOrUpdatedNodeId::Updated(child_id) = child {
+                if self.find_path_to_updated_node(child_id, target, path) {
+                    return true;
+                }
+            }
+        }
+        path.pop();
+        false
+    }
+
+    /// Returns the key of the value stored at the last node of `path`, as
+    /// found by `find_path_to_updated_node`, or None if the nibbles leading
+    /// there don't form a key.
+    fn key_at_path(&self, path: &[UpdatedNodeId]) -> Option<Vec<u8>> {
+        let mut nibbles = Vec::new();
+        for (parent, child) in path.iter().zip(path.iter().skip(1)) {
+            match &self.get_node_ref(*parent).node {
+                UpdatedMemTrieNode::Branch { children, .. } => {
+                    let child = Some(OldOrUpdatedNodeId::Updated(*child));
+                    nibbles.push(children.iter().position(|c| *c == child)? as u8);
+                }
+                UpdatedMemTrieNode::Extension { extension, .. } => {
+                    nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+                }
+                UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Leaf { .. } => return None,
+            }
+        }
+        if let UpdatedMemTrieNode::Leaf { extension, .. } = &self.get_node_ref(*path.last()?).node {
+            nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+        }
+        (nibbles.len() % 2 == 0).then(|| NibbleSlice::nibbles_to_bytes(&nibbles))
+    }
+
+    /// Returns the IDs of non-empty updated nodes which are not reachable
+    /// from the root. Such nodes are never written to the trie, but still
+    /// count as created, so any of them indicates a bug. Empty nodes are
+    /// skipped, as deletions leave them detached by design. Intended for
+    /// debugging.
+    pub fn find_orphans(&self) -> Vec<UpdatedNodeId> {
+        let mut reachable = vec![false; self.updated_nodes.len()];
+        let mut stack = vec![0];
+        while let Some(node_id) = stack.pop() {
+            reachable[node_id] = true;
+            let Some(node) = &self.updated_nodes[node_id] else {
+                continue;
+            };
+            let children: Vec<OldOrUpdatedNodeId> = match &node.node {
+                UpdatedMemTrieNode::Branch { children, .. } => {
+                    children.iter().flatten().copied().collect()
+                }
+                UpdatedMemTrieNode::Extension { child, .. } => vec![*child],
+                UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Leaf { .. } => vec![],
+            };
+            for child in children {
+                if let OldOrUpdatedNodeId::Updated(child_id) = child {
+                    stack.push(child_id);
+                }
+            }
+        }
+        (0..self.updated_nodes.len())
+            .filter(|node_id| {
+                !reachable[*node_id]
+                    && self.updated_nodes[*node_id]
+                        .as_ref()
+                        .is_some_and(|node| !matches!(node.node, UpdatedMemTrieNode::Empty))
+            })
+            .collect()
+    }
+
+    /// Returns the IDs of original nodes which are still referenced by the
+    /// updated nodes as old children, in the order of first reference, e.g.
+    /// to keep them resident until the update is applied. The root is
+    /// converted when the update is created, so it is never one of them.
+    /// Nodes spilled by `with_updated_nodes_cap` are not scanned.
+    pub fn referenced_old_nodes(&self) -> Vec<MemTrieNodeId> {
+        let mut seen = HashSet::new();
+        let mut old_nodes = Vec::new();
+        for node in self.updated_nodes.iter().flatten() {
+            let children: Vec<OldOrUpdatedNodeId> = match &node.node {
+                UpdatedMemTrieNode::Branch { children, .. } => {
+                    children.iter().flatten().copied().collect()
+                }
+                UpdatedMemTrieNode::Extension { child, .. } => vec![*child],
+                UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Leaf { .. } => vec![],
+            };
+            for child in children {
+                if let OldOrUpdatedNodeId::Old(node_id) = child {
+                    if seen.insert(node_id) {
+                        old_nodes.push(node_id);
+                    }
+                }
+            }
+        }
+        old_nodes
+    }
+
+    /// Returns the number of distinct original nodes which are still part of
+    /// the trie defined by the update: the nodes of subtrees referenced as
+    /// old children of updated nodes reachable from the root. This shows how
+    /// much of the original trie the update left untouched. Nodes spilled by
+    /// `with_updated_nodes_cap` are not scanned.
+    pub fn reused_original_node_count(&self) -> usize {
+        let mut reused = HashSet::new();
+        let mut stack = if self.updated_nodes.is_empty() { vec![] } else { vec![0] };
+        let mut old_stack = Vec::new();
+        while let Some(node_id) = stack.pop() {
+            let Some(node) = &self.updated_nodes[node_id] else {
+                continue;
+            };
+            let children: Vec<OldOrUpdatedNodeId> = match &node.node {
+                UpdatedMemTrieNode::Branch { children, .. } => {
+                    children.iter().flatten().copied().collect()
+                }
+                UpdatedMemTrieNode::Extension { child, .. } => vec![*child],
+                UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Leaf { .. } => vec![],
+            };
+            for child in children {
+                match child {
+                    OldOrUpdatedNodeId::Updated(child_id) => stack.push(child_id),
+                    OldOrUpdatedNodeId::Old(child_id) => old_stack.push(child_id),
+                }
+            }
+        }
+        while let Some(node_id) = old_stack.pop() {
+            if reused.insert(node_id) {
+                old_stack.extend(
+                    node_id.as_ptr(self.memory).view().iter_children().map(|child| child.id()),
+                );
+            }
+        }
+        reused.len()
+    }
+
+    /// Returns the nibbles of the key leading from the root to the given
+    /// updated node. For a leaf, its own extension is included as well, so
+    /// the result is the full key of the leaf. Intended for debugging.
+    pub fn key_prefix_of(&self, node_id: UpdatedNodeId) -> Vec<u8> {
+        let mut path = Vec::new();
+        assert!(
+            self.find_path_to_updated_node(0, node_id, &mut path),
+            "Node {} is not reachable from the root",
+            node_id
+        );
+        let mut nibbles = Vec::new();
+        for (parent_id, child_id) in path.iter().zip(path.iter().skip(1)) {
+            let child = OldOrUpdatedNodeId::Updated(*child_id);
+            match &self.get_node_ref(*parent_id).node {
+                UpdatedMemTrieNode::Branch { children, .. } => {
+                    let idx = children.iter().position(|c| *c == Some(child)).unwrap();
+                    nibbles.push(idx as u8);
+                }
+                UpdatedMemTrieNode::Extension { extension, .. } => {
+                    nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+                }
+                UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Leaf { .. } => unreachable!(),
+            }
+        }
+        if let UpdatedMemTrieNode::Leaf { extension, .. } = &self.get_node_ref(node_id).node {
+            nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+        }
+        nibbles
+    }
+
+    /// Returns the node with the given ID, either old or updated, in the
+    /// updated form. Accessing old nodes this way doesn't record them.
+    fn resolve_node(&self, node_id: OldOrUpdatedNodeId) -> UpdatedMemTrieNode {
+        match node_id {
+            OldOrUpdatedNodeId::Old(node_id) => {
+                MemTrieNodeWithSize::from_existing_node_view(node_id.as_ptr(self.memory).view())
+                    .node
+                    .into()
+            }
+            OldOrUpdatedNodeId::Updated(node_id) => self.get_node_ref(node_id).node.clone(),
+        }
+    }
+
+    /// Iterates over all key-value pairs with the given key prefix in sorted
+    /// order, reflecting inserts and deletes made so far in this update.
+    /// Subtrees outside of the prefix are not visited.
+    pub fn iter_prefix<'u>(&'u self, prefix: &[u8]) -> MemTrieUpdatePrefixIterator<'u, 'a, M, S> {
+        let prefix: Vec<u8> = NibbleSlice::new(prefix).iter().collect();
+        let mut stack = Vec::new();
+        // Descend to the first node which has all its keys under the prefix.
+        let mut path = Vec::new();
+        let mut node_id = OldOrUpdatedNodeId::Updated(0);
+        loop {
+            let remaining = &prefix[path.len()..];
+            if remaining.is_empty() {
+                stack.push((path, PrefixIteratorItem::Node(node_id)));
+                break;
+            }
+            match self.resolve_node(node_id) {
+                UpdatedMemTrieNode::Empty => break,
+                UpdatedMemTrieNode::Leaf { extension, .. } => {
+                    let extension: Vec<u8> =
+                        NibbleSlice::from_encoded(&extension).0.iter().collect();
+                    if extension.starts_with(remaining) {
+                        stack.push((path, PrefixIteratorItem::Node(node_id)));
+                    }
+                    break;
+                }
+                UpdatedMemTrieNode::Extension { extension, child } => {
+                    let extension: Vec<u8> =
+                        NibbleSlice::from_encoded(&extension).0.iter().collect();
+                    if extension.starts_with(remaining) {
+                        stack.push((path, PrefixIteratorItem::Node(node_id)));
+                        break;
+                    } else if remaining.starts_with(&extension) {
+                        path.extend(extension);
+                        node_id = child;
+                    } else {
+                        break;
+                    }
+                }
+                UpdatedMemTrieNode::Branch { children, .. } => {
+                    let Some(child) = children[remaining[0] as usize] else {
+                        break;
+                    };
+                    path.push(remaining[0]);
+                    node_id = child;
+                }
+            }
+        }
+        MemTrieUpdatePrefixIterator { update: self, stack }
+    }
+}
+
+/// Item to be visited by `MemTrieUpdatePrefixIterator`.
+enum PrefixIteratorItem {
+    Node(OldOrUpdatedNodeId),
+    Value(FlatStateValue),
+}
+
+/// In-order iterator over the trie being updated, see
+/// `MemTrieUpdate::iter_prefix`.
+pub struct MemTrieUpdatePrefixIterator<'u, 'a, M: ArenaMemory, S> {
+    update: &'u MemTrieUpdate<'a, M, S>,
+    /// Items to be visited, together with nibbles of the key leading to
+    /// them. The last item is visited first.
+    stack: Vec<(Vec<u8>, PrefixIteratorItem)>,
+}
+
+impl<'u, 'a, M: ArenaMemory, S> Iterator for MemTrieUpdatePrefixIterator<'u, 'a, M, S> {
+    type Item = (Vec<u8>, FlatStateValue);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((nibbles, item)) = self.stack.pop() {
+            let node_id = match item {
+                PrefixIteratorItem::Value(value) => {
+                    return Some((NibbleSlice::nibbles_to_bytes(&nibbles), value));
+                }
+                PrefixIteratorItem::Node(node_id) => node_id,
+            };
+            match self.update.resolve_node(node_id) {
+                UpdatedMemTrieNode::Empty => {}
+                UpdatedMemTrieNode::Leaf { extension, value, .. } => {
+                    let mut key = nibbles;
+                    key.extend(NibbleSlice::from_encoded(&extension).0.iter());
+                    return Some((NibbleSlice::nibbles_to_bytes(&key), value));
+                }
+                UpdatedMemTrieNode::Extension { extension, child } => {
+                    let mut key = nibbles;
+                    key.extend(NibbleSlice::from_encoded(&extension).0.iter());
+                    self.stack.push((key, PrefixIteratorItem::Node(child)));
+                }
+                UpdatedMemTrieNode::Branch { children, value, .. } => {
+                    for i in (0..16).rev() {
+                        if let Some(child) = children[i] {
+                            let mut key = nibbles.clone();
+                            key.push(i as u8);
+                            self.stack.push((key, PrefixIteratorItem::Node(child)));
+                        }
+                    }
+                    // The value is visited first, as its key is the shortest.
+                    if let Some(value) = value {
+                        self.stack.push((nibbles, PrefixIteratorItem::Value(value)));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+impl<'a, M: ArenaMemory, S> MemTrieUpdate<'a, M, S> {
+    /// To construct the new trie nodes, we need to create the new nodes in an
+    /// order such that children are created before their parents - essentially
+    /// a topological sort. We do this via a post-order traversal of the
+    /// updated nodes. After this function, `ordered_nodes` contains the IDs of
+    /// the updated nodes in the order they should be created.
+    fn post_order_traverse_updated_nodes(
+        node_id: UpdatedNodeId,
+        updated_nodes: &Vec<Option<UpdatedMemTrieNodeWithSize>>,
+        ordered_nodes: &mut Vec<UpdatedNodeId>,
+    ) {
+        let node = updated_nodes[node_id].as_ref().unwrap();
+        match &node.node {
+            UpdatedMemTrieNode::Empty => {
+                assert_eq!(node_id, 0); // only root can be empty
+                return;
+            }
+            UpdatedMemTrieNode::Branch { children, .. } => {
+                for child in children.iter() {
+                    if let Some(OldOrUpdatedNodeId::Updated(child_node_id)) = child {
+                        Self::post_order_traverse_updated_nodes(
+                            *child_node_id,
+                            updated_nodes,
+                            ordered_nodes,
+                        );
+                    }
+                }
+            }
+            UpdatedMemTrieNode::Extension { child, .. } => {
+                if let OldOrUpdatedNodeId::Updated(child_node_id) = child {
+                    Self::post_order_traverse_updated_nodes(
+                        *child_node_id,
+                        updated_nodes,
+                        ordered_nodes,
+                    );
+                }
+            }
+            _ => {}
+        }
+        ordered_nodes.push(node_id);
+    }
+
+    /// For each node in `ordered_nodes`, computes its hash and serialized data.
+    /// `ordered_nodes` is expected to follow the post-order traversal of the
+    /// updated nodes.
+    /// `updated_nodes` must be indexed by the node IDs in `ordered_nodes`.
+    ///
+    /// If `hash_cache` is given, nodes found in it are neither serialized nor
+    /// hashed again, and results for the other nodes are added to it. Nodes
+    /// are looked up by their raw form, which includes child hashes, so the
+    /// cache may be reused across updates, e.g. when reprocessing a block.
+    pub(crate) fn compute_hashes_and_serialized_nodes(
+        &self,
+        ordered_nodes: &Vec<UpdatedNodeId>,
+        updated_nodes: &Vec<Option<UpdatedMemTrieNodeWithSize>>,
+        hash_cache: Option<&mut NodeHashCache>,
+    ) -> Vec<(UpdatedNodeId, CryptoHash, Vec<u8>)> {
+        self.compute_hashes_and_serialized_nodes_with_hasher::<Sha256Hasher>(
+            ordered_nodes,
+            updated_nodes,
+            hash_cache,
+        )
+    }
+
+    /// Same as `compute_hashes_and_serialized_nodes`, but hashes nodes with
+    /// the given hasher. The original trie must be hashed with it too.
+    pub(crate) fn compute_hashes_and_serialized_nodes_with_hasher<H: Hasher>(
+        &self,
+        ordered_nodes: &Vec<UpdatedNodeId>,
+        updated_nodes: &Vec<Option<UpdatedMemTrieNodeWithSize>>,
+        mut hash_cache: Option<&mut NodeHashCache>,
+    ) -> Vec<(UpdatedNodeId, CryptoHash, Vec<u8>)> {
+        #[cfg(feature = "memtrie_timing")]
+        let start = Instant::now();
+        let memory = self.memory;
+        let mut result = Vec::<(CryptoHash, Vec<u8>)>::new();
+        for _ in 0..updated_nodes.len() {
+            result.push((CryptoHash::default(), Vec::new()));
+        }
+        let get_hash =
+            |node: OldOrUpdatedNodeId, result: &Vec<(CryptoHash, Vec<u8>)>| -> CryptoHash {
+                match node {
+                    OldOrUpdatedNodeId::Updated(node_id) => result[node_id].0,
+                    // IMPORTANT: getting a node hash for a child doesn't
+                    // record a new node read. In recorded storage, child node
+                    // is referenced by its hash, and we don't need to need the
+                    // whole node to verify parent hash.
+                    // TODO(#12361): consider fixing it, perhaps by taking this
+                    // hash from old version of the parent node.
+                    OldOrUpdatedNodeId::Old(node_id) => {
+                        node_id.as_ptr(memory).view().node_hash_with::<H>()
+                    }
+                }
+            };
+
+        for node_id in ordered_nodes.iter() {
+            let node = updated_nodes[*node_id].as_ref().unwrap();
+            let raw_node = node.node.to_raw_trie_node(|child| get_hash(child, &result));
+
+            let memory_usage = node.memory_usage;
+            let raw_node_with_size = RawTrieNodeWithSize { node: raw_node, memory_usage };
+            if let Some(cached) =
+                hash_cache.as_deref().and_then(|cache| cache.get(&raw_node_with_size))
+            {
+                result[*node_id] = cached.clone();
+                continue;
+            }
+            let node_serialized = borsh::to_vec(&raw_node_with_size).unwrap();
+            let node_hash = H::hash(&node_serialized);
+            #[cfg(test)]
+            NUM_NODES_HASHED.with(|num_nodes| num_nodes.set(num_nodes.get() + 1));
+            if let Some(cache) = hash_cache.as_deref_mut() {
+                cache.insert(raw_node_with_size, (node_hash, node_serialized.clone()));
+            }
+            result[*node_id] = (node_hash, node_serialized);
+        }
+
+        let hashes_and_serialized_nodes = ordered_nodes
+            .iter()
+            .map(|node_id| {
+                let (hash, serialized) = &mut result[*node_id];
+                (*node_id, *hash, std::mem::take(serialized))
+            })
+            .collect();
+        #[cfg(feature = "memtrie_timing")]
+        self.add_timings(|timings| timings.hashing += start.elapsed());
+        hashes_and_serialized_nodes
+    }
+
+    /// Same as `compute_hashes_and_serialized_nodes`, but returns an error
+    /// instead of panicking if the nodes are malformed, which may happen if
+    /// they were reconstructed from untrusted data. Each node in
+    /// `ordered_nodes` must exist, be non-empty and come after all its
+    /// updated children.
+    pub(crate) fn try_compute_hashes_and_serialized_nodes(
+        &self,
+        ordered_nodes: &[UpdatedNodeId],
+        updated_nodes: &[Option<UpdatedMemTrieNodeWithSize>],
+    ) -> Result<Vec<(UpdatedNodeId, CryptoHash, Vec<u8>)>, StorageError> {
+        let memory = self.memory;
+        try_hash_updated_nodes::<Sha256Hasher>(ordered_nodes, updated_nodes, |child| match child {
+            OldOrUpdatedNodeId::Old(node_id) => Some(node_id.as_ptr(memory).view().node_hash()),
+            OldOrUpdatedNodeId::Updated(_) => None,
+        })
+    }
+
+    /// Returns the size of the node serialized as `RawTrieNodeWithSize`,
+    /// without actually serializing it.
+    fn serialized_node_size(node: &UpdatedMemTrieNode) -> u64 {
+        // Enum discriminant and memory usage.
+        const HEADER_SIZE: u64 = 1 + size_of::<u64>() as u64;
+        // Length prefix of the extension.
+        const EXTENSION_LEN_SIZE: u64 = size_of::<u32>() as u64;
+        // Value length and hash.
+        const VALUE_REF_SIZE: u64 = (size_of::<u32>() + size_of::<CryptoHash>()) as u64;
+        const HASH_SIZE: u64 = size_of::<CryptoHash>() as u64;
+        // Bitmap of present children.
+        const CHILDREN_MASK_SIZE: u64 = size_of::<u16>() as u64;
+        match node {
+            UpdatedMemTrieNode::Empty => 0,
+            UpdatedMemTrieNode::Leaf { extension, .. } => {
+                HEADER_SIZE + EXTENSION_LEN_SIZE + extension.len() as u64 + VALUE_REF_SIZE
+            }
+            UpdatedMemTrieNode::Extension { extension, .. } => {
+                HEADER_SIZE + EXTENSION_LEN_SIZE + extension.len() as u64 + HASH_SIZE
+            }
+            UpdatedMemTrieNode::Branch { children, value, .. } => {
+                let num_children = children.iter().filter(|child| child.is_some()).count();
+                HEADER_SIZE
+                    + CHILDREN_MASK_SIZE
+                    + num_children as u64 * HASH_SIZE
+                    + value.as_ref().map_or(0, |_| VALUE_REF_SIZE)
+            }
+        }
+    }
+
+    /// Estimates the total size of `insertions` the update would produce,
+    /// without computing hashes and serializing nodes. Can be used for block
+    /// size budgeting before finalizing the update.
+    ///
+    /// The estimate is an upper bound: it counts all new nodes reachable from
+    /// the root and all inserted values, while in the final changes some of
+    /// them may cancel out with deletions of the same hash.
+    ///
+    /// Nodes are taken as they are when finalizing, so if there are spilled
+    /// nodes or prepared splits, the estimate works on a copy of them.
+    pub fn estimated_insertion_bytes(&self) -> Result<u64, StorageError> {
+        let finished;
+        let updated_nodes = if self.prepared_splits.is_empty() && self.num_spilled_nodes() == 0 {
+            &self.updated_nodes
+        } else {
+            finished = self.finished_copy()?;
+            &finished.updated_nodes
+        };
+        let mut ordered_nodes = Vec::new();
+        Self::post_order_traverse_updated_nodes(0, updated_nodes, &mut ordered_nodes);
+        let nodes_size: u64 = ordered_nodes
+            .iter()
+            .map(|node_id| {
+                Self::serialized_node_size(&updated_nodes[*node_id].as_ref().unwrap().node)
+            })
+            .sum();
+        let values_size: u64 = self.nodes_tracker.as_ref().map_or(0, |nodes_tracker| {
+            nodes_tracker.refcount_inserted_values.keys().map(|value| value.len() as u64).sum()
+        });
+        Ok(nodes_size + values_size)
+    }
+
+    /// Returns the size of the storage recorded so far, i.e. the size of the
+    /// state witness part produced by the accesses. The recorder is shared,
+    /// so the size includes everything recorded before this update was
+    /// created. Together with `estimated_insertion_bytes`, it can be used to
+    /// decide whether to include a transaction into a chunk. Returns 0 if
+    /// accesses are not tracked.
+    pub fn recorded_storage_size(&self) -> u64 {
+        self.nodes_tracker
+            .as_ref()
+            .and_then(|nodes_tracker| nodes_tracker.recorder.as_ref())
+            .map_or(0, |recorder| recorder.recorded_storage_size() as u64)
+    }
+
+    /// Converts the changes to memtrie changes. Also passes each new node
+    /// inserted, in hash and serialized form, to `on_new_node`, which takes
+    /// ownership of the serialized bytes.
+    ///
+    /// Updated nodes which ended up identical to the original nodes they
+    /// were converted from, e.g. after inserting and deleting the same key,
+    /// are not constructed again: their parents refer to the original nodes
+    /// instead. They are still passed to `on_new_node`, which cancels out
+    /// the deletion of the original nodes recorded on conversion. If the root
+    /// itself is unchanged, e.g. after inserting and deleting the same key,
+    /// no nodes are constructed at all and the original root is reused.
+    fn to_memtrie_changes_internal<H: Hasher>(
+        mut self,
+        mut on_new_node: impl FnMut(CryptoHash, Vec<u8>),
+    ) -> Result<MemTrieChanges, StorageError> {
+        self.reload_spilled_nodes()?;
+        self.squash_prepared_splits()?;
+        #[cfg(feature = "memtrie_shadow_values")]
+        self.drop_shadow_values();
+        MEMTRIE_NUM_NODES_CREATED_FROM_UPDATES
+            .with_label_values(&[&self.shard_uid])
+            .inc_by(self.updated_nodes.len() as u64);
+        #[cfg(feature = "memtrie_timing")]
+        let start = Instant::now();
+        let mut ordered_nodes = Vec::new();
+        Self::post_order_traverse_updated_nodes(0, &self.updated_nodes, &mut ordered_nodes);
+        #[cfg(feature = "memtrie_timing")]
+        self.add_timings(|timings| timings.traversal += start.elapsed());
+
+        let hashes_and_serialized_nodes = self
+            .compute_hashes_and_serialized_nodes_with_hasher::<H>(
+                &ordered_nodes,
+                &self.updated_nodes,
+                None,
+            );
+        #[cfg(feature = "memtrie_timing")]
+        self.observe_phases();
+
+        let original_nodes = &self.converted_from;
+        let mut unchanged_nodes = HashMap::new();
+        let node_ids_with_hashes: Vec<_> = hashes_and_serialized_nodes
+            .into_iter()
+            .map(|(node_id, hash, serialized)| {
+                on_new_node(hash, serialized);
+                let original_hash = original_nodes
+                    .get(&node_id)
+                    .map(|old_id| old_id.as_ptr(self.memory).view().node_hash_with::<H>());
+                if node_id != 0 && original_hash == Some(hash) {
+                    unchanged_nodes.insert(node_id, original_nodes[&node_id]);
+                }
+                (node_id, hash)
+            })
+            .collect();
+        if let (Some(root), Some((_, root_hash))) = (self.root, node_ids_with_hashes.last()) {
+            if root.as_ptr(self.memory).view().node_hash_with::<H>() == *root_hash {
+                return Ok(MemTrieChanges {
+                    node_ids_with_hashes: Vec::new(),
+                    updated_nodes: Vec::new(),
+                    unchanged_root: Some((root, *root_hash)),
+                });
+            }
+        }
+        if unchanged_nodes.is_empty() {
+            return Ok(MemTrieChanges {
+                node_ids_with_hashes,
+                updated_nodes: self.updated_nodes,
+                unchanged_root: None,
+            });
+        }
+
+        // Re-point the parents of unchanged nodes at the original nodes. The
+        // updated nodes below them become unreachable, so they are dropped.
+        let mut reachable = HashSet::new();
+        let mut stack = vec![0];
+        while let Some(node_id) = stack.pop() {
+            reachable.insert(node_id);
+            let node = &mut self.updated_nodes[node_id].as_mut().unwrap().node;
+            let children: Vec<&mut OldOrUpdatedNodeId> = match node {
+                UpdatedMemTrieNode::Branch { children, .. } => {
+                    children.iter_mut().flatten().collect()
+                }
+                UpdatedMemTrieNode::Extension { child, .. } => vec![child],
+                UpdatedMemTrieNode::Empty | UpdatedMemTrieNode::Leaf { .. } => vec![],
+            };
+            for child in children {
+                if let OldOrUpdatedNodeId::Updated(child_id) = *child {
+                    match unchanged_nodes.get(&child_id) {
+                        Some(old_id) => *child = OldOrUpdatedNodeId::Old(*old_id),
+                        None => stack.push(child_id),
+                    }
+                }
+            }
+        }
+        let node_ids_with_hashes = node_ids_with_hashes
+            .into_iter()
+            .filter(|(node_id, _)| reachable.contains(node_id))
+            .collect();
+        for (node_id, node) in self.updated_nodes.iter_mut().enumerate() {
+            if !reachable.contains(&node_id) {
+                *node = None;
+            }
+        }
+        Ok(MemTrieChanges {
+            node_ids_with_hashes,
+            updated_nodes: self.updated_nodes,
+            unchanged_root: None,
+        })
+    }
+
+    /// Computes the root hash of the trie with the changes made so far,
+    /// without finishing the update, e.g. to checkpoint progress of a long
+    /// update. May be called repeatedly; each call hashes all updated nodes.
+    pub fn checkpoint_root(&mut self) -> Result<CryptoHash, StorageError> {
+        self.reload_spilled_nodes()?;
+        self.squash_prepared_splits()?;
+        let mut ordered_nodes = Vec::new();
+        Self::post_order_traverse_updated_nodes(0, &self.updated_nodes, &mut ordered_nodes);
+        Ok(self
+            .compute_hashes_and_serialized_nodes(&ordered_nodes, &self.updated_nodes, None)
+            .last()
+            .map(|(_, hash, _)| *hash)
+            .unwrap_or_default())
+    }
+
+    /// Converts the updates to memtrie changes only.
+    pub fn to_memtrie_changes_only(self) -> Result<MemTrieChanges, StorageError> {
+        self.to_memtrie_changes_only_with_hasher::<Sha256Hasher>()
+    }
+
+    /// Same as `to_memtrie_changes_only`, but hashes nodes with the given
+    /// hasher. Only for tries which don't participate in consensus.
+    pub fn to_memtrie_changes_only_with_hasher<H: Hasher>(
+        self,
+    ) -> Result<MemTrieChanges, StorageError> {
+        self.to_memtrie_changes_internal::<H>(|_, _| {})
+    }
+}
+
+/// Applies the given memtrie changes to the in-memory trie data structure.
+/// Returns the new root hash.
+///
+/// If `node_cache` is given, nodes with hashes found in it are reused instead
+/// of being allocated again, and all constructed nodes are added to it. The
+/// caller must guarantee that the cached nodes stay alive.
+///
+/// If the arena capacity is limited and the new nodes don't fit into it, an
+/// error is returned before anything is allocated, so that no half-built root
+/// is left behind.
+///
+/// `on_alloc` is called with the kind and the allocation size of each node
+/// allocated in the arena.
+pub(super) fn construct_root_from_changes<A: ArenaMut>(
+    arena: &mut A,
+    changes: &MemTrieChanges,
+    mut node_cache: Option<&mut HashMap<CryptoHash, MemTrieNodeId>>,
+    mut on_alloc: impl FnMut(NodeKind, usize),
+) -> Result<Option<MemTrieNodeId>, StorageError> {
+    if let Some((root, _)) = changes.unchanged_root {
+        return Ok(Some(root));
+    }
+    let updated_nodes = &changes.updated_nodes;
+    let node_ids_with_hashes = &changes.node_ids_with_hashes;
+    let get_node = move |node_id: UpdatedNodeId| &updated_nodes[node_id].as_ref().unwrap().node;
+    let is_cached = |node_hash: &CryptoHash, node_cache: &Option<&mut HashMap<_, _>>| {
+        node_cache.as_ref().is_some_and(|cache| cache.contains_key(node_hash))
+    };
+
+    if let Some(remaining_capacity) = arena.remaining_capacity() {
+        // Children positions don't affect the encoded size, so placeholders
+        // are enough. Nodes which are going to be reused from the cache only
+        // by later nodes of the same changes are counted conservatively.
+        let required_capacity: usize = node_ids_with_hashes
+            .iter()
+            .filter(|(_, node_hash)| !is_cached(node_hash, &node_cache))
+            .map(|(node_id, _)| {
+                to_input_node(get_node(*node_id), |_| MemTrieNodeId::default()).encoded_size()
+            })
+            .sum();
+        if required_capacity > remaining_capacity {
+            return Err(StorageError::StorageInconsistentState(format!(
+                "Memtrie arena capacity exceeded: {} bytes required, {} bytes remaining",
+                required_capacity, remaining_capacity
+            )));
+        }
+    }
+
+    let mut last_node_id: Option<MemTrieNodeId> = None;
+    let mut updated_to_new_map = HashMap::<UpdatedNodeId, MemTrieNodeId>::new();
+    for (node_id, node_hash) in node_ids_with_hashes.iter() {
+        if let Some(cached_node_id) = node_cache.as_ref().and_then(|cache| cache.get(node_hash)) {
+            updated_to_new_map.insert(*node_id, *cached_node_id);
+            last_node_id = Some(*cached_node_id);
+            continue;
+        }
+        let node = to_input_node(get_node(*node_id), |child| match child {
+            OldOrUpdatedNodeId::Updated(child_id) => *updated_to_new_map.get(&child_id).unwrap(),
+            OldOrUpdatedNodeId::Old(child_id) => child_id,
+        });
+        let mem_node_id = MemTrieNodeId::new_with_hash(arena, node, *node_hash);
+        let mem_node = mem_node_id.as_ptr(arena.memory());
+        on_alloc(mem_node.node_kind(), mem_node.size_of_allocation());
+        if let Some(cache) = node_cache.as_mut() {
+            cache.insert(*node_hash, mem_node_id);
+        }
+        updated_to_new_map.insert(*node_id, mem_node_id);
+        last_node_id = Some(mem_node_id);
+    }
+
+    Ok(last_node_id)
+}
+
+/// Computes the hash and serialized form of each node in `ordered_nodes`,
+/// returning an error instead of panicking if the nodes are malformed. Each
+/// node must exist, be non-empty and come after all its children which are
+/// in `updated_nodes`. Hashes of the other children, i.e. old nodes and
+/// updated IDs without a node, are taken from `external_hash`, which returns
+/// `None` if the child is unknown.
+pub(crate) fn try_hash_updated_nodes<H: Hasher>(
+    ordered_nodes: &[UpdatedNodeId],
+    updated_nodes: &[Option<UpdatedMemTrieNodeWithSize>],
+    external_hash: impl Fn(OldOrUpdatedNodeId) -> Option<CryptoHash>,
+) -> Result<Vec<(UpdatedNodeId, CryptoHash, Vec<u8>)>, StorageError> {
+    let mut hashes = vec![None; updated_nodes.len()];
+    let mut result = Vec::with_capacity(ordered_nodes.len());
+    for node_id in ordered_nodes {
+        let node = updated_nodes.get(*node_id).and_then(|node| node.as_ref()).ok_or_else(|| {
+            StorageError::StorageInconsistentState(format!("Updated node {} is missing", node_id))
+        })?;
+        if let UpdatedMemTrieNode::Empty = node.node {
+            return Err(StorageError::StorageInconsistentState(format!(
+                "Updated node {} is empty",
+                node_id
+            )));
+        }
+        let mut unknown_child = None;
+        let raw_node = node.node.to_raw_trie_node(|child| {
+            let child_hash = match child {
+                OldOrUpdatedNodeId::Updated(child_id)
+                    if matches!(updated_nodes.get(child_id), Some(Some(_))) =>
+                {
+                    hashes[child_id]
+                }
+                _ => external_hash(child),
+            };
+            child_hash.unwrap_or_else(|| {
+                unknown_child.get_or_insert(child);
+                CryptoHash::default()
+            })
+        });
+        if let Some(child) = unknown_child {
+            return Err(StorageError::StorageInconsistentState(format!(
+                "Child {:?} of updated node {} is not hashed before it",
+                child, node_id
+            )));
+        }
+        let raw_node_with_size =
+            RawTrieNodeWithSize { node: raw_node, memory_usage: node.memory_usage };
+        let node_serialized = borsh::to_vec(&raw_node_with_size).unwrap();
+        let node_hash = H::hash(&node_serialized);
+        hashes[*node_id] = Some(node_hash);
+        result.push((*node_id, node_hash, node_serialized));
+    }
+    Ok(result)
+}
+
+/// Converts an updated node to the input for constructing a memtrie node,
+/// mapping its children with `map_child`.
+pub(crate) fn to_input_node<'a>(
+    node: &'a UpdatedMemTrieNode,
+    map_child: impl Fn(OldOrUpdatedNodeId) -> MemTrieNodeId,
+) -> InputMemTrieNode<'a> {
+    match node {
+        UpdatedMemTrieNode::Empty => unreachable!(),
+        UpdatedMemTrieNode::Branch { children, value, .. } => {
+            let mut new_children = [None; 16];
+            for i in 0..16 {
+                if let Some(child) = children[i] {
+                    new_children[i] = Some(map_child(child));
+                }
+            }
+            match value {
+                Some(value) => InputMemTrieNode::BranchWithValue { children: new_children, value },
+                None => InputMemTrieNode::Branch { children: new_children },
+            }
+        }
+        UpdatedMemTrieNode::Extension { extension, child } => {
+            InputMemTrieNode::Extension { extension, child: map_child(*child) }
+        }
+        UpdatedMemTrieNode::Leaf { extension, value, .. } => {
+            InputMemTrieNode::Leaf { value, extension }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::TestTriesBuilder;
+    use crate::trie::mem::arena::hybrid::{HybridArena, HybridArenaMemory};
+    use crate::trie::mem::arena::single_thread::STArena;
+    use crate::trie::mem::arena::{AllocCounter, AllocCounts, Arena};
+    use crate::trie::mem::hasher::{FnvHasher, Hasher, Sha256Hasher};
+    use crate::trie::mem::lookup::memtrie_lookup;
+    use crate::trie::mem::memtrie_update::GenericTrieUpdateInsertDelete;
+    use crate::trie::mem::memtries::MemTries;
+    use crate::trie::mem::node::{MemTrieNodeView, NodeKind};
+    use crate::trie::mem::reconstruction::MemTrieReconstructor;
+    use crate::trie::ops::interface::{GenericTrieUpdate, GenericTrieValue};
+    use crate::trie::trie_recording::TrieRecorder;
+    use crate::trie::trie_storage_update::TrieStorageUpdate;
+    use crate::trie::MemTrieChanges;
+    use crate::{
+        KeyLookupMode, NibbleSlice, PartialStorage, RawTrieNode, RawTrieNodeWithSize, ShardTries,
+        Trie, TrieChanges,
+    };
+    use assert_matches::assert_matches;
+    use near_primitives::challenge::PartialState;
+    use near_primitives::errors::StorageError;
+    use near_primitives::hash::{hash, CryptoHash};
+    use near_primitives::shard_layout::ShardUId;
+    use near_primitives::state::{FlatStateValue, ValueRef};
+    use near_primitives::types::{BlockHeight, StateRoot};
+    use rand::Rng;
+    use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+    use std::num::NonZeroU32;
+    use std::sync::Arc;
+
+    use super::{
+        construct_root_from_changes, MemOnly, MemTrieUpdate, MemTrieUpdateBuilder, MemTrieUpdateOp,
+        NodeHashCache, OldOrUpdatedNodeId, ReadCostClass, SharedValueRefcount, Tracking,
+        TrackingMode, UpdatedMemTrieNode, UpdatedMemTrieNodeWithSize, ZeroCrossing,
+        MEM_TRIE_CHANGES_VERSION, MEM_TRIE_SQUASH_OPS, NUM_NODES_HASHED,
+    };
+
+    struct TestTries {
+        mem: MemTries,
+        disk: ShardTries,
+        truth: HashMap<Vec<u8>, Option<ValueRef>>,
+        state_root: StateRoot,
+        check_deleted_keys: bool,
+    }
+
+    impl TestTries {
+        fn new(check_deleted_keys: bool) -> Self {
+            let mem = MemTries::new(ShardUId::single_shard());
+            let disk = TestTriesBuilder::new().build();
+            Self {
+                mem,
+                disk,
+                truth: HashMap::new(),
+                state_root: StateRoot::default(),
+                check_deleted_keys,
+            }
+        }
+
+        fn make_all_changes(&mut self, changes: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> TrieChanges {
+            let mut update =
+                self.mem.update(self.state_root, TrackingMode::Refcounts).unwrap_or_else(|_| {
+                    panic!("Trying to update root {:?} but it's not in memtries", self.state_root)
+                });
+            for (key, value) in changes {
+                if let Some(value) = value {
+                    update.insert(&key, value).unwrap();
+                } else {
+                    update.generic_delete(0, &key).unwrap();
+                }
+            }
+            update.to_trie_changes().unwrap()
+        }
+
+        fn make_memtrie_changes_only(
+            &mut self,
+            changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        ) -> MemTrieChanges {
+            let mut update = self.mem.update_memtrie_only(self.state_root).unwrap_or_else(|_| {
+                panic!("Trying to update root {:?} but it's not in memtries", self.state_root)
+            });
+            for (key, value) in changes {
+                if let Some(value) = value {
+                    update.insert_memtrie_only(&key, FlatStateValue::on_disk(&value)).unwrap();
+                } else {
+                    update.generic_delete(0, &key).unwrap();
+                }
+            }
+            update.to_memtrie_changes_only().unwrap()
+        }
+
+        fn make_disk_changes_only(
+            &mut self,
+            changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        ) -> TrieChanges {
+            let trie = self.disk.get_trie_for_shard(ShardUId::single_shard(), self.state_root);
+            trie.update(changes).unwrap()
+        }
+
+        fn check_consistency_across_all_changes_and_apply(
+            &mut self,
+            changes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        ) {
+            // First check consistency between the changes.
+            let memtrie_changes = self.make_memtrie_changes_only(changes.clone());
+            let disk_changes = self.make_disk_changes_only(changes.clone());
+            let mut all_changes = self.make_all_changes(changes.clone());
+
+            let memtrie_changes_from_all_changes = all_changes.memtrie_changes.take().unwrap();
+            assert_eq!(memtrie_changes, memtrie_changes_from_all_changes);
+            assert_eq!(disk_changes, all_changes);
+
+            // Then apply the changes and check consistency of new state roots.
+            let new_state_root_from_mem =
+                self.mem.apply_memtrie_changes(0, &memtrie_changes).unwrap();
+            let mut store_update = self.disk.store_update();
+            let new_state_root_from_disk =
+                self.disk.apply_all(&disk_changes, ShardUId::single_shard(), &mut store_update);
+            assert_eq!(new_state_root_from_mem, new_state_root_from_disk);
+            store_update.commit().unwrap();
+            self.state_root = new_state_root_from_mem;
+
+            // Update our truth.
+            for (key, value) in changes {
+                if let Some(value) = value {
+                    self.truth.insert(key, Some(ValueRef::new(&value)));
+                } else {
+                    if self.check_deleted_keys {
+                        self.truth.insert(key, None);
+                    } else {
+                        self.truth.remove(&key);
+                    }
+                }
+            }
+
+            // Check the truth against both memtrie and on-disk trie.
+            for (key, value_ref) in &self.truth {
+                let memtrie_root = if self.state_root == StateRoot::default() {
+                    None
+                } else {
+                    Some(self.mem.get_root(&self.state_root).unwrap())
+                };
+                let disk_trie =
+                    self.disk.get_trie_for_shard(ShardUId::single_shard(), self.state_root);
+                let memtrie_result =
+                    memtrie_root.and_then(|memtrie_root| memtrie_lookup(memtrie_root, key, None));
+                let disk_result = disk_trie.get_optimized_ref(key, KeyLookupMode::Trie).unwrap();
+                if let Some(value_ref) = value_ref {
+                    let memtrie_value_ref = memtrie_result
+                        .unwrap_or_else(|| {
+                            panic!("Key {} is in truth but not in memtrie", hex::encode(key))
+                        })
+                        .to_flat_value()
+                        .to_value_ref();
+                    let disk_value_ref = disk_result
+                        .unwrap_or_else(|| {
+                            panic!("Key {} is in truth but not in disk trie", hex::encode(key))
+                        })
+                        .into_value_ref();
+                    assert_eq!(
+                        memtrie_value_ref,
+                        *value_ref,
+                        "Value for key {} is incorrect for memtrie",
+                        hex::encode(key)
+                    );
+                    assert_eq!(
+                        disk_value_ref,
+                        *value_ref,
+                        "Value for key {} is incorrect for disk trie",
+                        hex::encode(key)
+                    );
+                } else {
+                    assert!(
+                        memtrie_result.is_none(),
+                        "Key {} is not in truth but is in memtrie",
+                        hex::encode(key)
+                    );
+                    assert!(
+                        disk_result.is_none(),
+                        "Key {} is not in truth but is in disk trie",
+                        hex::encode(key)
+                    );
+                }
+            }
+        }
+    }
+
+    fn parse_changes(s: &str) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        s.split('\n')
+            .map(|s| s.split('#').next().unwrap().trim())
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let mut parts = s.split(" = ");
+                let key = parts.next().unwrap();
+                let value = parts.next().unwrap();
+                let value =
+                    if value == "delete" { None } else { Some(hex::decode(value).unwrap()) };
+                (hex::decode(key).unwrap(), value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_meta_parse_changes() {
+        // Make sure that our test utility itself is fine.
+        let changes = parse_changes(
+            "
+                00ff = 00000001  # comments
+                01dd = delete
+                # comments
+                02ac = 0003
+            ",
+        );
+        assert_eq!(
+            changes,
+            vec![
+                (vec![0x00, 0xff], Some(vec![0x00, 0x00, 0x00, 0x01])),
+                (vec![0x01, 0xdd], None),
+                (vec![0x02, 0xac], Some(vec![0x00, 0x03])),
+            ]
+        );
+    }
+
+    // As of Oct 2023 this test by itself achieves 100% test coverage for the
+    // logic in this file (minus the unreachable cases). If you modify the code
+    // or the test, please check code coverage with e.g. tarpaulin.
+    #[test]
+    fn test_trie_consistency_manual() {
+        let mut tries = TestTries::new(true);
+        // Simple insertion from empty trie.
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                01 = 0001
+                02 = 0002
+            ",
+        ));
+        // Prepare some more complex values.
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                0000 = 0010  # extends a leaf
+                0100 = 0011  # extends another leaf
+                03 = 0012  # adds a branch
+                0444 = 0013  # adds a branch with a longer leaf
+                0500 = 0014  # adds a branch that has a branch underneath
+                05100000 = 0015
+                05100001 = 0016
+                05200000 = 0017
+                05200001 = 0018
+                05300000 = 0019
+                05300001 = 001a
+                05400000 = 001b
+                05400001 = 001c
+                05500000 = 001d
+                05501000 = 001e
+                05501001 = 001f
+            ",
+        ));
+        // Check insertion and deletion in a variety of cases.
+        // Code coverage is used to confirm we have covered all cases.
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = delete  # turns a branch with value into an extension
+                01 = 0027  # modifies the value at a branch
+                0100 = delete  # turns a branch with value into a leaf
+                03 = delete  # deletes a branch
+                0444 = 0020  # overwrites a leaf
+                0455 = 0022  # split leaf into branch at start
+                0456 = 0023  # split (pending) leaf into branch
+                05 = 0021  # turn branch into branch with value
+                05110000 = 0024  # split extension node into branch at start
+                05201000 = 0025  # split extension node into branch in the middle
+                05300010 = 0026  # split extension node into branch at the end
+                05400000 = delete  # turn 2-branch node into leaf that squashes with extension
+                05500000 = delete  # turn 2-branch node into extension that squashes with another extension
+            ",
+        ));
+
+        // sanity check here the truth is correct - i.e. our test itself is good.
+        let expected_truth = parse_changes(
+            "
+                00 = delete
+                0000 = 0010
+                01 = 0027
+                0100 = delete
+                02 = 0002
+                03 = delete
+                0444 = 0020
+                0455 = 0022
+                0456 = 0023
+                05 = 0021
+                0500 = 0014
+                05100000 = 0015
+                05100001 = 0016
+                05110000 = 0024
+                05200000 = 0017
+                05200001 = 0018
+                05201000 = 0025
+                05300000 = 0019
+                05300001 = 001a
+                05300010 = 0026
+                05400000 = delete
+                05400001 = 001c
+                05500000 = delete
+                05501000 = 001e
+                05501001 = 001f
+            ",
+        )
+        .into_iter()
+        .map(|(k, v)| (k, v.map(|v| ValueRef::new(&v))))
+        .collect::<HashMap<_, _>>();
+        assert_eq!(
+            tries.truth,
+            expected_truth,
+            "Differing keys: {:?}",
+            expected_truth
+                .keys()
+                .cloned()
+                .chain(tries.truth.keys().cloned())
+                .collect::<HashSet<_>>()
+                .into_iter()
+                .filter(|k| { expected_truth.get(k) != tries.truth.get(k) })
+                .collect::<Vec<_>>()
+        );
+
+        // Delete some non-existent keys.
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = delete  # non-existent branch
+                04 = delete  # branch without value
+                0445 = delete  # non-matching leaf
+                055011 = delete  # non-matching extension
+            ",
+        ));
+
+        // Make no changes
+        tries.check_consistency_across_all_changes_and_apply(Vec::new());
+
+        // Finally delete all keys.
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                0000 = delete
+                01 = delete
+                02 = delete
+                03 = delete
+                0444 = delete
+                0455 = delete
+                0456 = delete
+                05 = delete
+                0500 = delete
+                05100000 = delete
+                05100001 = delete
+                05110000 = delete
+                05200000 = delete
+                05200001 = delete
+                05201000 = delete
+                05300000 = delete
+                05300001 = delete
+                05300010 = delete
+                05400001 = delete
+                05501000 = delete
+                05501001 = delete
+            ",
+        ));
+
+        // Check a corner case that deleting a non-existent key from
+        // an empty trie does not panic.
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                08 = delete  # non-existent key when whole trie is empty
+            ",
+        ));
+
+        assert_eq!(tries.state_root, StateRoot::default());
+        // Garbage collect all roots we've added. This checks that the refcounts
+        // maintained by the in-memory tries are correct, because if any
+        // refcounts are too low this would panic, and if any refcounts are too
+        // high the number of allocs in the end would be non-zero.
+        tries.mem.delete_until_height(1);
+        assert_eq!(tries.mem.num_roots(), 0);
+        assert_eq!(tries.mem.arena().num_active_allocs(), 0);
+    }
+
+    /// Empty values are valid and only contribute `node_cost` to the memory
+    /// usage of the node holding them. Checks that they are handled the same
+    /// way as on disk at leaves, at branches and after squashing.
+    #[test]
+    fn test_trie_consistency_empty_values() {
+        let mut tries = TestTries::new(true);
+        let empty = || Some(vec![]);
+        // Empty value at a leaf, then the leaf gets split by a longer key.
+        tries.check_consistency_across_all_changes_and_apply(vec![(vec![0x01], empty())]);
+        tries.check_consistency_across_all_changes_and_apply(vec![
+            (vec![0x01, 0x10], empty()),
+            (vec![0x01, 0x20], Some(vec![0x12])),
+        ]);
+        // Empty value at a branch which also has a non-empty value child.
+        tries.check_consistency_across_all_changes_and_apply(vec![(vec![0x01], empty())]);
+        // Overwrite an empty value with a non-empty one and back.
+        tries.check_consistency_across_all_changes_and_apply(vec![(
+            vec![0x01, 0x10],
+            Some(vec![0x34]),
+        )]);
+        tries.check_consistency_across_all_changes_and_apply(vec![(vec![0x01, 0x10], empty())]);
+        // Branch with an empty value loses all its children and gets squashed
+        // into a leaf with an empty value.
+        tries.check_consistency_across_all_changes_and_apply(vec![
+            (vec![0x01, 0x10], None),
+            (vec![0x01, 0x20], None),
+        ]);
+        // Branch without value whose only remaining child is an empty-value
+        // leaf gets squashed into that leaf.
+        tries.check_consistency_across_all_changes_and_apply(vec![
+            (vec![0x02], empty()),
+            (vec![0x03], empty()),
+        ]);
+        tries.check_consistency_across_all_changes_and_apply(vec![
+            (vec![0x01], None),
+            (vec![0x02], None),
+        ]);
+        // Empty key with empty value.
+        tries.check_consistency_across_all_changes_and_apply(vec![(vec![], empty())]);
+        tries.check_consistency_across_all_changes_and_apply(vec![
+            (vec![], None),
+            (vec![0x03], None),
+        ]);
+        assert_eq!(tries.state_root, StateRoot::default());
+
+        tries.mem.delete_until_height(1);
+        assert_eq!(tries.mem.arena().num_active_allocs(), 0);
+    }
+
+    #[test]
+    fn test_estimated_insertion_bytes() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0100 = 0001
+                0101 = 0002
+                02 = 0003
+            ",
+        ));
+        let long_value = vec![7u8; 8000];
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        update.insert(&[0x00], vec![0x00, 0x05]).unwrap(); // overwrite
+        update.insert(&[0x01, 0x02], long_value).unwrap(); // extends a branch
+        update.insert(&[0x03, 0x33], vec![0x06]).unwrap(); // new leaf
+        update.generic_delete(0, &[0x02]).unwrap();
+        let estimate = update.estimated_insertion_bytes().unwrap();
+        let trie_changes = update.to_trie_changes().unwrap();
+        let actual: u64 = trie_changes
+            .insertions()
+            .iter()
+            .map(|insertion| insertion.payload().len() as u64)
+            .sum();
+        assert!(actual > 0);
+        assert!(estimate >= actual, "estimate {} is less than actual {}", estimate, actual);
+    }
+
+    #[test]
+    fn test_estimated_insertion_bytes_with_prepared_splits_and_spilled_nodes() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                1234 = 00
+                5678 = 01
+                12 = 02
+            ",
+        ));
+        let insertion_bytes = |changes: &TrieChanges| -> u64 {
+            changes.insertions().iter().map(|insertion| insertion.payload().len() as u64).sum()
+        };
+
+        // Leaves an empty node below a branch, which is only squashed when
+        // finalizing.
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        update.prepare_split(&[0x12, 0x35]).unwrap();
+        let estimate = update.estimated_insertion_bytes().unwrap();
+        let changes = update.to_trie_changes().unwrap();
+        assert_eq!(changes.new_root, tries.state_root);
+        assert!(estimate >= insertion_bytes(&changes));
+
+        let mut update = tries
+            .mem
+            .update_with_builder(
+                tries.state_root,
+                MemTrieUpdateBuilder::new("test".to_string())
+                    .with_tracking(TrackingMode::Refcounts)
+                    .with_updated_nodes_cap(2),
+            )
+            .unwrap();
+        update.prepare_split(&[0x12, 0x35]).unwrap();
+        for i in 0..20u8 {
+            update.insert(&[0x30, i], vec![i; 10]).unwrap();
+        }
+        assert!(update.num_spilled_nodes() > 0);
+        let estimate = update.estimated_insertion_bytes().unwrap();
+        let actual = insertion_bytes(&update.to_trie_changes().unwrap());
+        assert!(actual > 0);
+        assert!(estimate >= actual, "estimate {} is less than actual {}", estimate, actual);
+    }
+
+    #[test]
+    fn test_recorded_storage_size() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0100 = 0001
+                0101 = 0002
+                02 = 0003
+                0300 = 0004
+            ",
+        ));
+        let mut recorder = TrieRecorder::new(None);
+        let mut update = tries
+            .mem
+            .update(tries.state_root, TrackingMode::RefcountsAndAccesses(&mut recorder))
+            .unwrap();
+        assert_eq!(update.recorded_storage_size(), 0);
+        let mut last_size = 0;
+        for key in [[0x01, 0x02], [0x03, 0x01], [0x00, 0x01], [0x04, 0x00]] {
+            update.insert(&key, vec![0x05]).unwrap();
+            let size = update.recorded_storage_size();
+            assert!(size >= last_size, "size decreased from {} to {}", last_size, size);
+            last_size = size;
+        }
+        assert!(last_size > 0);
+        drop(update);
+
+        let PartialState::TrieValues(recorded) = recorder.recorded_storage().nodes;
+        let recorded_bytes: u64 = recorded.iter().map(|value| value.len() as u64).sum();
+        assert_eq!(last_size, recorded_bytes);
+    }
+
+    #[test]
+    fn test_get_ref_with_proof() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0100 = 0001
+                0101 = 0002
+                02 = 0003
+            ",
+        ));
+        let mut recorder = TrieRecorder::new(None);
+        let mut update = tries
+            .mem
+            .update(tries.state_root, TrackingMode::RefcountsAndAccesses(&mut recorder))
+            .unwrap();
+        let (value, proof) = update.get_ref_with_proof(&[0x01, 0x01]).unwrap();
+        assert_eq!(value, Some(FlatStateValue::Inlined(vec![0x00, 0x02])));
+        let (absent_value, absent_proof) = update.get_ref_with_proof(&[0x01, 0x02]).unwrap();
+        assert_eq!(absent_value, None);
+        drop(update);
+
+        // The values themselves are stored separately from the nodes.
+        let mut witness = proof.clone();
+        witness.push(vec![0x00, 0x02].into());
+        let trie = Trie::from_recorded_storage(
+            PartialStorage { nodes: PartialState::TrieValues(witness) },
+            tries.state_root,
+            false,
+        );
+        assert_eq!(trie.get(&[0x01, 0x01]).unwrap(), Some(vec![0x00, 0x02]));
+        let trie = Trie::from_recorded_storage(
+            PartialStorage { nodes: PartialState::TrieValues(absent_proof.clone()) },
+            tries.state_root,
+            false,
+        );
+        assert_eq!(trie.get(&[0x01, 0x02]).unwrap(), None);
+
+        let PartialState::TrieValues(recorded) = recorder.recorded_storage().nodes;
+        let recorded: HashSet<Arc<[u8]>> = recorded.into_iter().collect();
+        assert!(proof.iter().chain(&absent_proof).all(|node| recorded.contains(node)));
+    }
+
+    #[test]
+    fn test_witness_mode() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0100 = 0001
+                0101 = 0002
+                02 = 0003
+                0300 = 0004
+                0301 = 0005
+            ",
+        ));
+        // Extends a branch and squashes another one left with a single child.
+        let changes = parse_changes(
+            "
+                0102 = 0006
+                0300 = delete
+            ",
+        );
+        let mut recorder = TrieRecorder::new(None);
+        let mut update = tries
+            .mem
+            .update_with_builder(
+                tries.state_root,
+                MemTrieUpdateBuilder::new("test".to_string())
+                    .with_tracking(TrackingMode::RefcountsAndAccesses(&mut recorder))
+                    .with_witness_mode(true),
+            )
+            .unwrap();
+        for (key, value) in &changes {
+            match value {
+                Some(value) => update.insert(key, value.clone()).unwrap(),
+                None => update.delete(key).unwrap(),
+            }
+        }
+        // Read-only lookups are recorded as accesses, but not in the witness.
+        update.get_ref_with_proof(&[0x02]).unwrap();
+        let PartialState::TrieValues(witness) = update.into_witness();
+        let PartialState::TrieValues(recorded) = recorder.recorded_storage().nodes;
+        let recorded: HashSet<Arc<[u8]>> = recorded.into_iter().collect();
+        assert!(witness.iter().all(|node| recorded.contains(node)));
+        assert!(witness.len() < recorded.len());
+        assert_eq!(witness.iter().collect::<HashSet<_>>().len(), witness.len());
+
+        // Re-executing the changes on the witness alone gives the new root.
+        let trie = Trie::from_recorded_storage(
+            PartialStorage { nodes: PartialState::TrieValues(witness) },
+            tries.state_root,
+            false,
+        );
+        let new_root = trie.update(changes.clone()).unwrap().new_root;
+        tries.check_consistency_across_all_changes_and_apply(changes);
+        assert_eq!(new_root, tries.state_root);
+    }
+
+    #[test]
+    fn test_update_builder() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0100 = 0001
+                0101 = 0002
+            ",
+        ));
+        let root_id = tries.mem.get_root(&tries.state_root).unwrap().id();
+        let mut recorder = TrieRecorder::new(None);
+        let mut update = MemTrieUpdateBuilder::new("test".to_string())
+            .with_tracking(TrackingMode::RefcountsAndAccesses(&mut recorder))
+            .with_strict_deletes(true)
+            .with_intern_extensions(true)
+            .with_updated_nodes_cap(1000)
+            .with_compaction_interval(2)
+            .with_original_hashes(true)
+            .with_recorded_ops(true)
+            .with_zero_crossings(true)
+            .with_witness_mode(true)
+            .build(tries.mem.arena().memory(), Some(root_id))
+            .unwrap();
+        assert!(update.strict_deletes);
+        let instrumentation = update.instrumentation.as_ref().unwrap();
+        assert!(instrumentation.extension_pool.is_some());
+        assert!(instrumentation.spilled_nodes.is_some());
+        assert!(instrumentation.compaction.is_some());
+        assert!(instrumentation.witness.is_some());
+        assert_eq!(update.original_hash_of(0), Some(tries.state_root));
+
+        update.insert(&[0x02], vec![0x00, 0x03]).unwrap();
+        update.delete(&[0x00]).unwrap();
+        assert!(update.check_strict_delete(&[0x03]).is_err());
+        assert_eq!(update.recorded_ops().unwrap().len(), 2);
+        assert_eq!(
+            update.zero_crossing_values().unwrap(),
+            &[ZeroCrossing { key: Some(vec![0x00]), value_hash: hash(&[0x00, 0x00]) }]
+        );
+        let new_root = update.to_trie_changes().unwrap().new_root;
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                02 = 0003
+                00 = delete
+            ",
+        ));
+        assert_eq!(new_root, tries.state_root);
+    }
+
+    #[test]
+    fn test_compaction_bounds_updated_nodes() {
+        let mut tries = TestTries::new(true);
+        let run = |compaction: bool| {
+            let mut builder = MemTrieUpdateBuilder::new("test".to_string())
+                .with_tracking(TrackingMode::Refcounts);
+            if compaction {
+                builder = builder.with_compaction_interval(100);
+            }
+            let mut update = tries.mem.update_with_builder(tries.state_root, builder).unwrap();
+            let mut peak_updated_nodes = 0;
+            // Half of the inserted keys are deleted by the same update, which
+            // leaves unreachable updated nodes behind.
+            for i in 0..5000u32 {
+                update.insert(&(i * 7919 % 10000).to_be_bytes(), vec![i as u8; 50]).unwrap();
+                if i % 2 == 1 {
+                    update.delete(&((i - 1) * 7919 % 10000).to_be_bytes()).unwrap();
+                }
+                peak_updated_nodes = peak_updated_nodes.max(update.updated_nodes.len());
+            }
+            assert_eq!(update.find_orphans(), Vec::<usize>::new());
+            (update.to_trie_changes().unwrap(), peak_updated_nodes)
+        };
+        let (trie_changes, peak_updated_nodes) = run(false);
+        let (compacted_trie_changes, compacted_peak_updated_nodes) = run(true);
+        assert!(compacted_peak_updated_nodes < peak_updated_nodes);
+        assert_eq!(compacted_trie_changes.new_root, trie_changes.new_root);
+        assert_eq!(compacted_trie_changes.insertions(), trie_changes.insertions());
+        assert_eq!(compacted_trie_changes.deletions(), trie_changes.deletions());
+
+        let changes = compacted_trie_changes.memtrie_changes.unwrap();
+        assert_eq!(tries.mem.apply_memtrie_changes(1, &changes).unwrap(), trie_changes.new_root);
+    }
+
+    #[test]
+    fn test_insert_many_dedups_values() {
+        let mut tries = TestTries::new(true);
+        let values: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; 100]).collect();
+        let entries: BTreeMap<Vec<u8>, Vec<u8>> = (0..1000u32)
+            .map(|i| (i.to_be_bytes().to_vec(), values[i as usize % values.len()].clone()))
+            .collect();
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        update.insert_many(entries.clone()).unwrap();
+        let trie_changes = update.to_trie_changes().unwrap();
+
+        let value_hashes: HashSet<CryptoHash> = values.iter().map(|value| hash(value)).collect();
+        let value_insertions: Vec<_> = trie_changes
+            .insertions()
+            .iter()
+            .filter(|insertion| value_hashes.contains(insertion.hash()))
+            .collect();
+        assert_eq!(value_insertions.len(), 10);
+        assert_eq!(value_insertions.iter().map(|insertion| insertion.rc.get()).sum::<u32>(), 1000);
+
+        // The same trie is built as by inserting the entries one by one.
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        for (key, value) in entries {
+            update.insert(&key, value).unwrap();
+        }
+        assert_eq!(update.to_trie_changes().unwrap(), trie_changes);
+    }
+
+    #[test]
+    fn test_touch_values() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0100 = 0001
+                0101 = 0001
+            ",
+        ));
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        update.touch_values(&[vec![0x00], vec![0x01, 0x00], vec![0x01, 0x01]]).unwrap();
+        let trie_changes = update.to_trie_changes().unwrap();
+        assert_eq!(trie_changes.old_root, tries.state_root);
+        assert_eq!(trie_changes.new_root, tries.state_root);
+        assert!(trie_changes.deletions().is_empty());
+        let mut insertions: Vec<_> = trie_changes
+            .insertions()
+            .iter()
+            .map(|insertion| (*insertion.hash(), insertion.payload().to_vec(), insertion.rc.get()))
+            .collect();
+        insertions.sort();
+        let mut expected = vec![
+            (hash(&[0x00, 0x00]), vec![0x00, 0x00], 1),
+            (hash(&[0x00, 0x01]), vec![0x00, 0x01], 2),
+        ];
+        expected.sort();
+        assert_eq!(insertions, expected);
+
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        assert_matches!(
+            update.touch_values(&[vec![0x02]]),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+    }
+
+    #[test]
+    fn test_insert_returning_stored() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(vec![(vec![0x10], Some(vec![0x01]))]);
+        let threshold = FlatStateValue::INLINE_DISK_VALUE_THRESHOLD;
+        let short_value = vec![0x02; threshold];
+        let long_value = vec![0x03; threshold + 1];
+
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        let stored = update.insert_returning_stored(&[0x20], short_value.clone()).unwrap();
+        assert_eq!(stored, FlatStateValue::Inlined(short_value.clone()));
+        let stored = update.insert_returning_stored(&[0x30], long_value.clone()).unwrap();
+        assert_eq!(stored, FlatStateValue::Ref(ValueRef::new(&long_value)));
+        // Overwriting an existing key reports the form of the new value.
+        let stored = update.insert_returning_stored(&[0x10], long_value.clone()).unwrap();
+        assert_eq!(stored, FlatStateValue::Ref(ValueRef::new(&long_value)));
+        let stored = update.insert_returning_stored(&[0x30], short_value.clone()).unwrap();
+        assert_eq!(stored, FlatStateValue::Inlined(short_value));
+    }
+
+    #[test]
+    fn test_get_ref_with_cost() {
+        let mut tries = TestTries::new(true);
+        let short_value = vec![0x01; 10];
+        let long_value = vec![0x02; 8000];
+        tries.check_consistency_across_all_changes_and_apply(vec![
+            (vec![0x10], Some(short_value.clone())),
+            (vec![0x20], Some(long_value.clone())),
+        ]);
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        assert_eq!(
+            update.get_ref_with_cost(&[0x10]).unwrap(),
+            Some((FlatStateValue::Inlined(short_value.clone()), ReadCostClass::Inlined))
+        );
+        assert_eq!(
+            update.get_ref_with_cost(&[0x20]).unwrap(),
+            Some((FlatStateValue::Ref(ValueRef::new(&long_value)), ReadCostClass::Referenced))
+        );
+        assert_eq!(update.get_ref_with_cost(&[0x30]).unwrap(), None);
+        assert_eq!(update.read_values(), &BTreeSet::from([hash(&short_value), hash(&long_value)]));
+
+        // Values written by the update are classified the same way.
+        update.insert(&[0x30], long_value.clone()).unwrap();
+        assert_matches!(
+            update.get_ref_with_cost(&[0x30]).unwrap(),
+            Some((_, ReadCostClass::Referenced))
+        );
+    }
+
+    #[test]
+    fn test_insert_shared_value() {
+        let mut tries = TestTries::new(true);
+        let shard_uid = ShardUId::single_shard();
+        let origin_shard = ShardUId { version: 3, shard_id: 1 };
+        // The shard stores its state under the origin shard, like a child
+        // shard after resharding, and the origin shard owns the shared value.
+        let shared_value = vec![0x01; 1000];
+        let local_value = vec![0x02; 1000];
+        let value_ref = ValueRef::new(&shared_value);
+        let mut store_update = tries.disk.store_update();
+        store_update.set_shard_uid_mapping(shard_uid, origin_shard);
+        store_update.increment_refcount_by(
+            origin_shard,
+            &value_ref.hash,
+            &shared_value,
+            NonZeroU32::new(1).unwrap(),
+        );
+        store_update.commit().unwrap();
+        tries.check_consistency_across_all_changes_and_apply(parse_changes("00 = 0000"));
+
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        update.insert_shared_value(&[0x01], value_ref, origin_shard).unwrap();
+        update.insert_shared_value(&[0x02], value_ref, origin_shard).unwrap();
+        update.insert(&[0x03], local_value.clone()).unwrap();
+        assert_matches!(
+            update.insert_shared_value(&[0x04], value_ref, shard_uid),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        assert_eq!(
+            update.shared_value_refcounts().iter().collect::<Vec<_>>(),
+            vec![(&value_ref.hash, &SharedValueRefcount { origin_shard, rc: 2 })]
+        );
+        assert_eq!(update.get_value(&[0x02]).unwrap(), Some(FlatStateValue::Ref(value_ref)));
+
+        // Only the local value bytes are part of the insertions, the shared
+        // value is referenced without its bytes.
+        let shared_value_refcounts = update.shared_value_refcounts().clone();
+        let trie_changes = update.to_trie_changes().unwrap();
+        let inserted_hashes: HashSet<_> =
+            trie_changes.insertions().iter().map(|insertion| *insertion.hash()).collect();
+        assert!(inserted_hashes.contains(&hash(&local_value)));
+        assert!(!inserted_hashes.contains(&value_ref.hash));
+        assert!(trie_changes
+            .insertions()
+            .iter()
+            .all(|insertion| insertion.payload() != shared_value.as_slice()));
+
+        // Both references are persisted against the origin shard.
+        let mut store_update = tries.disk.store_update();
+        let new_root = tries.disk.apply_all(&trie_changes, shard_uid, &mut store_update);
+        tries
+            .disk
+            .apply_shared_value_refcounts(&shared_value_refcounts, &mut store_update)
+            .unwrap();
+        store_update.commit().unwrap();
+        tries.mem.apply_memtrie_changes(1, trie_changes.memtrie_changes.as_ref().unwrap()).unwrap();
+        let disk_trie = tries.disk.get_trie_for_shard(shard_uid, new_root);
+        assert_eq!(disk_trie.get(&[0x02]).unwrap(), Some(shared_value.clone()));
+
+        // Deleting both keys releases both references, but not the one of
+        // the origin shard.
+        let mut update = tries.mem.update(new_root, TrackingMode::Refcounts).unwrap();
+        update.delete(&[0x01]).unwrap();
+        update.delete(&[0x02]).unwrap();
+        let trie_changes = update.to_trie_changes().unwrap();
+        let mut store_update = tries.disk.store_update();
+        let new_root = tries.disk.apply_all(&trie_changes, shard_uid, &mut store_update);
+        store_update.commit().unwrap();
+        let disk_trie = tries.disk.get_trie_for_shard(shard_uid, new_root);
+        assert_eq!(disk_trie.get(&[0x02]).unwrap(), None);
+        assert_eq!(disk_trie.get(&[0x03]).unwrap(), Some(local_value));
+        assert_eq!(
+            tries.disk.store().get(origin_shard, &value_ref.hash).unwrap().as_ref(),
+            shared_value.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0100 = 0001
+                0101 = 0002
+                0101ff = 0003
+                02 = 0004
+            ",
+        ));
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        update.insert(&[0x03], vec![0x00, 0x05]).unwrap();
+        update.delete(&[0x02]).unwrap();
+        update.clear().unwrap();
+        assert_eq!(update.find_orphans(), Vec::<usize>::new());
+        let trie_changes = update.to_trie_changes().unwrap();
+        assert_eq!(trie_changes.new_root, CryptoHash::default());
+        assert!(trie_changes.insertions().is_empty());
+
+        let deleted: HashMap<CryptoHash, u32> = trie_changes
+            .deletions()
+            .iter()
+            .map(|deletion| (deletion.trie_node_or_value_hash, deletion.rc.get()))
+            .collect();
+        for value in [[0x00, 0x00], [0x00, 0x01], [0x00, 0x02], [0x00, 0x03], [0x00, 0x04]] {
+            assert_eq!(deleted.get(&hash(&value)), Some(&1));
+        }
+        assert_eq!(deleted.get(&tries.state_root), Some(&1));
+
+        let changes = trie_changes.memtrie_changes.unwrap();
+        assert_eq!(tries.mem.apply_memtrie_changes(1, &changes).unwrap(), CryptoHash::default());
+    }
+
+    #[test]
+    fn test_delete_range() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0444 = 0013
+                05 = 0021  # branch value at a proper prefix of both bounds
+                0500 = 0014
+                05100000 = 0015
+                05100001 = 0016
+                05200000 = 0017
+                05200001 = 0018
+                05300000 = 0019
+                05300001 = 001a
+                05400000 = 001b
+                05400001 = 001c
+                05500000 = 001d
+                05501000 = 001e
+                05501001 = 001f
+            ",
+        ));
+        let keys: [&[u8]; 6] = [
+            &[0x05, 0x10, 0x00, 0x00],
+            &[0x05, 0x10, 0x00, 0x01],
+            &[0x05, 0x20, 0x00, 0x00],
+            &[0x05, 0x20, 0x00, 0x01],
+            &[0x05, 0x30, 0x00, 0x00],
+            &[0x05, 0x30, 0x00, 0x01],
+        ];
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        assert_eq!(update.delete_range(&[0x05, 0x10], &[0x05, 0x31]).unwrap(), 6);
+        assert_eq!(update.find_orphans(), Vec::<usize>::new());
+        let range_changes = update.to_trie_changes().unwrap();
+
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        for key in keys {
+            update.delete(key).unwrap();
+        }
+        let key_changes = update.to_trie_changes().unwrap();
+        assert_eq!(range_changes.new_root, key_changes.new_root);
+        assert_eq!(range_changes.insertions(), key_changes.insertions());
+        assert_eq!(range_changes.deletions(), key_changes.deletions());
+
+        // The start is inclusive, while the end excludes the keys it prefixes.
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        assert_eq!(update.delete_range(&[0x05], &[0x05, 0x10]).unwrap(), 2);
+        assert_eq!(update.get_value(&[0x05]).unwrap(), None);
+        assert_eq!(update.get_value(&[0x05, 0x00]).unwrap(), None);
+        assert!(update.get_value(&[0x05, 0x10, 0x00, 0x00]).unwrap().is_some());
+        assert_eq!(update.delete_range(&[0x05, 0x10], &[0x05, 0x10]).unwrap(), 0);
+
+        let changes = keys.iter().map(|key| (key.to_vec(), None)).collect();
+        tries.check_consistency_across_all_changes_and_apply(changes);
+        assert_eq!(tries.state_root, range_changes.new_root);
+    }
+
+    #[test]
+    fn test_read_values() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0100 = 0001
+            ",
+        ));
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        assert!(update.read_values().is_empty());
+        let value_ref = update.get_ref(&[0x01, 0x00]).unwrap();
+        assert_eq!(value_ref, ValueRef::new(&[0x00, 0x01]));
+        assert_eq!(update.get_ref(&[0x02]).unwrap(), None);
+        update.insert(&[0x03], vec![0x00, 0x03]).unwrap();
+        assert_eq!(update.read_values(), &BTreeSet::from([hash(&[0x00, 0x01])]));
+
+        // A value written by the update is read like any other.
+        assert!(update.get_ref(&[0x03]).unwrap().is_some());
+        assert_eq!(
+            update.read_values(),
+            &BTreeSet::from([hash(&[0x00, 0x01]), hash(&[0x00, 0x03])])
+        );
+    }
+
+    /// A branch with all 16 children and a value is the largest node.
+    #[test]
+    fn test_full_branch_with_value() {
+        let full_branch_changes = || {
+            let mut changes = vec![(vec![0x01], Some(vec![0x01]))];
+            for i in 0..16u8 {
+                changes.push((vec![0x01, i << 4], Some(vec![i])));
+            }
+            changes
+        };
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(full_branch_changes());
+
+        // The root is an extension leading to the full branch.
+        let root = tries.mem.get_root(&tries.state_root).unwrap();
+        let MemTrieNodeView::Extension { child: branch, .. } = root.view() else {
+            panic!("Unexpected root: {:?}", root.view());
+        };
+        let branch_view = branch.view();
+        let MemTrieNodeView::BranchWithValue { children, .. } = &branch_view else {
+            panic!("Unexpected node: {:?}", branch_view);
+        };
+        assert_eq!(children.iter().count(), 16);
+        let raw_branch = branch_view.to_raw_trie_node_with_size();
+        assert_matches!(
+            &raw_branch.node,
+            RawTrieNode::BranchWithValue(_, children) if children.iter().count() == 16
+        );
+        assert_eq!(hash(&borsh::to_vec(&raw_branch).unwrap()), branch_view.node_hash());
+
+        // The updated node is hashed and serialized the same way, and it is
+        // reconstructed into the same trie.
+        let mut update = tries.mem.update_memtrie_only(StateRoot::default()).unwrap();
+        for (key, value) in full_branch_changes() {
+            update.insert(&key, value.unwrap()).unwrap();
+        }
+        let mut ordered_nodes = Vec::new();
+        MemTrieUpdate::<HybridArenaMemory, MemOnly>::post_order_traverse_updated_nodes(
+            0,
+            &update.updated_nodes,
+            &mut ordered_nodes,
+        );
+        let hashes_and_serialized =
+            update.compute_hashes_and_serialized_nodes(&ordered_nodes, &update.updated_nodes, None);
+        let (_, _, serialized) = hashes_and_serialized
+            .iter()
+            .find(|(_, node_hash, _)| *node_hash == branch_view.node_hash())
+            .unwrap();
+        assert_eq!(serialized, &borsh::to_vec(&raw_branch).unwrap());
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let mut arena = STArena::new("test".to_string());
+        let new_root =
+            construct_root_from_changes(&mut arena, &changes, None, |_, _| {}).unwrap().unwrap();
+        assert_eq!(new_root.as_ptr(arena.memory()).view().node_hash(), tries.state_root);
+
+        // Deleting the value leaves a full branch without a value.
+        tries.check_consistency_across_all_changes_and_apply(vec![(vec![0x01], None)]);
+        let root = tries.mem.get_root(&tries.state_root).unwrap();
+        let MemTrieNodeView::Extension { child: branch, .. } = root.view() else {
+            panic!("Unexpected root: {:?}", root.view());
+        };
+        assert_matches!(
+            branch.view(),
+            MemTrieNodeView::Branch { children, .. } if children.iter().count() == 16
+        );
+
+        // Deleting all but one child squashes the branch into a single leaf.
+        let changes = (1..16u8).map(|i| (vec![0x01, i << 4], None)).collect();
+        tries.check_consistency_across_all_changes_and_apply(changes);
+        let root = tries.mem.get_root(&tries.state_root).unwrap();
+        assert_matches!(root.view(), MemTrieNodeView::Leaf { .. });
+    }
+
+    #[test]
+    fn test_spill_updated_nodes() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                0100 = 0001
+                f000 = 00f0
+            ",
+        ));
+        let apply = |update: &mut MemTrieUpdate<HybridArenaMemory, MemOnly>| {
+            let mut num_spilled = 0;
+            for i in 0..200u16 {
+                update.insert(&i.to_be_bytes(), vec![i as u8; 50]).unwrap();
+                num_spilled = num_spilled.max(update.num_spilled_nodes());
+            }
+            // Out of order inserts reload the spilled nodes on their paths.
+            for i in (0..200u16).step_by(7) {
+                update.insert(&i.to_be_bytes(), vec![0; 10]).unwrap();
+            }
+            update.insert(&[0x01, 0x00], vec![0x02]).unwrap();
+            num_spilled = num_spilled.max(update.num_spilled_nodes());
+            update.delete(&[0x00, 0x05]).unwrap();
+            assert_eq!(update.num_spilled_nodes(), 0);
+            for i in 200..300u16 {
+                update.insert(&i.to_be_bytes(), vec![i as u8; 50]).unwrap();
+            }
+            num_spilled
+        };
+
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        assert_eq!(apply(&mut update), 0);
+        let expected_changes = update.to_memtrie_changes_only().unwrap();
+
+        let mut update = tries
+            .mem
+            .update_with_builder(
+                tries.state_root,
+                MemTrieUpdateBuilder::new("test".to_string()).with_updated_nodes_cap(8),
+            )
+            .unwrap();
+        assert!(apply(&mut update) > 0);
+        assert!(update.num_spilled_nodes() > 0);
+        // Lookups read spilled nodes back without reloading them.
+        let num_spilled = update.num_spilled_nodes();
+        for (key, value) in [
+            (vec![0x01, 0x00], Some(vec![0x00; 50])),
+            (250u16.to_be_bytes().to_vec(), Some(vec![250; 50])),
+            (vec![0xf0, 0x00], Some(vec![0x00, 0xf0])),
+            (vec![0x00, 0x05], None),
+        ] {
+            assert_eq!(
+                update.get_value(&key).unwrap(),
+                value.map(|value| FlatStateValue::on_disk(&value))
+            );
+        }
+        assert_eq!(update.num_spilled_nodes(), num_spilled);
+        assert_eq!(update.to_memtrie_changes_only().unwrap(), expected_changes);
+    }
+
+    #[test]
+    fn test_spill_file_failure() {
+        let tries = MemTries::new(ShardUId::single_shard());
+        // Returns an update with spilled nodes, which can't be read back as
+        // the spill file is replaced by an empty one.
+        let failing_update = || {
+            let mut update = tries
+                .update_with_builder(
+                    CryptoHash::default(),
+                    MemTrieUpdateBuilder::new("test".to_string())
+                        .with_tracking(TrackingMode::Refcounts)
+                        .with_updated_nodes_cap(8),
+                )
+                .unwrap();
+            for i in 0..200u16 {
+                update.insert(&i.to_be_bytes(), vec![i as u8; 50]).unwrap();
+            }
+            assert!(update.num_spilled_nodes() > 0);
+            update.instrumentation.as_mut().unwrap().spilled_nodes.as_mut().unwrap().file =
+                tempfile::tempfile().unwrap();
+            update
+        };
+        let key = 5u16.to_be_bytes();
+        assert_matches!(
+            failing_update().insert(&key, vec![0x00]),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        assert_matches!(
+            failing_update().get_ref(&key),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        assert_matches!(
+            failing_update().checkpoint_root(),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        assert_matches!(
+            failing_update().disk_insertion_count(),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        assert_matches!(
+            failing_update().to_trie_changes(),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+
+        let mut update = tries
+            .update_with_builder(
+                CryptoHash::default(),
+                MemTrieUpdateBuilder::new("test".to_string()).with_updated_nodes_cap(8),
+            )
+            .unwrap();
+        for i in 0..200u16 {
+            update.insert(&i.to_be_bytes(), vec![i as u8; 50]).unwrap();
+        }
+        update.instrumentation.as_mut().unwrap().spilled_nodes.as_mut().unwrap().file =
+            tempfile::tempfile().unwrap();
+        assert_matches!(
+            update.to_memtrie_changes_only(),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0100 = 0001
+                0200 = 0002
+            ",
+        ));
+        let root_id = tries.mem.get_root(&tries.state_root).unwrap().id();
+        let update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        assert_eq!(update.recorded_ops(), None);
+        let mut update = tries
+            .mem
+            .update_with_builder(
+                tries.state_root,
+                MemTrieUpdateBuilder::new("test".to_string()).with_recorded_ops(true),
+            )
+            .unwrap();
+        update.insert(&[0x03, 0x00], vec![0x00, 0x03]).unwrap();
+        update.insert_memtrie_only(&[0x04], FlatStateValue::Inlined(vec![0x04])).unwrap();
+        update.delete(&[0x01, 0x00]).unwrap();
+        update.delete_many([[0x02, 0x00].as_slice(), &[0x05]]).unwrap();
+        let ops = update.recorded_ops().unwrap().to_vec();
+        assert_eq!(ops.len(), 5);
+        assert_eq!(ops[2], MemTrieUpdateOp::Delete { key: vec![0x01, 0x00] });
+
+        let replayed = update.replay(&ops, Some(root_id)).unwrap();
+        assert_eq!(
+            replayed.to_memtrie_changes_only().unwrap(),
+            update.to_memtrie_changes_only().unwrap()
+        );
+
+        // Clearing leaves nothing to replay on top of.
+        let mut update = tries
+            .mem
+            .update_with_builder(
+                tries.state_root,
+                MemTrieUpdateBuilder::new("test".to_string()).with_recorded_ops(true),
+            )
+            .unwrap();
+        update.clear().unwrap();
+        update.insert(&[0x06], vec![0x06]).unwrap();
+        let ops = update.recorded_ops().unwrap().to_vec();
+        let replayed = update.replay(&ops, None).unwrap();
+        assert_eq!(
+            replayed.to_memtrie_changes_only().unwrap(),
+            update.to_memtrie_changes_only().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_disk_insertion_count() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0100 = 0001
+                0200 = 0002
+            ",
+        ));
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        assert_eq!(update.disk_insertion_count().unwrap(), 0);
+        update.insert(&[0x03, 0x00], vec![0x00, 0x03]).unwrap();
+        update.insert(&[0x04, 0x00], vec![0x00, 0x04]).unwrap();
+        update.delete(&[0x04, 0x00]).unwrap();
+        update.delete(&[0x02, 0x00]).unwrap();
+        update.insert(&[0x00], vec![0x00, 0x05]).unwrap();
+        update.insert(&[0x00], vec![0x00, 0x06]).unwrap();
+
+        let mut ordered_nodes = Vec::new();
+        MemTrieUpdate::<HybridArenaMemory, Tracking>::post_order_traverse_updated_nodes(
+            0,
+            &update.updated_nodes,
+            &mut ordered_nodes,
+        );
+        // Values 0003 and 0006 are new, the churned ones cancel out.
+        let count = update.disk_insertion_count().unwrap();
+        assert_eq!(count, ordered_nodes.len() + 2);
+
+        // Prepared splits are squashed back in the count, but not in the
+        // update itself.
+        update.prepare_split(&[0x05, 0x00]).unwrap();
+        let updated_nodes = update.updated_nodes.clone();
+        assert_eq!(update.disk_insertion_count().unwrap(), count);
+        assert_eq!(update.updated_nodes, updated_nodes);
+        assert_eq!(update.prepared_splits.len(), 1);
+        assert_eq!(count, update.to_trie_changes().unwrap().insertions.len());
+    }
+
+    #[test]
+    fn test_set_value_at() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(vec![
+            (vec![0x10], Some(vec![0x01])),
+            (vec![0x20], Some(vec![0x02])),
+        ]);
+        let root = tries.state_root;
+        let value = FlatStateValue::on_disk(&[0x03]);
+
+        // Setting a value at the root branch is the same as inserting the
+        // empty key.
+        let mut update = tries.mem.update_memtrie_only(root).unwrap();
+        assert!(matches!(
+            update.updated_nodes[0].as_ref().unwrap().node,
+            UpdatedMemTrieNode::Branch { value: None, .. }
+        ));
+        update.set_value_at(0, Some(GenericTrieValue::MemtrieOnly(value.clone()))).unwrap();
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let mut expected = tries.mem.update_memtrie_only(root).unwrap();
+        expected.insert_memtrie_only(&[], value).unwrap();
+        assert_eq!(changes, expected.to_memtrie_changes_only().unwrap());
+        let root_with_value = tries.mem.apply_memtrie_changes(1, &changes).unwrap();
+
+        // Clearing the value of a branch left with a single child squashes
+        // the branch into a leaf.
+        let mut update = tries.mem.update_memtrie_only(root_with_value).unwrap();
+        update.generic_delete(0, &[0x20]).unwrap();
+        update.set_value_at(0, None).unwrap();
+        assert!(matches!(
+            update.updated_nodes[0].as_ref().unwrap().node,
+            UpdatedMemTrieNode::Leaf { .. }
+        ));
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let mut expected = tries.mem.update_memtrie_only(root).unwrap();
+        expected.generic_delete(0, &[0x20]).unwrap();
+        let expected = expected.to_memtrie_changes_only().unwrap();
+        let root_hash = |changes: &MemTrieChanges| changes.node_ids_with_hashes.last().unwrap().1;
+        assert_eq!(root_hash(&changes), root_hash(&expected));
+
+        // With tracking, a full value is written to disk.
+        let mut update = tries.mem.update(root, TrackingMode::Refcounts).unwrap();
+        update.set_value_at(0, Some(GenericTrieValue::MemtrieAndDisk(vec![0x03]))).unwrap();
+        let mut changes = update.to_trie_changes().unwrap();
+        changes.memtrie_changes = None;
+        assert_eq!(changes, tries.make_disk_changes_only(vec![(vec![], Some(vec![0x03]))]));
+    }
+
+    #[test]
+    fn test_set_value_at_invalid_node() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(vec![
+            (vec![0x10], Some(vec![0x01])),
+            (vec![0x11], Some(vec![0x02])),
+        ]);
+        let value = GenericTrieValue::MemtrieAndDisk(vec![0x03]);
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        assert_matches!(
+            update.updated_nodes[0].as_ref().unwrap().node,
+            UpdatedMemTrieNode::Extension { .. }
+        );
+        assert_matches!(
+            update.set_value_at(0, Some(value.clone())),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        assert_matches!(
+            update.set_value_at(100, Some(value)),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        // The update is left intact.
+        let changes = update.to_trie_changes().unwrap();
+        assert_eq!(changes.new_root, tries.state_root);
+        assert!(changes.insertions.is_empty());
+        assert!(changes.deletions.is_empty());
+    }
+
+    #[test]
+    fn test_strict_deletes() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                12 = 00
+                1234 = 01
+            ",
+        ));
+
+        // In lenient mode, deleting absent keys is a no-op.
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        update.delete(&[0x13]).unwrap();
+        update.delete(&[0x12, 0x35]).unwrap();
+        let changes = update.to_memtrie_changes_only().unwrap();
+        assert_eq!(changes.node_ids_with_hashes.last().unwrap().1, tries.state_root);
+
+        let mut update = tries
+            .mem
+            .update_with_builder(
+                tries.state_root,
+                MemTrieUpdateBuilder::new("test".to_string()).with_strict_deletes(true),
+            )
+            .unwrap();
+        update.delete(&[0x12, 0x34]).unwrap();
+        // The key was already deleted in this update.
+        assert_matches!(
+            update.delete(&[0x12, 0x34]),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        assert_matches!(update.delete(&[0x13]), Err(StorageError::StorageInconsistentState(_)));
+        update.delete(&[0x12]).unwrap();
+    }
+
+    #[test]
+    fn test_rekey() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                10 = 0001
+                20 = 0002
+                30 = 0003
+            ",
+        ));
+        let mapping = vec![(vec![0x10], vec![0x40]), (vec![0x20], vec![0x21, 0x00])];
+
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        update.rekey(mapping.into_iter()).unwrap();
+        let trie_changes = update.to_trie_changes().unwrap();
+
+        let mut expected = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        expected.delete(&[0x10]).unwrap();
+        expected.delete(&[0x20]).unwrap();
+        expected.insert(&[0x40], vec![0x00, 0x01]).unwrap();
+        expected.insert(&[0x21, 0x00], vec![0x00, 0x02]).unwrap();
+        assert_eq!(trie_changes.new_root, expected.to_trie_changes().unwrap().new_root);
+
+        // Relocated values are neither added nor removed on disk.
+        let value_hashes = [hash(&[0x00, 0x01]), hash(&[0x00, 0x02])];
+        assert!(trie_changes.insertions().iter().all(|i| !value_hashes.contains(i.hash())));
+        assert!(trie_changes
+            .deletions()
+            .iter()
+            .all(|d| !value_hashes.contains(&d.trie_node_or_value_hash)));
+
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        assert_matches!(
+            update.rekey(vec![(vec![0x50], vec![0x51])].into_iter()),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic(expected = "Degenerate branch")]
+    fn test_degenerate_branch_detected() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                10 = 00
+                20 = 01
+            ",
+        ));
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        // Simulate a buggy operation which removes a child of the root
+        // branch without squashing it.
+        let mut root = update.take_node(0).unwrap();
+        let UpdatedMemTrieNode::Branch { children, .. } = &mut root.node else {
+            panic!("Expected root to be a branch");
+        };
+        children[2] = None;
+        update.place_node_at(0, root);
+    }
+
+    #[test]
+    fn test_unsquashed_degenerate_branch() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                10 = 00
+                20 = 01
+            ",
+        ));
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        // An operation may leave a degenerate branch to squash it later, and
+        // the branch may be taken and placed back meanwhile.
+        let mut root = update.take_node(0).unwrap();
+        let UpdatedMemTrieNode::Branch { children, .. } = &mut root.node else {
+            panic!("Expected root to be a branch");
+        };
+        let child = children[2].take();
+        update.place_unsquashed_node_at(0, root);
+        let root = update.take_node(0).unwrap();
+        update.place_node_at(0, root);
+
+        let mut root = update.take_node(0).unwrap();
+        let UpdatedMemTrieNode::Branch { children, .. } = &mut root.node else {
+            panic!("Expected root to be a branch");
+        };
+        children[2] = child;
+        update.place_node_at(0, root);
+        update.to_memtrie_changes_only().unwrap();
+    }
+
+    #[test]
+    fn test_try_compute_hashes_and_serialized_nodes() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        update.insert(&[0x11, 0x00], vec![0x00]).unwrap();
+        update.insert(&[0x11, 0x01], vec![0x01]).unwrap();
+        let mut ordered_nodes = Vec::new();
+        MemTrieUpdate::<HybridArenaMemory, MemOnly>::post_order_traverse_updated_nodes(
+            0,
+            &update.updated_nodes,
+            &mut ordered_nodes,
+        );
+        let expected =
+            update.compute_hashes_and_serialized_nodes(&ordered_nodes, &update.updated_nodes, None);
+        assert_eq!(
+            update
+                .try_compute_hashes_and_serialized_nodes(&ordered_nodes, &update.updated_nodes)
+                .unwrap(),
+            expected
+        );
+
+        // Parent before its children.
+        let reversed = ordered_nodes.iter().rev().copied().collect::<Vec<_>>();
+        assert_matches!(
+            update.try_compute_hashes_and_serialized_nodes(&reversed, &update.updated_nodes),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        // Missing and out of range nodes.
+        let mut updated_nodes = update.updated_nodes.clone();
+        updated_nodes[ordered_nodes[0]] = None;
+        assert_matches!(
+            update.try_compute_hashes_and_serialized_nodes(&ordered_nodes, &updated_nodes),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        assert_matches!(
+            update.try_compute_hashes_and_serialized_nodes(&[updated_nodes.len()], &updated_nodes),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        // Empty node.
+        let mut updated_nodes = update.updated_nodes.clone();
+        updated_nodes[ordered_nodes[0]] =
+            Some(UpdatedMemTrieNodeWithSize { node: UpdatedMemTrieNode::Empty, memory_usage: 0 });
+        assert_matches!(
+            update.try_compute_hashes_and_serialized_nodes(&ordered_nodes, &updated_nodes),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+    }
+
+    #[test]
+    fn test_to_raw_trie_node() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for key in [vec![0x11, 0x00], vec![0x11, 0x01], vec![0x22]] {
+            update.insert(&key, key.clone()).unwrap();
+        }
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let root = tries.apply_memtrie_changes(0, &changes).unwrap();
+
+        // Produce a leaf, an extension, and branches with and without value,
+        // with both old and updated children.
+        let mut update = tries.update_memtrie_only(root).unwrap();
+        update.insert(&[0x11], vec![0x11]).unwrap();
+        update.insert(&[0x33, 0x33], vec![0x33]).unwrap();
+        update.insert(&[0x33, 0x34], vec![0x34]).unwrap();
+        let mut ordered_nodes = Vec::new();
+        MemTrieUpdate::<HybridArenaMemory, MemOnly>::post_order_traverse_updated_nodes(
+            0,
+            &update.updated_nodes,
+            &mut ordered_nodes,
+        );
+        let hashes_and_serialized =
+            update.compute_hashes_and_serialized_nodes(&ordered_nodes, &update.updated_nodes, None);
+        let hashes: HashMap<_, _> =
+            hashes_and_serialized.iter().map(|(node_id, hash, _)| (*node_id, *hash)).collect();
+
+        let mut node_kinds = HashSet::new();
+        for (node_id, expected_hash, expected_serialized) in &hashes_and_serialized {
+            let node = update.updated_nodes[*node_id].as_ref().unwrap();
+            node_kinds.insert(match &node.node {
+                UpdatedMemTrieNode::Leaf { .. } => "leaf",
+                UpdatedMemTrieNode::Extension { .. } => "extension",
+                UpdatedMemTrieNode::Branch { value: None, .. } => "branch",
+                UpdatedMemTrieNode::Branch { value: Some(_), .. } => "branch_with_value",
+                UpdatedMemTrieNode::Empty => unreachable!(),
+            });
+            let raw_node = node.node.to_raw_trie_node(|child| match child {
+                OldOrUpdatedNodeId::Updated(child_id) => hashes[&child_id],
+                OldOrUpdatedNodeId::Old(child_id) => {
+                    child_id.as_ptr(tries.arena().memory()).view().node_hash()
+                }
+            });
+            let raw_node_with_size =
+                RawTrieNodeWithSize { node: raw_node, memory_usage: node.memory_usage };
+            let serialized = borsh::to_vec(&raw_node_with_size).unwrap();
+            assert_eq!(&serialized, expected_serialized);
+            assert_eq!(hash(&serialized), *expected_hash);
+        }
+        assert_eq!(node_kinds.len(), 4);
+    }
+
+    #[test]
+    fn test_find_orphans() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0100 = 0001
+                0101 = 0002
+            ",
+        ));
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        update.insert(&[0x01, 0x02], vec![0x00, 0x03]).unwrap();
+        update.delete(&[0x00]).unwrap();
+        assert_eq!(update.find_orphans(), Vec::<usize>::new());
+
+        let orphan = update.new_updated_node(UpdatedMemTrieNodeWithSize {
+            node: UpdatedMemTrieNode::Leaf {
+                extension: NibbleSlice::new(&[0x05]).encoded(true).into_vec().into(),
+                value: FlatStateValue::on_disk(&[0x05]),
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value: None,
+            },
+            memory_usage: 0,
+        });
+        assert_eq!(update.find_orphans(), vec![orphan]);
+    }
+
+    #[test]
+    fn test_intern_extensions() {
+        // Each group of keys shares a long prefix, and their suffixes are the
+        // same in all groups.
+        let mut entries = Vec::new();
+        for group in 0u8..8 {
+            for suffix in 0u8..4 {
+                let mut key = vec![group * 0x11];
+                key.extend([0x77; 20]);
+                key.push(suffix);
+                key.extend([0x55; 10]);
+                entries.push((key, vec![suffix]));
+            }
+        }
+        let tries = MemTries::new(ShardUId::single_shard());
+        let build = |intern: bool| {
+            let mut update = tries
+                .update_with_builder(
+                    CryptoHash::default(),
+                    MemTrieUpdateBuilder::new("test".to_string()).with_intern_extensions(intern),
+                )
+                .unwrap();
+            for (key, value) in &entries {
+                update.insert(key, value.clone()).unwrap();
+            }
+            let mut num_extensions = 0;
+            let mut allocations = HashSet::new();
+            for node in update.updated_nodes.iter().flatten() {
+                if let UpdatedMemTrieNode::Leaf { extension, .. }
+                | UpdatedMemTrieNode::Extension { extension, .. } = &node.node
+                {
+                    num_extensions += 1;
+                    allocations.insert(Arc::as_ptr(extension) as *const u8);
+                }
+            }
+            (update.to_memtrie_changes_only().unwrap(), num_extensions, allocations.len())
+        };
+
+        let (changes, num_extensions, num_allocations) = build(false);
+        assert_eq!(num_allocations, num_extensions);
+        let (interned_changes, num_interned_extensions, num_interned_allocations) = build(true);
+        assert_eq!(num_interned_extensions, num_extensions);
+        assert!(num_interned_allocations < num_allocations / 2);
+        assert_eq!(interned_changes, changes);
+    }
+
+    #[test]
+    fn test_unchanged_nodes_are_not_constructed() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0100 = 0001
+                0101 = 0002
+                02 = 0003
+            ",
+        ));
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        update.insert(&[0x01, 0x02], vec![0x00, 0x04]).unwrap();
+        update.delete(&[0x01, 0x02]).unwrap();
+        let trie_changes = update.to_trie_changes().unwrap();
+        assert_eq!(trie_changes.new_root, tries.state_root);
+        assert!(trie_changes.insertions().is_empty());
+        assert!(trie_changes.deletions().is_empty());
+
+        // Nothing is constructed, the original root is reused.
+        let changes = trie_changes.memtrie_changes.unwrap();
+        assert!(changes.node_ids_with_hashes.is_empty());
+        assert_eq!(changes.root_hash(), Some(tries.state_root));
+        let num_allocs_before = tries.mem.arena.num_active_allocs();
+        assert_eq!(tries.mem.apply_memtrie_changes(1, &changes).unwrap(), tries.state_root);
+        assert_eq!(tries.mem.arena.num_active_allocs(), num_allocs_before);
+        let decoded = MemTrieChanges::from_versioned_bytes(&changes.to_versioned_bytes()).unwrap();
+        assert_eq!(decoded, changes);
+
+        // Unchanged nodes below a changed root are not constructed either:
+        // only the extension at the root, the branch below it and the new
+        // leaf are constructed, on top of the original nodes.
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        update.insert(&[0x01, 0x02], vec![0x00, 0x04]).unwrap();
+        update.delete(&[0x01, 0x02]).unwrap();
+        update.insert(&[0x03], vec![0x05]).unwrap();
+        let changes = update.to_trie_changes().unwrap().memtrie_changes.unwrap();
+        assert_eq!(changes.node_ids_with_hashes.len(), 3);
+    }
+
+    #[test]
+    fn test_checkpoint_root() {
+        let entries: Vec<(Vec<u8>, Vec<u8>)> =
+            (0u8..20).map(|i| (vec![i % 4 * 0x11, i], vec![i])).collect();
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for (key, value) in &entries[..10] {
+            update.insert(key, value.clone()).unwrap();
+        }
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let root = tries.apply_memtrie_changes(0, &changes).unwrap();
+
+        let mut update = tries.update_memtrie_only(root).unwrap();
+        assert_eq!(update.checkpoint_root().unwrap(), root);
+        for (i, (key, value)) in entries.iter().enumerate().skip(10) {
+            update.insert(key, value.clone()).unwrap();
+            let checkpoint = update.checkpoint_root().unwrap();
+
+            let mut expected_update = tries.update_memtrie_only(root).unwrap();
+            for (key, value) in &entries[10..=i] {
+                expected_update.insert(key, value.clone()).unwrap();
+            }
+            let expected_changes = expected_update.to_memtrie_changes_only().unwrap();
+            assert_eq!(checkpoint, expected_changes.node_ids_with_hashes.last().unwrap().1);
+        }
+        let checkpoint = update.checkpoint_root().unwrap();
+        let changes = update.to_memtrie_changes_only().unwrap();
+        assert_eq!(checkpoint, changes.node_ids_with_hashes.last().unwrap().1);
+    }
+
+    #[test]
+    fn test_custom_hasher() {
+        let entries: Vec<(Vec<u8>, Vec<u8>)> =
+            (0u8..20).map(|i| (vec![i % 4 * 0x11, i], vec![i])).collect();
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        fn build_changes<H: Hasher>(
+            tries: &MemTries,
+            root: CryptoHash,
+            entries: &[(Vec<u8>, Vec<u8>)],
+        ) -> MemTrieChanges {
+            let mut update = tries.update_memtrie_only(root).unwrap();
+            for (key, value) in entries {
+                update.insert_memtrie_only(key, FlatStateValue::on_disk(value)).unwrap();
+            }
+            update.to_memtrie_changes_only_with_hasher::<H>().unwrap()
+        }
+
+        // Build the trie in two steps, so that hashes of old nodes are used.
+        let mut root = CryptoHash::default();
+        for chunk in entries.chunks(10) {
+            let changes = build_changes::<FnvHasher>(&tries, root, chunk);
+            root = tries.apply_memtrie_changes(0, &changes).unwrap();
+        }
+
+        // Hashing is deterministic regardless of the history, but differs
+        // from the default one.
+        let changes = build_changes::<FnvHasher>(&tries, CryptoHash::default(), &entries);
+        assert_eq!(changes.node_ids_with_hashes.last().unwrap().1, root);
+        let changes = build_changes::<Sha256Hasher>(&tries, CryptoHash::default(), &entries);
+        assert_ne!(changes.node_ids_with_hashes.last().unwrap().1, root);
+
+        let root_ptr = tries.get_root(&root).unwrap();
+        for (key, value) in &entries {
+            let result = memtrie_lookup(root_ptr, key, None).unwrap();
+            assert_eq!(result.to_flat_value(), FlatStateValue::on_disk(value));
+        }
+    }
+
+    #[test]
+    fn test_compute_hashes_with_cache() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                10 = 00
+                1100 = 01
+                20 = 02
+            ",
+        ));
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        update.insert(&[0x11, 0x01], vec![0x03]).unwrap();
+        update.insert(&[0x30], vec![0x04]).unwrap();
+        let mut ordered_nodes = Vec::new();
+        MemTrieUpdate::<HybridArenaMemory, MemOnly>::post_order_traverse_updated_nodes(
+            0,
+            &update.updated_nodes,
+            &mut ordered_nodes,
+        );
+        let num_nodes_hashed = || NUM_NODES_HASHED.with(|num_nodes| num_nodes.get());
+
+        let before = num_nodes_hashed();
+        let expected =
+            update.compute_hashes_and_serialized_nodes(&ordered_nodes, &update.updated_nodes, None);
+        assert_eq!(num_nodes_hashed() - before, ordered_nodes.len());
+
+        let mut cache = NodeHashCache::new();
+        let computed = update.compute_hashes_and_serialized_nodes(
+            &ordered_nodes,
+            &update.updated_nodes,
+            Some(&mut cache),
+        );
+        assert_eq!(computed, expected);
+        assert_eq!(cache.len(), ordered_nodes.len());
+
+        // Everything is taken from the cache the second time.
+        let before = num_nodes_hashed();
+        let computed = update.compute_hashes_and_serialized_nodes(
+            &ordered_nodes,
+            &update.updated_nodes,
+            Some(&mut cache),
+        );
+        assert_eq!(computed, expected);
+        assert_eq!(num_nodes_hashed(), before);
+    }
+
+    #[test]
+    fn test_prepare_split() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                1234 = 00
+                5678 = 01
+                12 = 02
+            ",
+        ));
+        let root_hash = |changes: &MemTrieChanges| changes.node_ids_with_hashes.last().unwrap().1;
+        // Keys splitting a leaf, splitting an extension and ending in the
+        // middle of a leaf key.
+        for key in [vec![0x12, 0x35], vec![0x13], vec![0x56]] {
+            let mut expected = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+            expected.insert(&key, vec![0x03]).unwrap();
+            let expected = expected.to_memtrie_changes_only().unwrap();
+
+            let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+            update.prepare_split(&key).unwrap();
+            let num_nodes = update.updated_nodes.len();
+            update.insert(&key, vec![0x03]).unwrap();
+            // The insert doesn't need to create any nodes.
+            assert_eq!(update.updated_nodes.len(), num_nodes);
+            assert_eq!(root_hash(&update.to_memtrie_changes_only().unwrap()), root_hash(&expected));
+
+            let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+            update.prepare_split(&key).unwrap();
+            assert_eq!(update.iter_prefix(&[]).count(), 3);
+            assert_eq!(root_hash(&update.to_memtrie_changes_only().unwrap()), tries.state_root);
+        }
+
+        // Nothing is prepared for an existing key.
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        update.prepare_split(&[0x12]).unwrap();
+        assert_eq!(update.updated_nodes.len(), 1);
+    }
+
+    #[test]
+    fn test_original_hash_of() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                10 = 00
+                20 = 01
+            ",
+        ));
+        let root = tries.mem.get_root(&tries.state_root).unwrap();
+        let MemTrieNodeView::Branch { children, .. } = root.view() else {
+            panic!("Expected root to be a branch");
+        };
+        let leaf_hash = children.get(1).unwrap().view().node_hash();
+
+        let update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        assert_eq!(update.original_hash_of(0), None);
+        let mut update = tries
+            .mem
+            .update_with_builder(
+                tries.state_root,
+                MemTrieUpdateBuilder::new("test".to_string()).with_original_hashes(true),
+            )
+            .unwrap();
+        // Only the root is converted, and the new leaf is created fresh.
+        update.insert(&[0x30], vec![0x02]).unwrap();
+        assert_eq!(update.updated_nodes.len(), 2);
+        assert_eq!(update.original_hash_of(0), Some(tries.state_root));
+        assert_eq!(update.original_hash_of(1), None);
+        // The leaf under 0x10 is converted and then split.
+        update.insert(&[0x10, 0x00], vec![0x03]).unwrap();
+        assert_eq!(update.original_hash_of(2), Some(leaf_hash));
+        let num_converted = (0..update.updated_nodes.len())
+            .filter(|id| update.original_hash_of(*id).is_some())
+            .count();
+        assert_eq!(num_converted, 2);
+    }
+
+    #[test]
+    fn test_key_prefix_of() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                1234 = 00
+                1256 = 01
+            ",
+        ));
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        update.insert(&[0x12, 0x57], vec![0x02]).unwrap();
+        update.insert(&[0x78], vec![0x03]).unwrap();
+
+        let mut leaf_keys = HashSet::new();
+        for (node_id, node) in update.updated_nodes.iter().enumerate() {
+            if let Some(UpdatedMemTrieNode::Leaf { .. }) = node.as_ref().map(|node| &node.node) {
+                let nibbles = update.key_prefix_of(node_id);
+                leaf_keys.insert(NibbleSlice::nibbles_to_bytes(&nibbles));
+            }
+        }
+        let expected: HashSet<Vec<u8>> =
+            [vec![0x12, 0x56], vec![0x12, 0x57], vec![0x78]].into_iter().collect();
+        assert_eq!(leaf_keys, expected);
+        assert_eq!(update.key_prefix_of(0), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_iter_prefix() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                12 = 00
+                1200 = 01
+                1201 = 02
+                1300 = 03
+            ",
+        ));
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        update.insert(&[0x12, 0x10], vec![0x04]).unwrap();
+        update.insert(&[0x12, 0x02], vec![0x05]).unwrap();
+        update.insert(&[0x11, 0x00], vec![0x06]).unwrap();
+        update.generic_delete(0, &[0x12, 0x01]).unwrap();
+
+        let value = |v: u8| FlatStateValue::on_disk(&[v]);
+        assert_eq!(
+            update.iter_prefix(&[0x12]).collect::<Vec<_>>(),
+            vec![
+                (vec![0x12], value(0x00)),
+                (vec![0x12, 0x00], value(0x01)),
+                (vec![0x12, 0x02], value(0x05)),
+                (vec![0x12, 0x10], value(0x04)),
+            ]
+        );
+        assert_eq!(
+            update.iter_prefix(&[0x13]).collect::<Vec<_>>(),
+            vec![(vec![0x13, 0x00], value(0x03))]
+        );
+        assert_eq!(update.iter_prefix(&[0x12, 0x01]).count(), 0);
+        assert_eq!(update.iter_prefix(&[0x14]).count(), 0);
+        let all_keys: Vec<_> = update.iter_prefix(&[]).map(|(key, _)| key).collect();
+        assert_eq!(
+            all_keys,
+            vec![
+                vec![0x11, 0x00],
+                vec![0x12],
+                vec![0x12, 0x00],
+                vec![0x12, 0x02],
+                vec![0x12, 0x10],
+                vec![0x13, 0x00],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_squash_ops_metrics() {
+        let shard_uid = "test_squash_ops_metrics".to_string();
+        let squash_ops =
+            |kind: &str| MEM_TRIE_SQUASH_OPS.with_label_values(&[&shard_uid, kind]).get();
+
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                10 = 00
+                1000 = 01
+                20 = 02
+            ",
+        ));
+        let root_id = tries.mem.get_root(&tries.state_root).unwrap().id();
+        let mut update =
+            MemTrieUpdate::new(Some(root_id), tries.mem.arena().memory(), shard_uid.clone());
+        // The branch holding the value of 0x10 loses its only child and
+        // becomes a leaf, which is then merged into the extension above it.
+        update.generic_delete(0, &[0x10, 0x00]).unwrap();
+        assert_eq!(squash_ops("branch_to_leaf"), 1);
+        assert_eq!(squash_ops("extension_merge_leaf"), 1);
+        // The root is left with a single child and no value.
+        update.generic_delete(0, &[0x20]).unwrap();
+        assert_eq!(squash_ops("branch_to_extension"), 1);
+        assert_eq!(squash_ops("extension_merge_leaf"), 2);
+
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                10 = 00
+                2000 = 01
+                2011 = 02
+            ",
+        ));
+        let root_id = tries.mem.get_root(&tries.state_root).unwrap().id();
+        let mut update =
+            MemTrieUpdate::new(Some(root_id), tries.mem.arena().memory(), shard_uid.clone());
+        // The root becomes an extension pointing to another extension.
+        update.generic_delete(0, &[0x10]).unwrap();
+        assert_eq!(squash_ops("branch_to_extension"), 2);
+        assert_eq!(squash_ops("extension_merge_extension"), 1);
+    }
+
+    #[test]
+    fn test_delete_many() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                1000 = 00
+                1001 = 01
+                20 = 02
+            ",
+        ));
+        let keys: [&[u8]; 2] = [&[0x10, 0x00], &[0x10, 0x01]];
+        let root_id = tries.mem.get_root(&tries.state_root).unwrap().id();
+        let make_update = |shard_uid: &str| {
+            MemTrieUpdate::new(Some(root_id), tries.mem.arena().memory(), shard_uid.to_string())
+        };
+        let root_hash = |changes: MemTrieChanges| changes.node_ids_with_hashes.last().unwrap().1;
+
+        let sequential_shard_uid = "test_delete_many_sequential";
+        let mut update = make_update(sequential_shard_uid);
+        for key in keys {
+            update.delete(key).unwrap();
+        }
+        let expected_root = root_hash(update.to_memtrie_changes_only().unwrap());
+
+        // Both keys share the branch under 0x10, which is squashed only once,
+        // straight into nothing, instead of into a leaf first.
+        let batched_shard_uid = "test_delete_many_batched";
+        let mut update = make_update(batched_shard_uid);
+        update.delete_many(keys).unwrap();
+        let squash_ops = |shard_uid: &str, kind: &str| {
+            MEM_TRIE_SQUASH_OPS.with_label_values(&[shard_uid, kind]).get()
+        };
+        assert_eq!(squash_ops(sequential_shard_uid, "branch_to_extension"), 2);
+        assert_eq!(squash_ops(batched_shard_uid, "branch_to_empty"), 1);
+        assert_eq!(squash_ops(batched_shard_uid, "extension_to_empty"), 1);
+        assert_eq!(squash_ops(batched_shard_uid, "branch_to_extension"), 1);
+        assert_eq!(squash_ops(batched_shard_uid, "extension_merge_leaf"), 1);
+        assert_matches!(
+            update.updated_nodes[0].as_ref().unwrap().node,
+            UpdatedMemTrieNode::Leaf { .. }
+        );
+        assert_eq!(root_hash(update.to_memtrie_changes_only().unwrap()), expected_root);
+    }
+
+    #[cfg(feature = "memtrie_timing")]
+    #[test]
+    fn test_update_timings() {
+        use std::time::{Duration, Instant};
+
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        assert_eq!(update.timings(), Default::default());
+        let start = Instant::now();
+        for i in 0..1000u32 {
+            update.insert(&i.to_be_bytes(), vec![1]).unwrap();
+        }
+        for i in (0..1000u32).step_by(2) {
+            update.delete(&i.to_be_bytes()).unwrap();
+        }
+        update.checkpoint_root().unwrap();
+        let total = start.elapsed();
+
+        let timings = update.timings();
+        assert!(timings.descent > Duration::ZERO);
+        assert!(timings.squash > Duration::ZERO);
+        assert!(timings.hashing > Duration::ZERO);
+        // The phases cover almost all the work, except for bookkeeping
+        // between them.
+        let sum = timings.descent + timings.squash + timings.traversal + timings.hashing;
+        assert!(sum <= total, "{:?} > {:?}", sum, total);
+        assert!(sum * 2 >= total, "{:?} < {:?} / 2", sum, total);
+    }
+
+    #[cfg(feature = "memtrie_timing")]
+    #[test]
+    fn test_update_phase_seconds() {
+        use crate::trie::mem::metrics::MEM_TRIE_UPDATE_PHASE_SECONDS;
+        use near_primitives::types::ShardId;
+
+        // Other tests don't update this shard, so they don't add samples.
+        let shard = ShardUId::new(7, ShardId::new(2062));
+        let shard_uid = shard.to_string();
+        let sample_counts = || {
+            ["apply", "traverse", "hash"].map(|phase| {
+                MEM_TRIE_UPDATE_PHASE_SECONDS
+                    .with_label_values(&[&shard_uid, phase])
+                    .get_sample_count()
+            })
+        };
+        let mut tries = MemTries::new(shard);
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for i in 0..10u8 {
+            update.insert(&[i], vec![1]).unwrap();
+        }
+        update.delete(&[0]).unwrap();
+        assert_eq!(sample_counts(), [0; 3]);
+        // Each phase is observed once per update, however many operations
+        // it has.
+        tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+        assert_eq!(sample_counts(), [1; 3]);
+    }
+
+    #[cfg(feature = "memtrie_shadow_values")]
+    #[test]
+    fn test_shadow_value_slots() {
+        use super::ValueSlot;
+
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                12 = 00
+                34 = 01
+            ",
+        ));
+        let value = |v: u8| FlatStateValue::on_disk(&[v]);
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        assert_eq!(update.get_from_slot(ValueSlot::Primary, &[0x12]).unwrap(), Some(value(0x00)));
+        assert_eq!(update.get_from_slot(ValueSlot::Shadow, &[0x12]).unwrap(), None);
+
+        update.insert_to_slot(ValueSlot::Shadow, &[0x12], value(0x02)).unwrap();
+        update.insert_to_slot(ValueSlot::Primary, &[0x34], value(0x04)).unwrap();
+        assert_eq!(update.get_from_slot(ValueSlot::Primary, &[0x12]).unwrap(), Some(value(0x00)));
+        assert_eq!(update.get_from_slot(ValueSlot::Shadow, &[0x12]).unwrap(), Some(value(0x02)));
+        assert_eq!(update.get_from_slot(ValueSlot::Shadow, &[0x34]).unwrap(), None);
+        // Only keys with a primary value can have a shadow value.
+        assert!(update.insert_to_slot(ValueSlot::Shadow, &[0x56], value(0x03)).is_err());
+        assert_eq!(update.get_from_slot(ValueSlot::Shadow, &[0x56]).unwrap(), None);
+
+        // Replacing the primary value keeps the shadow value.
+        update.insert_to_slot(ValueSlot::Shadow, &[0x34], value(0x05)).unwrap();
+        update.insert_to_slot(ValueSlot::Primary, &[0x34], value(0x06)).unwrap();
+        assert_eq!(update.get_from_slot(ValueSlot::Shadow, &[0x34]).unwrap(), Some(value(0x05)));
+
+        // The shadow value follows the primary one when nodes are split and
+        // squashed back.
+        update.insert_to_slot(ValueSlot::Primary, &[0x13], value(0x07)).unwrap();
+        assert_eq!(update.get_from_slot(ValueSlot::Shadow, &[0x12]).unwrap(), Some(value(0x02)));
+        assert_eq!(update.get_from_slot(ValueSlot::Shadow, &[0x13]).unwrap(), None);
+        update.delete_from_slot(ValueSlot::Primary, &[0x13]).unwrap();
+        assert_eq!(update.get_from_slot(ValueSlot::Shadow, &[0x12]).unwrap(), Some(value(0x02)));
+
+        update.delete_from_slot(ValueSlot::Shadow, &[0x34]).unwrap();
+        assert_eq!(update.get_from_slot(ValueSlot::Primary, &[0x34]).unwrap(), Some(value(0x06)));
+        assert_eq!(update.get_from_slot(ValueSlot::Shadow, &[0x34]).unwrap(), None);
+        // Deleting the primary value deletes the shadow value too.
+        update.delete_from_slot(ValueSlot::Primary, &[0x12]).unwrap();
+        assert_eq!(update.get_from_slot(ValueSlot::Primary, &[0x12]).unwrap(), None);
+        assert_eq!(update.get_from_slot(ValueSlot::Shadow, &[0x12]).unwrap(), None);
+
+        // Shadow values don't affect the state root.
+        update.insert_to_slot(ValueSlot::Shadow, &[0x34], value(0x08)).unwrap();
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let mut expected = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        expected.insert_memtrie_only(&[0x34], value(0x06)).unwrap();
+        expected.delete(&[0x12]).unwrap();
+        assert!(changes.canonical_eq(&expected.to_memtrie_changes_only().unwrap()));
+    }
+
+    // As of Oct 2023 this randomized test was seen to cover all branches except
+    // deletion of keys from empty tries and deleting all keys from the trie.
+    #[test]
+    fn test_trie_consistency_random() {
+        const MAX_KEYS: usize = 100;
+        const SLOWDOWN: usize = 5;
+        let mut tries = TestTries::new(false);
+        for batch in 0..1000 {
+            println!("Batch {}:", batch);
+            let mut existing_keys = tries.truth.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
+            // The more keys we have, the less we insert, the more we delete.
+            let num_insertions =
+                rand::thread_rng().gen_range(0..=(MAX_KEYS - existing_keys.len()) / SLOWDOWN);
+            let num_deletions =
+                rand::thread_rng().gen_range(0..=(existing_keys.len() + SLOWDOWN - 1) / SLOWDOWN);
+            let mut changes = Vec::new();
+            for _ in 0..num_insertions {
+                let key_length = rand::thread_rng().gen_range(0..=10);
+                let existing_key = existing_keys
+                    .get(rand::thread_rng().gen_range(0..existing_keys.len().max(1)))
+                    .cloned()
+                    .unwrap_or_default();
+                let reuse_prefix_length = rand::thread_rng().gen_range(0..=existing_key.len());
+                let mut key = Vec::<u8>::new();
+                for i in 0..key_length {
+                    if i < reuse_prefix_length {
+                        key.push(existing_key[i]);
+                    } else {
+                        // Limit nibbles to 4, so that we can generate keys that relate to
+                        // each other more frequently.
+                        let nibble0 = rand::thread_rng().gen::<u8>() % 4;
+                        let nibble1 = rand::thread_rng().gen::<u8>() % 4;
+                        key.push(nibble0 << 4 | nibble1);
+                    }
+                }
+
+                let mut value_length = rand::thread_rng().gen_range(0..=10);
+                if value_length == 10 {
+                    value_length = 8000; // make a long value that is not inlined
+                }
+                let mut value = Vec::<u8>::new();
+                for _ in 0..value_length {
+                    value.push(rand::thread_rng().gen());
+                }
+                println!(
+                    "  {} = {}",
+                    hex::encode(&key),
+                    if value.len() > 10 {
+                        hex::encode(&value[0..10]) + "..."
+                    } else {
+                        hex::encode(&value)
+                    }
+                );
+                changes.push((key.clone(), Some(value.clone())));
+                // Add it to existing keys so that we can insert more keys similar
+                // to this as well as delete some of these keys too.
+                existing_keys.push(key);
+            }
+            for _ in 0..num_deletions {
+                let key = existing_keys
+                    .get(rand::thread_rng().gen_range(0..existing_keys.len()))
+                    .cloned()
+                    .unwrap_or_default();
+                println!("  {} = delete", hex::encode(&key));
+                changes.push((key.clone(), None));
+            }
+            tries.check_consistency_across_all_changes_and_apply(changes);
+        }
+    }
+
+    fn insert_changes_to_memtrie(
+        memtrie: &mut MemTries,
+        prev_state_root: CryptoHash,
+        block_height: BlockHeight,
+        changes: &str,
+    ) -> CryptoHash {
+        let changes = parse_changes(changes);
+        let mut update = memtrie.update_memtrie_only(prev_state_root).unwrap();
+
+        for (key, value) in changes {
+            if let Some(value) = value {
+                update.insert_memtrie_only(&key, FlatStateValue::on_disk(&value)).unwrap();
+            } else {
+                update.generic_delete(0, &key).unwrap();
+            }
+        }
+
+        let changes = update.to_memtrie_changes_only().unwrap();
+        memtrie.apply_memtrie_changes(block_height, &changes).unwrap()
+    }
+
+    #[test]
+    fn test_gc_hybrid_memtrie() {
+        let state_root = StateRoot::default();
+        let mut memtrie = MemTries::new(ShardUId::single_shard());
+        assert!(!memtrie.arena.has_shared_memory());
+        let mut alloc_counter = AllocCounter::start();
+
+        // Insert in some initial data for height 0
+        // The trie consists of the extension ff0, the branch below it, the
+        // leaf of ff00, the branch with value of ff01 and the leaf of ff0101.
+        let changes = "
+            ff00 = 0000
+            ff01 = 0100
+            ff0101 = 0101
+        ";
+        let state_root = insert_changes_to_memtrie(&mut memtrie, state_root, 0, changes);
+        let counts = alloc_counter.take();
+        assert_eq!((counts.allocs, counts.deallocs), (5, 0));
+
+        // Freeze the current memory in memtrie
+        let frozen_arena = memtrie.arena.freeze();
+        let hybrid_arena =
+            HybridArena::from_frozen("test_hybrid".to_string(), frozen_arena.clone());
+        memtrie.arena = hybrid_arena;
+        assert!(memtrie.arena.has_shared_memory());
+        assert_eq!(alloc_counter.take(), AllocCounts::default());
+
+        // Insert in some more data for height 1 in hybrid memtrie
+        // Try to make sure we share some node allocations (ff01 and ff0101) with height 0
+        // Node ff01 effectively has a refcount of 2, one from height 0 and one from height 1
+        // The new nodes are the extension, the branch below it, the branch
+        // with value of ff00, the branch below it and the leaves of ff0000
+        // and ff0001.
+
+        let changes = "
+            ff0000 = 1000
+            ff0001 = 1001
+        ";
+        insert_changes_to_memtrie(&mut memtrie, state_root, 1, changes);
+        let insert_counts = alloc_counter.take();
+        assert_eq!((insert_counts.allocs, insert_counts.deallocs), (6, 0));
+
+        // Now try to garbage collect the height 0 root
+        // Memory consumption should not change as height 0 is frozen
+        let num_active_allocs = memtrie.arena.num_active_allocs();
+        let active_allocs_bytes = memtrie.arena.active_allocs_bytes();
+        memtrie.delete_until_height(1);
+        assert_eq!(memtrie.arena.num_active_allocs(), num_active_allocs);
+        assert_eq!(memtrie.arena.active_allocs_bytes(), active_allocs_bytes);
+        assert_eq!(alloc_counter.take(), AllocCounts::default());
+
+        // Now try to garbage collect the height 1 root
+        // The final memory allocation should be what we had during the time of freezing
+        memtrie.delete_until_height(2);
+        assert_eq!(memtrie.arena.num_active_allocs(), frozen_arena.num_active_allocs());
+        assert_eq!(memtrie.arena.active_allocs_bytes(), frozen_arena.active_allocs_bytes());
+        // Exactly the nodes allocated for height 1 are deallocated.
+        let counts = alloc_counter.take();
+        assert_eq!((counts.allocs, counts.deallocs), (0, insert_counts.allocs));
+        assert_eq!(counts.dealloc_bytes, insert_counts.alloc_bytes);
+    }
+
+    #[test]
+    fn test_retain_latest_hybrid_memtrie() {
+        let mut memtrie = MemTries::new(ShardUId::single_shard());
+        let state_root = insert_changes_to_memtrie(
+            &mut memtrie,
+            StateRoot::default(),
+            0,
+            "
+                ff00 = 0000
+                ff01 = 0100
+            ",
+        );
+        let frozen_arena = memtrie.arena.freeze();
+        memtrie.arena = HybridArena::from_frozen("test_hybrid".to_string(), frozen_arena.clone());
+
+        let mut state_roots = vec![state_root];
+        for height in 1..5u8 {
+            let changes = format!("ff{:02x} = {:02x}00", height + 1, height);
+            let prev_state_root = *state_roots.last().unwrap();
+            let state_root =
+                insert_changes_to_memtrie(&mut memtrie, prev_state_root, height.into(), &changes);
+            state_roots.push(state_root);
+        }
+
+        memtrie.retain_latest(2);
+        for (height, state_root) in state_roots.iter().enumerate() {
+            assert_eq!(memtrie.get_root(state_root).is_ok(), height >= 3, "height {height}");
+        }
+        // Keeping at least as many roots as there are is a no-op.
+        memtrie.retain_latest(3);
+        assert_eq!(memtrie.num_roots(), 2);
+
+        // Frozen nodes are not reclaimed, so deleting all roots brings the
+        // memory back to the frozen state.
+        memtrie.retain_latest(0);
+        assert_eq!(memtrie.num_roots(), 0);
+        assert_eq!(memtrie.arena.num_active_allocs(), frozen_arena.num_active_allocs());
+        assert_eq!(memtrie.arena.active_allocs_bytes(), frozen_arena.active_allocs_bytes());
+    }
+
+    #[test]
+    fn test_from_nodes_storage() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                01 = 0006
+                0100 = 0001
+                0101 = 0002
+                0200 = 0003
+            ",
+        ));
+        let changes = parse_changes(
+            "
+                0102 = 0004
+                0200 = 0005
+                00 = delete
+            ",
+        );
 
-            // Check the truth against both memtrie and on-disk trie.
-            for (key, value_ref) in &self.truth {
-                let memtrie_root = if self.state_root == StateRoot::default() {
-                    None
-                } else {
-                    Some(self.mem.get_root(&self.state_root).unwrap())
-                };
-                let disk_trie =
-                    self.disk.get_trie_for_shard(ShardUId::single_shard(), self.state_root);
-                let memtrie_result =
-                    memtrie_root.and_then(|memtrie_root| memtrie_lookup(memtrie_root, key, None));
-                let disk_result = disk_trie.get_optimized_ref(key, KeyLookupMode::Trie).unwrap();
-                if let Some(value_ref) = value_ref {
-                    let memtrie_value_ref = memtrie_result
-                        .unwrap_or_else(|| {
-                            panic!("Key {} is in truth but not in memtrie", hex::encode(key))
-                        })
-                        .to_flat_value()
-                        .to_value_ref();
-                    let disk_value_ref = disk_result
-                        .unwrap_or_else(|| {
-                            panic!("Key {} is in truth but not in disk trie", hex::encode(key))
-                        })
-                        .into_value_ref();
-                    assert_eq!(
-                        memtrie_value_ref,
-                        *value_ref,
-                        "Value for key {} is incorrect for memtrie",
-                        hex::encode(key)
-                    );
-                    assert_eq!(
-                        disk_value_ref,
-                        *value_ref,
-                        "Value for key {} is incorrect for disk trie",
-                        hex::encode(key)
-                    );
-                } else {
-                    assert!(
-                        memtrie_result.is_none(),
-                        "Key {} is not in truth but is in memtrie",
-                        hex::encode(key)
-                    );
-                    assert!(
-                        disk_result.is_none(),
-                        "Key {} is not in truth but is in disk trie",
-                        hex::encode(key)
-                    );
-                }
+        let trie = tries.disk.get_trie_for_shard(ShardUId::single_shard(), tries.state_root);
+        let mut storage = TrieStorageUpdate::new(&trie);
+        let storage_root = trie.move_node_to_mutable(&mut storage, &trie.root).unwrap().0;
+        for (key, value) in changes.clone() {
+            match value {
+                Some(value) => storage
+                    .generic_insert(storage_root, &key, GenericTrieValue::MemtrieAndDisk(value))
+                    .unwrap(),
+                None => storage.generic_delete(storage_root, &key).unwrap(),
             }
         }
+
+        let root_id = tries.mem.get_root(&tries.state_root).unwrap().id();
+        let memory = tries.mem.arena().memory();
+        let shard_uid = ShardUId::single_shard().to_string();
+        // Old nodes can't be resolved against an unrelated memtrie.
+        assert_matches!(
+            MemTrieUpdate::from_nodes_storage(
+                &storage,
+                storage_root,
+                None,
+                memory,
+                shard_uid.clone(),
+            ),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+
+        let bridged = MemTrieUpdate::from_nodes_storage(
+            &storage,
+            storage_root,
+            Some(root_id),
+            memory,
+            shard_uid,
+        )
+        .unwrap();
+        let bridged_changes = bridged.to_memtrie_changes_only().unwrap();
+        let expected_changes = tries.make_memtrie_changes_only(changes);
+        let disk_root = storage.flatten_nodes(&trie.root, storage_root).unwrap().new_root;
+
+        let bridged_root = tries.mem.apply_memtrie_changes(1, &bridged_changes).unwrap();
+        let expected_root = tries.mem.apply_memtrie_changes(2, &expected_changes).unwrap();
+        assert_eq!(bridged_root, disk_root);
+        assert_eq!(bridged_root, expected_root);
+        // Values of old nodes which were updated on disk stay inlined.
+        let root = tries.mem.get_root(&bridged_root).unwrap();
+        assert_eq!(
+            memtrie_lookup(root, &[0x01], None).unwrap().to_flat_value(),
+            FlatStateValue::Inlined(vec![0x00, 0x06])
+        );
+    }
+
+    /// Deletes the value of the branch at 0x10 having the given children
+    /// with `delete_branch_value`, and checks that the result matches the
+    /// plain deletion from the disk trie. The root is then an extension;
+    /// returns the number of its nibbles and the kind of its child.
+    fn delete_branch_value_and_check(child_keys: &[&[u8]]) -> (usize, NodeKind) {
+        let mut tries = TestTries::new(true);
+        let mut changes = vec![(vec![0x10], Some(vec![0x10]))];
+        changes.extend(child_keys.iter().map(|key| (key.to_vec(), Some(key.to_vec()))));
+        tries.check_consistency_across_all_changes_and_apply(changes);
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        // Keys without a branch value are left alone.
+        for key in [vec![0x10, 0x00], vec![0x11], vec![0x10, 0x0f]] {
+            assert!(!update.delete_branch_value(&key).unwrap());
+        }
+        assert!(update.delete_branch_value(&[0x10]).unwrap());
+        assert!(!update.delete_branch_value(&[0x10]).unwrap());
+        let root =
+            tries.mem.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+        tries.check_consistency_across_all_changes_and_apply(vec![(vec![0x10], None)]);
+        assert_eq!(root, tries.state_root);
+        let root = tries.mem.get_root(&root).unwrap();
+        let MemTrieNodeView::Extension { extension, child, .. } = root.view() else {
+            panic!("Unexpected root: {:?}", root.view());
+        };
+        (NibbleSlice::from_encoded(extension).0.len(), child.node_kind())
     }
 
-    fn parse_changes(s: &str) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
-        s.split('\n')
-            .map(|s| s.split('#').next().unwrap().trim())
-            .filter(|s| !s.is_empty())
-            .map(|s| {
-                let mut parts = s.split(" = ");
-                let key = parts.next().unwrap();
-                let value = parts.next().unwrap();
-                let value =
-                    if value == "delete" { None } else { Some(hex::decode(value).unwrap()) };
-                (hex::decode(key).unwrap(), value)
-            })
-            .collect()
+    #[test]
+    fn test_delete_branch_value() {
+        assert_eq!(
+            delete_branch_value_and_check(&[&[0x10, 0x00], &[0x10, 0x10], &[0x10, 0x20]]),
+            (2, NodeKind::Branch)
+        );
+        assert_eq!(
+            delete_branch_value_and_check(&[&[0x10, 0x00], &[0x10, 0x10]]),
+            (2, NodeKind::Branch)
+        );
+        // The only child is a branch, which the extension now leads to.
+        assert_eq!(
+            delete_branch_value_and_check(&[&[0x10, 0x01], &[0x10, 0x02]]),
+            (3, NodeKind::Branch)
+        );
     }
 
     #[test]
-    fn test_meta_parse_changes() {
-        // Make sure that our test utility itself is fine.
-        let changes = parse_changes(
+    fn test_extend_child_branch_memory_usage() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
             "
-                00ff = 00000001  # comments
-                01dd = delete
-                # comments
-                02ac = 0003
+                1100 = 00
+                1200 = 01
+                2000 = 02
             ",
-        );
+        ));
+        // The root branch is left with a single child, which is a branch, so
+        // the root becomes an extension leading to it.
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        update.delete(&[0x20, 0x00]).unwrap();
+        let extension = update.updated_nodes[0].as_ref().unwrap();
+        let UpdatedMemTrieNode::Extension { child: OldOrUpdatedNodeId::Updated(child_id), .. } =
+            &extension.node
+        else {
+            panic!("Unexpected root: {:?}", extension.node);
+        };
+        let branch = update.updated_nodes[*child_id].as_ref().unwrap();
+        assert_matches!(branch.node, UpdatedMemTrieNode::Branch { .. });
         assert_eq!(
-            changes,
-            vec![
-                (vec![0x00, 0xff], Some(vec![0x00, 0x00, 0x00, 0x01])),
-                (vec![0x01, 0xdd], None),
-                (vec![0x02, 0xac], Some(vec![0x00, 0x03])),
-            ]
+            extension.memory_usage,
+            extension.node.memory_usage_direct() + branch.memory_usage
+        );
+
+        // The serialized root matches the same structure built from scratch.
+        let mut ordered_nodes = Vec::new();
+        MemTrieUpdate::<HybridArenaMemory, MemOnly>::post_order_traverse_updated_nodes(
+            0,
+            &update.updated_nodes,
+            &mut ordered_nodes,
         );
+        let hashes_and_serialized =
+            update.compute_hashes_and_serialized_nodes(&ordered_nodes, &update.updated_nodes, None);
+        let (_, root_hash, serialized) = hashes_and_serialized.last().unwrap();
+        let raw_root = borsh::from_slice::<RawTrieNodeWithSize>(serialized).unwrap();
+        let mut expected_tries = TestTries::new(true);
+        expected_tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                1100 = 00
+                1200 = 01
+            ",
+        ));
+        let expected_root = expected_tries.mem.get_root(&expected_tries.state_root).unwrap();
+        assert_matches!(expected_root.view(), MemTrieNodeView::Extension { .. });
+        assert_eq!(raw_root.memory_usage, expected_root.view().memory_usage());
+        assert_eq!(*root_hash, expected_tries.state_root);
     }
 
-    // As of Oct 2023 this test by itself achieves 100% test coverage for the
-    // logic in this file (minus the unreachable cases). If you modify the code
-    // or the test, please check code coverage with e.g. tarpaulin.
     #[test]
-    fn test_trie_consistency_manual() {
+    fn test_referenced_old_nodes() {
         let mut tries = TestTries::new(true);
-        // Simple insertion from empty trie.
         tries.check_consistency_across_all_changes_and_apply(parse_changes(
             "
-                00 = 0000
-                01 = 0001
-                02 = 0002
+                1010 = 00
+                1020 = 01
+                1030 = 02
+                2010 = 03
+                2020 = 04
+                3010 = 05
             ",
         ));
-        // Prepare some more complex values.
+        let root = tries.mem.get_root(&tries.state_root).unwrap();
+        let root_children = root.view().iter_children().collect::<Vec<_>>();
+        let extension = root_children[0].view();
+        let branch = extension.iter_children().next().unwrap().view();
+        let mut expected_old_nodes = HashSet::new();
+        expected_old_nodes.insert(root_children[1].id());
+        expected_old_nodes.insert(root_children[2].id());
+        expected_old_nodes.extend(branch.iter_children().map(|child| child.id()));
+        assert_eq!(expected_old_nodes.len(), 5);
+
+        // Initially, only the children of the root are referenced.
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        let initial_old_nodes = root_children.iter().map(|child| child.id()).collect::<Vec<_>>();
+        assert_eq!(update.referenced_old_nodes(), initial_old_nodes);
+        // After an insertion, the untouched siblings along its path are.
+        update.insert(&[0x10, 0x40], vec![0x06]).unwrap();
+        let old_nodes = update.referenced_old_nodes();
+        assert_eq!(old_nodes.len(), expected_old_nodes.len());
+        assert_eq!(old_nodes.into_iter().collect::<HashSet<_>>(), expected_old_nodes);
+    }
+
+    #[test]
+    fn test_reused_original_node_count() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for i in 0..1000u32 {
+            let key = (i * 7919).to_be_bytes();
+            update.insert_memtrie_only(&key, FlatStateValue::Inlined(vec![1])).unwrap();
+        }
+        let root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+        let num_nodes = tries.changes_between(&CryptoHash::default(), &root, None).unwrap();
+        let num_nodes = num_nodes.node_ids_with_hashes.len();
+
+        // Overwriting a key converts exactly the nodes on its path.
+        let key = (500 * 7919u32).to_be_bytes();
+        let mut nodes_accessed = Vec::new();
+        tries.lookup(&root, &key, Some(&mut nodes_accessed)).unwrap().unwrap();
+        let mut update = tries.update_memtrie_only(root).unwrap();
+        update.insert_memtrie_only(&key, FlatStateValue::Inlined(vec![2])).unwrap();
+        assert_eq!(update.reused_original_node_count(), num_nodes - nodes_accessed.len());
+
+        // An update without changes only converts the root.
+        let update = tries.update_memtrie_only(root).unwrap();
+        assert_eq!(update.reused_original_node_count(), num_nodes - 1);
+        let update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        assert_eq!(update.reused_original_node_count(), 0);
+    }
+
+    #[test]
+    fn test_descents_share_converted_nodes() {
+        let mut tries = TestTries::new(true);
         tries.check_consistency_across_all_changes_and_apply(parse_changes(
             "
-                0000 = 0010  # extends a leaf
-                0100 = 0011  # extends another leaf
-                03 = 0012  # adds a branch
-                0444 = 0013  # adds a branch with a longer leaf
-                0500 = 0014  # adds a branch that has a branch underneath
-                05100000 = 0015
-                05100001 = 0016
-                05200000 = 0017
-                05200001 = 0018
-                05300000 = 0019
-                05300001 = 001a
-                05400000 = 001b
-                05400001 = 001c
-                05500000 = 001d
-                05501000 = 001e
-                05501001 = 001f
+                1010 = 00
+                1020 = 01
+                2010 = 02
             ",
         ));
-        // Check insertion and deletion in a variety of cases.
-        // Code coverage is used to confirm we have covered all cases.
+        let root = tries.mem.get_root(&tries.state_root).unwrap();
+        let ancestor = root.view().iter_children().next().unwrap();
+        let ancestor_hash = ancestor.view().node_hash();
+
+        // Both keys descend through the same original node, which is only
+        // converted once.
+        let mut update = tries
+            .mem
+            .update_with_builder(
+                tries.state_root,
+                MemTrieUpdateBuilder::new("test".to_string())
+                    .with_tracking(TrackingMode::Refcounts)
+                    .with_original_hashes(true),
+            )
+            .unwrap();
+        update.insert(&[0x10, 0x30], vec![0x03]).unwrap();
+        update.insert(&[0x10, 0x40], vec![0x04]).unwrap();
+        let num_conversions = (0..update.updated_nodes.len())
+            .filter(|node_id| update.original_hash_of(*node_id) == Some(ancestor_hash))
+            .count();
+        assert_eq!(num_conversions, 1);
+
+        // The original node is only recorded as deleted once.
+        let changes = update.to_trie_changes().unwrap();
+        let deletion = changes
+            .deletions
+            .iter()
+            .find(|deletion| deletion.trie_node_or_value_hash == ancestor_hash)
+            .unwrap();
+        assert_eq!(deletion.rc.get(), 1);
+    }
+
+    #[test]
+    fn test_edit_aliased_subtrees() {
+        fn build(entries: &[([u8; 1], u8)]) -> (MemTries, CryptoHash) {
+            let mut tries = MemTries::new(ShardUId::single_shard());
+            let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+            for (key, value) in entries {
+                update.insert_memtrie_only(key, FlatStateValue::on_disk(&[*value])).unwrap();
+            }
+            let root =
+                tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+            (tries, root)
+        }
+        // The subtrees under nibbles 1 and 2 are identical, so the rebuilt
+        // trie references a single node from both positions.
+        let (tries, root) = build(&[([0x10], 0), ([0x11], 1), ([0x20], 0), ([0x21], 1)]);
+        let mut nodes = Vec::new();
+        tries.serialize_all_nodes(&root, |_, node| nodes.push(node.to_vec()), None).unwrap();
+        let mut arena = STArena::new("test".to_string());
+        let mut reconstructor = MemTrieReconstructor::new(&mut arena, root);
+        for node in &nodes {
+            reconstructor.add_node(node).unwrap();
+        }
+        let dag_root = reconstructor.finish().unwrap();
+        let mut dag =
+            MemTries::new_from_arena_and_root(ShardUId::single_shard(), 0, arena, dag_root);
+        let root_view = dag.get_root(&root).unwrap().view();
+        let children: Vec<_> = root_view.iter_children().collect();
+        assert_eq!(children.len(), 2);
+        assert_eq!(children[0].id(), children[1].id());
+        let shared_hash = children[0].view().node_hash();
+
+        // Editing through one position leaves the other one intact.
+        let mut update = dag.update(root, TrackingMode::Refcounts).unwrap();
+        update.insert(&[0x12], vec![2]).unwrap();
+        let changes = update.to_trie_changes().unwrap();
+        let (_, expected_root) =
+            build(&[([0x10], 0), ([0x11], 1), ([0x12], 2), ([0x20], 0), ([0x21], 1)]);
+        assert_eq!(changes.new_root, expected_root);
+        let deletion = changes
+            .deletions
+            .iter()
+            .find(|deletion| deletion.trie_node_or_value_hash == shared_hash)
+            .unwrap();
+        assert_eq!(deletion.rc.get(), 1);
+
+        // Editing through both positions releases both references.
+        let mut update = dag.update(root, TrackingMode::Refcounts).unwrap();
+        update.insert(&[0x12], vec![2]).unwrap();
+        update.delete(&[0x21]).unwrap();
+        let changes = update.to_trie_changes().unwrap();
+        let (_, expected_root) = build(&[([0x10], 0), ([0x11], 1), ([0x12], 2), ([0x20], 0)]);
+        assert_eq!(changes.new_root, expected_root);
+        let deletion = changes
+            .deletions
+            .iter()
+            .find(|deletion| deletion.trie_node_or_value_hash == shared_hash)
+            .unwrap();
+        assert_eq!(deletion.rc.get(), 2);
+        let new_root =
+            dag.apply_memtrie_changes(1, changes.memtrie_changes.as_ref().unwrap()).unwrap();
+        assert_eq!(new_root, expected_root);
+        assert!(dag.lookup(&new_root, &[0x22], None).unwrap().is_none());
+        assert!(dag.lookup(&new_root, &[0x21], None).unwrap().is_none());
+        assert!(dag.lookup(&new_root, &[0x11], None).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_value_transitions() {
+        let mut tries = TestTries::new(true);
         tries.check_consistency_across_all_changes_and_apply(parse_changes(
             "
-                00 = delete  # turns a branch with value into an extension
-                01 = 0027  # modifies the value at a branch
-                0100 = delete  # turns a branch with value into a leaf
-                03 = delete  # deletes a branch
-                0444 = 0020  # overwrites a leaf
-                0455 = 0022  # split leaf into branch at start
-                0456 = 0023  # split (pending) leaf into branch
-                05 = 0021  # turn branch into branch with value
-                05110000 = 0024  # split extension node into branch at start
-                05201000 = 0025  # split extension node into branch in the middle
-                05300010 = 0026  # split extension node into branch at the end
-                05400000 = delete  # turn 2-branch node into leaf that squashes with extension
-                05500000 = delete  # turn 2-branch node into extension that squashes with another extension
+                00 = 0000
+                01 = 0001
+                02 = 0002
             ",
         ));
+        let mut update = tries
+            .mem
+            .update_with_builder(
+                tries.state_root,
+                MemTrieUpdateBuilder::new("test".to_string()).with_recorded_ops(true),
+            )
+            .unwrap();
+        assert!(update.value_transitions().is_empty());
+        update.insert(&[0x03], vec![0x03]).unwrap();
+        update.insert(&[0x00], vec![0x04]).unwrap();
+        update.delete(&[0x01]).unwrap();
+        // Writing the original value is not a transition.
+        update.insert(&[0x02], vec![0x00, 0x02]).unwrap();
+        assert_eq!(
+            update.value_transitions(),
+            vec![
+                (vec![0x00], Some(hash(&[0x00, 0x00])), Some(hash(&[0x04]))),
+                (vec![0x01], Some(hash(&[0x00, 0x01])), None),
+                (vec![0x03], None, Some(hash(&[0x03]))),
+            ]
+        );
+    }
 
-        // sanity check here the truth is correct - i.e. our test itself is good.
-        let expected_truth = parse_changes(
+    #[test]
+    fn test_to_flat_state_changes() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
             "
-                00 = delete
-                0000 = 0010
-                01 = 0027
-                0100 = delete
+                00 = 0000
+                01 = 0001
                 02 = 0002
-                03 = delete
-                0444 = 0020
-                0455 = 0022
-                0456 = 0023
-                05 = 0021
-                0500 = 0014
-                05100000 = 0015
-                05100001 = 0016
-                05110000 = 0024
-                05200000 = 0017
-                05200001 = 0018
-                05201000 = 0025
-                05300000 = 0019
-                05300001 = 001a
-                05300010 = 0026
-                05400000 = delete
-                05400001 = 001c
-                05500000 = delete
-                05501000 = 001e
-                05501001 = 001f
             ",
-        )
-        .into_iter()
-        .map(|(k, v)| (k, v.map(|v| ValueRef::new(&v))))
-        .collect::<HashMap<_, _>>();
+        ));
+        let mut update = tries
+            .mem
+            .update_with_builder(
+                tries.state_root,
+                MemTrieUpdateBuilder::new("test".to_string()).with_recorded_ops(true),
+            )
+            .unwrap();
+        update.insert(&[0x03], vec![0x03]).unwrap();
+        update.insert(&[0x00], vec![0x04]).unwrap();
+        update.delete(&[0x01]).unwrap();
+        // None of the following change the net key-value state.
+        update.insert(&[0x04], vec![0x05]).unwrap();
+        update.delete(&[0x04]).unwrap();
+        update.delete(&[0x05]).unwrap();
+        update.insert_memtrie_only(&[0x02], FlatStateValue::on_disk(&[0x00, 0x02])).unwrap();
         assert_eq!(
-            tries.truth,
-            expected_truth,
-            "Differing keys: {:?}",
-            expected_truth
-                .keys()
-                .cloned()
-                .chain(tries.truth.keys().cloned())
-                .collect::<HashSet<_>>()
-                .into_iter()
-                .filter(|k| { expected_truth.get(k) != tries.truth.get(k) })
-                .collect::<Vec<_>>()
+            update.to_flat_state_changes(),
+            vec![
+                (vec![0x00], Some(FlatStateValue::on_disk(&[0x04]))),
+                (vec![0x01], None),
+                (vec![0x03], Some(FlatStateValue::on_disk(&[0x03]))),
+            ]
+        );
+
+        // Applying the flat changes to the original entries gives the entries
+        // of the updated trie.
+        let mut expected_entries: BTreeMap<Vec<u8>, FlatStateValue> = BTreeMap::new();
+        let original = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        expected_entries.extend(original.iter_prefix(&[]));
+        for (key, value) in update.to_flat_state_changes() {
+            match value {
+                Some(value) => expected_entries.insert(key, value),
+                None => expected_entries.remove(&key),
+            };
+        }
+        assert_eq!(update.iter_prefix(&[]).collect::<BTreeMap<_, _>>(), expected_entries);
+
+        // After clearing, all original keys are deleted unless inserted again.
+        let mut update = tries
+            .mem
+            .update_with_builder(
+                tries.state_root,
+                MemTrieUpdateBuilder::new("test".to_string()).with_recorded_ops(true),
+            )
+            .unwrap();
+        update.insert(&[0x03], vec![0x03]).unwrap();
+        update.clear().unwrap();
+        update.insert(&[0x00], vec![0x00, 0x00]).unwrap();
+        update.insert(&[0x01], vec![0x06]).unwrap();
+        assert_eq!(
+            update.to_flat_state_changes(),
+            vec![(vec![0x01], Some(FlatStateValue::on_disk(&[0x06]))), (vec![0x02], None)]
+        );
+
+        // Operations which are combinations of others are reflected too, and
+        // replaying them gives the same entries.
+        let mut update = tries
+            .mem
+            .update_with_builder(
+                tries.state_root,
+                MemTrieUpdateBuilder::new("test".to_string())
+                    .with_tracking(TrackingMode::Refcounts)
+                    .with_recorded_ops(true),
+            )
+            .unwrap();
+        update.rekey(std::iter::once((vec![0x00], vec![0x05]))).unwrap();
+        update.touch_values(&[vec![0x01]]).unwrap();
+        update.update_values([(vec![0x01], vec![0x07])]).unwrap();
+        update
+            .insert_shared_value(&[0x06], ValueRef::new(&[0x08]), ShardUId::single_shard())
+            .unwrap();
+        let node_id = update.find_updated_node_for_key(&[0x06]);
+        update.set_value_at(node_id, Some(GenericTrieValue::MemtrieAndDisk(vec![0x09]))).unwrap();
+        assert_eq!(
+            update.to_flat_state_changes(),
+            vec![
+                (vec![0x00], None),
+                (vec![0x01], Some(FlatStateValue::on_disk(&[0x07]))),
+                (vec![0x05], Some(FlatStateValue::on_disk(&[0x00, 0x00]))),
+                (vec![0x06], Some(FlatStateValue::on_disk(&[0x09]))),
+            ]
+        );
+        let replayed = update.replay(update.recorded_ops().unwrap(), update.root).unwrap();
+        assert_eq!(
+            replayed.iter_prefix(&[]).collect::<Vec<_>>(),
+            update.iter_prefix(&[]).collect::<Vec<_>>()
         );
+    }
+
+    /// Applies A then B and B then A to the base root, asserting that the
+    /// resulting roots are equal if the two sets of keys are disjoint, which
+    /// is what makes updates of disjoint keys order-independent. If the sets
+    /// overlap, the roots may differ. Returns both roots.
+    fn assert_commutative(
+        tries: &mut MemTries,
+        update_ops_a: &[MemTrieUpdateOp],
+        update_ops_b: &[MemTrieUpdateOp],
+        base_root: StateRoot,
+    ) -> (StateRoot, StateRoot) {
+        let mut apply = |first: &[MemTrieUpdateOp], second: &[MemTrieUpdateOp]| {
+            let changes = {
+                let update = tries.update_memtrie_only(base_root).unwrap();
+                let ops = [first, second].concat();
+                update.replay(&ops, update.root).unwrap().to_memtrie_changes_only().unwrap()
+            };
+            tries.apply_memtrie_changes(1, &changes).unwrap()
+        };
+        let root_ab = apply(update_ops_a, update_ops_b);
+        let root_ba = apply(update_ops_b, update_ops_a);
+        // Operations other than single key writes, e.g. clearing and
+        // deleting ranges, are never considered disjoint from anything.
+        let keys = |ops: &[MemTrieUpdateOp]| -> Option<HashSet<Vec<u8>>> {
+            ops.iter()
+                .map(|op| match op {
+                    MemTrieUpdateOp::Insert { key, .. }
+                    | MemTrieUpdateOp::InsertMemtrieOnly { key, .. }
+                    | MemTrieUpdateOp::Delete { key } => Some(key.clone()),
+                    MemTrieUpdateOp::DeleteRange { .. }
+                    | MemTrieUpdateOp::Clear
+                    | MemTrieUpdateOp::Rekey { .. }
+                    | MemTrieUpdateOp::TouchValues { .. } => None,
+                })
+                .collect()
+        };
+        if let (Some(keys_a), Some(keys_b)) = (keys(update_ops_a), keys(update_ops_b)) {
+            if keys_a.is_disjoint(&keys_b) {
+                assert_eq!(root_ab, root_ba, "updates of disjoint keys don't commute");
+            }
+        }
+        (root_ab, root_ba)
+    }
 
-        // Delete some non-existent keys.
+    #[test]
+    fn test_disjoint_updates_commute() {
+        let mut tries = TestTries::new(true);
         tries.check_consistency_across_all_changes_and_apply(parse_changes(
             "
-                00 = delete  # non-existent branch
-                04 = delete  # branch without value
-                0445 = delete  # non-matching leaf
-                055011 = delete  # non-matching extension
+                00 = 0000
+                0100 = 0001
+                0101 = 0002
+                02 = 0003
             ",
         ));
+        let insert = |key: &[u8], value: &[u8]| MemTrieUpdateOp::Insert {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        let delete = |key: &[u8]| MemTrieUpdateOp::Delete { key: key.to_vec() };
+        let base_root = tries.state_root;
 
-        // Make no changes
-        tries.check_consistency_across_all_changes_and_apply(Vec::new());
+        let disjoint_pairs = [
+            // Different subtrees.
+            (vec![insert(&[0x03], &[0x04])], vec![insert(&[0x04], &[0x05])]),
+            // Siblings under the same branch, with squashing after deletion.
+            (vec![delete(&[0x01, 0x00])], vec![insert(&[0x01, 0x02], &[0x06])]),
+            (vec![delete(&[0x01, 0x00])], vec![delete(&[0x01, 0x01])]),
+            // A key and its prefix.
+            (vec![insert(&[0x01], &[0x07])], vec![delete(&[0x01, 0x01]), delete(&[0x00])]),
+            (
+                vec![MemTrieUpdateOp::InsertMemtrieOnly {
+                    key: vec![0x02, 0x00],
+                    value: FlatStateValue::on_disk(&[0x08]),
+                }],
+                vec![delete(&[0x02]), insert(&[0x05], &[0x09])],
+            ),
+        ];
+        for (ops_a, ops_b) in disjoint_pairs {
+            assert_commutative(&mut tries.mem, &ops_a, &ops_b, base_root);
+        }
 
-        // Finally delete all keys.
+        // Writing different values to the same key doesn't commute.
+        let (root_ab, root_ba) = assert_commutative(
+            &mut tries.mem,
+            &[insert(&[0x00], &[0x0a])],
+            &[insert(&[0x00], &[0x0b])],
+            base_root,
+        );
+        assert_ne!(root_ab, root_ba);
+        let (root_ab, root_ba) = assert_commutative(
+            &mut tries.mem,
+            &[insert(&[0x03], &[0x0c])],
+            &[delete(&[0x03])],
+            base_root,
+        );
+        assert_ne!(root_ab, root_ba);
+        // Overlapping updates may still happen to commute.
+        let (root_ab, root_ba) = assert_commutative(
+            &mut tries.mem,
+            &[delete(&[0x02])],
+            &[delete(&[0x02]), insert(&[0x03], &[0x0d])],
+            base_root,
+        );
+        assert_eq!(root_ab, root_ba);
+        let (root_ab, root_ba) =
+            assert_commutative(&mut tries.mem, &[MemTrieUpdateOp::Clear], &[], base_root);
+        assert_eq!(root_ab, root_ba);
+    }
+
+    #[test]
+    fn test_insert_alias() {
+        let mut tries = TestTries::new(true);
+        let value = vec![0x01, 0x02];
+        tries.check_consistency_across_all_changes_and_apply(vec![(
+            vec![0x00],
+            Some(value.clone()),
+        )]);
+        let value_hash = hash(&value);
+
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        assert!(!update.insert_alias(&[0x01], &[0x02]).unwrap());
+        assert!(update.insert_alias(&[0x01], &[0x00]).unwrap());
+        assert_eq!(update.get_ref(&[0x00]).unwrap().hash, value_hash);
+        assert_eq!(update.get_ref(&[0x01]).unwrap().hash, value_hash);
+        // The value gets a second reference on top of the existing one.
+        let changes = update.to_trie_changes().unwrap();
+        let insertion =
+            changes.insertions.iter().find(|insertion| insertion.hash() == &value_hash).unwrap();
+        assert_eq!(insertion.rc.get(), 1);
+        assert_eq!(insertion.payload(), value.as_slice());
+        assert!(changes
+            .deletions
+            .iter()
+            .all(|deletion| deletion.trie_node_or_value_hash != value_hash));
+
+        // Once both keys reference the value, deleting them removes two
+        // references.
+        tries.check_consistency_across_all_changes_and_apply(vec![(
+            vec![0x01],
+            Some(value.clone()),
+        )]);
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        update.delete(&[0x00]).unwrap();
+        update.delete(&[0x01]).unwrap();
+        let changes = update.to_trie_changes().unwrap();
+        let deletion = changes
+            .deletions
+            .iter()
+            .find(|deletion| deletion.trie_node_or_value_hash == value_hash)
+            .unwrap();
+        assert_eq!(deletion.rc.get(), 2);
+
+        // Values stored as references can only be aliased in memtrie.
+        let big_value_ref = ValueRef::new(&[0x03; 5000]);
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        update.insert_memtrie_only(&[0x02], FlatStateValue::Ref(big_value_ref)).unwrap();
+        assert!(update.insert_alias(&[0x03], &[0x02]).unwrap());
+        assert_eq!(update.get_value(&[0x03]).unwrap(), Some(FlatStateValue::Ref(big_value_ref)));
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        update.insert(&[0x02], vec![0x03; 5000]).unwrap();
+        assert_matches!(
+            update.insert_alias(&[0x03], &[0x02]),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+    }
+
+    #[test]
+    fn test_to_trie_changes_matches_collected_nodes() {
+        let mut tries = TestTries::new(true);
         tries.check_consistency_across_all_changes_and_apply(parse_changes(
             "
-                0000 = delete
-                01 = delete
-                02 = delete
-                03 = delete
-                0444 = delete
-                0455 = delete
-                0456 = delete
-                05 = delete
-                0500 = delete
-                05100000 = delete
-                05100001 = delete
-                05110000 = delete
-                05200000 = delete
-                05200001 = delete
-                05201000 = delete
-                05300000 = delete
-                05300001 = delete
-                05300010 = delete
-                05400001 = delete
-                05501000 = delete
-                05501001 = delete
+                00 = 0000
+                01 = 0001
             ",
         ));
+        let make_update = || {
+            let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+            for i in 0..100u8 {
+                update.insert(&[i, i], vec![i]).unwrap();
+            }
+            update.delete(&[0x01]).unwrap();
+            update
+        };
 
-        // Check a corner case that deleting a non-existent key from
-        // an empty trie does not panic.
+        // The previous approach, collecting all new nodes before adding
+        // them to the refcount changes.
+        let mut update = make_update();
+        let old_root = update.root.unwrap().as_ptr(update.memory).view().node_hash();
+        let mut refcount_changes = update.nodes_tracker.take().unwrap().finalize();
+        let mut new_nodes = Vec::new();
+        let memtrie_changes = update
+            .to_memtrie_changes_internal::<Sha256Hasher>(|hash, node| {
+                new_nodes.push((hash, node));
+            })
+            .unwrap();
+        for (node_hash, node_serialized) in new_nodes {
+            refcount_changes.add(node_hash, node_serialized, 1);
+        }
+        let (insertions, deletions) = refcount_changes.into_changes();
+        let expected_changes = TrieChanges {
+            old_root,
+            new_root: memtrie_changes.node_ids_with_hashes.last().unwrap().1,
+            insertions,
+            deletions,
+            memtrie_changes: Some(memtrie_changes),
+            children_memtrie_changes: Default::default(),
+        };
+        assert_eq!(make_update().to_trie_changes().unwrap(), expected_changes);
+    }
+
+    #[test]
+    fn test_update_values() {
+        let mut tries = TestTries::new(true);
         tries.check_consistency_across_all_changes_and_apply(parse_changes(
             "
-                08 = delete  # non-existent key when whole trie is empty
+                00 = 0000
+                01 = 0001
+                02 = 0002
+                0200 = 0003
+                0201 = 0004
             ",
         ));
+        let updates = vec![
+            (vec![0x02, 0x00], vec![0x05; 100]),
+            (vec![0x02], vec![0x06]),
+            (vec![0x00], vec![0x07; 5000]),
+        ];
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        update.update_values(updates.clone()).unwrap();
+        let changes = update.to_trie_changes().unwrap();
 
-        assert_eq!(tries.state_root, StateRoot::default());
-        // Garbage collect all roots we've added. This checks that the refcounts
-        // maintained by the in-memory tries are correct, because if any
-        // refcounts are too low this would panic, and if any refcounts are too
-        // high the number of allocs in the end would be non-zero.
-        tries.mem.delete_until_height(1);
-        assert_eq!(tries.mem.num_roots(), 0);
-        assert_eq!(tries.mem.arena().num_active_allocs(), 0);
+        // Same changes as when inserting the values.
+        let mut expected_update =
+            tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        for (key, value) in updates.clone() {
+            expected_update.insert(&key, value).unwrap();
+        }
+        assert_eq!(changes, expected_update.to_trie_changes().unwrap());
+
+        // Only the nodes on the paths to the keys are new: the root
+        // extension, the branch below it, the leaf of 00, the branch with
+        // value of 02, the branch below it and the leaf of 0200.
+        let memtrie_changes = changes.memtrie_changes.as_ref().unwrap();
+        assert_eq!(memtrie_changes.node_ids_with_hashes.len(), 6);
+        let old_values: HashSet<_> = [vec![0x00, 0x00], vec![0x00, 0x02], vec![0x00, 0x03]]
+            .iter()
+            .map(|value| hash(value))
+            .collect();
+        let deleted_values: HashSet<_> = changes
+            .deletions
+            .iter()
+            .map(|deletion| deletion.trie_node_or_value_hash)
+            .filter(|hash| old_values.contains(hash))
+            .collect();
+        assert_eq!(deleted_values, old_values);
+        for (_, value) in &updates {
+            assert!(changes.insertions.iter().any(|insertion| insertion.hash() == &hash(value)));
+        }
+        tries.check_consistency_across_all_changes_and_apply(
+            updates.into_iter().map(|(key, value)| (key, Some(value))).collect(),
+        );
+
+        // Absent keys can't be updated.
+        let mut update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        for key in [vec![0x03], vec![0x02, 0x02], vec![0x02, 0x00, 0x00], vec![]] {
+            assert_matches!(
+                update.update_values([(key, vec![0x08])]),
+                Err(StorageError::StorageInconsistentState(_))
+            );
+        }
+        // A batch stops at the first absent key; the keys preceding it are
+        // already updated.
+        assert_matches!(
+            update.update_values([(vec![0x00], vec![0x08]), (vec![0x03], vec![0x08])]),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        assert_eq!(update.get_value(&[0x00]).unwrap(), Some(FlatStateValue::inlined(&[0x08])));
     }
 
-    // As of Oct 2023 this randomized test was seen to cover all branches except
-    // deletion of keys from empty tries and deleting all keys from the trie.
     #[test]
-    fn test_trie_consistency_random() {
-        const MAX_KEYS: usize = 100;
-        const SLOWDOWN: usize = 5;
-        let mut tries = TestTries::new(false);
-        for batch in 0..1000 {
-            println!("Batch {}:", batch);
-            let mut existing_keys = tries.truth.iter().map(|(k, _)| k.clone()).collect::<Vec<_>>();
-            // The more keys we have, the less we insert, the more we delete.
-            let num_insertions =
-                rand::thread_rng().gen_range(0..=(MAX_KEYS - existing_keys.len()) / SLOWDOWN);
-            let num_deletions =
-                rand::thread_rng().gen_range(0..=(existing_keys.len() + SLOWDOWN - 1) / SLOWDOWN);
-            let mut changes = Vec::new();
-            for _ in 0..num_insertions {
-                let key_length = rand::thread_rng().gen_range(0..=10);
-                let existing_key = existing_keys
-                    .get(rand::thread_rng().gen_range(0..existing_keys.len().max(1)))
-                    .cloned()
-                    .unwrap_or_default();
-                let reuse_prefix_length = rand::thread_rng().gen_range(0..=existing_key.len());
-                let mut key = Vec::<u8>::new();
-                for i in 0..key_length {
-                    if i < reuse_prefix_length {
-                        key.push(existing_key[i]);
-                    } else {
-                        // Limit nibbles to 4, so that we can generate keys that relate to
-                        // each other more frequently.
-                        let nibble0 = rand::thread_rng().gen::<u8>() % 4;
-                        let nibble1 = rand::thread_rng().gen::<u8>() % 4;
-                        key.push(nibble0 << 4 | nibble1);
-                    }
-                }
-
-                let mut value_length = rand::thread_rng().gen_range(0..=10);
-                if value_length == 10 {
-                    value_length = 8000; // make a long value that is not inlined
-                }
-                let mut value = Vec::<u8>::new();
-                for _ in 0..value_length {
-                    value.push(rand::thread_rng().gen());
-                }
-                println!(
-                    "  {} = {}",
-                    hex::encode(&key),
-                    if value.len() > 10 {
-                        hex::encode(&value[0..10]) + "..."
-                    } else {
-                        hex::encode(&value)
-                    }
-                );
-                changes.push((key.clone(), Some(value.clone())));
-                // Add it to existing keys so that we can insert more keys similar
-                // to this as well as delete some of these keys too.
-                existing_keys.push(key);
+    fn test_update_values_resumes_descent() {
+        let mut tries = TestTries::new(true);
+        let keys = vec![
+            vec![0x00],
+            vec![0x00, 0x01],
+            vec![0x00, 0x01, 0x02],
+            vec![0x00, 0x10],
+            vec![0x01, 0x00],
+            vec![0x01, 0x00, 0x00, 0x00],
+            vec![0x01, 0x01],
+            vec![0x20],
+        ];
+        tries.check_consistency_across_all_changes_and_apply(
+            keys.iter().map(|key| (key.clone(), Some(vec![0x01]))).collect(),
+        );
+        // Sorted keys, a repeated key and keys going back up the trie, with
+        // and without compactions in between.
+        let mut batch = keys.clone();
+        batch.push(vec![0x01, 0x00]);
+        batch.push(vec![0x00, 0x01, 0x02]);
+        batch.push(vec![0x00, 0x01, 0x02]);
+        let updates: Vec<_> = batch
+            .into_iter()
+            .enumerate()
+            .map(|(i, key)| (key, vec![i as u8 + 2; 1 + 30 * i]))
+            .collect();
+        for compaction_interval in [None, Some(3), Some(4)] {
+            let mut builder = MemTrieUpdateBuilder::new("test".to_string())
+                .with_tracking(TrackingMode::Refcounts);
+            if let Some(interval) = compaction_interval {
+                builder = builder.with_compaction_interval(interval);
             }
-            for _ in 0..num_deletions {
-                let key = existing_keys
-                    .get(rand::thread_rng().gen_range(0..existing_keys.len()))
-                    .cloned()
-                    .unwrap_or_default();
-                println!("  {} = delete", hex::encode(&key));
-                changes.push((key.clone(), None));
+            let mut update = tries.mem.update_with_builder(tries.state_root, builder).unwrap();
+            let mut expected_update =
+                tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+            // Leave garbage behind so that compactions renumber nodes.
+            for update in [&mut update, &mut expected_update] {
+                update.insert(&[0x30], vec![0x01]).unwrap();
+                update.delete(&[0x30]).unwrap();
             }
-            tries.check_consistency_across_all_changes_and_apply(changes);
+            update.update_values(updates.clone()).unwrap();
+            for (key, value) in updates.clone() {
+                expected_update.insert(&key, value).unwrap();
+            }
+            assert_eq!(
+                update.to_trie_changes().unwrap(),
+                expected_update.to_trie_changes().unwrap()
+            );
         }
     }
 
-    fn insert_changes_to_memtrie(
-        memtrie: &mut MemTries,
-        prev_state_root: CryptoHash,
-        block_height: BlockHeight,
-        changes: &str,
-    ) -> CryptoHash {
-        let changes = parse_changes(changes);
-        let mut update = memtrie.update(prev_state_root, TrackingMode::None).unwrap();
-
-        for (key, value) in changes {
-            if let Some(value) = value {
-                update.insert_memtrie_only(&key, FlatStateValue::on_disk(&value)).unwrap();
-            } else {
-                update.generic_delete(0, &key).unwrap();
-            }
-        }
+    #[test]
+    fn test_mem_trie_changes_versioned_bytes() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0100 = 0001
+                0101 = 0002
+            ",
+        ));
+        // The update refers to existing nodes and adds a branch with value.
+        let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+        update.insert_memtrie_only(&[0x01], FlatStateValue::Inlined(vec![0x03])).unwrap();
+        update.insert_memtrie_only(&[0x02], FlatStateValue::on_disk(&[0x04; 5000])).unwrap();
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let bytes = changes.to_versioned_bytes();
+        assert_eq!(bytes[0], MEM_TRIE_CHANGES_VERSION);
+        let decoded = MemTrieChanges::from_versioned_bytes(&bytes).unwrap();
+        assert_eq!(decoded, changes);
+        let root = tries.mem.apply_memtrie_changes(1, &decoded).unwrap();
+        assert_eq!(root, changes.node_ids_with_hashes.last().unwrap().1);
 
-        let changes = update.to_memtrie_changes_only();
-        memtrie.apply_memtrie_changes(block_height, &changes)
+        assert_matches!(
+            MemTrieChanges::from_versioned_bytes(&[]),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        let mut unknown_version = bytes.clone();
+        unknown_version[0] = MEM_TRIE_CHANGES_VERSION + 1;
+        assert_matches!(
+            MemTrieChanges::from_versioned_bytes(&unknown_version),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        assert_matches!(
+            MemTrieChanges::from_versioned_bytes(&bytes[..bytes.len() - 1]),
+            Err(StorageError::StorageInconsistentState(_))
+        );
     }
 
-    #[test]
-    fn test_gc_hybrid_memtrie() {
-        let state_root = StateRoot::default();
-        let mut memtrie = MemTries::new(ShardUId::single_shard());
-        assert!(!memtrie.arena.has_shared_memory());
+    /// Changes with a leaf below a branch without value, in the version 0
+    /// layout.
+    const MEM_TRIE_CHANGES_V0: &[u8] = &[
+        0, // Version.
+        2, 0, 0, 0, // Two node IDs with hashes.
+        0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // (0, [1; 32])
+        1, 0, 0, 0, 0, 0, 0, 0, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+        2, 2, 2, 2, 2, 2, 2, 2, 2, 2, // (1, [2; 32])
+        2, 0, 0, 0, // Two updated nodes.
+        1, 1, 1, 0, 0, 0, 0x20, 1, 1, 0, 0, 0, 0x07, // Some(Leaf([0x20], Inlined([0x07])))
+        10, 0, 0, 0, 0, 0, 0, 0, // Memory usage 10.
+        1, 3, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, // Some(Branch([Some(Updated(0)),
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // None, ...],
+        0, // None))
+        20, 0, 0, 0, 0, 0, 0, 0, // Memory usage 20.
+    ];
 
-        // Insert in some initial data for height 0
-        let changes = "
-            ff00 = 0000
-            ff01 = 0100
-            ff0101 = 0101
-        ";
-        let state_root = insert_changes_to_memtrie(&mut memtrie, state_root, 0, changes);
+    /// The same changes in the version 1 layout.
+    const MEM_TRIE_CHANGES_V1: &[u8] = &[
+        1, // Version.
+        2, 0, 0, 0, // Two node IDs with hashes.
+        0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1,
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // (0, [1; 32])
+        1, 0, 0, 0, 0, 0, 0, 0, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2,
+        2, 2, 2, 2, 2, 2, 2, 2, 2, 2, // (1, [2; 32])
+        2, 0, 0, 0, // Two updated nodes.
+        1, 1, 1, 0, 0, 0, 0x20, 1, 1, 0, 0, 0, 0x07, // Some(Leaf([0x20], Inlined([0x07])))
+        10, 0, 0, 0, 0, 0, 0, 0, // Memory usage 10.
+        1, 3, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, // Some(Branch([Some(Updated(0)),
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // None, ...],
+        0, // None))
+        20, 0, 0, 0, 0, 0, 0, 0, // Memory usage 20.
+        0, // No unchanged root.
+    ];
 
-        // Freeze the current memory in memtrie
-        let frozen_arena = memtrie.arena.freeze();
-        let hybrid_arena =
-            HybridArena::from_frozen("test_hybrid".to_string(), frozen_arena.clone());
-        memtrie.arena = hybrid_arena;
-        assert!(memtrie.arena.has_shared_memory());
+    /// Returns the changes encoded by `MEM_TRIE_CHANGES_V0` and
+    /// `MEM_TRIE_CHANGES_V1`.
+    fn pinned_mem_trie_changes() -> MemTrieChanges {
+        let mut children: Box<[Option<OldOrUpdatedNodeId>; 16]> = Default::default();
+        children[0] = Some(OldOrUpdatedNodeId::Updated(0));
+        MemTrieChanges {
+            node_ids_with_hashes: vec![(0, CryptoHash([1; 32])), (1, CryptoHash([2; 32]))],
+            updated_nodes: vec![
+                Some(UpdatedMemTrieNodeWithSize {
+                    node: UpdatedMemTrieNode::Leaf {
+                        extension: vec![0x20].into(),
+                        value: FlatStateValue::Inlined(vec![0x07]),
+                        #[cfg(feature = "memtrie_shadow_values")]
+                        shadow_value: None,
+                    },
+                    memory_usage: 10,
+                }),
+                Some(UpdatedMemTrieNodeWithSize {
+                    node: UpdatedMemTrieNode::Branch {
+                        children,
+                        value: None,
+                        #[cfg(feature = "memtrie_shadow_values")]
+                        shadow_value: None,
+                    },
+                    memory_usage: 20,
+                }),
+            ],
+            unchanged_root: None,
+        }
+    }
 
-        // Insert in some more data for height 1 in hybrid memtrie
-        // Try to make sure we share some node allocations (ff01 and ff0101) with height 0
-        // Node ff01 effectively has a refcount of 2, one from height 0 and one from height 1
+    #[test]
+    fn test_mem_trie_changes_version_0() {
+        let expected = pinned_mem_trie_changes();
+        let decoded = MemTrieChanges::from_versioned_bytes(MEM_TRIE_CHANGES_V0).unwrap();
+        assert_eq!(decoded, expected);
+        // Re-encoding migrates the changes to the current version.
+        assert_eq!(decoded.to_versioned_bytes(), MEM_TRIE_CHANGES_V1);
+    }
 
-        let changes = "
-            ff0000 = 1000
-            ff0001 = 1001
-        ";
-        insert_changes_to_memtrie(&mut memtrie, state_root, 1, changes);
+    #[test]
+    fn test_mem_trie_changes_version_1() {
+        let expected = pinned_mem_trie_changes();
+        assert_eq!(MEM_TRIE_CHANGES_VERSION, 1);
+        assert_eq!(MemTrieChanges::from_versioned_bytes(MEM_TRIE_CHANGES_V1).unwrap(), expected);
+        // The layout doesn't depend on features: shadow values are not
+        // serialized.
+        #[allow(unused_mut)]
+        let mut changes = expected;
+        #[cfg(feature = "memtrie_shadow_values")]
+        if let Some(UpdatedMemTrieNode::Leaf { shadow_value, .. }) =
+            changes.updated_nodes[0].as_mut().map(|node| &mut node.node)
+        {
+            *shadow_value = Some(FlatStateValue::Inlined(vec![0x08]));
+        }
+        assert_eq!(changes.to_versioned_bytes(), MEM_TRIE_CHANGES_V1);
+    }
 
-        // Now try to garbage collect the height 0 root
-        // Memory consumption should not change as height 0 is frozen
-        let num_active_allocs = memtrie.arena.num_active_allocs();
-        let active_allocs_bytes = memtrie.arena.active_allocs_bytes();
-        memtrie.delete_until_height(1);
-        assert_eq!(memtrie.arena.num_active_allocs(), num_active_allocs);
-        assert_eq!(memtrie.arena.active_allocs_bytes(), active_allocs_bytes);
+    #[test]
+    fn test_zero_crossing_values() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                01 = 0001
+            ",
+        ));
+        let update = tries.mem.update(tries.state_root, TrackingMode::Refcounts).unwrap();
+        assert_eq!(update.zero_crossing_values(), None);
+        let mut update = tries
+            .mem
+            .update_with_builder(
+                tries.state_root,
+                MemTrieUpdateBuilder::new("test".to_string())
+                    .with_tracking(TrackingMode::Refcounts)
+                    .with_zero_crossings(true),
+            )
+            .unwrap();
+        update.delete(&[0x00]).unwrap();
+        update.insert(&[0x00], vec![0x00, 0x00]).unwrap();
+        // The value of 01 is referenced by another key before being removed
+        // from 01, so its refcount doesn't reach zero.
+        update.insert(&[0x02], vec![0x00, 0x01]).unwrap();
+        update.insert(&[0x01], vec![0x00, 0x02]).unwrap();
+        assert_eq!(
+            update.zero_crossing_values().unwrap(),
+            &[ZeroCrossing { key: Some(vec![0x00]), value_hash: hash(&[0x00, 0x00]) }]
+        );
+        // The net changes are unaffected.
+        let changes = update.to_trie_changes().unwrap();
+        let reinserted_hash = hash(&[0x00, 0x00]);
+        assert!(!changes.deletions.iter().any(|d| d.trie_node_or_value_hash == reinserted_hash));
+    }
 
-        // Now try to garbage collect the height 1 root
-        // The final memory allocation should be what we had during the time of freezing
-        memtrie.delete_until_height(2);
-        assert_eq!(memtrie.arena.num_active_allocs(), frozen_arena.num_active_allocs());
-        assert_eq!(memtrie.arena.active_allocs_bytes(), frozen_arena.active_allocs_bytes());
+    #[test]
+    fn test_mem_trie_changes_canonical_eq() {
+        let mut tries = TestTries::new(true);
+        tries.check_consistency_across_all_changes_and_apply(parse_changes(
+            "
+                00 = 0000
+                0100 = 0001
+            ",
+        ));
+        let keys = [vec![0x01], vec![0x01, 0x01], vec![0x20], vec![0x21, 0x00]];
+        let changes_for = |keys: &[Vec<u8>], last_value: u8| {
+            let mut update = tries.mem.update_memtrie_only(tries.state_root).unwrap();
+            for key in keys {
+                update.insert_memtrie_only(key, FlatStateValue::Inlined(key.clone())).unwrap();
+            }
+            update.insert_memtrie_only(&[0x03], FlatStateValue::Inlined(vec![last_value])).unwrap();
+            update.to_memtrie_changes_only().unwrap()
+        };
+        let changes = changes_for(&keys, 0);
+        let reversed_keys: Vec<_> = keys.iter().rev().cloned().collect();
+        let reordered_changes = changes_for(&reversed_keys, 0);
+        // The same nodes are constructed, under different IDs.
+        assert_ne!(changes, reordered_changes);
+        assert!(changes.canonical_eq(&reordered_changes));
+        assert!(reordered_changes.canonical_eq(&changes));
+        assert!(changes.canonical_eq(&changes));
+
+        let other_changes = changes_for(&reversed_keys, 1);
+        assert!(!changes.canonical_eq(&other_changes));
+        assert!(!changes.canonical_eq(&MemTrieChanges::default()));
     }
 }
@@ -1,25 +1,34 @@
-use std::collections::{BTreeMap, HashMap};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use near_primitives::errors::StorageError;
 use near_primitives::hash::CryptoHash;
 use near_primitives::shard_layout::ShardUId;
+use near_primitives::state::{FlatStateValue, ValueRef};
 use near_primitives::types::{BlockHeight, StateRoot};
 
-use crate::trie::mem::arena::ArenaMut;
+use crate::trie::mem::arena::{ArenaMemory, ArenaMut};
 use crate::trie::mem::metrics::MEMTRIE_NUM_ROOTS;
-use crate::trie::MemTrieChanges;
-use crate::Trie;
+use crate::trie::ops::interface::{HasValueLength, UpdatedNodeId};
+use crate::trie::{Children, MemTrieChanges};
+use crate::{NibbleSlice, RawTrieNode, RawTrieNodeWithSize, Trie};
 
 use super::arena::hybrid::{HybridArena, HybridArenaMemory};
 use super::arena::single_thread::STArena;
 use super::arena::Arena;
 use super::arena::FrozenArena;
+use super::bloom::BloomFilter;
+use super::flexible_data::children::ChildrenView;
 use super::flexible_data::value::ValueView;
 use super::iter::STMemTrieIterator;
-use super::lookup::memtrie_lookup;
-use super::memtrie_update::{construct_root_from_changes, MemTrieUpdate, TrackingMode};
-use super::node::{MemTrieNodeId, MemTrieNodePtr};
+use super::lookup::{memtrie_lookup, push_children_with_nibbles, SubtreeHashCache};
+use super::memtrie_update::{
+    construct_root_from_changes, MemOnly, MemTrieUpdate, MemTrieUpdateBuilder, OldOrUpdatedNodeId,
+    Tracking, TrackingMode, TrieAccesses, UpdatedMemTrieNode, UpdatedMemTrieNodeWithSize,
+};
+use super::node::{MemTrieNodeId, MemTrieNodePtr, MemTrieNodeView, NodeKind};
 
 /// `MemTries` (logically) owns the memory of multiple tries.
 /// Tries may share nodes with each other via refcounting. The way the
@@ -39,10 +48,107 @@ pub struct MemTries {
     /// number of times the state root appears in this map is equal to the
     /// sum of the refcounts of each `MemTrieNodeId`s in `roots[state hash]`.
     heights: BTreeMap<BlockHeight, Vec<StateRoot>>,
+    /// Pin handles of roots which must not be garbage collected. A root is
+    /// pinned while any `RootPin` cloned from its handle is alive.
+    pins: HashMap<StateRoot, Arc<()>>,
     /// Shard UID, for exporting metrics only.
     shard_uid: ShardUId,
 }
 
+/// Keeps a root of `MemTries` alive regardless of its height until dropped.
+/// Obtained via `MemTries::pin_root`.
+#[derive(Debug)]
+pub struct RootPin {
+    root: StateRoot,
+    _handle: Arc<()>,
+}
+
+impl RootPin {
+    pub fn root(&self) -> &StateRoot {
+        &self.root
+    }
+}
+
+/// Statistics of the values stored under a trie root.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ValueStats {
+    /// Number of value references, i.e. the number of keys.
+    pub references: u64,
+    /// Number of distinct values, by value hash.
+    pub distinct: u64,
+    /// Total length of the values over all references.
+    pub total_value_bytes: u64,
+}
+
+/// First mismatch found by `MemTries::verify_entries`.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum VerifyError {
+    #[error("key {} is missing from the trie", hex::encode(.0))]
+    MissingKey(Vec<u8>),
+    #[error("key {} is in the trie but not in the entries", hex::encode(.0))]
+    ExtraValue(Vec<u8>),
+    #[error("key {} has value hash {actual}, expected {expected}", hex::encode(.key))]
+    WrongValueHash { key: Vec<u8>, expected: CryptoHash, actual: CryptoHash },
+    #[error("trie walk was cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+impl From<WalkError> for VerifyError {
+    fn from(err: WalkError) -> Self {
+        match err {
+            WalkError::Cancelled => Self::Cancelled,
+            WalkError::Storage(err) => Self::Storage(err),
+        }
+    }
+}
+
+/// Error of a walk over the whole trie which can be cancelled.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum WalkError {
+    #[error("trie walk was cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+/// Node which is not in canonical trie form, found by
+/// `MemTries::verify_canonical`. Paths are the nibbles leading to the node.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum CanonicalViolation {
+    #[error("extension without nibbles at {path:?}")]
+    EmptyExtension { path: Vec<u8> },
+    #[error("extension over another extension at {path:?}")]
+    ExtensionOverExtension { path: Vec<u8> },
+    #[error("extension over a leaf at {path:?}")]
+    ExtensionOverLeaf { path: Vec<u8> },
+    #[error("branch with fewer than two children and values at {path:?}")]
+    DegenerateBranch { path: Vec<u8> },
+    #[error("trie walk was cancelled")]
+    Cancelled,
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+}
+
+impl From<WalkError> for CanonicalViolation {
+    fn from(err: WalkError) -> Self {
+        match err {
+            WalkError::Cancelled => Self::Cancelled,
+            WalkError::Storage(err) => Self::Storage(err),
+        }
+    }
+}
+
+/// Fails with `WalkError::Cancelled` if the walk was asked to stop. Walks
+/// call this at branch nodes only, to keep the overhead low.
+fn check_cancelled(cancel: Option<&AtomicBool>) -> Result<(), WalkError> {
+    match cancel {
+        Some(cancel) if cancel.load(Ordering::Relaxed) => Err(WalkError::Cancelled),
+        _ => Ok(()),
+    }
+}
+
 /// Frozen arena together with supported roots and heights.
 /// Used to construct new memtries which share nodes from the same arena.
 #[derive(Clone)]
@@ -58,6 +164,7 @@ impl MemTries {
             arena: STArena::new(shard_uid.to_string()).into(),
             roots: HashMap::new(),
             heights: Default::default(),
+            pins: HashMap::new(),
             shard_uid,
         }
     }
@@ -70,6 +177,7 @@ impl MemTries {
             arena: HybridArena::from_frozen(shard_uid.to_string(), frozen_memtries.arena),
             roots: frozen_memtries.roots,
             heights: frozen_memtries.heights,
+            pins: HashMap::new(),
             shard_uid,
         }
     }
@@ -84,6 +192,7 @@ impl MemTries {
             arena: arena.into(),
             roots: HashMap::new(),
             heights: Default::default(),
+            pins: HashMap::new(),
             shard_uid,
         };
         tries.insert_root(root.as_ptr(tries.arena.memory()).view().node_hash(), root, block_height);
@@ -92,18 +201,67 @@ impl MemTries {
 
     /// This function should perform the entire construction of the new trie, possibly based on some existing
     /// trie nodes. This internally takes care of refcounting and inserts a new root into the memtrie.
+    /// Fails without modifying the memtrie if the arena capacity is exceeded.
     pub fn apply_memtrie_changes(
         &mut self,
         block_height: BlockHeight,
         changes: &MemTrieChanges,
-    ) -> CryptoHash {
-        if let Some(root) = construct_root_from_changes(&mut self.arena, changes) {
+    ) -> Result<CryptoHash, StorageError> {
+        self.apply_memtrie_changes_with_observer(block_height, changes, |_, _| {})
+    }
+
+    /// Same as `apply_memtrie_changes`, but calls `on_alloc` with the kind
+    /// and the size of every node allocated in the arena, e.g. to attribute
+    /// arena growth to specific updates when profiling memory.
+    pub fn apply_memtrie_changes_with_observer(
+        &mut self,
+        block_height: BlockHeight,
+        changes: &MemTrieChanges,
+        on_alloc: impl FnMut(NodeKind, usize),
+    ) -> Result<CryptoHash, StorageError> {
+        if let Some(root) = construct_root_from_changes(&mut self.arena, changes, None, on_alloc)? {
             let state_root = root.as_ptr(self.arena.memory()).view().node_hash();
             self.insert_root(state_root, root, block_height);
-            state_root
+            Ok(state_root)
         } else {
-            CryptoHash::default()
+            Ok(CryptoHash::default())
+        }
+    }
+
+    /// Applies a sequence of memtrie changes in order, each at its own block
+    /// height, and returns the new state roots.
+    ///
+    /// Equivalent to calling `apply_memtrie_changes` for each item, but nodes
+    /// constructed by earlier changes in the batch are reused by later ones
+    /// if they have the same hash, instead of allocating duplicates. This is
+    /// common when consecutive blocks touch overlapping subtrees.
+    ///
+    /// On failure, changes preceding the failed one stay applied.
+    pub fn apply_memtrie_changes_batch(
+        &mut self,
+        items: &[(BlockHeight, MemTrieChanges)],
+    ) -> Result<Vec<CryptoHash>, StorageError> {
+        // All cached nodes are reachable from roots inserted during the
+        // batch, so they stay alive until the end of it.
+        let mut node_cache = HashMap::new();
+        let mut state_roots = Vec::with_capacity(items.len());
+        for (block_height, changes) in items {
+            let root = construct_root_from_changes(
+                &mut self.arena,
+                changes,
+                Some(&mut node_cache),
+                |_, _| {},
+            )?;
+            let state_root = if let Some(root) = root {
+                let state_root = root.as_ptr(self.arena.memory()).view().node_hash();
+                self.insert_root(state_root, root, *block_height);
+                state_root
+            } else {
+                CryptoHash::default()
+            };
+            state_roots.push(state_root);
         }
+        Ok(state_roots)
     }
 
     fn insert_root(
@@ -142,14 +300,23 @@ impl MemTries {
     /// `block_height`. This internally manages refcounts. If a trie root
     /// is expired but is still used at a higher height, it will still be
     /// valid until all references to that root expires.
+    ///
+    /// Pinned roots are kept at their heights, so they are deleted by the
+    /// first call made after their pins are dropped.
     pub fn delete_until_height(&mut self, block_height: BlockHeight) {
+        self.pins.retain(|_, handle| Arc::strong_count(handle) > 1);
         let mut to_delete = vec![];
         self.heights.retain(|height, state_roots| {
             if *height < block_height {
-                for state_root in state_roots {
-                    to_delete.push(*state_root)
-                }
-                false
+                state_roots.retain(|state_root| {
+                    if self.pins.contains_key(state_root) {
+                        true
+                    } else {
+                        to_delete.push(*state_root);
+                        false
+                    }
+                });
+                !state_roots.is_empty()
             } else {
                 true
             }
@@ -159,6 +326,31 @@ impl MemTries {
         }
     }
 
+    /// Deletes all roots except the `n` most recent ones, like
+    /// `delete_until_height` with the height of the oldest root to keep. So
+    /// roots at the same height as that one, as well as pinned roots, are
+    /// kept too. As with `delete_until_height`, the memory of nodes in the
+    /// frozen part of the arena is not reclaimed.
+    pub fn retain_latest(&mut self, n: usize) {
+        let mut num_roots = 0;
+        let mut oldest_height_to_keep = BlockHeight::MAX;
+        for (height, state_roots) in self.heights.iter().rev() {
+            if num_roots >= n {
+                break;
+            }
+            num_roots += state_roots.len();
+            oldest_height_to_keep = *height;
+        }
+        self.delete_until_height(oldest_height_to_keep);
+    }
+
+    /// Prevents `delete_until_height` from deleting the given root until the
+    /// returned pin, and all other pins of the same root, are dropped.
+    pub fn pin_root(&mut self, root: &CryptoHash) -> RootPin {
+        let handle = self.pins.entry(*root).or_default();
+        RootPin { root: *root, _handle: handle.clone() }
+    }
+
     fn delete_root(&mut self, state_root: &CryptoHash) {
         if let Some(ids) = self.roots.get_mut(state_root) {
             let last_id = ids.last().unwrap();
@@ -177,14 +369,47 @@ impl MemTries {
             .set(self.roots.len() as i64);
     }
 
+    /// Starts an update which tracks on-disk changes as well, so that it can
+    /// be converted to `TrieChanges`.
     pub fn update<'a>(
         &'a self,
         root: CryptoHash,
         mode: TrackingMode<'a>,
-    ) -> Result<MemTrieUpdate<'a, HybridArenaMemory>, StorageError> {
-        let root_id =
-            if root == CryptoHash::default() { None } else { Some(self.get_root(&root)?.id()) };
-        Ok(MemTrieUpdate::new(root_id, &self.arena.memory(), self.shard_uid.to_string(), mode))
+    ) -> Result<MemTrieUpdate<'a, HybridArenaMemory, Tracking>, StorageError> {
+        let root_id = self.get_update_root_id(&root)?;
+        Ok(MemTrieUpdate::new_tracking(
+            root_id,
+            &self.arena.memory(),
+            self.shard_uid.to_string(),
+            mode,
+        ))
+    }
+
+    /// Starts an update configured by the given builder, e.g. to enable
+    /// debugging options.
+    pub fn update_with_builder<'a, S>(
+        &'a self,
+        root: CryptoHash,
+        builder: MemTrieUpdateBuilder<'a, S>,
+    ) -> Result<MemTrieUpdate<'a, HybridArenaMemory, S>, StorageError> {
+        let root_id = self.get_update_root_id(&root)?;
+        builder.build(self.arena.memory(), root_id)
+    }
+
+    /// Starts an update which only produces changes for the in-memory trie.
+    pub fn update_memtrie_only(
+        &self,
+        root: CryptoHash,
+    ) -> Result<MemTrieUpdate<'_, HybridArenaMemory, MemOnly>, StorageError> {
+        let root_id = self.get_update_root_id(&root)?;
+        Ok(MemTrieUpdate::new(root_id, &self.arena.memory(), self.shard_uid.to_string()))
+    }
+
+    fn get_update_root_id(&self, root: &CryptoHash) -> Result<Option<MemTrieNodeId>, StorageError> {
+        if root == &CryptoHash::default() {
+            return Ok(None);
+        }
+        Ok(Some(self.get_root(root)?.id()))
     }
 
     /// Returns an iterator over the memtrie for the given trie root.
@@ -209,12 +434,675 @@ impl MemTries {
         Ok(memtrie_lookup(root, key, nodes_accessed))
     }
 
+    /// Caches the proof nodes leading to the subtree under `prefix`, so that
+    /// `memtrie_prove` doesn't walk them again for every key under the prefix.
+    /// Node hashes are stored in the arena anyway, so the cache mostly saves
+    /// the descent and the serialization of the nodes along it.
+    pub fn cache_subtree_hashes(
+        &self,
+        root: &CryptoHash,
+        prefix: &[u8],
+    ) -> Result<SubtreeHashCache<'_, HybridArenaMemory>, StorageError> {
+        Ok(SubtreeHashCache::new(self.get_root(root)?, prefix))
+    }
+
+    /// Returns the number of nodes on the path from the root to the node
+    /// holding the value of the key, both included, or None if the key is
+    /// absent.
+    pub fn key_depth(&self, root: &CryptoHash, key: &[u8]) -> Result<Option<usize>, StorageError> {
+        if root == &CryptoHash::default() {
+            return Ok(None);
+        }
+        let mut nodes_accessed = Vec::new();
+        let value = self.lookup(root, key, Some(&mut nodes_accessed))?;
+        Ok(value.map(|_| nodes_accessed.len()))
+    }
+
+    /// Returns the nodes and values accessed by looking up each of the keys,
+    /// deduplicated, i.e. what a state witness must contain for a
+    /// transaction which only reads these keys.
+    pub fn witness_for_reads(
+        &self,
+        root: &CryptoHash,
+        keys: &[Vec<u8>],
+    ) -> Result<TrieAccesses, StorageError> {
+        let mut accesses = TrieAccesses::default();
+        if root == &CryptoHash::default() {
+            return Ok(accesses);
+        }
+        let root = self.get_root(root)?;
+        let mut nodes_accessed = Vec::new();
+        for key in keys {
+            if let Some(value) = memtrie_lookup(root, key, Some(&mut nodes_accessed)) {
+                let value = value.to_flat_value();
+                accesses.values.insert(value.to_value_ref().hash, value);
+            }
+            accesses.nodes.extend(nodes_accessed.drain(..));
+        }
+        Ok(accesses)
+    }
+
+    /// Returns the root hash of the subtree under `prefix` as if it were a
+    /// standalone trie, holding the keys under `prefix` stripped of it, or
+    /// None if there are no such keys. If `prefix` ends inside of a leaf or an
+    /// extension, the rebased root is a shortened copy of that node, which
+    /// has a different hash than any node of the memtrie.
+    pub fn rebased_root(
+        &self,
+        root: &CryptoHash,
+        prefix: &[u8],
+    ) -> Result<Option<CryptoHash>, StorageError> {
+        if root == &CryptoHash::default() {
+            return Ok(None);
+        }
+        let mut node = self.get_root(root)?;
+        let mut nibbles = NibbleSlice::new(prefix);
+        loop {
+            let view = node.view();
+            if nibbles.is_empty() {
+                return Ok(Some(view.node_hash()));
+            }
+            match view {
+                MemTrieNodeView::Leaf { extension, value } => {
+                    let extension = NibbleSlice::from_encoded(extension).0;
+                    if !extension.starts_with(&nibbles) {
+                        return Ok(None);
+                    }
+                    let node = UpdatedMemTrieNode::Leaf {
+                        extension: extension.mid(nibbles.len()).encoded(true).to_vec().into(),
+                        value: value.to_flat_value(),
+                        #[cfg(feature = "memtrie_shadow_values")]
+                        shadow_value: None,
+                    };
+                    return Ok(Some(rebased_node_hash(&node, 0, CryptoHash::default())));
+                }
+                MemTrieNodeView::Extension { extension, child, .. } => {
+                    let extension = NibbleSlice::from_encoded(extension).0;
+                    if nibbles.starts_with(&extension) {
+                        nibbles = nibbles.mid(extension.len());
+                        node = child;
+                    } else if extension.starts_with(&nibbles) {
+                        let child_view = child.view();
+                        let node = UpdatedMemTrieNode::Extension {
+                            extension: extension.mid(nibbles.len()).encoded(false).to_vec().into(),
+                            child: OldOrUpdatedNodeId::Old(child.id()),
+                        };
+                        return Ok(Some(rebased_node_hash(
+                            &node,
+                            child_view.memory_usage(),
+                            child_view.node_hash(),
+                        )));
+                    } else {
+                        return Ok(None);
+                    }
+                }
+                MemTrieNodeView::Branch { children, .. }
+                | MemTrieNodeView::BranchWithValue { children, .. } => {
+                    let Some(child) = children.get(nibbles.at(0) as usize) else {
+                        return Ok(None);
+                    };
+                    nibbles = nibbles.mid(1);
+                    node = child;
+                }
+            }
+        }
+    }
+
     /// Freezes memtrie. The result is used as a shared data to construct new
     /// memtries.
     pub fn freeze(self) -> FrozenMemTries {
         FrozenMemTries { arena: self.arena.freeze(), roots: self.roots, heights: self.heights }
     }
 
+    /// Counts nodes of the trie `root_b` which are also present, by hash, in
+    /// the trie `root_a`. Useful to estimate how much memory will be actually
+    /// reclaimed after deleting one of the roots while the other survives.
+    ///
+    /// Subtrees with equal hashes are identical, so once a shared node is
+    /// found, its subtree is counted without further lookups.
+    ///
+    /// The walk is aborted with `WalkError::Cancelled` once `cancel` is set.
+    pub fn shared_node_count(
+        &self,
+        root_a: &CryptoHash,
+        root_b: &CryptoHash,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<usize, WalkError> {
+        if root_a == &CryptoHash::default() || root_b == &CryptoHash::default() {
+            return Ok(0);
+        }
+        let mut hashes_a = HashSet::new();
+        collect_node_hashes(self.get_root(root_a)?, &mut hashes_a, cancel)?;
+        count_shared_nodes(self.get_root(root_b)?, &hashes_a, cancel)
+    }
+
+    /// Computes statistics of the values stored under the given root, in a
+    /// single walk over the trie.
+    ///
+    /// The walk is aborted with `WalkError::Cancelled` once `cancel` is set.
+    pub fn value_stats(
+        &self,
+        root: &CryptoHash,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<ValueStats, WalkError> {
+        let mut stats = ValueStats::default();
+        if root == &CryptoHash::default() {
+            return Ok(stats);
+        }
+        let mut value_hashes = HashSet::new();
+        collect_value_stats(self.get_root(root)?, &mut stats, &mut value_hashes, cancel)?;
+        stats.distinct = value_hashes.len() as u64;
+        Ok(stats)
+    }
+
+    /// Returns, for each kind of node, the number of nodes of this kind under
+    /// the given root and the total size of their arena allocations. Nodes
+    /// reachable through several paths are counted once, so the sizes add up
+    /// to the arena memory held by the trie.
+    ///
+    /// The walk is aborted with `WalkError::Cancelled` once `cancel` is set.
+    pub fn arena_allocation_histogram(
+        &self,
+        root: &CryptoHash,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<Vec<(NodeKind, usize, usize)>, WalkError> {
+        let mut histogram: Vec<_> =
+            [NodeKind::Leaf, NodeKind::Extension, NodeKind::Branch, NodeKind::BranchWithValue]
+                .into_iter()
+                .map(|kind| (kind, 0, 0))
+                .collect();
+        if root == &CryptoHash::default() {
+            return Ok(histogram);
+        }
+        let mut visited = HashSet::new();
+        let mut stack = vec![self.get_root(root)?];
+        while let Some(node) = stack.pop() {
+            if !visited.insert(node.id()) {
+                continue;
+            }
+            if matches!(node.node_kind(), NodeKind::Branch | NodeKind::BranchWithValue) {
+                check_cancelled(cancel)?;
+            }
+            let (_, count, bytes) =
+                histogram.iter_mut().find(|(kind, _, _)| *kind == node.node_kind()).unwrap();
+            *count += 1;
+            *bytes += node.size_of_allocation();
+            stack.extend(node.view().iter_children());
+        }
+        Ok(histogram)
+    }
+
+    /// Returns up to `limit` entries of the trie with keys not less than
+    /// `start_key`, in key order, together with the key to continue from, or
+    /// None if there are no more entries. This allows iterating over large
+    /// tries in chunks, e.g. for paginated queries. Continuation keys are
+    /// plain keys, so they stay valid as long as the root is alive.
+    pub fn iter_from(
+        &self,
+        root: &CryptoHash,
+        start_key: &[u8],
+        limit: usize,
+    ) -> Result<(Vec<(Vec<u8>, FlatStateValue)>, Option<Vec<u8>>), StorageError> {
+        let mut entries = Vec::new();
+        if root == &CryptoHash::default() {
+            return Ok((entries, None));
+        }
+        let start: Vec<u8> = NibbleSlice::new(start_key).iter().collect();
+        // Nodes to visit along with their key nibbles, the next one on top.
+        let mut stack = vec![(Vec::new(), self.get_root(root)?)];
+        while let Some((nibbles, node)) = stack.pop() {
+            // Skip subtrees with all keys less than the start key.
+            let len = nibbles.len().min(start.len());
+            if nibbles[..len] < start[..len] {
+                continue;
+            }
+            let view = node.view();
+            let entry = match &view {
+                MemTrieNodeView::Leaf { extension, value } => {
+                    let mut key = nibbles.clone();
+                    key.extend(NibbleSlice::from_encoded(extension).0.iter());
+                    Some((key, value))
+                }
+                MemTrieNodeView::BranchWithValue { value, .. } => Some((nibbles.clone(), value)),
+                MemTrieNodeView::Extension { .. } | MemTrieNodeView::Branch { .. } => None,
+            };
+            if let Some((key, value)) = entry {
+                if key >= start {
+                    let key = NibbleSlice::nibbles_to_bytes(&key);
+                    if entries.len() == limit {
+                        return Ok((entries, Some(key)));
+                    }
+                    entries.push((key, value.to_flat_value()));
+                }
+            }
+            match view {
+                MemTrieNodeView::Leaf { .. } => {}
+                MemTrieNodeView::Extension { extension, child, .. } => {
+                    let mut child_nibbles = nibbles;
+                    child_nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+                    stack.push((child_nibbles, child));
+                }
+                MemTrieNodeView::Branch { children, .. }
+                | MemTrieNodeView::BranchWithValue { children, .. } => {
+                    for i in (0..16).rev() {
+                        if let Some(child) = children.get(i) {
+                            let mut child_nibbles = nibbles.clone();
+                            child_nibbles.push(i as u8);
+                            stack.push((child_nibbles, child));
+                        }
+                    }
+                }
+            }
+        }
+        Ok((entries, None))
+    }
+
+    /// Returns an iterator over the keys of the trie under the given root, in
+    /// key order. Only the structure of the trie is walked, so values are
+    /// never materialized, which is cheaper than `iter_from` when only the
+    /// key set is needed, e.g. to build an index.
+    pub fn keys(
+        &self,
+        root: &CryptoHash,
+    ) -> Result<impl Iterator<Item = Vec<u8>> + '_, StorageError> {
+        // Nodes to visit along with their key nibbles, the next one on top.
+        let mut stack = Vec::new();
+        if root != &CryptoHash::default() {
+            stack.push((Vec::new(), self.get_root(root)?));
+        }
+        Ok(std::iter::from_fn(move || {
+            while let Some((mut nibbles, node)) = stack.pop() {
+                match node.view() {
+                    MemTrieNodeView::Leaf { extension, .. } => {
+                        nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+                        return Some(NibbleSlice::nibbles_to_bytes(&nibbles));
+                    }
+                    MemTrieNodeView::Extension { extension, child, .. } => {
+                        nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+                        stack.push((nibbles, child));
+                    }
+                    MemTrieNodeView::Branch { children, .. } => {
+                        push_children_with_nibbles(&mut stack, &nibbles, children);
+                    }
+                    MemTrieNodeView::BranchWithValue { children, .. } => {
+                        push_children_with_nibbles(&mut stack, &nibbles, children);
+                        return Some(NibbleSlice::nibbles_to_bytes(&nibbles));
+                    }
+                }
+            }
+            None
+        }))
+    }
+
+    /// Returns the keys under the given root whose value has the given hash,
+    /// in key order, e.g. to find what references a value before cleaning it
+    /// up. Passing them to `MemTrieUpdate::delete_many` removes all the
+    /// references to the value from the trie.
+    ///
+    /// The walk is aborted with `WalkError::Cancelled` once `cancel` is set.
+    pub fn keys_referencing_value(
+        &self,
+        root: &CryptoHash,
+        value_hash: &CryptoHash,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<Vec<Vec<u8>>, WalkError> {
+        let mut keys = Vec::new();
+        // Nodes to visit along with their key nibbles, the next one on top.
+        let mut stack = Vec::new();
+        if root != &CryptoHash::default() {
+            stack.push((Vec::new(), self.get_root(root)?));
+        }
+        let references_value = |value: &ValueView| match value {
+            ValueView::Ref { hash, .. } => hash == value_hash,
+            ValueView::Inlined(_) => &value.to_flat_value().to_value_ref().hash == value_hash,
+        };
+        while let Some((mut nibbles, node)) = stack.pop() {
+            match node.view() {
+                MemTrieNodeView::Leaf { extension, value } => {
+                    if references_value(&value) {
+                        nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+                        keys.push(NibbleSlice::nibbles_to_bytes(&nibbles));
+                    }
+                }
+                MemTrieNodeView::Extension { extension, child, .. } => {
+                    nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+                    stack.push((nibbles, child));
+                }
+                MemTrieNodeView::Branch { children, .. } => {
+                    check_cancelled(cancel)?;
+                    push_children_with_nibbles(&mut stack, &nibbles, children);
+                }
+                MemTrieNodeView::BranchWithValue { children, value, .. } => {
+                    check_cancelled(cancel)?;
+                    if references_value(&value) {
+                        keys.push(NibbleSlice::nibbles_to_bytes(&nibbles));
+                    }
+                    push_children_with_nibbles(&mut stack, &nibbles, children);
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    /// Computes the changes which construct `to_root` on top of `from_root`:
+    /// nodes of `to_root` with hashes absent from `from_root` become updated
+    /// nodes, while the other ones refer to the nodes of `from_root`. So the
+    /// changes can be applied as long as `from_root` is alive, e.g. after
+    /// `to_root` itself is garbage collected. The root is always included.
+    ///
+    /// The walk is aborted with `WalkError::Cancelled` once `cancel` is set.
+    pub fn changes_between(
+        &self,
+        from_root: &CryptoHash,
+        to_root: &CryptoHash,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<MemTrieChanges, WalkError> {
+        let mut changes = MemTrieChanges::default();
+        if to_root == &CryptoHash::default() {
+            return Ok(changes);
+        }
+        let mut from_nodes = HashMap::new();
+        if from_root != &CryptoHash::default() {
+            collect_node_ids_by_hash(self.get_root(from_root)?, &mut from_nodes, cancel)?;
+        }
+        add_subtree_diff(self.get_root(to_root)?, &from_nodes, &mut changes, cancel)?;
+        Ok(changes)
+    }
+
+    /// Returns the lexicographically first key whose value or presence
+    /// differs between the two roots, or None if the roots hold the same
+    /// entries. Subtrees with equal hashes at the same position are skipped,
+    /// so this is cheap for roots sharing most of their nodes, e.g. to debug
+    /// a state root mismatch.
+    ///
+    /// The walk is aborted with `WalkError::Cancelled` once `cancel` is set.
+    pub fn first_differing_key(
+        &self,
+        local_root: &CryptoHash,
+        expected_root: &CryptoHash,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<Option<Vec<u8>>, WalkError> {
+        let position = |root: &CryptoHash| -> Result<_, StorageError> {
+            if root == &CryptoHash::default() {
+                return Ok(None);
+            }
+            Ok(Some(TriePosition { node: self.get_root(root)?, consumed: 0 }))
+        };
+        let local = position(local_root)?;
+        let expected = position(expected_root)?;
+        first_differing_key_impl(local, expected, &mut Vec::new(), cancel)
+    }
+
+    /// Returns the number of value bytes added minus the number of value
+    /// bytes removed on the way from `from_root` to `to_root`. Subtrees
+    /// present in both roots are skipped by hash, so values they share are
+    /// not counted, while a value is counted once per key holding it.
+    ///
+    /// The walk is aborted with `WalkError::Cancelled` once `cancel` is set.
+    pub fn value_byte_delta(
+        &self,
+        from_root: &CryptoHash,
+        to_root: &CryptoHash,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<i64, WalkError> {
+        let node_hashes = |root: &CryptoHash| -> Result<_, WalkError> {
+            let mut hashes = HashSet::new();
+            if root != &CryptoHash::default() {
+                collect_node_hashes(self.get_root(root)?, &mut hashes, cancel)?;
+            }
+            Ok(hashes)
+        };
+        let from_hashes = node_hashes(from_root)?;
+        let to_hashes = node_hashes(to_root)?;
+        let bytes_outside = |root: &CryptoHash, skipped: &HashSet<CryptoHash>| {
+            if root == &CryptoHash::default() {
+                return Ok(0);
+            }
+            Ok::<_, WalkError>(value_bytes_outside(self.get_root(root)?, skipped, cancel)? as i64)
+        };
+        Ok(bytes_outside(to_root, &from_hashes)? - bytes_outside(from_root, &to_hashes)?)
+    }
+
+    /// Checks in a single pass over the trie that the entries, given by key
+    /// and value hash, are exactly the values under the root in the range
+    /// from the smallest to the largest key of the entries, e.g. to check a
+    /// state part. Keys of the entries must be distinct. Returns the first
+    /// mismatch by key order.
+    ///
+    /// The walk is aborted with `VerifyError::Cancelled` once `cancel` is set.
+    pub fn verify_entries(
+        &self,
+        root: &CryptoHash,
+        entries: &[(Vec<u8>, CryptoHash)],
+        cancel: Option<&AtomicBool>,
+    ) -> Result<(), VerifyError> {
+        let mut entries: Vec<_> = entries.iter().collect();
+        entries.sort_by(|(key, _), (other_key, _)| key.cmp(other_key));
+        let (Some(first), Some(last)) = (entries.first(), entries.last()) else {
+            return Ok(());
+        };
+        let mut values = Vec::new();
+        if root != &CryptoHash::default() {
+            let range = NibbleSlice::new(&first.0).iter().collect::<Vec<_>>()
+                ..=NibbleSlice::new(&last.0).iter().collect::<Vec<_>>();
+            let root = self.get_root(root)?;
+            collect_values_in_range(root, &mut Vec::new(), &range, &mut values, cancel)?;
+        }
+        let mut entries = entries.into_iter().peekable();
+        let mut values = values.into_iter().peekable();
+        loop {
+            match (entries.peek(), values.peek()) {
+                (None, None) => return Ok(()),
+                (Some((key, expected)), Some((value_key, actual))) if key == value_key => {
+                    if expected != actual {
+                        return Err(VerifyError::WrongValueHash {
+                            key: key.clone(),
+                            expected: *expected,
+                            actual: *actual,
+                        });
+                    }
+                    entries.next();
+                    values.next();
+                }
+                (Some((key, _)), Some((value_key, _))) if key > value_key => {
+                    return Err(VerifyError::ExtraValue(value_key.clone()));
+                }
+                (None, Some((value_key, _))) => {
+                    return Err(VerifyError::ExtraValue(value_key.clone()));
+                }
+                (Some((key, _)), _) => return Err(VerifyError::MissingKey(key.clone())),
+            }
+        }
+    }
+
+    /// Checks that the trie under the given root is in canonical form, which
+    /// updates always produce: extensions have nibbles and are followed by
+    /// branches, and branches have at least two children and values in total.
+    /// Returns the first violation in key order, e.g. to detect corruption
+    /// caused by a buggy update or a bad snapshot.
+    ///
+    /// The walk is aborted with `CanonicalViolation::Cancelled` once `cancel` is set.
+    pub fn verify_canonical(
+        &self,
+        root: &CryptoHash,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<(), CanonicalViolation> {
+        if root == &CryptoHash::default() {
+            return Ok(());
+        }
+        let mut stack = vec![(Vec::new(), self.get_root(root)?)];
+        while let Some((nibbles, node)) = stack.pop() {
+            match node.view() {
+                MemTrieNodeView::Leaf { .. } => {}
+                MemTrieNodeView::Extension { extension, child, .. } => {
+                    let extension = NibbleSlice::from_encoded(extension).0;
+                    if extension.is_empty() {
+                        return Err(CanonicalViolation::EmptyExtension { path: nibbles });
+                    }
+                    match child.node_kind() {
+                        NodeKind::Extension => {
+                            return Err(CanonicalViolation::ExtensionOverExtension {
+                                path: nibbles,
+                            });
+                        }
+                        NodeKind::Leaf => {
+                            return Err(CanonicalViolation::ExtensionOverLeaf { path: nibbles });
+                        }
+                        NodeKind::Branch | NodeKind::BranchWithValue => {}
+                    }
+                    let mut child_nibbles = nibbles;
+                    child_nibbles.extend(extension.iter());
+                    stack.push((child_nibbles, child));
+                }
+                MemTrieNodeView::Branch { children, .. } => {
+                    check_cancelled(cancel)?;
+                    if children.iter().count() < 2 {
+                        return Err(CanonicalViolation::DegenerateBranch { path: nibbles });
+                    }
+                    push_children_with_nibbles(&mut stack, &nibbles, children);
+                }
+                MemTrieNodeView::BranchWithValue { children, .. } => {
+                    check_cancelled(cancel)?;
+                    if children.iter().next().is_none() {
+                        return Err(CanonicalViolation::DegenerateBranch { path: nibbles });
+                    }
+                    push_children_with_nibbles(&mut stack, &nibbles, children);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds a bloom filter of the given number of bits containing all keys
+    /// under the given root, for cheap checks whether a key may be present.
+    ///
+    /// The walk is aborted with `WalkError::Cancelled` once `cancel` is set.
+    pub fn build_key_bloom(
+        &self,
+        root: &CryptoHash,
+        bits: usize,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<BloomFilter, WalkError> {
+        let mut bloom = BloomFilter::new(bits);
+        if root != &CryptoHash::default() {
+            insert_keys_into_bloom(self.get_root(root)?, &mut Vec::new(), &mut bloom, cancel)?;
+        }
+        Ok(bloom)
+    }
+
+    /// Computes a hash of the structure of the trie under the given root:
+    /// node types, extensions and branch layout. Values are substituted by
+    /// a constant, so tries with the same key sets but different values have
+    /// the same shape hash.
+    ///
+    /// The walk is aborted with `WalkError::Cancelled` once `cancel` is set.
+    pub fn shape_hash(
+        &self,
+        root: &CryptoHash,
+        cancel: Option<&AtomicBool>,
+    ) -> Result<CryptoHash, WalkError> {
+        if root == &CryptoHash::default() {
+            return Ok(CryptoHash::default());
+        }
+        compute_shape_hash(self.get_root(root)?, cancel)
+    }
+
+    /// Emits the hash and the serialized `RawTrieNodeWithSize` of every node
+    /// under the given root to `sink`, parents before children and children
+    /// in the order of their indices. Nodes are deduplicated by hash, so each
+    /// one is emitted exactly once even if shared by several subtrees.
+    ///
+    /// The walk is aborted with `WalkError::Cancelled` once `cancel` is set.
+    pub fn serialize_all_nodes(
+        &self,
+        root: &CryptoHash,
+        mut sink: impl FnMut(CryptoHash, &[u8]),
+        cancel: Option<&AtomicBool>,
+    ) -> Result<(), WalkError> {
+        if root == &CryptoHash::default() {
+            return Ok(());
+        }
+        serialize_subtree(self.get_root(root)?, &mut HashSet::new(), &mut sink, cancel)
+    }
+
+    /// Returns whether there are any keys starting with `prefix` under the
+    /// given root. Every non-empty subtree contains a value, so it is enough
+    /// to find the node covering the prefix.
+    pub fn prefix_exists(&self, root: &CryptoHash, prefix: &[u8]) -> Result<bool, StorageError> {
+        if root == &CryptoHash::default() {
+            return Ok(false);
+        }
+        let mut nibbles = NibbleSlice::new(prefix);
+        let mut node = self.get_root(root)?;
+        loop {
+            match node.view() {
+                MemTrieNodeView::Leaf { extension, .. } => {
+                    return Ok(NibbleSlice::from_encoded(extension).0.starts_with(&nibbles));
+                }
+                MemTrieNodeView::Extension { extension, child, .. } => {
+                    let extension_nibbles = NibbleSlice::from_encoded(extension).0;
+                    if extension_nibbles.starts_with(&nibbles) {
+                        return Ok(true);
+                    }
+                    if !nibbles.starts_with(&extension_nibbles) {
+                        return Ok(false);
+                    }
+                    nibbles = nibbles.mid(extension_nibbles.len());
+                    node = child;
+                }
+                MemTrieNodeView::Branch { children, .. }
+                | MemTrieNodeView::BranchWithValue { children, .. } => {
+                    if nibbles.is_empty() {
+                        return Ok(true);
+                    }
+                    let Some(child) = children.get(nibbles.at(0) as usize) else {
+                        return Ok(false);
+                    };
+                    nibbles = nibbles.mid(1);
+                    node = child;
+                }
+            }
+        }
+    }
+
+    /// Returns the number of nibbles the lookups of `key_a` and `key_b` under
+    /// the given root descend together, i.e. the depth of the deepest trie
+    /// node visited by both lookups. It may be smaller than the common prefix
+    /// of the keys, e.g. if both keys end up at the same leaf or diverge
+    /// inside an extension.
+    pub fn shared_path_depth(
+        &self,
+        root: &CryptoHash,
+        key_a: &[u8],
+        key_b: &[u8],
+    ) -> Result<usize, StorageError> {
+        if root == &CryptoHash::default() {
+            return Ok(0);
+        }
+        Ok(compute_shared_path_depth(self.get_root(root)?, key_a, key_b))
+    }
+
+    /// Simulates corruption by replacing the trie registered for the given
+    /// state root with a trie where the value of `key` is `value`. The root
+    /// hash is kept as is, so lookups by it see the corrupted trie.
+    #[cfg(test)]
+    pub(crate) fn corrupt_value_for_test(
+        &mut self,
+        state_root: &CryptoHash,
+        key: &[u8],
+        value: &[u8],
+    ) {
+        let mut update = self.update_memtrie_only(*state_root).unwrap();
+        update.insert_memtrie_only(key, FlatStateValue::on_disk(value)).unwrap();
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let root = construct_root_from_changes(&mut self.arena, &changes, None, |_, _| {})
+            .unwrap()
+            .unwrap();
+        // The original root is leaked, which is fine for tests.
+        root.add_ref(self.arena.memory_mut());
+        self.roots.get_mut(state_root).unwrap()[0] = root;
+    }
+
     #[cfg(test)]
     pub fn arena(&self) -> &HybridArena {
         &self.arena
@@ -226,56 +1114,577 @@ impl MemTries {
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::MemTries;
-    use crate::trie::mem::arena::Arena;
-    use crate::trie::mem::node::{InputMemTrieNode, MemTrieNodeId};
-    use crate::NibbleSlice;
-    use near_primitives::hash::CryptoHash;
-    use near_primitives::shard_layout::ShardUId;
-    use near_primitives::state::FlatStateValue;
-    use near_primitives::types::BlockHeight;
-    use rand::seq::SliceRandom;
-    use rand::Rng;
+/// Collects hashes of all nodes in the subtree. Subtrees of already seen
+/// nodes are skipped, as their hashes are already collected.
+fn collect_node_hashes<M: ArenaMemory>(
+    node: MemTrieNodePtr<M>,
+    hashes: &mut HashSet<CryptoHash>,
+    cancel: Option<&AtomicBool>,
+) -> Result<(), WalkError> {
+    let view = node.view();
+    if !hashes.insert(view.node_hash()) {
+        return Ok(());
+    }
+    if matches!(view, MemTrieNodeView::Branch { .. } | MemTrieNodeView::BranchWithValue { .. }) {
+        check_cancelled(cancel)?;
+    }
+    for child in view.iter_children() {
+        collect_node_hashes(child, hashes, cancel)?;
+    }
+    Ok(())
+}
 
-    #[test]
-    fn test_refcount() {
-        // Here we test multiple cases:
-        //  - Each height possibly having multiple state roots (due to forks)
-        //    (and possibly with the same state roots)
-        //  - Each state root possibly having multiple actual nodes that have
-        //    the same hash (as we don't deduplicate in general)
-        //  - A state root being possibly the same as another of a different
-        //    height.
-        //
-        // And we make sure that the GC refcounting works correctly.
-        let mut tries = MemTries::new(ShardUId::single_shard());
-        let mut available_hashes: Vec<(BlockHeight, CryptoHash)> = Vec::new();
-        for height in 100..=200 {
-            let num_roots_at_height = rand::thread_rng().gen_range(1..=4);
-            for _ in 0..num_roots_at_height {
-                match rand::thread_rng().gen_range(0..4) {
-                    0 if !available_hashes.is_empty() => {
-                        // Reuse an existing root 25% of the time.
-                        let (_, root) = available_hashes.choose(&mut rand::thread_rng()).unwrap();
-                        let root = tries.get_root(root).unwrap().id();
-                        let state_root = root.as_ptr(tries.arena.memory()).view().node_hash();
-                        tries.insert_root(state_root, root, height);
-                        available_hashes.push((height, state_root));
-                    }
-                    _ => {
-                        // Construct a new root 75% of the time.
-                        let root = MemTrieNodeId::new(
-                            &mut tries.arena,
-                            InputMemTrieNode::Leaf {
-                                value: &FlatStateValue::Inlined(format!("{}", height).into_bytes()),
-                                extension: &NibbleSlice::new(&[]).encoded(true),
-                            },
-                        );
-                        let state_root = root.as_ptr(tries.arena.memory()).view().node_hash();
-                        tries.insert_root(state_root, root, height);
-                        available_hashes.push((height, state_root));
+fn collect_node_ids_by_hash<M: ArenaMemory>(
+    node: MemTrieNodePtr<M>,
+    node_ids: &mut HashMap<CryptoHash, MemTrieNodeId>,
+    cancel: Option<&AtomicBool>,
+) -> Result<(), WalkError> {
+    let view = node.view();
+    if node_ids.insert(view.node_hash(), node.id()).is_some() {
+        return Ok(());
+    }
+    if matches!(view, MemTrieNodeView::Branch { .. } | MemTrieNodeView::BranchWithValue { .. }) {
+        check_cancelled(cancel)?;
+    }
+    for child in view.iter_children() {
+        collect_node_ids_by_hash(child, node_ids, cancel)?;
+    }
+    Ok(())
+}
+
+/// Adds the nodes of the subtree which are absent from `from_nodes` to the
+/// changes in post-order, referring to the present ones as old nodes.
+/// Returns the updated node ID of the subtree root.
+fn add_subtree_diff<M: ArenaMemory>(
+    node: MemTrieNodePtr<M>,
+    from_nodes: &HashMap<CryptoHash, MemTrieNodeId>,
+    changes: &mut MemTrieChanges,
+    cancel: Option<&AtomicBool>,
+) -> Result<UpdatedNodeId, WalkError> {
+    let view = node.view();
+    let node_hash = view.node_hash();
+    let memory_usage = view.memory_usage();
+    let node = match view {
+        MemTrieNodeView::Leaf { extension, value } => UpdatedMemTrieNode::Leaf {
+            extension: extension.into(),
+            value: value.to_flat_value(),
+            #[cfg(feature = "memtrie_shadow_values")]
+            shadow_value: None,
+        },
+        MemTrieNodeView::Extension { extension, child, .. } => UpdatedMemTrieNode::Extension {
+            extension: extension.into(),
+            child: add_child_diff(child, from_nodes, changes, cancel)?,
+        },
+        MemTrieNodeView::Branch { children, .. } => {
+            check_cancelled(cancel)?;
+            UpdatedMemTrieNode::Branch {
+                children: add_children_diff(children, from_nodes, changes, cancel)?,
+                value: None,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value: None,
+            }
+        }
+        MemTrieNodeView::BranchWithValue { children, value, .. } => {
+            check_cancelled(cancel)?;
+            UpdatedMemTrieNode::Branch {
+                children: add_children_diff(children, from_nodes, changes, cancel)?,
+                value: Some(value.to_flat_value()),
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value: None,
+            }
+        }
+    };
+    let node_id = changes.updated_nodes.len();
+    changes.updated_nodes.push(Some(UpdatedMemTrieNodeWithSize { node, memory_usage }));
+    changes.node_ids_with_hashes.push((node_id, node_hash));
+    Ok(node_id)
+}
+
+fn add_child_diff<M: ArenaMemory>(
+    child: MemTrieNodePtr<M>,
+    from_nodes: &HashMap<CryptoHash, MemTrieNodeId>,
+    changes: &mut MemTrieChanges,
+    cancel: Option<&AtomicBool>,
+) -> Result<OldOrUpdatedNodeId, WalkError> {
+    Ok(match from_nodes.get(&child.view().node_hash()) {
+        Some(from_node_id) => OldOrUpdatedNodeId::Old(*from_node_id),
+        None => OldOrUpdatedNodeId::Updated(add_subtree_diff(child, from_nodes, changes, cancel)?),
+    })
+}
+
+fn add_children_diff<M: ArenaMemory>(
+    children: ChildrenView<M>,
+    from_nodes: &HashMap<CryptoHash, MemTrieNodeId>,
+    changes: &mut MemTrieChanges,
+    cancel: Option<&AtomicBool>,
+) -> Result<Box<[Option<OldOrUpdatedNodeId>; 16]>, WalkError> {
+    let mut updated_children = Box::<[Option<OldOrUpdatedNodeId>; 16]>::default();
+    for i in 0..16 {
+        if let Some(child) = children.get(i) {
+            updated_children[i] = Some(add_child_diff(child, from_nodes, changes, cancel)?);
+        }
+    }
+    Ok(updated_children)
+}
+
+/// A position in a trie at some nibble path: the node whose subtree
+/// contains it, and the number of the node's extension nibbles which are
+/// already part of the path.
+struct TriePosition<'a, M: ArenaMemory> {
+    node: MemTrieNodePtr<'a, M>,
+    consumed: usize,
+}
+
+impl<'a, M: ArenaMemory> TriePosition<'a, M> {
+    /// Moves past extensions which are consumed entirely.
+    fn normalize(mut self) -> Self {
+        while let MemTrieNodeView::Extension { extension, child, .. } = self.node.view() {
+            if self.consumed < NibbleSlice::from_encoded(extension).0.len() {
+                break;
+            }
+            self = Self { node: child, consumed: 0 };
+        }
+        self
+    }
+
+    fn value(&self) -> Option<ValueRef> {
+        match self.node.view() {
+            MemTrieNodeView::Leaf { extension, value } => {
+                let at_end = self.consumed == NibbleSlice::from_encoded(extension).0.len();
+                at_end.then(|| value.to_flat_value().to_value_ref())
+            }
+            MemTrieNodeView::BranchWithValue { value, .. } => {
+                Some(value.to_flat_value().to_value_ref())
+            }
+            MemTrieNodeView::Branch { .. } | MemTrieNodeView::Extension { .. } => None,
+        }
+    }
+
+    fn child(&self, nibble: u8) -> Option<Self> {
+        match self.node.view() {
+            MemTrieNodeView::Leaf { extension, .. }
+            | MemTrieNodeView::Extension { extension, .. } => {
+                let nibbles = NibbleSlice::from_encoded(extension).0;
+                (self.consumed < nibbles.len() && nibbles.at(self.consumed) == nibble)
+                    .then(|| Self { node: self.node, consumed: self.consumed + 1 })
+            }
+            MemTrieNodeView::Branch { children, .. }
+            | MemTrieNodeView::BranchWithValue { children, .. } => {
+                children.get(nibble as usize).map(|child| Self { node: child, consumed: 0 })
+            }
+        }
+    }
+}
+
+fn first_differing_key_impl<M: ArenaMemory>(
+    local: Option<TriePosition<M>>,
+    expected: Option<TriePosition<M>>,
+    nibbles: &mut Vec<u8>,
+    cancel: Option<&AtomicBool>,
+) -> Result<Option<Vec<u8>>, WalkError> {
+    let local = local.map(TriePosition::normalize);
+    let expected = expected.map(TriePosition::normalize);
+    if let (Some(local), Some(expected)) = (&local, &expected) {
+        if local.consumed == 0
+            && expected.consumed == 0
+            && local.node.view().node_hash() == expected.node.view().node_hash()
+        {
+            return Ok(None);
+        }
+    }
+    let at_branch = |position: &Option<TriePosition<M>>| {
+        position.as_ref().is_some_and(|position| {
+            matches!(position.node.node_kind(), NodeKind::Branch | NodeKind::BranchWithValue)
+        })
+    };
+    if at_branch(&local) || at_branch(&expected) {
+        check_cancelled(cancel)?;
+    }
+    let local_value = local.as_ref().and_then(TriePosition::value);
+    let expected_value = expected.as_ref().and_then(TriePosition::value);
+    if local_value != expected_value {
+        return Ok(Some(NibbleSlice::nibbles_to_bytes(nibbles)));
+    }
+    for nibble in 0..16 {
+        let local_child = local.as_ref().and_then(|local| local.child(nibble));
+        let expected_child = expected.as_ref().and_then(|expected| expected.child(nibble));
+        if local_child.is_none() && expected_child.is_none() {
+            continue;
+        }
+        nibbles.push(nibble);
+        let key = first_differing_key_impl(local_child, expected_child, nibbles, cancel)?;
+        nibbles.pop();
+        if key.is_some() {
+            return Ok(key);
+        }
+    }
+    Ok(None)
+}
+
+/// Sums the lengths of the values in the subtree, skipping the subtrees
+/// whose hashes are in `skipped`.
+fn value_bytes_outside<M: ArenaMemory>(
+    node: MemTrieNodePtr<M>,
+    skipped: &HashSet<CryptoHash>,
+    cancel: Option<&AtomicBool>,
+) -> Result<u64, WalkError> {
+    let view = node.view();
+    if skipped.contains(&view.node_hash()) {
+        return Ok(0);
+    }
+    let mut value_bytes = match &view {
+        MemTrieNodeView::Leaf { value, .. } => HasValueLength::len(value),
+        MemTrieNodeView::BranchWithValue { value, .. } => {
+            check_cancelled(cancel)?;
+            HasValueLength::len(value)
+        }
+        MemTrieNodeView::Branch { .. } => {
+            check_cancelled(cancel)?;
+            0
+        }
+        MemTrieNodeView::Extension { .. } => 0,
+    };
+    for child in view.iter_children() {
+        value_bytes += value_bytes_outside(child, skipped, cancel)?;
+    }
+    Ok(value_bytes)
+}
+
+/// Collects the keys and value hashes of the subtree in order, skipping the
+/// parts outside of the given range of nibbles.
+fn collect_values_in_range<M: ArenaMemory>(
+    node: MemTrieNodePtr<M>,
+    nibbles: &mut Vec<u8>,
+    range: &RangeInclusive<Vec<u8>>,
+    values: &mut Vec<(Vec<u8>, CryptoHash)>,
+    cancel: Option<&AtomicBool>,
+) -> Result<(), WalkError> {
+    // All keys of the subtree start with `nibbles`, so compare it with the
+    // same number of nibbles of the bounds.
+    let (start, end) = (range.start(), range.end());
+    if nibbles.as_slice() < &start[..nibbles.len().min(start.len())]
+        || nibbles.as_slice() > &end[..nibbles.len().min(end.len())]
+    {
+        return Ok(());
+    }
+    let prefix_len = nibbles.len();
+    let mut add_value = |nibbles: &Vec<u8>, value: ValueView| {
+        if range.contains(nibbles) {
+            let key = NibbleSlice::nibbles_to_bytes(nibbles);
+            values.push((key, value.to_flat_value().to_value_ref().hash));
+        }
+    };
+    match node.view() {
+        MemTrieNodeView::Leaf { extension, value } => {
+            nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+            add_value(nibbles, value);
+        }
+        MemTrieNodeView::Extension { extension, child, .. } => {
+            nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+            collect_values_in_range(child, nibbles, range, values, cancel)?;
+        }
+        MemTrieNodeView::Branch { children, .. } => {
+            collect_children_values_in_range(children, nibbles, range, values, cancel)?;
+        }
+        MemTrieNodeView::BranchWithValue { children, value, .. } => {
+            add_value(nibbles, value);
+            collect_children_values_in_range(children, nibbles, range, values, cancel)?;
+        }
+    }
+    nibbles.truncate(prefix_len);
+    Ok(())
+}
+
+fn collect_children_values_in_range<M: ArenaMemory>(
+    children: ChildrenView<M>,
+    nibbles: &mut Vec<u8>,
+    range: &RangeInclusive<Vec<u8>>,
+    values: &mut Vec<(Vec<u8>, CryptoHash)>,
+    cancel: Option<&AtomicBool>,
+) -> Result<(), WalkError> {
+    check_cancelled(cancel)?;
+    for i in 0..16 {
+        if let Some(child) = children.get(i) {
+            nibbles.push(i as u8);
+            collect_values_in_range(child, nibbles, range, values, cancel)?;
+            nibbles.pop();
+        }
+    }
+    Ok(())
+}
+
+fn insert_keys_into_bloom<M: ArenaMemory>(
+    node: MemTrieNodePtr<M>,
+    nibbles: &mut Vec<u8>,
+    bloom: &mut BloomFilter,
+    cancel: Option<&AtomicBool>,
+) -> Result<(), WalkError> {
+    let prefix_len = nibbles.len();
+    match node.view() {
+        MemTrieNodeView::Leaf { extension, .. } => {
+            nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+            bloom.insert(&NibbleSlice::nibbles_to_bytes(nibbles));
+        }
+        MemTrieNodeView::Extension { extension, child, .. } => {
+            nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+            insert_keys_into_bloom(child, nibbles, bloom, cancel)?;
+        }
+        MemTrieNodeView::Branch { children, .. } => {
+            insert_children_keys_into_bloom(children, nibbles, bloom, cancel)?;
+        }
+        MemTrieNodeView::BranchWithValue { children, .. } => {
+            bloom.insert(&NibbleSlice::nibbles_to_bytes(nibbles));
+            insert_children_keys_into_bloom(children, nibbles, bloom, cancel)?;
+        }
+    }
+    nibbles.truncate(prefix_len);
+    Ok(())
+}
+
+fn insert_children_keys_into_bloom<M: ArenaMemory>(
+    children: ChildrenView<M>,
+    nibbles: &mut Vec<u8>,
+    bloom: &mut BloomFilter,
+    cancel: Option<&AtomicBool>,
+) -> Result<(), WalkError> {
+    check_cancelled(cancel)?;
+    for i in 0..16 {
+        if let Some(child) = children.get(i) {
+            nibbles.push(i as u8);
+            insert_keys_into_bloom(child, nibbles, bloom, cancel)?;
+            nibbles.pop();
+        }
+    }
+    Ok(())
+}
+
+fn serialize_subtree<M: ArenaMemory>(
+    node: MemTrieNodePtr<M>,
+    seen: &mut HashSet<CryptoHash>,
+    sink: &mut impl FnMut(CryptoHash, &[u8]),
+    cancel: Option<&AtomicBool>,
+) -> Result<(), WalkError> {
+    let view = node.view();
+    let node_hash = view.node_hash();
+    if !seen.insert(node_hash) {
+        return Ok(());
+    }
+    if matches!(view, MemTrieNodeView::Branch { .. } | MemTrieNodeView::BranchWithValue { .. }) {
+        check_cancelled(cancel)?;
+    }
+    sink(node_hash, &borsh::to_vec(&view.to_raw_trie_node_with_size()).unwrap());
+    for child in view.iter_children() {
+        serialize_subtree(child, seen, sink, cancel)?;
+    }
+    Ok(())
+}
+
+fn count_shared_nodes<M: ArenaMemory>(
+    node: MemTrieNodePtr<M>,
+    hashes: &HashSet<CryptoHash>,
+    cancel: Option<&AtomicBool>,
+) -> Result<usize, WalkError> {
+    let view = node.view();
+    if hashes.contains(&view.node_hash()) {
+        return count_nodes(node, cancel);
+    }
+    if matches!(view, MemTrieNodeView::Branch { .. } | MemTrieNodeView::BranchWithValue { .. }) {
+        check_cancelled(cancel)?;
+    }
+    view.iter_children().map(|child| count_shared_nodes(child, hashes, cancel)).sum()
+}
+
+fn count_nodes<M: ArenaMemory>(
+    node: MemTrieNodePtr<M>,
+    cancel: Option<&AtomicBool>,
+) -> Result<usize, WalkError> {
+    let view = node.view();
+    if matches!(view, MemTrieNodeView::Branch { .. } | MemTrieNodeView::BranchWithValue { .. }) {
+        check_cancelled(cancel)?;
+    }
+    let num_child_nodes = view
+        .iter_children()
+        .map(|child| count_nodes(child, cancel))
+        .sum::<Result<usize, WalkError>>()?;
+    Ok(1 + num_child_nodes)
+}
+
+/// Hash of a leaf or an extension built by `MemTries::rebased_root`, given
+/// the memory usage and the hash of the child of an extension.
+fn rebased_node_hash(
+    node: &UpdatedMemTrieNode,
+    child_memory_usage: u64,
+    child_hash: CryptoHash,
+) -> CryptoHash {
+    let raw_node = node.to_raw_trie_node(|_| child_hash);
+    let memory_usage = node.memory_usage_direct() + child_memory_usage;
+    RawTrieNodeWithSize { node: raw_node, memory_usage }.hash()
+}
+
+/// Hashes the node as a raw trie node where each value is replaced by the
+/// same constant, memory usage is omitted, and children are referenced by
+/// their shape hashes.
+fn compute_shape_hash<M: ArenaMemory>(
+    node: MemTrieNodePtr<M>,
+    cancel: Option<&AtomicBool>,
+) -> Result<CryptoHash, WalkError> {
+    const SHAPE_VALUE: ValueRef = ValueRef { length: 0, hash: CryptoHash::new() };
+    let shape_children = |children: ChildrenView<M>| -> Result<Children, WalkError> {
+        check_cancelled(cancel)?;
+        let mut shape_children = Children::default();
+        for i in 0..16 {
+            if let Some(child) = children.get(i) {
+                shape_children.0[i] = Some(compute_shape_hash(child, cancel)?);
+            }
+        }
+        Ok(shape_children)
+    };
+    let shape_node = match node.view() {
+        MemTrieNodeView::Leaf { extension, .. } => {
+            RawTrieNode::Leaf(extension.to_vec(), SHAPE_VALUE)
+        }
+        MemTrieNodeView::Extension { extension, child, .. } => {
+            RawTrieNode::Extension(extension.to_vec(), compute_shape_hash(child, cancel)?)
+        }
+        MemTrieNodeView::Branch { children, .. } => {
+            RawTrieNode::BranchNoValue(shape_children(children)?)
+        }
+        MemTrieNodeView::BranchWithValue { children, .. } => {
+            RawTrieNode::BranchWithValue(SHAPE_VALUE, shape_children(children)?)
+        }
+    };
+    Ok(CryptoHash::hash_borsh(&shape_node))
+}
+
+fn collect_value_stats<M: ArenaMemory>(
+    node: MemTrieNodePtr<M>,
+    stats: &mut ValueStats,
+    value_hashes: &mut HashSet<CryptoHash>,
+    cancel: Option<&AtomicBool>,
+) -> Result<(), WalkError> {
+    let view = node.view();
+    if matches!(view, MemTrieNodeView::Branch { .. } | MemTrieNodeView::BranchWithValue { .. }) {
+        check_cancelled(cancel)?;
+    }
+    if let MemTrieNodeView::Leaf { value, .. } | MemTrieNodeView::BranchWithValue { value, .. } =
+        &view
+    {
+        stats.references += 1;
+        stats.total_value_bytes += value.len() as u64;
+        value_hashes.insert(value.to_flat_value().to_value_ref().hash);
+    }
+    for child in view.iter_children() {
+        collect_value_stats(child, stats, value_hashes, cancel)?;
+    }
+    Ok(())
+}
+
+fn compute_shared_path_depth<M: ArenaMemory>(
+    root: MemTrieNodePtr<M>,
+    key_a: &[u8],
+    key_b: &[u8],
+) -> usize {
+    let mut nibbles_a = NibbleSlice::new(key_a);
+    let mut nibbles_b = NibbleSlice::new(key_b);
+    let mut node = root;
+    let mut depth = 0;
+    loop {
+        match node.view() {
+            MemTrieNodeView::Leaf { .. } => return depth,
+            MemTrieNodeView::Extension { extension, child, .. } => {
+                let extension_nibbles = NibbleSlice::from_encoded(extension).0;
+                if !nibbles_a.starts_with(&extension_nibbles)
+                    || !nibbles_b.starts_with(&extension_nibbles)
+                {
+                    return depth;
+                }
+                nibbles_a = nibbles_a.mid(extension_nibbles.len());
+                nibbles_b = nibbles_b.mid(extension_nibbles.len());
+                depth += extension_nibbles.len();
+                node = child;
+            }
+            MemTrieNodeView::Branch { children, .. }
+            | MemTrieNodeView::BranchWithValue { children, .. } => {
+                if nibbles_a.is_empty()
+                    || nibbles_b.is_empty()
+                    || nibbles_a.at(0) != nibbles_b.at(0)
+                {
+                    return depth;
+                }
+                let Some(child) = children.get(nibbles_a.at(0) as usize) else {
+                    return depth;
+                };
+                nibbles_a = nibbles_a.mid(1);
+                nibbles_b = nibbles_b.mid(1);
+                depth += 1;
+                node = child;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CanonicalViolation, MemTries, ValueStats, VerifyError, WalkError};
+    use crate::trie::mem::arena::single_thread::STArena;
+    use crate::trie::mem::arena::Arena;
+    use crate::trie::mem::flexible_data::value::NUM_VALUE_DEREFS;
+    use crate::trie::mem::lookup::memtrie_lookup;
+    use crate::trie::mem::memtrie_update::TrackingMode;
+    use crate::trie::mem::node::{InputMemTrieNode, MemTrieNodeId, MemTrieNodeView, NodeKind};
+    use crate::trie::mem::sparse::compute_trie_root;
+    use crate::trie::Children;
+    use crate::{NibbleSlice, PartialStorage, RawTrieNode, RawTrieNodeWithSize, Trie};
+    use assert_matches::assert_matches;
+    use near_primitives::challenge::PartialState;
+    use near_primitives::errors::StorageError;
+    use near_primitives::hash::{hash, CryptoHash};
+    use near_primitives::shard_layout::ShardUId;
+    use near_primitives::state::FlatStateValue;
+    use near_primitives::types::BlockHeight;
+    use rand::seq::SliceRandom;
+    use rand::Rng;
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_refcount() {
+        // Here we test multiple cases:
+        //  - Each height possibly having multiple state roots (due to forks)
+        //    (and possibly with the same state roots)
+        //  - Each state root possibly having multiple actual nodes that have
+        //    the same hash (as we don't deduplicate in general)
+        //  - A state root being possibly the same as another of a different
+        //    height.
+        //
+        // And we make sure that the GC refcounting works correctly.
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut available_hashes: Vec<(BlockHeight, CryptoHash)> = Vec::new();
+        for height in 100..=200 {
+            let num_roots_at_height = rand::thread_rng().gen_range(1..=4);
+            for _ in 0..num_roots_at_height {
+                match rand::thread_rng().gen_range(0..4) {
+                    0 if !available_hashes.is_empty() => {
+                        // Reuse an existing root 25% of the time.
+                        let (_, root) = available_hashes.choose(&mut rand::thread_rng()).unwrap();
+                        let root = tries.get_root(root).unwrap().id();
+                        let state_root = root.as_ptr(tries.arena.memory()).view().node_hash();
+                        tries.insert_root(state_root, root, height);
+                        available_hashes.push((height, state_root));
+                    }
+                    _ => {
+                        // Construct a new root 75% of the time.
+                        let root = MemTrieNodeId::new(
+                            &mut tries.arena,
+                            InputMemTrieNode::Leaf {
+                                value: &FlatStateValue::Inlined(format!("{}", height).into_bytes()),
+                                extension: &NibbleSlice::new(&[]).encoded(true),
+                            },
+                        );
+                        let state_root = root.as_ptr(tries.arena.memory()).view().node_hash();
+                        tries.insert_root(state_root, root, height);
+                        available_hashes.push((height, state_root));
                     }
                 }
             }
@@ -293,4 +1702,963 @@ mod tests {
         assert_eq!(tries.arena.num_active_allocs(), 0);
         assert_eq!(tries.num_roots(), 0);
     }
+
+    #[test]
+    fn test_pin_root() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut roots = vec![];
+        for height in 0..3 {
+            let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+            update.insert_memtrie_only(&[height as u8], FlatStateValue::Inlined(vec![1])).unwrap();
+            let changes = update.to_memtrie_changes_only().unwrap();
+            roots.push(tries.apply_memtrie_changes(height, &changes).unwrap());
+        }
+
+        let pin = tries.pin_root(&roots[1]);
+        let second_pin = tries.pin_root(&roots[1]);
+        assert_eq!(pin.root(), &roots[1]);
+        tries.delete_until_height(3);
+        assert!(tries.get_root(&roots[0]).is_err());
+        assert!(tries.get_root(&roots[1]).is_ok());
+        assert!(tries.get_root(&roots[2]).is_err());
+
+        drop(pin);
+        tries.delete_until_height(3);
+        assert!(tries.get_root(&roots[1]).is_ok());
+
+        drop(second_pin);
+        tries.delete_until_height(3);
+        assert!(tries.get_root(&roots[1]).is_err());
+        assert_eq!(tries.num_roots(), 0);
+        assert_eq!(tries.arena.num_active_allocs(), 0);
+    }
+
+    #[test]
+    fn test_apply_memtrie_changes_batch() {
+        let build_tries = || {
+            let mut tries = MemTries::new(ShardUId::single_shard());
+            let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+            for i in 0..16u8 {
+                update.insert_memtrie_only(&[i << 4, 0], FlatStateValue::Inlined(vec![i])).unwrap();
+            }
+            let changes = update.to_memtrie_changes_only().unwrap();
+            let root = tries.apply_memtrie_changes(0, &changes).unwrap();
+            (tries, root)
+        };
+        // Forks of the same base root, where the last one repeats the first.
+        let make_items = |tries: &MemTries, base_root: CryptoHash| {
+            [(1, 100), (2, 101), (3, 100)]
+                .into_iter()
+                .map(|(height, value)| {
+                    let mut update = tries.update_memtrie_only(base_root).unwrap();
+                    update
+                        .insert_memtrie_only(&[0x10, 0], FlatStateValue::Inlined(vec![value]))
+                        .unwrap();
+                    (height, update.to_memtrie_changes_only().unwrap())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let (mut batch_tries, base_root) = build_tries();
+        let batch_items = make_items(&batch_tries, base_root);
+        let batch_roots = batch_tries.apply_memtrie_changes_batch(&batch_items).unwrap();
+
+        let (mut single_tries, base_root) = build_tries();
+        let single_items = make_items(&single_tries, base_root);
+        let single_roots: Vec<_> = single_items
+            .iter()
+            .map(|(height, changes)| single_tries.apply_memtrie_changes(*height, changes).unwrap())
+            .collect();
+
+        assert_eq!(batch_roots, single_roots);
+        assert_eq!(batch_roots[0], batch_roots[2]);
+        // All nodes of the last changes are reused from the first ones.
+        let num_reused_nodes =
+            single_tries.arena.num_active_allocs() - batch_tries.arena.num_active_allocs();
+        assert!(num_reused_nodes > 0);
+        assert_eq!(num_reused_nodes, batch_items[2].1.node_ids_with_hashes.len());
+
+        batch_tries.delete_until_height(4);
+        assert_eq!(batch_tries.arena.num_active_allocs(), 0);
+        assert_eq!(batch_tries.num_roots(), 0);
+    }
+
+    #[test]
+    fn test_apply_memtrie_changes_capacity_exceeded() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for i in 0..16u8 {
+            update.insert_memtrie_only(&[i << 4, 0], FlatStateValue::Inlined(vec![i])).unwrap();
+        }
+        let changes = update.to_memtrie_changes_only().unwrap();
+
+        tries.arena.set_capacity_limit(Some(100));
+        let err = tries.apply_memtrie_changes(0, &changes).unwrap_err();
+        assert!(matches!(err, StorageError::StorageInconsistentState(_)), "{:?}", err);
+        // Nothing is allocated on failure.
+        assert_eq!(tries.arena.num_active_allocs(), 0);
+        assert_eq!(tries.arena.active_allocs_bytes(), 0);
+        assert_eq!(tries.num_roots(), 0);
+
+        tries.arena.set_capacity_limit(Some(100_000));
+        let state_root = tries.apply_memtrie_changes(0, &changes).unwrap();
+        assert_ne!(state_root, CryptoHash::default());
+        assert_eq!(tries.arena.num_active_allocs(), 17);
+    }
+
+    #[test]
+    fn test_shared_node_count() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        // The subtree under nibble 1 consists of a branch and two leaves.
+        for (key, value) in [(0x00, 0), (0x10, 1), (0x11, 2)] {
+            update.insert_memtrie_only(&[key], FlatStateValue::Inlined(vec![value])).unwrap();
+        }
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let root_a = tries.apply_memtrie_changes(0, &changes).unwrap();
+        let mut update = tries.update_memtrie_only(root_a).unwrap();
+        update.insert_memtrie_only(&[0x00], FlatStateValue::Inlined(vec![3])).unwrap();
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let root_b = tries.apply_memtrie_changes(1, &changes).unwrap();
+
+        assert_eq!(tries.shared_node_count(&root_a, &root_b, None).unwrap(), 3);
+        assert_eq!(tries.shared_node_count(&root_b, &root_a, None).unwrap(), 3);
+        assert_eq!(tries.shared_node_count(&root_a, &root_a, None).unwrap(), 5);
+        assert_eq!(tries.shared_node_count(&root_a, &CryptoHash::default(), None).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_value_stats() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        assert_eq!(tries.value_stats(&CryptoHash::default(), None).unwrap(), ValueStats::default());
+        let shared_value = vec![7u8; 100];
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for key in [0x00, 0x10, 0x11] {
+            update.insert_memtrie_only(&[key], FlatStateValue::on_disk(&shared_value)).unwrap();
+        }
+        update.insert_memtrie_only(&[0x10, 0x00], FlatStateValue::Inlined(vec![1, 2])).unwrap();
+        update.insert_memtrie_only(&[0x20], FlatStateValue::Inlined(vec![1, 2])).unwrap();
+        update.insert_memtrie_only(&[0x21], FlatStateValue::Inlined(vec![3])).unwrap();
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let state_root = tries.apply_memtrie_changes(0, &changes).unwrap();
+
+        let stats = tries.value_stats(&state_root, None).unwrap();
+        assert!(stats.references > stats.distinct);
+        assert_eq!(stats, ValueStats { references: 6, distinct: 3, total_value_bytes: 305 });
+    }
+
+    #[test]
+    fn test_arena_allocation_histogram() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        // A branch with a leaf and an extension to a branch with a value.
+        for (key, value) in [(vec![0x00], 0), (vec![0x12, 0x30], 1), (vec![0x12], 2)] {
+            update.insert_memtrie_only(&key, FlatStateValue::Inlined(vec![value])).unwrap();
+        }
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let state_root = tries.apply_memtrie_changes(0, &changes).unwrap();
+
+        let histogram = tries.arena_allocation_histogram(&state_root, None).unwrap();
+        let counts: Vec<_> = histogram.iter().map(|(kind, count, _)| (*kind, *count)).collect();
+        assert_eq!(
+            counts,
+            vec![
+                (NodeKind::Leaf, 2),
+                (NodeKind::Extension, 1),
+                (NodeKind::Branch, 1),
+                (NodeKind::BranchWithValue, 1),
+            ]
+        );
+        // All allocations of the arena belong to the only root.
+        let total_bytes: usize = histogram.iter().map(|(_, _, bytes)| bytes).sum();
+        assert_eq!(total_bytes, tries.arena.active_allocs_bytes());
+    }
+
+    #[test]
+    fn test_shape_hash() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut apply = |height: BlockHeight, changes: &[(&[u8], u8)]| {
+            let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+            for (key, value) in changes {
+                // Values of different sizes for different memory usages.
+                let value = FlatStateValue::Inlined(vec![*value; *value as usize + 1]);
+                update.insert_memtrie_only(key, value).unwrap();
+            }
+            let changes = update.to_memtrie_changes_only().unwrap();
+            tries.apply_memtrie_changes(height, &changes).unwrap()
+        };
+        let keys: [&[u8]; 4] = [&[0x00], &[0x12, 0x30], &[0x12], &[0x12, 0x34]];
+        let root_a = apply(0, &[(keys[0], 0), (keys[1], 1), (keys[2], 2)]);
+        let root_b = apply(1, &[(keys[0], 3), (keys[1], 4), (keys[2], 5)]);
+        let root_c = apply(2, &[(keys[0], 0), (keys[3], 1), (keys[2], 2)]);
+        let root_d = apply(3, &[(keys[0], 0), (keys[1], 1)]);
+        assert_ne!(root_a, root_b);
+
+        let shape_a = tries.shape_hash(&root_a, None).unwrap();
+        assert_eq!(tries.shape_hash(&root_b, None).unwrap(), shape_a);
+        assert_ne!(tries.shape_hash(&root_c, None).unwrap(), shape_a);
+        assert_ne!(tries.shape_hash(&root_d, None).unwrap(), shape_a);
+        assert_eq!(tries.shape_hash(&CryptoHash::default(), None).unwrap(), CryptoHash::default());
+    }
+
+    #[test]
+    fn test_shared_path_depth() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for key in [vec![0x12, 0x34], vec![0x12, 0x56], vec![0x78]] {
+            update.insert_memtrie_only(&key, FlatStateValue::Inlined(key.clone())).unwrap();
+        }
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let root = tries.apply_memtrie_changes(0, &changes).unwrap();
+
+        // The root is a branch, followed by an extension with nibble 2 for
+        // keys starting with nibble 1, and by a branch at depth 2.
+        let depth = |key_a: &[u8], key_b: &[u8]| tries.shared_path_depth(&root, key_a, key_b);
+        assert_eq!(depth(&[0x12, 0x34], &[0x12, 0x56]).unwrap(), 2);
+        assert_eq!(depth(&[0x12, 0x34], &[0x12, 0x35]).unwrap(), 3);
+        // Keys sharing 3 nibbles end up at the same leaf at depth 3 anyway.
+        assert_eq!(depth(&[0x12, 0x34], &[0x12, 0x3f]).unwrap(), 3);
+        // The keys share 3 nibbles, but the lookups diverge at the extension.
+        assert_eq!(depth(&[0x13, 0x00], &[0x13, 0x01]).unwrap(), 1);
+        assert_eq!(depth(&[0x12, 0x34], &[0x78]).unwrap(), 0);
+        assert_eq!(depth(&[0x12, 0x34], &[0x12]).unwrap(), 2);
+        assert_eq!(tries.shared_path_depth(&CryptoHash::default(), &[0x12], &[0x12]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_prefix_exists() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for key in [vec![0x12, 0x34, 0x56], vec![0x12, 0x34, 0x78], vec![0x9a]] {
+            update.insert_memtrie_only(&key, FlatStateValue::Inlined(key.clone())).unwrap();
+        }
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let root = tries.apply_memtrie_changes(0, &changes).unwrap();
+
+        // Prefixes ending at the root branch, inside the extension with
+        // nibbles 2, 3, 4, at the branch below it and inside leaves.
+        assert!(tries.prefix_exists(&root, &[]).unwrap());
+        assert!(tries.prefix_exists(&root, &[0x12]).unwrap());
+        assert!(tries.prefix_exists(&root, &[0x12, 0x34]).unwrap());
+        assert!(tries.prefix_exists(&root, &[0x12, 0x34, 0x56]).unwrap());
+        assert!(tries.prefix_exists(&root, &[0x9a]).unwrap());
+        assert!(!tries.prefix_exists(&root, &[0x13]).unwrap());
+        assert!(!tries.prefix_exists(&root, &[0x12, 0x44]).unwrap());
+        assert!(!tries.prefix_exists(&root, &[0x12, 0x34, 0x57]).unwrap());
+        assert!(!tries.prefix_exists(&root, &[0x12, 0x34, 0x56, 0x00]).unwrap());
+        assert!(!tries.prefix_exists(&root, &[0x9b]).unwrap());
+        assert!(!tries.prefix_exists(&root, &[0x50]).unwrap());
+        assert!(!tries.prefix_exists(&CryptoHash::default(), &[]).unwrap());
+    }
+
+    #[test]
+    fn test_changes_between() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for i in 0..16u8 {
+            update.insert_memtrie_only(&[i << 4, 0], FlatStateValue::Inlined(vec![i])).unwrap();
+        }
+        let from_root =
+            tries.apply_memtrie_changes(1, &update.to_memtrie_changes_only().unwrap()).unwrap();
+        let mut update = tries.update_memtrie_only(from_root).unwrap();
+        update.insert_memtrie_only(&[0x10, 0], FlatStateValue::Inlined(vec![100])).unwrap();
+        update.insert_memtrie_only(&[0x20, 1], FlatStateValue::Inlined(vec![101])).unwrap();
+        update.delete(&[0x30, 0]).unwrap();
+        let to_root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+
+        let changes = tries.changes_between(&from_root, &to_root, None).unwrap();
+        let mut to_node_hashes = HashSet::new();
+        super::collect_node_hashes(tries.get_root(&to_root).unwrap(), &mut to_node_hashes);
+        assert!(changes.node_ids_with_hashes.len() < to_node_hashes.len());
+
+        // Changes only depend on the nodes of `from_root`.
+        tries.delete_until_height(1);
+        assert!(tries.get_root(&to_root).is_err());
+        assert_eq!(tries.apply_memtrie_changes(2, &changes).unwrap(), to_root);
+        let root = tries.get_root(&to_root).unwrap();
+        assert_eq!(
+            memtrie_lookup(root, &[0x20, 1], None).map(|value| value.to_flat_value()),
+            Some(FlatStateValue::Inlined(vec![101]))
+        );
+
+        // Identical roots still produce the root node.
+        let changes = tries.changes_between(&from_root, &from_root, None).unwrap();
+        assert_eq!(changes.node_ids_with_hashes.len(), 1);
+        assert_eq!(tries.apply_memtrie_changes(3, &changes).unwrap(), from_root);
+        let changes = tries.changes_between(&from_root, &CryptoHash::default(), None).unwrap();
+        assert_eq!(tries.apply_memtrie_changes(3, &changes).unwrap(), CryptoHash::default());
+    }
+
+    #[test]
+    fn test_value_byte_delta() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for (key, len) in [(0x00, 10), (0x10, 20), (0x11, 30), (0x20, 1000)] {
+            update.insert_memtrie_only(&[key], FlatStateValue::on_disk(&vec![key; len])).unwrap();
+        }
+        let from_root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+        let mut update = tries.update_memtrie_only(from_root).unwrap();
+        update.insert_memtrie_only(&[0x00], FlatStateValue::Inlined(vec![0; 15])).unwrap();
+        update.delete(&[0x10]).unwrap();
+        update.insert_memtrie_only(&[0x12], FlatStateValue::Inlined(vec![1; 40])).unwrap();
+        let to_root =
+            tries.apply_memtrie_changes(1, &update.to_memtrie_changes_only().unwrap()).unwrap();
+
+        assert_eq!(tries.value_byte_delta(&from_root, &to_root, None).unwrap(), 5 - 20 + 40);
+        assert_eq!(tries.value_byte_delta(&to_root, &from_root, None).unwrap(), -25);
+        assert_eq!(tries.value_byte_delta(&to_root, &to_root, None).unwrap(), 0);
+        assert_eq!(tries.value_byte_delta(&CryptoHash::default(), &from_root, None).unwrap(), 1060);
+        assert_eq!(tries.value_byte_delta(&to_root, &CryptoHash::default(), None).unwrap(), -1085);
+    }
+
+    #[test]
+    fn test_first_differing_key() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let keys: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i, i.wrapping_mul(37), 0]).collect();
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for key in &keys {
+            update.insert_memtrie_only(key, FlatStateValue::Inlined(key.clone())).unwrap();
+        }
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let expected_root = tries.apply_memtrie_changes(0, &changes).unwrap();
+
+        // A single changed value in the middle of the trie.
+        let mut update = tries.update_memtrie_only(expected_root).unwrap();
+        update.insert_memtrie_only(&keys[50], FlatStateValue::Inlined(vec![1])).unwrap();
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let changed_value = tries.apply_memtrie_changes(1, &changes).unwrap();
+        // A deleted key, and a later added one which changes the trie shape.
+        let mut update = tries.update_memtrie_only(expected_root).unwrap();
+        update.delete(&keys[50]).unwrap();
+        update.insert_memtrie_only(&[70, 1], FlatStateValue::Inlined(vec![2])).unwrap();
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let deleted_key = tries.apply_memtrie_changes(1, &changes).unwrap();
+        // An added key which is a prefix of an existing one.
+        let mut update = tries.update_memtrie_only(expected_root).unwrap();
+        update.insert_memtrie_only(&[50], FlatStateValue::Inlined(vec![3])).unwrap();
+        let added_key =
+            tries.apply_memtrie_changes(1, &update.to_memtrie_changes_only().unwrap()).unwrap();
+
+        for local_root in [changed_value, deleted_key] {
+            assert_eq!(
+                tries.first_differing_key(&local_root, &expected_root, None).unwrap(),
+                Some(keys[50].clone())
+            );
+        }
+        assert_eq!(
+            tries.first_differing_key(&added_key, &expected_root, None).unwrap(),
+            Some(vec![50])
+        );
+        assert_eq!(tries.first_differing_key(&expected_root, &expected_root, None).unwrap(), None);
+        assert_eq!(
+            tries.first_differing_key(&CryptoHash::default(), &expected_root, None).unwrap(),
+            Some(keys[0].clone())
+        );
+    }
+
+    #[test]
+    fn test_key_depth() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for key in [vec![0x20], vec![0x10], vec![0x10, 0x10], vec![0x10, 0x10, 0x10]] {
+            update.insert_memtrie_only(&key, FlatStateValue::Inlined(key.clone())).unwrap();
+        }
+        let root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+
+        // Root branch, leaf.
+        assert_eq!(tries.key_depth(&root, &[0x20]).unwrap(), Some(2));
+        // Root branch, then an extension and a branch with value per byte,
+        // ending with a leaf.
+        assert_eq!(tries.key_depth(&root, &[0x10]).unwrap(), Some(3));
+        assert_eq!(tries.key_depth(&root, &[0x10, 0x10]).unwrap(), Some(5));
+        assert_eq!(tries.key_depth(&root, &[0x10, 0x10, 0x10]).unwrap(), Some(6));
+        assert_eq!(tries.key_depth(&root, &[0x30]).unwrap(), None);
+        assert_eq!(tries.key_depth(&root, &[0x10, 0x11]).unwrap(), None);
+        assert_eq!(tries.key_depth(&CryptoHash::default(), &[0x20]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_verify_entries() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        let mut entries = Vec::new();
+        for i in 0..50u8 {
+            let key = vec![i, i.wrapping_mul(101)];
+            let value = FlatStateValue::Inlined(vec![i]);
+            entries.push((key.clone(), value.to_value_ref().hash));
+            update.insert_memtrie_only(&key, value).unwrap();
+        }
+        // A key which is a prefix of other ones.
+        let value = FlatStateValue::Inlined(vec![100]);
+        entries.push((vec![10], value.to_value_ref().hash));
+        update.insert_memtrie_only(&[10], value).unwrap();
+        let root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+        entries.shuffle(&mut rand::thread_rng());
+
+        assert_eq!(tries.verify_entries(&root, &entries, None), Ok(()));
+        // Any contiguous chunk of sorted entries is fine as well.
+        let mut sorted_entries = entries.clone();
+        sorted_entries.sort();
+        assert_eq!(tries.verify_entries(&root, &sorted_entries[10..30], None), Ok(()));
+
+        // A key absent from the trie.
+        let mut missing = sorted_entries[10..30].to_vec();
+        missing.push((vec![20, 0], CryptoHash::default()));
+        let result = tries.verify_entries(&root, &missing, None);
+        assert_eq!(result, Err(VerifyError::MissingKey(vec![20, 0])));
+        // A key absent from the entries.
+        let mut extra = sorted_entries[10..30].to_vec();
+        let (removed_key, _) = extra.remove(5);
+        assert_eq!(
+            tries.verify_entries(&root, &extra, None),
+            Err(VerifyError::ExtraValue(removed_key))
+        );
+        // A wrong value hash.
+        let mut wrong_hash = entries.clone();
+        let entry = wrong_hash.iter_mut().find(|(key, _)| key == &[10]).unwrap();
+        let actual = std::mem::replace(&mut entry.1, CryptoHash::default());
+        assert_eq!(
+            tries.verify_entries(&root, &wrong_hash, None),
+            Err(VerifyError::WrongValueHash {
+                key: vec![10],
+                expected: CryptoHash::default(),
+                actual,
+            })
+        );
+    }
+
+    #[test]
+    fn test_build_key_bloom() {
+        let mut rng = rand::thread_rng();
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        let mut keys: Vec<Vec<u8>> = (0..1000).map(|_| rng.gen::<[u8; 8]>().to_vec()).collect();
+        // Keys which are prefixes of other keys are stored in branches.
+        keys.push(keys[0][..4].to_vec());
+        for key in &keys {
+            update.insert_memtrie_only(key, FlatStateValue::Inlined(vec![1])).unwrap();
+        }
+        let root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+
+        let bloom = tries.build_key_bloom(&root, keys.len() * 16, None).unwrap();
+        for key in &keys {
+            assert!(bloom.might_contain(key));
+        }
+        // Absent keys have a different length than the present ones.
+        let false_positives =
+            (0..1000).filter(|_| bloom.might_contain(&rng.gen::<[u8; 7]>())).count();
+        assert!(false_positives < 50, "Too many false positives: {}", false_positives);
+
+        let empty_bloom = tries.build_key_bloom(&CryptoHash::default(), 64, None).unwrap();
+        assert!(!empty_bloom.might_contain(&keys[0]));
+    }
+
+    #[test]
+    fn test_serialize_all_nodes() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        // Identical subtrees under 0x1 and 0x2 are emitted once.
+        for key in [[0x10, 0x00], [0x10, 0x01], [0x20, 0x00], [0x20, 0x01], [0x30, 0x00]] {
+            update.insert_memtrie_only(&key, FlatStateValue::Inlined(vec![key[1]])).unwrap();
+        }
+        update.insert_memtrie_only(&[0x30], FlatStateValue::Inlined(vec![0x30; 100])).unwrap();
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let root = tries.apply_memtrie_changes(0, &changes).unwrap();
+
+        let mut nodes = HashMap::new();
+        let mut num_emitted = 0;
+        tries
+            .serialize_all_nodes(
+                &root,
+                |node_hash, serialized| {
+                    assert_eq!(CryptoHash::hash_bytes(serialized), node_hash);
+                    num_emitted += 1;
+                    nodes.insert(node_hash, serialized.to_vec());
+                },
+                None,
+            )
+            .unwrap();
+        assert_eq!(num_emitted, nodes.len());
+        assert!(num_emitted < changes.node_ids_with_hashes.len());
+
+        // Values are only referenced by the nodes, which is enough to
+        // reproduce the hashes.
+        fn rebuild(
+            arena: &mut STArena,
+            nodes: &HashMap<CryptoHash, Vec<u8>>,
+            node_hash: &CryptoHash,
+        ) -> MemTrieNodeId {
+            let rebuild_children = |arena: &mut STArena, children: &Children| {
+                let mut new_children = [None; 16];
+                for (i, child) in children.iter() {
+                    new_children[i as usize] = Some(rebuild(arena, nodes, child));
+                }
+                new_children
+            };
+            let node = borsh::from_slice::<RawTrieNodeWithSize>(&nodes[node_hash]).unwrap().node;
+            let value = match &node {
+                RawTrieNode::Leaf(_, value) | RawTrieNode::BranchWithValue(value, _) => {
+                    Some(FlatStateValue::Ref(*value))
+                }
+                _ => None,
+            };
+            let input = match &node {
+                RawTrieNode::Leaf(extension, _) => {
+                    InputMemTrieNode::Leaf { value: value.as_ref().unwrap(), extension }
+                }
+                RawTrieNode::Extension(extension, child) => {
+                    InputMemTrieNode::Extension { extension, child: rebuild(arena, nodes, child) }
+                }
+                RawTrieNode::BranchNoValue(children) => {
+                    InputMemTrieNode::Branch { children: rebuild_children(arena, children) }
+                }
+                RawTrieNode::BranchWithValue(_, children) => InputMemTrieNode::BranchWithValue {
+                    children: rebuild_children(arena, children),
+                    value: value.as_ref().unwrap(),
+                },
+            };
+            MemTrieNodeId::new(arena, input)
+        }
+        let mut arena = STArena::new("test".to_string());
+        let new_root = rebuild(&mut arena, &nodes, &root);
+        let rebuilt_tries =
+            MemTries::new_from_arena_and_root(ShardUId::single_shard(), 0, arena, new_root);
+        assert_eq!(rebuilt_tries.get_root(&root).unwrap().view().node_hash(), root);
+    }
+
+    #[test]
+    fn test_iter_from() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut rng = rand::thread_rng();
+        let mut expected = std::collections::BTreeMap::new();
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for _ in 0..50 {
+            let key_len = rng.gen_range(0..4);
+            let key: Vec<u8> = (0..key_len).map(|_| rng.gen_range(0..4) * 0x11).collect();
+            let value = FlatStateValue::Inlined(vec![rng.gen()]);
+            update.insert_memtrie_only(&key, value.clone()).unwrap();
+            expected.insert(key, value);
+        }
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let state_root = tries.apply_memtrie_changes(0, &changes).unwrap();
+        let expected: Vec<_> = expected.into_iter().collect();
+
+        let (entries, next_key) = tries.iter_from(&state_root, &[], usize::MAX).unwrap();
+        assert_eq!(entries, expected);
+        assert_eq!(next_key, None);
+
+        for limit in [1, 3, 7] {
+            let mut entries = Vec::new();
+            let mut start_key = Some(vec![]);
+            while let Some(key) = start_key {
+                let (chunk, next_key) = tries.iter_from(&state_root, &key, limit).unwrap();
+                assert!(chunk.len() <= limit);
+                entries.extend(chunk);
+                start_key = next_key;
+            }
+            assert_eq!(entries, expected);
+        }
+
+        // Start keys don't need to be present in the trie.
+        let (entries, _) = tries.iter_from(&state_root, &[0x12], usize::MAX).unwrap();
+        let expected_tail: Vec<_> = expected
+            .iter()
+            .filter(|(key, _)| key.as_slice() >= [0x12].as_slice())
+            .cloned()
+            .collect();
+        assert_eq!(entries, expected_tail);
+        let (entries, next_key) = tries.iter_from(&state_root, &[0xff], 1).unwrap();
+        assert!(entries.is_empty());
+        assert_eq!(next_key, None);
+    }
+
+    #[test]
+    fn test_cancel_walks() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for i in 0..1000u32 {
+            update.insert_memtrie_only(&i.to_be_bytes(), FlatStateValue::Inlined(vec![1])).unwrap();
+        }
+        let root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+
+        // Cancelling from the middle of the walk stops it at the next branch.
+        let cancel = AtomicBool::new(false);
+        let mut num_emitted = 0;
+        let result = tries.serialize_all_nodes(
+            &root,
+            |_, _| {
+                num_emitted += 1;
+                if num_emitted == 10 {
+                    cancel.store(true, Ordering::Relaxed);
+                }
+            },
+            Some(&cancel),
+        );
+        assert_eq!(result, Err(WalkError::Cancelled));
+        // Only the remaining children of the last branch may be emitted after
+        // the cancellation.
+        assert!(num_emitted <= 10 + 16, "{num_emitted} nodes emitted");
+
+        assert_eq!(
+            tries.arena_allocation_histogram(&root, Some(&cancel)),
+            Err(WalkError::Cancelled)
+        );
+        assert_eq!(
+            tries.changes_between(&CryptoHash::default(), &root, Some(&cancel)),
+            Err(WalkError::Cancelled)
+        );
+        // The walk over the old root is cancellable too.
+        assert_eq!(tries.changes_between(&root, &root, Some(&cancel)), Err(WalkError::Cancelled));
+        assert_eq!(tries.shared_node_count(&root, &root, Some(&cancel)), Err(WalkError::Cancelled));
+        assert_eq!(tries.value_stats(&root, Some(&cancel)), Err(WalkError::Cancelled));
+        assert_eq!(
+            tries.first_differing_key(&root, &CryptoHash::default(), Some(&cancel)),
+            Err(WalkError::Cancelled)
+        );
+        let value_hash = hash(&[1]);
+        assert_eq!(
+            tries.keys_referencing_value(&root, &value_hash, Some(&cancel)),
+            Err(WalkError::Cancelled)
+        );
+        assert_eq!(
+            tries.value_byte_delta(&CryptoHash::default(), &root, Some(&cancel)),
+            Err(WalkError::Cancelled)
+        );
+        let entries: Vec<_> =
+            (0..1000u32).map(|i| (i.to_be_bytes().to_vec(), value_hash)).collect();
+        assert_eq!(
+            tries.verify_entries(&root, &entries, Some(&cancel)),
+            Err(VerifyError::Cancelled)
+        );
+        assert_eq!(
+            tries.verify_canonical(&root, Some(&cancel)),
+            Err(CanonicalViolation::Cancelled)
+        );
+        assert_eq!(tries.build_key_bloom(&root, 1 << 14, Some(&cancel)), Err(WalkError::Cancelled));
+        assert_eq!(tries.shape_hash(&root, Some(&cancel)), Err(WalkError::Cancelled));
+
+        // Walks complete as long as the token isn't set.
+        cancel.store(false, Ordering::Relaxed);
+        tries.serialize_all_nodes(&root, |_, _| {}, Some(&cancel)).unwrap();
+        tries.arena_allocation_histogram(&root, Some(&cancel)).unwrap();
+        let changes = tries.changes_between(&CryptoHash::default(), &root, Some(&cancel)).unwrap();
+        assert_eq!(tries.apply_memtrie_changes(1, &changes).unwrap(), root);
+        tries.changes_between(&root, &root, Some(&cancel)).unwrap();
+        assert_eq!(
+            tries.shared_node_count(&root, &root, Some(&cancel)),
+            tries.shared_node_count(&root, &root, None)
+        );
+        assert_eq!(tries.value_stats(&root, Some(&cancel)).unwrap().references, 1000);
+        assert_eq!(
+            tries.first_differing_key(&root, &CryptoHash::default(), Some(&cancel)),
+            Ok(Some(0u32.to_be_bytes().to_vec()))
+        );
+        assert_eq!(
+            tries.keys_referencing_value(&root, &value_hash, Some(&cancel)).unwrap().len(),
+            1000
+        );
+        assert_eq!(tries.value_byte_delta(&CryptoHash::default(), &root, Some(&cancel)), Ok(1000));
+        assert_eq!(tries.verify_entries(&root, &entries, Some(&cancel)), Ok(()));
+        assert_eq!(tries.verify_canonical(&root, Some(&cancel)), Ok(()));
+        tries.build_key_bloom(&root, 1 << 14, Some(&cancel)).unwrap();
+        assert_eq!(tries.shape_hash(&root, Some(&cancel)), tries.shape_hash(&root, None));
+    }
+
+    #[test]
+    fn test_keys_referencing_value() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let shared_value = vec![7u8; 100];
+        let value_hash = hash(&shared_value);
+        let keys = vec![vec![0x00], vec![0x10], vec![0x10, 0x00], vec![0x23, 0x45]];
+        let mut update = tries.update(CryptoHash::default(), TrackingMode::Refcounts).unwrap();
+        for key in &keys {
+            update.insert(key, shared_value.clone()).unwrap();
+        }
+        update.insert(&[0x11], vec![1, 2, 3]).unwrap();
+        let changes = update.to_trie_changes().unwrap().memtrie_changes.unwrap();
+        let root = tries.apply_memtrie_changes(0, &changes).unwrap();
+
+        assert_eq!(tries.keys_referencing_value(&root, &value_hash, None).unwrap(), keys);
+        assert_eq!(
+            tries.keys_referencing_value(&root, &hash(&[1, 2, 3]), None).unwrap(),
+            vec![vec![0x11]]
+        );
+        assert!(tries.keys_referencing_value(&root, &hash(&[0]), None).unwrap().is_empty());
+        let no_keys =
+            tries.keys_referencing_value(&CryptoHash::default(), &value_hash, None).unwrap();
+        assert!(no_keys.is_empty());
+
+        let mut update = tries.update(root, TrackingMode::Refcounts).unwrap();
+        update.delete_many(keys.iter().map(|key| key.as_slice())).unwrap();
+        let trie_changes = update.to_trie_changes().unwrap();
+        let deleted: HashMap<CryptoHash, u32> = trie_changes
+            .deletions()
+            .iter()
+            .map(|deletion| (deletion.trie_node_or_value_hash, deletion.rc.get()))
+            .collect();
+        assert_eq!(deleted.get(&value_hash), Some(&4));
+        let changes = trie_changes.memtrie_changes.unwrap();
+        let new_root = tries.apply_memtrie_changes(1, &changes).unwrap();
+        assert!(tries.keys_referencing_value(&new_root, &value_hash, None).unwrap().is_empty());
+        assert_eq!(tries.keys(&new_root).unwrap().collect::<Vec<_>>(), vec![vec![0x11]]);
+    }
+
+    #[test]
+    fn test_keys() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        assert_eq!(tries.keys(&CryptoHash::default()).unwrap().count(), 0);
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for i in 0..300u32 {
+            let key = (i * 7919 % 1000).to_be_bytes()[2..].to_vec();
+            update.insert_memtrie_only(&key, FlatStateValue::Inlined(vec![1])).unwrap();
+        }
+        // Keys terminating at branches with values, inlined and not.
+        update.insert_memtrie_only(&[0x01], FlatStateValue::Inlined(vec![2])).unwrap();
+        update.insert_memtrie_only(&[0x02], FlatStateValue::on_disk(&[3; 5000])).unwrap();
+        let root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+
+        let (entries, _) = tries.iter_from(&root, &[], usize::MAX).unwrap();
+        let expected_keys = entries.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+        assert!(expected_keys.contains(&vec![0x01]));
+        assert!(expected_keys.contains(&vec![0x02]));
+        let num_derefs = || NUM_VALUE_DEREFS.with(|num_derefs| num_derefs.get());
+        let num_derefs_before = num_derefs();
+        let keys = tries.keys(&root).unwrap().collect::<Vec<_>>();
+        assert_eq!(num_derefs(), num_derefs_before);
+        assert_eq!(keys, expected_keys);
+    }
+
+    #[test]
+    fn test_witness_for_reads() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut values = HashMap::new();
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for i in 0..100u32 {
+            let key = i.to_be_bytes().to_vec();
+            let value = if i % 10 == 0 { vec![i as u8; 5000] } else { vec![i as u8] };
+            update.insert_memtrie_only(&key, FlatStateValue::on_disk(&value)).unwrap();
+            values.insert(key, value);
+        }
+        let root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+
+        // Present keys, with inlined and referenced values, an absent key
+        // and a repeated key.
+        let read_keys: Vec<Vec<u8>> =
+            [3u32, 10, 57, 200, 3].iter().map(|i| i.to_be_bytes().to_vec()).collect();
+        let accesses = tries.witness_for_reads(&root, &read_keys).unwrap();
+        assert_eq!(accesses.values.len(), 3);
+        assert_eq!(accesses, tries.witness_for_reads(&root, &read_keys[..4]).unwrap());
+
+        // Bytes of values which aren't inlined come from the disk.
+        let mut witness: Vec<Arc<[u8]>> = accesses.nodes.values().cloned().collect();
+        for value in accesses.values.values() {
+            let bytes = match value {
+                FlatStateValue::Inlined(bytes) => bytes.clone(),
+                FlatStateValue::Ref(value_ref) => {
+                    values.values().find(|value| hash(value) == value_ref.hash).unwrap().clone()
+                }
+            };
+            witness.push(bytes.into());
+        }
+        let trie = Trie::from_recorded_storage(
+            PartialStorage { nodes: PartialState::TrieValues(witness) },
+            root,
+            false,
+        );
+        for key in &read_keys {
+            assert_eq!(trie.get(key).unwrap().as_ref(), values.get(key));
+        }
+        // Keys which weren't read can't be proven.
+        assert_matches!(trie.get(&58u32.to_be_bytes()), Err(StorageError::MissingTrieValue(_, _)));
+        let empty_accesses = tries.witness_for_reads(&CryptoHash::default(), &read_keys).unwrap();
+        assert_eq!(empty_accesses, Default::default());
+    }
+
+    #[test]
+    fn test_rebased_root() {
+        let entries: Vec<(Vec<u8>, Vec<u8>)> = [
+            &[0xaa][..],
+            &[0xaa, 0x01],
+            &[0xaa, 0x02],
+            &[0xaa, 0x02, 0x03],
+            &[0xbb, 0x01, 0x02],
+            &[0xcc, 0x01, 0x02, 0x03],
+            &[0xcc, 0x01, 0x02, 0x04],
+            &[0xcd, 0x00],
+        ]
+        .iter()
+        .map(|key| (key.to_vec(), [key, &[0x05; 50][..]].concat()))
+        .collect();
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for (key, value) in &entries {
+            update.insert_memtrie_only(key, FlatStateValue::on_disk(value)).unwrap();
+        }
+        let root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+        assert_eq!(compute_trie_root(&entries), root);
+
+        // Prefixes ending at a branch, inside of a leaf, inside of an
+        // extension, at a key and at no key.
+        for prefix in [
+            &[][..],
+            &[0xaa],
+            &[0xaa, 0x02],
+            &[0xbb],
+            &[0xbb, 0x01],
+            &[0xcc],
+            &[0xcc, 0x01],
+            &[0xcd, 0x00],
+            &[0xdd],
+            &[0xbb, 0x02],
+            &[0xcc, 0x01, 0x02, 0x03, 0x04],
+        ] {
+            let rebased_entries: Vec<_> = entries
+                .iter()
+                .filter_map(|(key, value)| {
+                    Some((key.strip_prefix(prefix)?.to_vec(), value.clone()))
+                })
+                .collect();
+            let expected =
+                (!rebased_entries.is_empty()).then(|| compute_trie_root(&rebased_entries));
+            assert_eq!(tries.rebased_root(&root, prefix).unwrap(), expected, "{:?}", prefix);
+        }
+        assert_eq!(tries.rebased_root(&CryptoHash::default(), &[0xaa]).unwrap(), None);
+    }
+
+    #[test]
+    fn test_verify_canonical() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        assert_eq!(tries.verify_canonical(&CryptoHash::default(), None), Ok(()));
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for key in [&[0x00][..], &[0x00, 0x01], &[0x12, 0x34], &[0x12, 0x35], &[0x20]] {
+            update.insert_memtrie_only(key, FlatStateValue::Inlined(key.to_vec())).unwrap();
+        }
+        let root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+        assert_eq!(tries.verify_canonical(&root, None), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_canonical_violations() {
+        fn insert_root(tries: &mut MemTries, input: InputMemTrieNode) -> CryptoHash {
+            let root = MemTrieNodeId::new(&mut tries.arena, input);
+            let state_root = root.as_ptr(tries.arena.memory()).view().node_hash();
+            tries.insert_root(state_root, root, 0);
+            state_root
+        }
+
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let value = FlatStateValue::Inlined(vec![1]);
+        let leaf_extension = NibbleSlice::new(&[0x01]).encoded(true);
+        let extension = NibbleSlice::new(&[0x23]).encoded(false);
+        let empty_extension = NibbleSlice::new(&[]).encoded(false);
+        let leaf = MemTrieNodeId::new(
+            &mut tries.arena,
+            InputMemTrieNode::Leaf { value: &value, extension: &leaf_extension },
+        );
+        let mut children = [None; 16];
+        children[1] = Some(leaf);
+        children[2] = Some(leaf);
+        let branch = MemTrieNodeId::new(&mut tries.arena, InputMemTrieNode::Branch { children });
+
+        // Violations below a valid branch are reported with their path.
+        let bad_extension = MemTrieNodeId::new(
+            &mut tries.arena,
+            InputMemTrieNode::Extension { extension: &extension, child: leaf },
+        );
+        let mut children = [None; 16];
+        children[3] = Some(branch);
+        children[5] = Some(bad_extension);
+        let root = insert_root(&mut tries, InputMemTrieNode::Branch { children });
+        assert_eq!(
+            tries.verify_canonical(&root, None),
+            Err(CanonicalViolation::ExtensionOverLeaf { path: vec![5] })
+        );
+
+        let root = insert_root(
+            &mut tries,
+            InputMemTrieNode::Extension { extension: &extension, child: bad_extension },
+        );
+        assert_eq!(
+            tries.verify_canonical(&root, None),
+            Err(CanonicalViolation::ExtensionOverExtension { path: vec![] })
+        );
+
+        let root = insert_root(
+            &mut tries,
+            InputMemTrieNode::Extension { extension: &empty_extension, child: branch },
+        );
+        assert_eq!(
+            tries.verify_canonical(&root, None),
+            Err(CanonicalViolation::EmptyExtension { path: vec![] })
+        );
+
+        let mut children = [None; 16];
+        children[4] = Some(leaf);
+        let single_child_branch =
+            MemTrieNodeId::new(&mut tries.arena, InputMemTrieNode::Branch { children });
+        let root = insert_root(
+            &mut tries,
+            InputMemTrieNode::Extension { extension: &extension, child: single_child_branch },
+        );
+        assert_eq!(
+            tries.verify_canonical(&root, None),
+            Err(CanonicalViolation::DegenerateBranch { path: vec![2, 3] })
+        );
+
+        let root = insert_root(
+            &mut tries,
+            InputMemTrieNode::BranchWithValue { children: [None; 16], value: &value },
+        );
+        assert_eq!(
+            tries.verify_canonical(&root, None),
+            Err(CanonicalViolation::DegenerateBranch { path: vec![] })
+        );
+    }
+
+    #[test]
+    fn test_apply_memtrie_changes_with_observer() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for i in 0..100u32 {
+            update.insert_memtrie_only(&i.to_be_bytes(), FlatStateValue::Inlined(vec![1])).unwrap();
+        }
+        let root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+
+        let mut update = tries.update_memtrie_only(root).unwrap();
+        update.insert_memtrie_only(&[0, 0, 0, 1], FlatStateValue::Inlined(vec![2; 100])).unwrap();
+        update.insert_memtrie_only(&[0, 0, 0, 1, 0], FlatStateValue::Inlined(vec![3])).unwrap();
+        update.insert_memtrie_only(&[0, 0], FlatStateValue::Inlined(vec![4])).unwrap();
+        let changes = update.to_memtrie_changes_only().unwrap();
+        let num_allocs_before = tries.arena.num_active_allocs();
+        let allocs_bytes_before = tries.arena.active_allocs_bytes();
+        let mut events = Vec::new();
+        tries
+            .apply_memtrie_changes_with_observer(1, &changes, |kind, size| {
+                events.push((kind, size));
+            })
+            .unwrap();
+
+        assert_eq!(events.len(), changes.node_ids_with_hashes.len());
+        assert_eq!(events.len(), tries.arena.num_active_allocs() - num_allocs_before);
+        assert_eq!(
+            events.iter().map(|(_, size)| size).sum::<usize>(),
+            tries.arena.active_allocs_bytes() - allocs_bytes_before
+        );
+        // The key under 0x00000001 turns its leaf into a branch with a value
+        // and a new leaf, and 0x0000 is stored in a branch as well.
+        for kind in [NodeKind::Leaf, NodeKind::BranchWithValue] {
+            assert!(events.iter().any(|(event_kind, _)| *event_kind == kind));
+        }
+    }
 }
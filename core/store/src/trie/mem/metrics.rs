@@ -1,3 +1,5 @@
+#[cfg(feature = "memtrie_timing")]
+use near_o11y::metrics::{exponential_buckets, try_create_histogram_vec, HistogramVec};
 use near_o11y::metrics::{
     try_create_int_counter, try_create_int_counter_vec, try_create_int_gauge_vec, IntCounter,
     IntCounterVec, IntGaugeVec,
@@ -29,3 +31,24 @@ pub static MEMTRIE_NUM_LOOKUPS: LazyLock<IntCounter> = LazyLock::new(|| {
     )
     .unwrap()
 });
+
+pub static MEM_TRIE_SQUASH_OPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    try_create_int_counter_vec(
+        "near_memtrie_squash_ops",
+        "Number of node transformations performed when squashing in-memory trie updates",
+        &["shard_uid", "kind"],
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "memtrie_timing")]
+pub static MEM_TRIE_UPDATE_PHASE_SECONDS: LazyLock<HistogramVec> = LazyLock::new(|| {
+    try_create_histogram_vec(
+        "near_memtrie_update_phase_seconds",
+        "Time in-memory trie updates spend in each phase: applying inserts and deletes, \
+         ordering updated nodes and hashing them, observed once per update",
+        &["shard_uid", "phase"],
+        Some(exponential_buckets(0.000001, 2.0, 24).unwrap()),
+    )
+    .unwrap()
+});
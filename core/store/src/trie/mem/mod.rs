@@ -1,7 +1,9 @@
 mod arena;
+pub mod bloom;
 mod construction;
 pub(crate) mod flexible_data;
 mod freelist;
+pub mod hasher;
 pub mod iter;
 pub mod loading;
 mod lookup;
@@ -12,6 +14,8 @@ pub mod metrics;
 pub(crate) mod nibbles_utils;
 pub mod node;
 mod parallel_loader;
+pub mod reconstruction;
+pub mod sparse;
 
 /// Check this, because in the code we conveniently assume usize is 8 bytes.
 /// In-memory trie can't possibly work under 32-bit anyway.
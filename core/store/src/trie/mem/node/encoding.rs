@@ -5,6 +5,7 @@ use crate::trie::mem::flexible_data::encoding::{BorshFixedSize, RawDecoder, RawE
 use crate::trie::mem::flexible_data::extension::EncodedExtensionHeader;
 use crate::trie::mem::flexible_data::value::EncodedValueHeader;
 use crate::trie::mem::flexible_data::FlexibleDataHeader;
+use crate::trie::mem::hasher::Hasher;
 use borsh::{BorshDeserialize, BorshSerialize};
 use near_primitives::hash::CryptoHash;
 use std::mem::size_of;
@@ -13,7 +14,7 @@ use smallvec::SmallVec;
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug, BorshSerialize, BorshDeserialize)]
 #[borsh(use_discriminant = true)]
-pub(crate) enum NodeKind {
+pub enum NodeKind {
     Leaf = 0,
     Extension = 1,
     Branch = 2,
@@ -109,9 +110,35 @@ impl BorshFixedSize for BranchWithValueHeader {
         + EncodedChildrenHeader::SERIALIZED_SIZE;
 }
 
+impl InputMemTrieNode<'_> {
+    /// Returns the number of bytes the node takes in the arena once encoded.
+    pub(crate) fn encoded_size(&self) -> usize {
+        match self {
+            Self::Leaf { value, extension } => {
+                LeafHeader::SERIALIZED_SIZE
+                    + EncodedExtensionHeader::from_input(extension).flexible_data_length()
+                    + EncodedValueHeader::from_input(value).flexible_data_length()
+            }
+            Self::Extension { extension, .. } => {
+                ExtensionHeader::SERIALIZED_SIZE
+                    + EncodedExtensionHeader::from_input(extension).flexible_data_length()
+            }
+            Self::Branch { children } => {
+                BranchHeader::SERIALIZED_SIZE
+                    + EncodedChildrenHeader::from_input(children).flexible_data_length()
+            }
+            Self::BranchWithValue { children, value } => {
+                BranchWithValueHeader::SERIALIZED_SIZE
+                    + EncodedChildrenHeader::from_input(children).flexible_data_length()
+                    + EncodedValueHeader::from_input(value).flexible_data_length()
+            }
+        }
+    }
+}
+
 impl MemTrieNodeId {
     /// Encodes the data.
-    pub(crate) fn new_impl(
+    pub(crate) fn new_impl<H: Hasher>(
         arena: &mut impl ArenaMut,
         node: InputMemTrieNode,
         node_hash: Option<CryptoHash>,
@@ -135,11 +162,12 @@ impl MemTrieNodeId {
             }
             _ => {}
         }
+        let encoded_size = node.encoded_size();
         // Prepare the raw node, for memory usage and hash computation.
         let raw_node_with_size = if matches!(&node, InputMemTrieNode::Leaf { .. }) {
             None
         } else {
-            Some(node.to_raw_trie_node_with_size_non_leaf(arena.memory()))
+            Some(node.to_raw_trie_node_with_size_non_leaf_with::<H, _>(arena.memory()))
         };
 
         // Finally, encode the data.
@@ -173,7 +201,9 @@ impl MemTrieNodeId {
                     common: CommonHeader { refcount: 0, kind: NodeKind::Extension },
                     nonleaf: NonLeafHeader::new(
                         raw_node_with_size.memory_usage,
-                        node_hash.unwrap_or_else(|| raw_node_with_size.hash()),
+                        node_hash.unwrap_or_else(|| {
+                            H::hash(&borsh::to_vec(&raw_node_with_size).unwrap())
+                        }),
                     ),
                     child: child.pos,
                     extension: extension_header,
@@ -192,7 +222,9 @@ impl MemTrieNodeId {
                     common: CommonHeader { refcount: 0, kind: NodeKind::Branch },
                     nonleaf: NonLeafHeader::new(
                         raw_node_with_size.memory_usage,
-                        node_hash.unwrap_or_else(|| raw_node_with_size.hash()),
+                        node_hash.unwrap_or_else(|| {
+                            H::hash(&borsh::to_vec(&raw_node_with_size).unwrap())
+                        }),
                     ),
                     children: children_header,
                 });
@@ -213,7 +245,9 @@ impl MemTrieNodeId {
                     common: CommonHeader { refcount: 0, kind: NodeKind::BranchWithValue },
                     nonleaf: NonLeafHeader::new(
                         raw_node_with_size.memory_usage,
-                        node_hash.unwrap_or_else(|| raw_node_with_size.hash()),
+                        node_hash.unwrap_or_else(|| {
+                            H::hash(&borsh::to_vec(&raw_node_with_size).unwrap())
+                        }),
                     ),
                     children: children_header,
                     value: value_header,
@@ -223,6 +257,7 @@ impl MemTrieNodeId {
                 data.finish()
             }
         };
+        debug_assert_eq!(data.len(), encoded_size);
         Self { pos: data.raw_pos() }
     }
 
@@ -325,9 +360,13 @@ impl<'a, M: ArenaMemory> MemTrieNodePtr<'a, M> {
         }
     }
 
+    pub(crate) fn node_kind(&self) -> NodeKind {
+        self.decoder().peek::<CommonHeader>().kind
+    }
+
     /// Calculates the size of the allocation with only a pointer to the start
     /// of the trie node's allocation.
-    fn size_of_allocation(&self) -> usize {
+    pub(crate) fn size_of_allocation(&self) -> usize {
         let mut decoder = self.decoder();
         let kind = decoder.peek::<CommonHeader>().kind;
         match kind {
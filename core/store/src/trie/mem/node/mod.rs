@@ -1,6 +1,7 @@
 use super::arena::{ArenaMemory, ArenaMut, ArenaPos, ArenaPtr};
 use super::flexible_data::children::ChildrenView;
 use super::flexible_data::value::ValueView;
+use super::hasher::{Hasher, Sha256Hasher};
 use crate::trie::{Children, TRIE_COSTS};
 use crate::{RawTrieNode, RawTrieNodeWithSize};
 use derive_where::derive_where;
@@ -13,6 +14,8 @@ mod encoding;
 mod tests;
 mod view;
 
+pub use encoding::NodeKind;
+
 /// The memory position of an encoded in-memory trie node.
 /// With an `ArenaMemory`, this can be turned into a `MemTrieNodePtr`
 /// to access to the node's contents.
@@ -29,7 +32,12 @@ pub struct MemTrieNodeId {
 
 impl MemTrieNodeId {
     pub fn new(arena: &mut impl ArenaMut, input: InputMemTrieNode) -> Self {
-        Self::new_impl(arena, input, None)
+        Self::new_impl::<Sha256Hasher>(arena, input, None)
+    }
+
+    /// Same as `new`, but hashes the node with the given hasher.
+    pub fn new_with_hasher<H: Hasher>(arena: &mut impl ArenaMut, input: InputMemTrieNode) -> Self {
+        Self::new_impl::<H>(arena, input, None)
     }
 
     pub fn new_with_hash(
@@ -37,7 +45,7 @@ impl MemTrieNodeId {
         input: InputMemTrieNode,
         hash: CryptoHash,
     ) -> Self {
-        Self::new_impl(arena, input, Some(hash))
+        Self::new_impl::<Sha256Hasher>(arena, input, Some(hash))
     }
 
     pub fn as_ptr<'a, M: ArenaMemory>(&self, arena: &'a M) -> MemTrieNodePtr<'a, M> {
@@ -124,6 +132,15 @@ impl<'a> InputMemTrieNode<'a> {
     pub fn to_raw_trie_node_with_size_non_leaf<Memory: ArenaMemory>(
         &self,
         arena: &Memory,
+    ) -> RawTrieNodeWithSize {
+        self.to_raw_trie_node_with_size_non_leaf_with::<Sha256Hasher, Memory>(arena)
+    }
+
+    /// Same as `to_raw_trie_node_with_size_non_leaf`, for tries hashed with
+    /// the given hasher.
+    pub fn to_raw_trie_node_with_size_non_leaf_with<H: Hasher, Memory: ArenaMemory>(
+        &self,
+        arena: &Memory,
     ) -> RawTrieNodeWithSize {
         match self {
             Self::Leaf { .. } => {
@@ -134,7 +151,7 @@ impl<'a> InputMemTrieNode<'a> {
                 let memory_usage = TRIE_COSTS.node_cost
                     + extension.len() as u64 * TRIE_COSTS.byte_of_key
                     + view.memory_usage();
-                let node = RawTrieNode::Extension(extension.to_vec(), view.node_hash());
+                let node = RawTrieNode::Extension(extension.to_vec(), view.node_hash_with::<H>());
                 RawTrieNodeWithSize { node, memory_usage }
             }
             Self::Branch { children, .. } => {
@@ -143,7 +160,7 @@ impl<'a> InputMemTrieNode<'a> {
                 for (i, child) in children.iter().enumerate() {
                     if let Some(child) = child {
                         let view = child.as_ptr(arena).view();
-                        hashes[i] = Some(view.node_hash());
+                        hashes[i] = Some(view.node_hash_with::<H>());
                         memory_usage += view.memory_usage();
                     }
                 }
@@ -162,7 +179,7 @@ impl<'a> InputMemTrieNode<'a> {
                 for (i, child) in children.iter().enumerate() {
                     if let Some(child) = child {
                         let view = child.as_ptr(arena).view();
-                        hashes[i] = Some(view.node_hash());
+                        hashes[i] = Some(view.node_hash_with::<H>());
                         memory_usage += view.memory_usage();
                     }
                 }
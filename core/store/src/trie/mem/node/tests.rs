@@ -1,5 +1,6 @@
 use crate::trie::mem::arena::single_thread::STArena;
 use crate::trie::mem::arena::Arena;
+use crate::trie::mem::hasher::{FnvHasher, Hasher};
 use crate::trie::mem::node::{InputMemTrieNode, MemTrieNodeId, MemTrieNodeView};
 use crate::trie::Children;
 use crate::{RawTrieNode, RawTrieNodeWithSize};
@@ -277,3 +278,26 @@ fn test_basic_branch_with_value_node() {
         _ => panic!("Unexpected view type: {:?}", node_ptr.view()),
     }
 }
+
+#[test]
+fn test_node_with_custom_hasher() {
+    let mut arena = STArena::new("".to_owned());
+    let child = MemTrieNodeId::new(
+        &mut arena,
+        InputMemTrieNode::Leaf { extension: &[0, 1], value: &FlatStateValue::Inlined(vec![2, 3]) },
+    );
+    let node = MemTrieNodeId::new_with_hasher::<FnvHasher>(
+        &mut arena,
+        InputMemTrieNode::Extension { extension: &[4, 5], child },
+    );
+    let child_view = child.as_ptr(arena.memory()).view();
+    let view = node.as_ptr(arena.memory()).view();
+    let child_hash = child_view.node_hash_with::<FnvHasher>();
+    assert_ne!(child_hash, child_view.node_hash());
+    let expected_raw_node = RawTrieNodeWithSize {
+        node: RawTrieNode::Extension(vec![4, 5], child_hash),
+        memory_usage: view.memory_usage(),
+    };
+    assert_eq!(view.node_hash(), FnvHasher::hash(&borsh::to_vec(&expected_raw_node).unwrap()));
+    assert_eq!(view.node_hash_with::<FnvHasher>(), view.node_hash());
+}
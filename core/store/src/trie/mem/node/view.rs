@@ -1,16 +1,22 @@
 use super::{MemTrieNodePtr, MemTrieNodeView};
 use crate::trie::mem::arena::ArenaMemory;
+use crate::trie::mem::hasher::{Hasher, Sha256Hasher};
 use crate::trie::TRIE_COSTS;
 use crate::{RawTrieNode, RawTrieNodeWithSize};
-use near_primitives::hash::{hash, CryptoHash};
+use near_primitives::hash::CryptoHash;
 
 impl<'a, M: ArenaMemory> MemTrieNodeView<'a, M> {
     /// Returns the node's hash. Requires that the hash is already computed.
     pub fn node_hash(&self) -> CryptoHash {
+        self.node_hash_with::<Sha256Hasher>()
+    }
+
+    /// Same as `node_hash`, for tries hashed with the given hasher.
+    pub fn node_hash_with<H: Hasher>(&self) -> CryptoHash {
         match self {
             Self::Leaf { .. } => {
                 let node = self.clone().to_raw_trie_node_with_size();
-                hash(&borsh::to_vec(&node).unwrap())
+                H::hash(&borsh::to_vec(&node).unwrap())
             }
             Self::Extension { hash, .. }
             | Self::Branch { hash, .. }
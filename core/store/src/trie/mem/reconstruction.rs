@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+
+use near_primitives::errors::StorageError;
+use near_primitives::hash::{hash, CryptoHash};
+use near_primitives::state::FlatStateValue;
+
+use super::arena::ArenaMut;
+use super::hasher::Sha256Hasher;
+use super::memtrie_update::{
+    to_input_node, try_hash_updated_nodes, OldOrUpdatedNodeId, UpdatedMemTrieNode,
+    UpdatedMemTrieNodeWithSize,
+};
+use super::node::MemTrieNodeId;
+use crate::{RawTrieNode, RawTrieNodeWithSize};
+
+/// Builds an in-memory trie from serialized `RawTrieNodeWithSize` nodes
+/// delivered one at a time, e.g. while state is downloaded.
+///
+/// Nodes refer to their children by hash, so they may arrive in any order:
+/// a node is allocated in the arena as soon as all of its children are, and
+/// kept serialized until then. Values are only known by reference, so they
+/// are never inlined into the new nodes.
+pub struct MemTrieReconstructor<'a, A: ArenaMut> {
+    arena: &'a mut A,
+    root: CryptoHash,
+    /// Nodes allocated in the arena so far.
+    built: HashMap<CryptoHash, MemTrieNodeId>,
+    /// Delivered nodes which are not allocated yet, with the number of their
+    /// distinct children which are not allocated yet.
+    pending: HashMap<CryptoHash, (RawTrieNode, usize)>,
+    /// Pending nodes waiting for each missing child.
+    waiting_parents: HashMap<CryptoHash, Vec<CryptoHash>>,
+}
+
+impl<'a, A: ArenaMut> MemTrieReconstructor<'a, A> {
+    pub fn new(arena: &'a mut A, root: CryptoHash) -> Self {
+        Self {
+            arena,
+            root,
+            built: HashMap::new(),
+            pending: HashMap::new(),
+            waiting_parents: HashMap::new(),
+        }
+    }
+
+    /// Accepts the next serialized node. Returns the root once all the nodes
+    /// under it were delivered. Nodes delivered several times are ignored.
+    pub fn add_node(&mut self, serialized: &[u8]) -> Result<Option<MemTrieNodeId>, StorageError> {
+        let node_hash = hash(serialized);
+        if self.built.contains_key(&node_hash) || self.pending.contains_key(&node_hash) {
+            return Ok(self.root());
+        }
+        let node = borsh::from_slice::<RawTrieNodeWithSize>(serialized)
+            .map_err(|err| {
+                StorageError::StorageInconsistentState(format!(
+                    "Failed to decode trie node {}: {}",
+                    node_hash, err
+                ))
+            })?
+            .node;
+        let mut missing_children = child_hashes(&node);
+        missing_children.sort();
+        missing_children.dedup();
+        missing_children.retain(|child_hash| !self.built.contains_key(child_hash));
+        for child_hash in &missing_children {
+            self.waiting_parents.entry(*child_hash).or_default().push(node_hash);
+        }
+        let num_missing_children = missing_children.len();
+        self.pending.insert(node_hash, (node, num_missing_children));
+        if num_missing_children == 0 {
+            self.build(node_hash)?;
+        }
+        Ok(self.root())
+    }
+
+    /// Returns the root if all the nodes under it were delivered.
+    pub fn root(&self) -> Option<MemTrieNodeId> {
+        self.built.get(&self.root).copied()
+    }
+
+    /// Returns the root, failing if some nodes under it were not delivered.
+    pub fn finish(self) -> Result<MemTrieNodeId, StorageError> {
+        self.root().ok_or_else(|| {
+            StorageError::StorageInconsistentState(format!(
+                "Incomplete delivery of trie {}: {} nodes are waiting for {} missing nodes",
+                self.root,
+                self.pending.len(),
+                self.waiting_parents
+                    .keys()
+                    .filter(|child_hash| !self.pending.contains_key(*child_hash))
+                    .count()
+            ))
+        })
+    }
+
+    /// Allocates the given pending node, whose children are all allocated,
+    /// then the pending nodes which were only waiting for it, and so on.
+    /// Nodes are hashed before being allocated, so that nodes which don't
+    /// match their hash are never allocated.
+    fn build(&mut self, node_hash: CryptoHash) -> Result<(), StorageError> {
+        let mut ready = vec![node_hash];
+        while let Some(node_hash) = ready.pop() {
+            let (node, _) = self.pending.remove(&node_hash).unwrap();
+            let updated_nodes = [Some(self.to_updated_node(&node))];
+            let memory = self.arena.memory();
+            let (_, actual_hash, _) = try_hash_updated_nodes::<Sha256Hasher>(
+                &[0],
+                &updated_nodes,
+                |child| match child {
+                    OldOrUpdatedNodeId::Old(child_id) => {
+                        Some(child_id.as_ptr(memory).view().node_hash())
+                    }
+                    OldOrUpdatedNodeId::Updated(_) => None,
+                },
+            )?
+            .remove(0);
+            if actual_hash != node_hash {
+                return Err(StorageError::StorageInconsistentState(format!(
+                    "Trie node {} was reconstructed with hash {}",
+                    node_hash, actual_hash
+                )));
+            }
+            let node = &updated_nodes[0].as_ref().unwrap().node;
+            let input = to_input_node(node, |child| match child {
+                OldOrUpdatedNodeId::Old(child_id) => child_id,
+                OldOrUpdatedNodeId::Updated(_) => unreachable!("Children are allocated first"),
+            });
+            let node_id = MemTrieNodeId::new_with_hash(self.arena, input, node_hash);
+            self.built.insert(node_hash, node_id);
+            for parent_hash in self.waiting_parents.remove(&node_hash).unwrap_or_default() {
+                let (_, num_missing_children) = self.pending.get_mut(&parent_hash).unwrap();
+                *num_missing_children -= 1;
+                if *num_missing_children == 0 {
+                    ready.push(parent_hash);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Converts the given pending node, whose children are all allocated, to
+    /// an updated node referring to them as old nodes.
+    fn to_updated_node(&self, node: &RawTrieNode) -> UpdatedMemTrieNodeWithSize {
+        let memory = self.arena.memory();
+        let mut memory_usage = 0;
+        let mut to_child = |child_hash: &CryptoHash| {
+            let child_id = self.built[child_hash];
+            memory_usage += child_id.as_ptr(memory).view().memory_usage();
+            OldOrUpdatedNodeId::Old(child_id)
+        };
+        let node = match node {
+            RawTrieNode::Leaf(extension, value) => UpdatedMemTrieNode::Leaf {
+                extension: extension.as_slice().into(),
+                value: FlatStateValue::Ref(*value),
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value: None,
+            },
+            RawTrieNode::Extension(extension, child_hash) => UpdatedMemTrieNode::Extension {
+                extension: extension.as_slice().into(),
+                child: to_child(child_hash),
+            },
+            RawTrieNode::BranchNoValue(raw_children)
+            | RawTrieNode::BranchWithValue(_, raw_children) => {
+                let mut children = Box::<[Option<OldOrUpdatedNodeId>; 16]>::default();
+                for (i, child_hash) in raw_children.iter() {
+                    children[i as usize] = Some(to_child(child_hash));
+                }
+                let value = match node {
+                    RawTrieNode::BranchWithValue(value, _) => Some(FlatStateValue::Ref(*value)),
+                    _ => None,
+                };
+                UpdatedMemTrieNode::Branch {
+                    children,
+                    value,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value: None,
+                }
+            }
+        };
+        memory_usage += node.memory_usage_direct();
+        UpdatedMemTrieNodeWithSize { node, memory_usage }
+    }
+}
+
+fn child_hashes(node: &RawTrieNode) -> Vec<CryptoHash> {
+    match node {
+        RawTrieNode::Leaf(..) => vec![],
+        RawTrieNode::Extension(_, child_hash) => vec![*child_hash],
+        RawTrieNode::BranchNoValue(children) | RawTrieNode::BranchWithValue(_, children) => {
+            children.iter().map(|(_, child_hash)| *child_hash).collect()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assert_matches::assert_matches;
+    use near_primitives::errors::StorageError;
+    use near_primitives::hash::CryptoHash;
+    use near_primitives::shard_layout::ShardUId;
+    use near_primitives::state::FlatStateValue;
+    use rand::seq::SliceRandom;
+
+    use super::MemTrieReconstructor;
+    use crate::trie::mem::arena::single_thread::STArena;
+    use crate::trie::mem::memtries::MemTries;
+    use crate::RawTrieNodeWithSize;
+
+    #[test]
+    fn test_reconstruct_in_random_order() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        let mut keys = Vec::new();
+        for i in 0..200u32 {
+            let key = (i * 7919 % 1000).to_be_bytes()[1..].to_vec();
+            // Identical leaves under a branch are shared by the new trie.
+            let value = FlatStateValue::on_disk(&[i as u8 % 4; 100]);
+            update.insert_memtrie_only(&key, value).unwrap();
+            keys.push(key);
+        }
+        let root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+        let mut nodes = Vec::new();
+        tries.serialize_all_nodes(&root, |_, node| nodes.push(node.to_vec()), None).unwrap();
+        nodes.shuffle(&mut rand::thread_rng());
+
+        let mut arena = STArena::new("test".to_string());
+        let mut reconstructor = MemTrieReconstructor::new(&mut arena, root);
+        let (last_node, nodes) = nodes.split_last().unwrap();
+        for node in nodes {
+            assert_eq!(reconstructor.add_node(node).unwrap(), None);
+        }
+        // Duplicates are ignored.
+        assert_eq!(reconstructor.add_node(&nodes[0]).unwrap(), None);
+        let new_root = reconstructor.add_node(last_node).unwrap().unwrap();
+        assert_eq!(reconstructor.finish().unwrap(), new_root);
+
+        let new_tries =
+            MemTries::new_from_arena_and_root(ShardUId::single_shard(), 0, arena, new_root);
+        assert_eq!(new_tries.get_root(&root).unwrap().view().node_hash(), root);
+        for key in &keys {
+            let value = tries.lookup(&root, key, None).unwrap().unwrap().to_flat_value();
+            let new_value = new_tries.lookup(&root, key, None).unwrap().unwrap().to_flat_value();
+            assert_eq!(new_value.to_value_ref(), value.to_value_ref());
+        }
+    }
+
+    #[test]
+    fn test_reconstruct_incomplete() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for key in [[0x00], [0x10], [0x11]] {
+            update.insert_memtrie_only(&key, FlatStateValue::Inlined(key.to_vec())).unwrap();
+        }
+        let root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+        let mut nodes = Vec::new();
+        tries.serialize_all_nodes(&root, |_, node| nodes.push(node.to_vec()), None).unwrap();
+
+        let mut arena = STArena::new("test".to_string());
+        let mut reconstructor = MemTrieReconstructor::new(&mut arena, root);
+        // The leaf of 0x11 is never delivered.
+        for node in &nodes[..nodes.len() - 1] {
+            assert_eq!(reconstructor.add_node(node).unwrap(), None);
+        }
+        assert_matches!(reconstructor.finish(), Err(StorageError::StorageInconsistentState(_)));
+
+        let mut arena = STArena::new("test".to_string());
+        let mut reconstructor = MemTrieReconstructor::new(&mut arena, root);
+        assert_matches!(
+            reconstructor.add_node(&[0xff]),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+    }
+
+    #[test]
+    fn test_reconstruct_wrong_memory_usage() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        update.insert_memtrie_only(&[0x00], FlatStateValue::Inlined(vec![0x00])).unwrap();
+        let root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+        let mut nodes = Vec::new();
+        tries.serialize_all_nodes(&root, |_, node| nodes.push(node.to_vec()), None).unwrap();
+        let mut node = borsh::from_slice::<RawTrieNodeWithSize>(&nodes[0]).unwrap();
+        node.memory_usage += 1;
+
+        // The node is consistent with its own hash, but not with the memory
+        // usage of its contents.
+        let mut arena = STArena::new("test".to_string());
+        let mut reconstructor = MemTrieReconstructor::new(&mut arena, root);
+        assert_matches!(
+            reconstructor.add_node(&borsh::to_vec(&node).unwrap()),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        assert!(reconstructor.add_node(&nodes[0]).unwrap().is_some());
+    }
+}
@@ -0,0 +1,377 @@
+use std::collections::{BTreeMap, HashMap};
+
+use near_primitives::errors::StorageError;
+use near_primitives::hash::CryptoHash;
+use near_primitives::state::FlatStateValue;
+
+use crate::trie::ops::interface::UpdatedNodeId;
+use crate::NibbleSlice;
+
+use super::hasher::Sha256Hasher;
+use super::memtrie_update::{
+    try_hash_updated_nodes, OldOrUpdatedNodeId, UpdatedMemTrieNode, UpdatedMemTrieNodeWithSize,
+};
+
+/// Item known at some nibble path of a sparse trie.
+#[derive(Debug, Clone)]
+enum SparseItem {
+    Value(FlatStateValue),
+    /// Subtree which is only known by its root hash and memory usage.
+    Placeholder {
+        hash: CryptoHash,
+        memory_usage: u64,
+    },
+}
+
+/// Builder of a trie from a subset of its keys, e.g. the keys proven by a
+/// state proof, where the subtrees without known keys are replaced by
+/// placeholders. Allows to compute the root of the full trie without having
+/// all of its nodes.
+///
+/// The memory usage of a subtree is part of the hashes of its ancestors, so
+/// placeholders need it as well as the hash.
+#[derive(Debug, Clone, Default)]
+pub struct SparseMemTrie {
+    /// Items by nibble path.
+    items: BTreeMap<Vec<u8>, SparseItem>,
+}
+
+/// Nodes of a sparse trie being built, indexed by updated node IDs like in
+/// `MemTrieUpdate`. Nodes are added after their children, so the built nodes
+/// in the order of their IDs follow the post-order traversal.
+#[derive(Default)]
+struct SparseNodes {
+    /// Built nodes, or `None` for placeholders.
+    updated_nodes: Vec<Option<UpdatedMemTrieNodeWithSize>>,
+    /// Hashes and memory usages of placeholders.
+    placeholders: HashMap<UpdatedNodeId, (CryptoHash, u64)>,
+}
+
+impl SparseNodes {
+    fn memory_usage(&self, node_id: UpdatedNodeId) -> u64 {
+        match &self.updated_nodes[node_id] {
+            Some(node) => node.memory_usage,
+            None => self.placeholders[&node_id].1,
+        }
+    }
+}
+
+impl SparseMemTrie {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a known key with its value.
+    pub fn with_value(mut self, key: &[u8], value: FlatStateValue) -> Self {
+        let nibbles = NibbleSlice::new(key).iter().collect();
+        self.items.insert(nibbles, SparseItem::Value(value));
+        self
+    }
+
+    /// Adds a pruned subtree rooted at the given nibble path, which may have
+    /// odd length, e.g. for a child of a branch.
+    pub fn with_placeholder(
+        mut self,
+        nibbles: Vec<u8>,
+        hash: CryptoHash,
+        memory_usage: u64,
+    ) -> Self {
+        self.items.insert(nibbles, SparseItem::Placeholder { hash, memory_usage });
+        self
+    }
+
+    /// Computes the root hash of the trie. Fails if a placeholder is at a
+    /// path which is a prefix of the path of another item, as the subtree
+    /// would then be known.
+    pub fn compute_root(&self) -> Result<CryptoHash, StorageError> {
+        let items: Vec<_> = self.items.iter().collect();
+        if items.is_empty() {
+            return Ok(CryptoHash::default());
+        }
+        let mut nodes = SparseNodes::default();
+        let root = Self::build_subtree(&items, 0, &mut nodes)?;
+        if let Some((hash, _)) = nodes.placeholders.get(&root) {
+            return Ok(*hash);
+        }
+        // The proven parts of the trie are untrusted, so hashing must not
+        // panic on malformed nodes.
+        let ordered_nodes: Vec<_> = (0..nodes.updated_nodes.len())
+            .filter(|id| nodes.updated_nodes[*id].is_some())
+            .collect();
+        let hashes = try_hash_updated_nodes::<Sha256Hasher>(
+            &ordered_nodes,
+            &nodes.updated_nodes,
+            |child| match child {
+                OldOrUpdatedNodeId::Updated(node_id) => {
+                    nodes.placeholders.get(&node_id).map(|(hash, _)| *hash)
+                }
+                OldOrUpdatedNodeId::Old(_) => None,
+            },
+        )?;
+        let (_, root_hash, _) = hashes.last().expect("Root is hashed last");
+        Ok(*root_hash)
+    }
+
+    /// Builds the subtree of the given items at the given depth, which all
+    /// share the first `depth` nibbles. Like in `MemTrieUpdate`, children are
+    /// referenced as updated nodes, which are added to `nodes` with their
+    /// memory usages. Returns the ID of the subtree root.
+    fn build_subtree(
+        items: &[(&Vec<u8>, &SparseItem)],
+        depth: usize,
+        nodes: &mut SparseNodes,
+    ) -> Result<UpdatedNodeId, StorageError> {
+        let (first_nibbles, first_item) = items[0];
+        let (last_nibbles, _) = items[items.len() - 1];
+        let node = if items.len() == 1 {
+            match first_item {
+                SparseItem::Placeholder { hash, memory_usage } if first_nibbles.len() == depth => {
+                    let node_id = nodes.updated_nodes.len();
+                    nodes.updated_nodes.push(None);
+                    nodes.placeholders.insert(node_id, (*hash, *memory_usage));
+                    return Ok(node_id);
+                }
+                SparseItem::Placeholder { .. } => {
+                    let child = Self::build_subtree(items, first_nibbles.len(), nodes)?;
+                    let extension = &first_nibbles[depth..];
+                    UpdatedMemTrieNode::Extension {
+                        extension: NibbleSlice::encode_nibbles(extension, false).as_slice().into(),
+                        child: OldOrUpdatedNodeId::Updated(child),
+                    }
+                }
+                SparseItem::Value(value) => UpdatedMemTrieNode::Leaf {
+                    extension: NibbleSlice::encode_nibbles(&first_nibbles[depth..], true)
+                        .as_slice()
+                        .into(),
+                    value: value.clone(),
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value: None,
+                },
+            }
+        } else {
+            // Items are sorted, so the common prefix of the first and the
+            // last item is shared by all of them.
+            let common_len = first_nibbles[depth..]
+                .iter()
+                .zip(&last_nibbles[depth..])
+                .take_while(|(nibble, other_nibble)| nibble == other_nibble)
+                .count();
+            if common_len > 0 {
+                let child = Self::build_subtree(items, depth + common_len, nodes)?;
+                let extension = &first_nibbles[depth..depth + common_len];
+                UpdatedMemTrieNode::Extension {
+                    extension: NibbleSlice::encode_nibbles(extension, false).as_slice().into(),
+                    child: OldOrUpdatedNodeId::Updated(child),
+                }
+            } else {
+                let (value, mut rest) = if first_nibbles.len() == depth {
+                    let SparseItem::Value(value) = first_item else {
+                        return Err(StorageError::StorageInconsistentState(format!(
+                            "Placeholder at {:?} covers other items",
+                            first_nibbles
+                        )));
+                    };
+                    (Some(value.clone()), &items[1..])
+                } else {
+                    (None, items)
+                };
+                let mut children = Box::<[Option<OldOrUpdatedNodeId>; 16]>::default();
+                while let Some((nibbles, _)) = rest.first() {
+                    let nibble = nibbles[depth];
+                    let group_len =
+                        rest.iter().take_while(|(nibbles, _)| nibbles[depth] == nibble).count();
+                    let child = Self::build_subtree(&rest[..group_len], depth + 1, nodes)?;
+                    children[nibble as usize] = Some(OldOrUpdatedNodeId::Updated(child));
+                    rest = &rest[group_len..];
+                }
+                UpdatedMemTrieNode::Branch {
+                    children,
+                    value,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value: None,
+                }
+            }
+        };
+
+        let mut memory_usage = node.memory_usage_direct();
+        let children = match &node {
+            UpdatedMemTrieNode::Extension { child, .. } => vec![*child],
+            UpdatedMemTrieNode::Branch { children, .. } => {
+                children.iter().flatten().copied().collect()
+            }
+            _ => vec![],
+        };
+        for child in children {
+            if let OldOrUpdatedNodeId::Updated(child) = child {
+                memory_usage += nodes.memory_usage(child);
+            }
+        }
+        nodes.updated_nodes.push(Some(UpdatedMemTrieNodeWithSize { node, memory_usage }));
+        Ok(nodes.updated_nodes.len() - 1)
+    }
+}
+
+/// Computes the root of the trie holding the given entries, without
+/// allocating the trie in an arena. If a key appears several times, the last
+/// value is used. Useful to validate a claimed root against a full set of
+/// entries, e.g. in tests or tools.
+pub fn compute_trie_root(entries: &[(Vec<u8>, Vec<u8>)]) -> CryptoHash {
+    let trie = entries.iter().fold(SparseMemTrie::new(), |trie, (key, value)| {
+        trie.with_value(key, FlatStateValue::on_disk(value))
+    });
+    trie.compute_root().expect("Trie without placeholders is always consistent")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use near_primitives::errors::StorageError;
+    use near_primitives::hash::CryptoHash;
+    use near_primitives::shard_layout::ShardUId;
+    use near_primitives::state::FlatStateValue;
+
+    use super::{compute_trie_root, SparseMemTrie};
+    use crate::trie::mem::arena::ArenaMemory;
+    use crate::trie::mem::flexible_data::children::ChildrenView;
+    use crate::trie::mem::memtries::MemTries;
+    use crate::trie::mem::node::{MemTrieNodePtr, MemTrieNodeView};
+    use crate::NibbleSlice;
+
+    /// Adds the values of the nodes in the proof to the sparse trie, and the
+    /// children of the nodes which are not in the proof as placeholders.
+    fn add_proof_to_sparse_trie<M: ArenaMemory>(
+        node: MemTrieNodePtr<M>,
+        proof: &HashSet<CryptoHash>,
+        nibbles: &mut Vec<u8>,
+        sparse: SparseMemTrie,
+    ) -> SparseMemTrie {
+        let view = node.view();
+        if !proof.contains(&view.node_hash()) {
+            return sparse.with_placeholder(nibbles.clone(), view.node_hash(), view.memory_usage());
+        }
+        let prefix_len = nibbles.len();
+        let sparse = match view {
+            MemTrieNodeView::Leaf { extension, value } => {
+                nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+                sparse.with_value(&NibbleSlice::nibbles_to_bytes(nibbles), value.to_flat_value())
+            }
+            MemTrieNodeView::Extension { extension, child, .. } => {
+                nibbles.extend(NibbleSlice::from_encoded(extension).0.iter());
+                add_proof_to_sparse_trie(child, proof, nibbles, sparse)
+            }
+            MemTrieNodeView::Branch { children, .. } => {
+                add_children_to_sparse_trie(children, proof, nibbles, sparse)
+            }
+            MemTrieNodeView::BranchWithValue { children, value, .. } => {
+                let sparse = sparse
+                    .with_value(&NibbleSlice::nibbles_to_bytes(nibbles), value.to_flat_value());
+                add_children_to_sparse_trie(children, proof, nibbles, sparse)
+            }
+        };
+        nibbles.truncate(prefix_len);
+        sparse
+    }
+
+    fn add_children_to_sparse_trie<M: ArenaMemory>(
+        children: ChildrenView<M>,
+        proof: &HashSet<CryptoHash>,
+        nibbles: &mut Vec<u8>,
+        mut sparse: SparseMemTrie,
+    ) -> SparseMemTrie {
+        for i in 0..16 {
+            if let Some(child) = children.get(i) {
+                nibbles.push(i as u8);
+                sparse = add_proof_to_sparse_trie(child, proof, nibbles, sparse);
+                nibbles.pop();
+            }
+        }
+        sparse
+    }
+
+    #[test]
+    fn test_sparse_trie_root() {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        let mut keys = Vec::new();
+        for i in 0..200u32 {
+            let key = (i * 7919 % 1000).to_be_bytes()[1..].to_vec();
+            update.insert_memtrie_only(&key, FlatStateValue::Inlined(vec![i as u8])).unwrap();
+            keys.push(key);
+        }
+        // Keys stored in branches, and a value stored as a reference.
+        update.insert_memtrie_only(&keys[0][..2], FlatStateValue::Inlined(vec![1])).unwrap();
+        update.insert_memtrie_only(&keys[1], FlatStateValue::on_disk(&[2; 5000])).unwrap();
+        let root =
+            tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap();
+
+        let mut proof = HashSet::new();
+        for key in [&keys[0], &keys[1], &keys[0][..2].to_vec(), &keys[100]] {
+            let mut nodes_accessed = Vec::new();
+            tries.lookup(&root, key, Some(&mut nodes_accessed)).unwrap().unwrap();
+            proof.extend(nodes_accessed.into_iter().map(|(hash, _)| hash));
+        }
+        let root_node = tries.get_root(&root).unwrap();
+        let sparse =
+            add_proof_to_sparse_trie(root_node, &proof, &mut Vec::new(), SparseMemTrie::new());
+        assert_eq!(sparse.compute_root().unwrap(), root);
+
+        // Only the root is known.
+        let sparse = add_proof_to_sparse_trie(
+            root_node,
+            &HashSet::from([root_node.view().node_hash()]),
+            &mut Vec::new(),
+            SparseMemTrie::new(),
+        );
+        assert_eq!(sparse.compute_root().unwrap(), root);
+        // Nothing is known.
+        let sparse = SparseMemTrie::new().with_placeholder(
+            vec![],
+            root_node.view().node_hash(),
+            root_node.view().memory_usage(),
+        );
+        assert_eq!(sparse.compute_root().unwrap(), root);
+        assert_eq!(SparseMemTrie::new().compute_root().unwrap(), CryptoHash::default());
+
+        // A wrong value changes the root.
+        let sparse =
+            add_proof_to_sparse_trie(root_node, &proof, &mut Vec::new(), SparseMemTrie::new())
+                .with_value(&keys[100], FlatStateValue::Inlined(vec![0]));
+        assert_ne!(sparse.compute_root().unwrap(), root);
+        // A placeholder can't cover other items.
+        let sparse = SparseMemTrie::new()
+            .with_value(&[0x12], FlatStateValue::Inlined(vec![0]))
+            .with_placeholder(vec![1], CryptoHash::default(), 0);
+        assert_matches::assert_matches!(
+            sparse.compute_root(),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+    }
+
+    fn memtrie_root(entries: &[(Vec<u8>, Vec<u8>)]) -> CryptoHash {
+        let mut tries = MemTries::new(ShardUId::single_shard());
+        let mut update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+        for (key, value) in entries {
+            update.insert(key, value.clone()).unwrap();
+        }
+        tries.apply_memtrie_changes(0, &update.to_memtrie_changes_only().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_compute_trie_root() {
+        let mut entries = Vec::new();
+        for i in 0..500u32 {
+            let key = (i * 7919 % 1000).to_be_bytes()[1..].to_vec();
+            entries.push((key, vec![i as u8; (i % 7) as usize + 1]));
+        }
+        // Keys stored in branches, a value large enough not to be inlined,
+        // and an overwritten value.
+        entries.push((vec![0x00], vec![1]));
+        entries.push((vec![0x00, 0x01], vec![2; 5000]));
+        entries.push((entries[0].0.clone(), vec![3]));
+        for num_entries in [0, 1, 2, 10, entries.len()] {
+            let entries = &entries[..num_entries];
+            assert_eq!(compute_trie_root(entries), memtrie_root(entries));
+        }
+    }
+}
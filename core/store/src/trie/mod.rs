@@ -10,7 +10,9 @@ pub(crate) use crate::trie::config::{
 use crate::trie::iterator::TrieIterator;
 pub use crate::trie::nibble_slice::NibbleSlice;
 pub use crate::trie::prefetching_trie_storage::{PrefetchApi, PrefetchError};
-pub use crate::trie::shard_tries::{KeyForStateChanges, ShardTries, WrappedTrieChanges};
+pub use crate::trie::shard_tries::{
+    KeyForStateChanges, MemTrieMismatch, ShardTries, WrappedTrieChanges,
+};
 pub use crate::trie::state_snapshot::{
     SnapshotError, StateSnapshot, StateSnapshotConfig, STATE_SNAPSHOT_COLUMNS,
 };
@@ -21,6 +23,7 @@ pub use from_flat::construct_trie_from_flat;
 use itertools::Itertools;
 use mem::memtrie_update::{TrackingMode, UpdatedMemTrieNodeWithSize};
 use mem::memtries::MemTries;
+use mem::node::MemTrieNodeId;
 use near_primitives::challenge::PartialState;
 use near_primitives::hash::{hash, CryptoHash};
 pub use near_primitives::shard_layout::ShardUId;
@@ -207,7 +210,7 @@ impl UpdatedTrieStorageNodeWithSize {
                 let slice = NibbleSlice::from_encoded(&extension);
                 write!(f, "{}Leaf({:?}, val)", spaces, slice.0)?;
             }
-            GenericTrieNode::Branch { children, value } => {
+            GenericTrieNode::Branch { children, value, .. } => {
                 writeln!(
                     f,
                     "{}Branch({}){{",
@@ -507,6 +510,36 @@ impl TrieRefcountDeltaMap {
         *old_rc -= refcount as i32;
     }
 
+    /// Adds all refcount deltas from `other` to this map, so that additions
+    /// and subtractions of the same hash cancel out.
+    pub fn merge(&mut self, other: TrieRefcountDeltaMap) {
+        for (hash, (value, rc)) in other.map {
+            let (old_value, old_rc) = self.map.entry(hash).or_insert((None, 0));
+            if value.is_some() {
+                *old_value = value;
+            }
+            *old_rc += rc;
+        }
+    }
+
+    fn from_changes(
+        insertions: Vec<TrieRefcountAddition>,
+        deletions: Vec<TrieRefcountSubtraction>,
+    ) -> Self {
+        let mut delta_map = Self::new();
+        for insertion in insertions {
+            delta_map.add(
+                insertion.trie_node_or_value_hash,
+                insertion.trie_node_or_value,
+                insertion.rc.get(),
+            );
+        }
+        for deletion in deletions {
+            delta_map.subtract(deletion.trie_node_or_value_hash, deletion.rc.get());
+        }
+        delta_map
+    }
+
     pub fn into_changes(self) -> (Vec<TrieRefcountAddition>, Vec<TrieRefcountSubtraction>) {
         let num_insertions = self.map.iter().filter(|(_h, (_v, rc))| *rc > 0).count();
         let mut insertions = Vec::with_capacity(num_insertions);
@@ -541,6 +574,9 @@ pub struct MemTrieChanges {
     /// It implies that the root node is the last one in the list.
     node_ids_with_hashes: Vec<(UpdatedNodeId, CryptoHash)>,
     updated_nodes: Vec<Option<UpdatedMemTrieNodeWithSize>>,
+    /// Original root, with its hash, which is reused as the new root if the
+    /// update didn't change the trie. Then no nodes are constructed.
+    unchanged_root: Option<(MemTrieNodeId, CryptoHash)>,
 }
 
 ///
@@ -601,6 +637,38 @@ impl TrieChanges {
     pub fn deletions(&self) -> &[TrieRefcountSubtraction] {
         self.deletions.as_slice()
     }
+
+    /// Merges disk changes of the `next` update, which must be applied right
+    /// after this one, so that both can be committed at once. Nodes and
+    /// values deleted by one update and inserted by the other cancel out and
+    /// are not written at all.
+    ///
+    /// Memtrie changes can't be merged, so changes carrying them are
+    /// rejected; the caller must take them out and apply them separately.
+    /// On error, nothing is changed.
+    pub fn merge_disk_changes(&mut self, next: TrieChanges) -> Result<(), StorageError> {
+        if self.new_root != next.old_root {
+            return Err(StorageError::StorageInconsistentState(format!(
+                "Merged trie changes must be consecutive, got roots {} and {}",
+                self.new_root, next.old_root
+            )));
+        }
+        if [&*self, &next].iter().any(|changes| {
+            changes.memtrie_changes.is_some() || !changes.children_memtrie_changes.is_empty()
+        }) {
+            return Err(StorageError::StorageInconsistentState(
+                "Trie changes carrying memtrie changes can't be merged".to_string(),
+            ));
+        }
+        let mut delta_map = TrieRefcountDeltaMap::from_changes(
+            std::mem::take(&mut self.insertions),
+            std::mem::take(&mut self.deletions),
+        );
+        delta_map.merge(TrieRefcountDeltaMap::from_changes(next.insertions, next.deletions));
+        (self.insertions, self.deletions) = delta_map.into_changes();
+        self.new_root = next.new_root;
+        Ok(())
+    }
 }
 
 /// Result of applying state part to Trie.
@@ -1726,7 +1794,7 @@ impl Trie {
             .map(|(shard_uid, memtrie)| {
                 // It's fine to use tracking mode as None here because the recording is handled by
                 // the parent memtrie and doesn't need to be tracked for children.
-                (shard_uid, memtrie.update(self.root, TrackingMode::None).unwrap())
+                (shard_uid, memtrie.update_memtrie_only(self.root).unwrap())
             })
             .collect_vec();
 
@@ -1753,11 +1821,11 @@ impl Trie {
             }
         }
 
-        let mut trie_changes = trie_update.to_trie_changes();
+        let mut trie_changes = trie_update.to_trie_changes()?;
         for (shard_uid, trie_update) in child_updates {
             trie_changes
                 .children_memtrie_changes
-                .insert(**shard_uid, trie_update.to_memtrie_changes_only());
+                .insert(**shard_uid, trie_update.to_memtrie_changes_only()?);
         }
 
         Ok(trie_changes)
@@ -1834,7 +1902,7 @@ impl Trie {
     ) -> Result<StateRoot, StorageError> {
         let mut trie_update = TrieStorageUpdate::new(&self);
         let root_node = self.move_node_to_mutable(&mut trie_update, &self.root)?;
-        trie_update.retain_split_shard(boundary_account, retain_mode);
+        trie_update.retain_split_shard(boundary_account, retain_mode)?;
         #[cfg(test)]
         {
             self.memory_usage_verify(&trie_update, GenericNodeOrIndex::Updated(root_node.0));
@@ -2279,6 +2347,72 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_merge_disk_changes() {
+        let shard_uid = ShardUId::single_shard();
+        let initial_changes = vec![
+            (b"alpha".to_vec(), Some(b"one".to_vec())),
+            (b"beta".to_vec(), Some(b"two".to_vec())),
+        ];
+        let tries = TestTriesBuilder::new().build();
+        let root =
+            test_populate_trie(&tries, &Trie::EMPTY_ROOT, shard_uid, initial_changes.clone());
+
+        // The second update restores the value removed by the first one.
+        let mut trie_changes = tries
+            .get_trie_for_shard(shard_uid, root)
+            .update(vec![(b"alpha".to_vec(), Some(b"three".to_vec()))])
+            .unwrap();
+        let mut store_update = tries.store_update();
+        let intermediate_root = tries.apply_all(&trie_changes, shard_uid, &mut store_update);
+        store_update.commit().unwrap();
+        let next_trie_changes = tries
+            .get_trie_for_shard(shard_uid, intermediate_root)
+            .update(vec![
+                (b"alpha".to_vec(), Some(b"one".to_vec())),
+                (b"beta".to_vec(), Some(b"four".to_vec())),
+            ])
+            .unwrap();
+        let churned_hashes = [hash(b"one"), hash(b"three")];
+        assert!(trie_changes
+            .deletions
+            .iter()
+            .any(|d| d.trie_node_or_value_hash == churned_hashes[0]));
+        assert!(next_trie_changes.insertions().iter().any(|i| i.hash() == &churned_hashes[0]));
+
+        // Only consecutive changes can be merged.
+        assert_matches!(
+            trie_changes.clone().merge_disk_changes(trie_changes.clone()),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+        // Memtrie changes can't be merged.
+        let mut with_memtrie_changes = next_trie_changes.clone();
+        with_memtrie_changes.memtrie_changes = Some(MemTrieChanges::default());
+        assert_matches!(
+            trie_changes.clone().merge_disk_changes(with_memtrie_changes),
+            Err(StorageError::StorageInconsistentState(_))
+        );
+
+        trie_changes.merge_disk_changes(next_trie_changes).unwrap();
+        assert_eq!(trie_changes.old_root, root);
+        assert!(trie_changes.insertions().iter().all(|i| !churned_hashes.contains(i.hash())));
+        assert!(trie_changes
+            .deletions
+            .iter()
+            .all(|d| !churned_hashes.contains(&d.trie_node_or_value_hash)));
+
+        // Merged changes lead to the same state when applied at once.
+        let tries = TestTriesBuilder::new().build();
+        let root = test_populate_trie(&tries, &Trie::EMPTY_ROOT, shard_uid, initial_changes);
+        assert_eq!(trie_changes.old_root, root);
+        let mut store_update = tries.store_update();
+        let new_root = tries.apply_all(&trie_changes, shard_uid, &mut store_update);
+        store_update.commit().unwrap();
+        let trie = tries.get_trie_for_shard(shard_uid, new_root);
+        assert_eq!(trie.get(b"alpha"), Ok(Some(b"one".to_vec())));
+        assert_eq!(trie.get(b"beta"), Ok(Some(b"four".to_vec())));
+    }
+
     #[test]
     fn test_trie_restart() {
         let store = create_test_store();
@@ -50,7 +50,7 @@ where
         loop {
             path.push(node_id);
             // Take out the current node; we'd have to change it no matter what.
-            let GenericUpdatedTrieNodeWithSize { node, memory_usage } = self.take_node(node_id);
+            let GenericUpdatedTrieNodeWithSize { node, memory_usage } = self.take_node(node_id)?;
             let children_memory_usage = memory_usage.saturating_sub(node.memory_usage_direct());
 
             match node {
@@ -58,8 +58,10 @@ where
                     // There was no node here, create a new leaf.
                     let value_handle = self.store_value(value);
                     let node = GenericUpdatedTrieNode::Leaf {
-                        extension: partial.encoded(true).into_vec().into_boxed_slice(),
+                        extension: partial.encoded(true).as_slice().into(),
                         value: value_handle,
+                        #[cfg(feature = "memtrie_shadow_values")]
+                        shadow_value: None,
                     };
                     let memory_usage = node.memory_usage_direct();
                     self.place_node_at(
@@ -68,15 +70,25 @@ where
                     );
                     break;
                 }
-                GenericUpdatedTrieNode::Branch { children, value: old_value } => {
+                GenericUpdatedTrieNode::Branch {
+                    children,
+                    value: old_value,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value,
+                } => {
                     if partial.is_empty() {
                         // This branch node is exactly where the value should be added.
                         if let Some(value) = old_value {
                             self.delete_value(value)?;
                         }
                         let value_handle = self.store_value(value);
-                        let node =
-                            GenericUpdatedTrieNode::Branch { children, value: Some(value_handle) };
+                        // The shadow value of the key, if any, stays.
+                        let node = GenericUpdatedTrieNode::Branch {
+                            children,
+                            value: Some(value_handle),
+                            #[cfg(feature = "memtrie_shadow_values")]
+                            shadow_value,
+                        };
                         let memory_usage = children_memory_usage + node.memory_usage_direct();
                         self.place_node_at(
                             node_id,
@@ -98,6 +110,8 @@ where
                             GenericUpdatedTrieNode::Branch {
                                 children: new_children,
                                 value: old_value,
+                                #[cfg(feature = "memtrie_shadow_values")]
+                                shadow_value,
                             },
                             Some(new_node_id),
                         );
@@ -106,14 +120,24 @@ where
                         continue;
                     }
                 }
-                GenericUpdatedTrieNode::Leaf { extension, value: old_value } => {
+                GenericUpdatedTrieNode::Leaf {
+                    extension,
+                    value: old_value,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value,
+                } => {
                     let existing_key = NibbleSlice::from_encoded(&extension).0;
                     let common_prefix = partial.common_prefix(&existing_key);
                     if common_prefix == existing_key.len() && common_prefix == partial.len() {
                         // We're at the exact leaf. Rewrite the value at this leaf.
                         self.delete_value(old_value)?;
                         let value_handle = self.store_value(value);
-                        let node = GenericUpdatedTrieNode::Leaf { extension, value: value_handle };
+                        let node = GenericUpdatedTrieNode::Leaf {
+                            extension,
+                            value: value_handle,
+                            #[cfg(feature = "memtrie_shadow_values")]
+                            shadow_value,
+                        };
                         let memory_usage = node.memory_usage_direct();
                         self.place_node_at(
                             node_id,
@@ -128,13 +152,19 @@ where
                         let branch_node = if existing_key.is_empty() {
                             // Existing key being empty means the old value now lives at the branch.
                             children_memory_usage = 0;
-                            GenericUpdatedTrieNode::Branch { children, value: Some(old_value) }
+                            GenericUpdatedTrieNode::Branch {
+                                children,
+                                value: Some(old_value),
+                                #[cfg(feature = "memtrie_shadow_values")]
+                                shadow_value,
+                            }
                         } else {
                             let branch_idx = existing_key.at(0) as usize;
-                            let new_extension = existing_key.mid(1).encoded(true).into_vec();
                             let new_node = GenericUpdatedTrieNode::Leaf {
-                                extension: new_extension.into_boxed_slice(),
+                                extension: existing_key.mid(1).encoded(true).as_slice().into(),
                                 value: old_value,
+                                #[cfg(feature = "memtrie_shadow_values")]
+                                shadow_value,
                             };
                             let memory_usage = new_node.memory_usage_direct();
                             children_memory_usage = memory_usage;
@@ -143,11 +173,16 @@ where
                                 memory_usage,
                             });
                             children[branch_idx] = Some(GenericNodeOrIndex::Updated(new_node_id));
-                            GenericUpdatedTrieNode::Branch { children, value: None }
+                            GenericUpdatedTrieNode::Branch {
+                                children,
+                                value: None,
+                                #[cfg(feature = "memtrie_shadow_values")]
+                                shadow_value: None,
+                            }
                         };
                         let memory_usage =
                             branch_node.memory_usage_direct() + children_memory_usage;
-                        self.place_node_at(
+                        self.place_unsquashed_node_at(
                             node_id,
                             GenericUpdatedTrieNodeWithSize { node: branch_node, memory_usage },
                         );
@@ -159,9 +194,11 @@ where
                             extension: existing_key
                                 .mid(common_prefix)
                                 .encoded(true)
-                                .into_vec()
-                                .into_boxed_slice(),
+                                .as_slice()
+                                .into(),
                             value: old_value,
+                            #[cfg(feature = "memtrie_shadow_values")]
+                            shadow_value,
                         };
                         let leaf_memory_usage = leaf_node.memory_usage_direct();
                         let leaf_node_id = self.place_node(GenericUpdatedTrieNodeWithSize {
@@ -171,8 +208,8 @@ where
                         let extension_node = GenericUpdatedTrieNode::Extension {
                             extension: partial
                                 .encoded_leftmost(common_prefix, false)
-                                .into_vec()
-                                .into_boxed_slice(),
+                                .as_slice()
+                                .into(),
                             child: GenericNodeOrIndex::Updated(leaf_node_id),
                         };
                         let extension_memory_usage = extension_node.memory_usage_direct();
@@ -200,11 +237,7 @@ where
                             old_child
                         } else {
                             let inner_child_node = GenericUpdatedTrieNode::Extension {
-                                extension: existing_key
-                                    .mid(1)
-                                    .encoded(false)
-                                    .into_vec()
-                                    .into_boxed_slice(),
+                                extension: existing_key.mid(1).encoded(false).as_slice().into(),
                                 child: old_child,
                             };
                             child_memory_usage =
@@ -218,10 +251,15 @@ where
 
                         let mut children = Box::<[_; 16]>::default();
                         children[idx as usize] = Some(child);
-                        let branch_node = GenericUpdatedTrieNode::Branch { children, value: None };
+                        let branch_node = GenericUpdatedTrieNode::Branch {
+                            children,
+                            value: None,
+                            #[cfg(feature = "memtrie_shadow_values")]
+                            shadow_value: None,
+                        };
                         let branch_memory_usage =
                             branch_node.memory_usage_direct() + child_memory_usage;
-                        self.place_node_at(
+                        self.place_unsquashed_node_at(
                             node_id,
                             GenericUpdatedTrieNodeWithSize {
                                 node: branch_node,
@@ -253,8 +291,8 @@ where
                             extension: existing_key
                                 .mid(common_prefix)
                                 .encoded(false)
-                                .into_vec()
-                                .into_boxed_slice(),
+                                .as_slice()
+                                .into(),
                             child: old_child,
                         };
                         let inner_child_memory_usage =
@@ -266,8 +304,8 @@ where
                         let child_node = GenericUpdatedTrieNode::Extension {
                             extension: existing_key
                                 .encoded_leftmost(common_prefix, false)
-                                .into_vec()
-                                .into_boxed_slice(),
+                                .as_slice()
+                                .into(),
                             child: GenericNodeOrIndex::Updated(inner_child_node_id),
                         };
                         let memory_usage = child_node.memory_usage_direct();
@@ -287,7 +325,7 @@ where
             let node_id = path.get(i).unwrap();
             let child_id = path.get(i + 1).unwrap();
             let child_memory_usage = self.get_node_ref(*child_id).memory_usage;
-            let mut node = self.take_node(*node_id);
+            let mut node = self.take_node(*node_id)?;
             node.memory_usage += child_memory_usage;
             self.place_node_at(*node_id, node);
         }
@@ -302,11 +340,29 @@ where
     /// consistent by changing the types of any nodes along the way.
     ///
     /// Deleting a non-existent key is allowed, and is a no-op.
-    fn generic_delete(
+    fn generic_delete(&mut self, node_id: UpdatedNodeId, key: &[u8]) -> Result<(), StorageError> {
+        self.generic_delete_impl(node_id, key, true).map(|_| ())
+    }
+
+    /// Same as `generic_delete`, but doesn't squash the nodes on the path to
+    /// the key, so that several deletions can be squashed together. Until
+    /// then, the trie may contain empty nodes and degenerate branches.
+    ///
+    /// Returns the path to the deleted key, or None if the key didn't exist.
+    fn generic_delete_without_squash(
+        &mut self,
+        node_id: UpdatedNodeId,
+        key: &[u8],
+    ) -> Result<Option<Vec<UpdatedNodeId>>, StorageError> {
+        self.generic_delete_impl(node_id, key, false)
+    }
+
+    fn generic_delete_impl(
         &mut self,
         mut node_id: UpdatedNodeId,
         key: &[u8],
-    ) -> Result<(), StorageError> {
+        squash: bool,
+    ) -> Result<Option<Vec<UpdatedNodeId>>, StorageError> {
         let mut partial = NibbleSlice::new(key);
         // Path to find the key to delete.
         // Needed to squash nodes and recompute memory usages in the end.
@@ -315,7 +371,7 @@ where
 
         loop {
             path.push(node_id);
-            let GenericUpdatedTrieNodeWithSize { node, memory_usage } = self.take_node(node_id);
+            let GenericUpdatedTrieNodeWithSize { node, memory_usage } = self.take_node(node_id)?;
             let children_memory_usage = memory_usage.saturating_sub(node.memory_usage_direct());
 
             match node {
@@ -325,14 +381,24 @@ where
                     key_deleted = false;
                     break;
                 }
-                GenericUpdatedTrieNode::Leaf { extension, value } => {
+                GenericUpdatedTrieNode::Leaf {
+                    extension,
+                    value,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value,
+                } => {
                     if NibbleSlice::from_encoded(&extension).0 == partial {
                         self.delete_value(value)?;
                         self.place_node_at(node_id, GenericUpdatedTrieNodeWithSize::empty());
                         break;
                     } else {
                         // Key being deleted doesn't exist. Put the leaf back.
-                        let node = GenericUpdatedTrieNode::Leaf { extension, value };
+                        let node = GenericUpdatedTrieNode::Leaf {
+                            extension,
+                            value,
+                            #[cfg(feature = "memtrie_shadow_values")]
+                            shadow_value,
+                        };
                         self.place_node_at(
                             node_id,
                             GenericUpdatedTrieNodeWithSize { node, memory_usage },
@@ -341,11 +407,21 @@ where
                         break;
                     }
                 }
-                GenericUpdatedTrieNode::Branch { mut children, value } => {
+                GenericUpdatedTrieNode::Branch {
+                    mut children,
+                    value,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value,
+                } => {
                     if partial.is_empty() {
                         if value.is_none() {
                             // Key being deleted doesn't exist.
-                            let node = GenericUpdatedTrieNode::Branch { children, value };
+                            let node = GenericUpdatedTrieNode::Branch {
+                                children,
+                                value,
+                                #[cfg(feature = "memtrie_shadow_values")]
+                                shadow_value,
+                            };
                             self.place_node_at(
                                 node_id,
                                 GenericUpdatedTrieNodeWithSize { node, memory_usage },
@@ -354,13 +430,18 @@ where
                             break;
                         };
                         self.delete_value(value.unwrap())?;
-                        self.calc_memory_usage_and_store(
+                        let node = GenericUpdatedTrieNode::Branch {
+                            children,
+                            value: None,
+                            #[cfg(feature = "memtrie_shadow_values")]
+                            shadow_value: None,
+                        };
+                        let memory_usage = children_memory_usage + node.memory_usage_direct();
+                        // if needed, branch will be squashed at the end of the function.
+                        self.place_unsquashed_node_at(
                             node_id,
-                            children_memory_usage,
-                            GenericUpdatedTrieNode::Branch { children, value: None },
-                            None,
+                            GenericUpdatedTrieNodeWithSize { node, memory_usage },
                         );
-                        // if needed, branch will be squashed at the end of the function.
                         break;
                     } else {
                         let child = &mut children[partial.at(0) as usize];
@@ -368,7 +449,12 @@ where
                             Some(node_id) => node_id,
                             None => {
                                 // Key being deleted doesn't exist.
-                                let node = GenericUpdatedTrieNode::Branch { children, value };
+                                let node = GenericUpdatedTrieNode::Branch {
+                                    children,
+                                    value,
+                                    #[cfg(feature = "memtrie_shadow_values")]
+                                    shadow_value,
+                                };
                                 self.place_node_at(
                                     node_id,
                                     GenericUpdatedTrieNodeWithSize { node, memory_usage },
@@ -382,7 +468,12 @@ where
                         self.calc_memory_usage_and_store(
                             node_id,
                             children_memory_usage,
-                            GenericUpdatedTrieNode::Branch { children, value },
+                            GenericUpdatedTrieNode::Branch {
+                                children,
+                                value,
+                                #[cfg(feature = "memtrie_shadow_values")]
+                                shadow_value,
+                            },
                             Some(new_child_id),
                         );
 
@@ -428,23 +519,24 @@ where
         // Now we recompute memory usage and possibly squash nodes to keep the
         // trie structure unique.
         let mut child_memory_usage = 0;
-        for node_id in path.into_iter().rev() {
+        for &node_id in path.iter().rev() {
             // First, recompute memory usage, emulating the recursive descent.
-            let GenericUpdatedTrieNodeWithSize { node, mut memory_usage } = self.take_node(node_id);
+            let GenericUpdatedTrieNodeWithSize { node, mut memory_usage } =
+                self.take_node(node_id)?;
             memory_usage += child_memory_usage;
             self.place_node_at(node_id, GenericUpdatedTrieNodeWithSize { node, memory_usage });
 
             // Then, squash node to ensure unique trie structure, changing its
             // type if needed. If `key_deleted` is false, trie structure is
             // untouched.
-            if key_deleted {
+            if key_deleted && squash {
                 self.squash_node(node_id)?;
             }
 
             child_memory_usage = self.get_node_ref(node_id).memory_usage;
         }
 
-        Ok(())
+        Ok(key_deleted.then_some(path))
     }
 }
 
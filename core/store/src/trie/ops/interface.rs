@@ -1,4 +1,7 @@
+use std::sync::Arc;
+
 use near_primitives::errors::StorageError;
+use near_primitives::hash::CryptoHash;
 use near_primitives::state::FlatStateValue;
 
 use crate::trie::{ValueHandle, TRIE_COSTS};
@@ -20,12 +23,32 @@ pub enum GenericTrieValue {
 /// Trait for trie values to get their length.
 pub trait HasValueLength {
     fn len(&self) -> u64;
+
+    /// Hash of the value, if the value type can provide it, so that generic
+    /// logic can deduplicate values by hash.
+    fn value_hash(&self) -> Option<CryptoHash> {
+        None
+    }
+}
+
+impl<T: HasValueLength + ?Sized> HasValueLength for &T {
+    fn len(&self) -> u64 {
+        (**self).len()
+    }
+
+    fn value_hash(&self) -> Option<CryptoHash> {
+        (**self).value_hash()
+    }
 }
 
 impl HasValueLength for FlatStateValue {
     fn len(&self) -> u64 {
         self.value_len() as u64
     }
+
+    fn value_hash(&self) -> Option<CryptoHash> {
+        Some(self.to_value_ref().hash)
+    }
 }
 
 impl HasValueLength for ValueHandle {
@@ -57,20 +80,43 @@ pub enum GenericTrieNode<TrieNodePtr, GenericValueHandle> {
     /// node to ease implementation.
     Empty,
     Leaf {
-        extension: Box<[u8]>,
+        extension: Arc<[u8]>,
         value: GenericValueHandle,
+        /// EXPERIMENTAL, NON-CONSENSUS: secondary value of the key, see
+        /// `MemTrieUpdate::insert_to_slot`. Not part of the node hash.
+        #[cfg(feature = "memtrie_shadow_values")]
+        shadow_value: Option<GenericValueHandle>,
     },
     Extension {
-        extension: Box<[u8]>,
+        extension: Arc<[u8]>,
         child: TrieNodePtr,
     },
     /// Corresponds to either a Branch or BranchWithValue node.
     Branch {
         children: Box<[Option<TrieNodePtr>; 16]>,
         value: Option<GenericValueHandle>,
+        /// Like for `Leaf`, only set if `value` is.
+        #[cfg(feature = "memtrie_shadow_values")]
+        shadow_value: Option<GenericValueHandle>,
     },
 }
 
+impl<N, V> GenericTrieNode<N, V> {
+    /// Whether the node is a branch with fewer than two members, counting
+    /// both children and the value. Such branches may exist temporarily
+    /// while the trie is restructured, but a canonical trie has none: they
+    /// must be squashed into a leaf, an extension or nothing.
+    pub fn is_degenerate_branch(&self) -> bool {
+        match self {
+            Self::Branch { children, value, .. } => {
+                let num_children = children.iter().filter(|child| child.is_some()).count();
+                num_children + usize::from(value.is_some()) < 2
+            }
+            Self::Empty | Self::Leaf { .. } | Self::Extension { .. } => false,
+        }
+    }
+}
+
 impl<N, V> GenericTrieNode<N, V>
 where
     V: HasValueLength,
@@ -89,7 +135,7 @@ where
                 // children of branch nodes which means cost has to be 0
                 0
             }
-            Self::Leaf { extension, value } => {
+            Self::Leaf { extension, value, .. } => {
                 TRIE_COSTS.node_cost
                     + (extension.len() as u64) * TRIE_COSTS.byte_of_key
                     + Self::memory_usage_value(value.len())
@@ -117,13 +163,33 @@ impl<N, V> From<GenericTrieNode<N, V>> for GenericUpdatedTrieNode<N, V> {
     fn from(node: GenericTrieNode<N, V>) -> Self {
         match node {
             GenericTrieNode::Empty => Self::Empty,
-            GenericTrieNode::Leaf { extension, value } => Self::Leaf { extension, value },
+            GenericTrieNode::Leaf {
+                extension,
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            } => Self::Leaf {
+                extension,
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            },
             GenericTrieNode::Extension { extension, child } => {
                 Self::Extension { extension, child: GenericNodeOrIndex::Old(child) }
             }
-            GenericTrieNode::Branch { children, value } => {
+            GenericTrieNode::Branch {
+                children,
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            } => {
                 let children = Box::new(children.map(|child| child.map(GenericNodeOrIndex::Old)));
-                Self::Branch { children, value }
+                Self::Branch {
+                    children,
+                    value,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value,
+                }
             }
         }
     }
@@ -205,7 +271,7 @@ pub(crate) trait GenericTrieUpdate<'a, GenericTrieNodePtr, GenericValueHandle> {
     fn take_node(
         &mut self,
         node_id: UpdatedNodeId,
-    ) -> GenericUpdatedTrieNodeWithSize<GenericTrieNodePtr, GenericValueHandle>;
+    ) -> Result<GenericUpdatedTrieNodeWithSize<GenericTrieNodePtr, GenericValueHandle>, StorageError>;
 
     /// Puts a node to the set of updated nodes at specific index.
     /// Needed when reference to node from parent needs to be preserved.
@@ -215,6 +281,18 @@ pub(crate) trait GenericTrieUpdate<'a, GenericTrieNodePtr, GenericValueHandle> {
         node: GenericUpdatedTrieNodeWithSize<GenericTrieNodePtr, GenericValueHandle>,
     );
 
+    /// Same as `place_node_at`, but the node may be a degenerate branch, see
+    /// `GenericTrieNode::is_degenerate_branch`. Used by operations which
+    /// restructure the trie in several steps; the branch must be completed or
+    /// squashed before the update is finalized.
+    fn place_unsquashed_node_at(
+        &mut self,
+        node_id: UpdatedNodeId,
+        node: GenericUpdatedTrieNodeWithSize<GenericTrieNodePtr, GenericValueHandle>,
+    ) {
+        self.place_node_at(node_id, node);
+    }
+
     /// Puts a new node into the set of updated nodes.
     fn place_node(
         &mut self,
@@ -232,4 +310,13 @@ pub(crate) trait GenericTrieUpdate<'a, GenericTrieNodePtr, GenericValueHandle> {
 
     /// Deletes a state value from the trie.
     fn delete_value(&mut self, value: GenericValueHandle) -> Result<(), StorageError>;
+
+    /// Called whenever squashing transforms a node, with `kind` naming the
+    /// transformation. Only used for metrics, so it is a no-op by default.
+    fn record_squash_op(&self, _kind: &'static str) {}
+
+    /// Called with the time spent by each call of `squash_node`. Only used
+    /// for profiling, so it is a no-op by default.
+    #[cfg(feature = "memtrie_timing")]
+    fn record_squash_time(&self, _elapsed: std::time::Duration) {}
 }
@@ -157,7 +157,7 @@ where
         match decision {
             RetainDecision::RetainAll => return Ok(()),
             RetainDecision::DiscardAll => {
-                let _ = self.take_node(node_id);
+                self.take_node(node_id)?;
                 self.place_node_at(node_id, GenericUpdatedTrieNodeWithSize::empty());
                 return Ok(());
             }
@@ -166,14 +166,19 @@ where
             }
         }
 
-        let GenericUpdatedTrieNodeWithSize { node, memory_usage } = self.take_node(node_id);
+        let GenericUpdatedTrieNodeWithSize { node, memory_usage } = self.take_node(node_id)?;
         match node {
             GenericUpdatedTrieNode::Empty => {
                 // Nowhere to descend.
                 self.place_node_at(node_id, GenericUpdatedTrieNodeWithSize::empty());
                 return Ok(());
             }
-            GenericUpdatedTrieNode::Leaf { extension, value } => {
+            GenericUpdatedTrieNode::Leaf {
+                extension,
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            } => {
                 let full_key_nibbles =
                     [key_nibbles, NibbleSlice::from_encoded(&extension).0.iter().collect_vec()]
                         .concat();
@@ -183,16 +188,28 @@ where
                     self.place_node_at(
                         node_id,
                         GenericUpdatedTrieNodeWithSize {
-                            node: GenericUpdatedTrieNode::Leaf { extension, value },
+                            node: GenericUpdatedTrieNode::Leaf {
+                                extension,
+                                value,
+                                #[cfg(feature = "memtrie_shadow_values")]
+                                shadow_value,
+                            },
                             memory_usage,
                         },
                     );
                 }
                 return Ok(());
             }
-            GenericUpdatedTrieNode::Branch { mut children, mut value } => {
+            GenericUpdatedTrieNode::Branch {
+                mut children,
+                mut value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                mut shadow_value,
+            } => {
                 if !intervals_nibbles.iter().any(|interval| interval.contains(&key_nibbles)) {
                     value = None;
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    let _ = shadow_value.take();
                 }
 
                 let mut memory_usage = 0;
@@ -219,9 +236,17 @@ where
                     }
                 }
 
-                let node = GenericUpdatedTrieNode::Branch { children, value };
+                let node = GenericUpdatedTrieNode::Branch {
+                    children,
+                    value,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value,
+                };
                 memory_usage += node.memory_usage_direct();
-                self.place_node_at(node_id, GenericUpdatedTrieNodeWithSize { node, memory_usage });
+                self.place_unsquashed_node_at(
+                    node_id,
+                    GenericUpdatedTrieNodeWithSize { node, memory_usage },
+                );
             }
             GenericUpdatedTrieNode::Extension { extension, child } => {
                 let new_child_id = self.ensure_updated(child)?;
@@ -293,7 +318,11 @@ where
     N: Debug,
     V: Debug + HasValueLength,
 {
-    fn retain_split_shard(&mut self, boundary_account: &AccountId, retain_mode: RetainMode);
+    fn retain_split_shard(
+        &mut self,
+        boundary_account: &AccountId,
+        retain_mode: RetainMode,
+    ) -> Result<(), StorageError>;
 }
 
 impl<'a, N, V, T> GenericTrieUpdateRetain<'a, N, V> for T
@@ -302,10 +331,14 @@ where
     V: Debug + HasValueLength,
     T: GenericTrieUpdateRetainInner<'a, N, V>,
 {
-    fn retain_split_shard(&mut self, boundary_account: &AccountId, retain_mode: RetainMode) {
+    fn retain_split_shard(
+        &mut self,
+        boundary_account: &AccountId,
+        retain_mode: RetainMode,
+    ) -> Result<(), StorageError> {
         let intervals = boundary_account_to_intervals(boundary_account, retain_mode);
         let intervals_nibbles = intervals_to_nibbles(&intervals);
-        self.retain_multi_range_recursive(0, vec![], &intervals_nibbles).unwrap();
+        self.retain_multi_range_recursive(0, vec![], &intervals_nibbles)
     }
 }
 
@@ -1,3 +1,6 @@
+use std::sync::Arc;
+
+use itertools::Itertools;
 use near_primitives::errors::StorageError;
 
 use crate::NibbleSlice;
@@ -25,10 +28,20 @@ where
     /// induction on subtrees.
     /// For single key removal, it is called for every node on the path from
     /// the leaf to the root.
-    /// For range removal, it is called in the end of recursive range removal
-    /// function, which is the definition of post-order traversal.
+    /// For range removal, `generic_delete_range` calls it for every visited
+    /// node after all its children, which is the definition of post-order
+    /// traversal.
     fn squash_node(&mut self, node_id: UpdatedNodeId) -> Result<(), StorageError> {
-        let GenericUpdatedTrieNodeWithSize { node, memory_usage } = self.take_node(node_id);
+        #[cfg(feature = "memtrie_timing")]
+        let start = std::time::Instant::now();
+        let result = self.squash_node_impl(node_id);
+        #[cfg(feature = "memtrie_timing")]
+        self.record_squash_time(start.elapsed());
+        result
+    }
+
+    fn squash_node_impl(&mut self, node_id: UpdatedNodeId) -> Result<(), StorageError> {
+        let GenericUpdatedTrieNodeWithSize { node, memory_usage } = self.take_node(node_id)?;
         match node {
             GenericUpdatedTrieNode::Empty => {
                 // Empty node will be absorbed by its parent node, so defer that.
@@ -39,7 +52,12 @@ where
                 // had deleted a leaf it would become Empty instead.
                 unreachable!();
             }
-            GenericUpdatedTrieNode::Branch { mut children, value } => {
+            GenericUpdatedTrieNode::Branch {
+                mut children,
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            } => {
                 // Remove any children that are now empty (removed).
                 for child in children.iter_mut() {
                     if let Some(GenericNodeOrIndex::Updated(child_node_id)) = child {
@@ -54,16 +72,17 @@ where
                 if num_children == 0 {
                     match value {
                         None => {
+                            self.record_squash_op("branch_to_empty");
                             self.place_node_at(node_id, GenericUpdatedTrieNodeWithSize::empty())
                         }
                         Some(value) => {
                             // Branch with zero children and a value becomes leaf.
+                            self.record_squash_op("branch_to_leaf");
                             let leaf_node = GenericUpdatedTrieNode::Leaf {
-                                extension: NibbleSlice::new(&[])
-                                    .encoded(true)
-                                    .into_vec()
-                                    .into_boxed_slice(),
+                                extension: NibbleSlice::new(&[]).encoded(true).into_vec().into(),
                                 value,
+                                #[cfg(feature = "memtrie_shadow_values")]
+                                shadow_value,
                             };
                             let memory_usage = leaf_node.memory_usage_direct();
                             self.place_node_at(
@@ -74,6 +93,7 @@ where
                     }
                 } else if num_children == 1 && value.is_none() {
                     // Branch with 1 child but no value becomes extension.
+                    self.record_squash_op("branch_to_extension");
                     let (idx, child) = children
                         .into_iter()
                         .enumerate()
@@ -82,14 +102,19 @@ where
                     let extension = NibbleSlice::new(&[(idx << 4) as u8])
                         .encoded_leftmost(1, false)
                         .into_vec()
-                        .into_boxed_slice();
+                        .into();
                     self.extend_child(node_id, extension, child)?;
                 } else {
                     // Branch with more than 1 children stays branch.
                     self.place_node_at(
                         node_id,
                         GenericUpdatedTrieNodeWithSize {
-                            node: GenericUpdatedTrieNode::Branch { children, value },
+                            node: GenericUpdatedTrieNode::Branch {
+                                children,
+                                value,
+                                #[cfg(feature = "memtrie_shadow_values")]
+                                shadow_value,
+                            },
                             memory_usage,
                         },
                     );
@@ -109,28 +134,40 @@ where
         // The node being squashed.
         node_id: UpdatedNodeId,
         // The current extension.
-        extension: Box<[u8]>,
+        extension: Arc<[u8]>,
         // The current child.
         child_id: GenericNodeOrIndex<N>,
     ) -> Result<(), StorageError> {
         let child_id = self.ensure_updated(child_id)?;
-        let GenericUpdatedTrieNodeWithSize { node, memory_usage } = self.take_node(child_id);
+        let GenericUpdatedTrieNodeWithSize { node, memory_usage } = self.take_node(child_id)?;
         let child_child_memory_usage = memory_usage.saturating_sub(node.memory_usage_direct());
         match node {
             GenericUpdatedTrieNode::Empty => {
+                self.record_squash_op("extension_to_empty");
                 self.place_node_at(node_id, GenericUpdatedTrieNodeWithSize::empty());
             }
             // If the child is a leaf (which could happen if a branch node lost
             // all its branches and only had a value left, or is left with only
             // one branch and that was squashed to a leaf).
-            GenericUpdatedTrieNode::Leaf { extension: child_extension, value } => {
+            GenericUpdatedTrieNode::Leaf {
+                extension: child_extension,
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            } => {
+                self.record_squash_op("extension_merge_leaf");
                 let child_extension = NibbleSlice::from_encoded(&child_extension).0;
                 let extension = NibbleSlice::from_encoded(&extension)
                     .0
                     .merge_encoded(&child_extension, true)
                     .into_vec()
-                    .into_boxed_slice();
-                let node = GenericUpdatedTrieNode::Leaf { extension, value };
+                    .into();
+                let node = GenericUpdatedTrieNode::Leaf {
+                    extension,
+                    value,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value,
+                };
                 let memory_usage = node.memory_usage_direct();
                 self.place_node_at(node_id, GenericUpdatedTrieNodeWithSize { node, memory_usage });
             }
@@ -144,6 +181,9 @@ where
                     extension,
                     child: GenericNodeOrIndex::Updated(child_id),
                 };
+                // The branch is kept as is, so its memory usage is still the
+                // usage of its whole subtree: it comes either from the
+                // original node or from the updates along the path.
                 let memory_usage = memory_usage + node.memory_usage_direct();
                 self.place_node_at(node_id, GenericUpdatedTrieNodeWithSize { node, memory_usage });
             }
@@ -153,12 +193,13 @@ where
                 extension: child_extension,
                 child: inner_child,
             } => {
+                self.record_squash_op("extension_merge_extension");
                 let child_extension = NibbleSlice::from_encoded(&child_extension).0;
                 let merged_extension = NibbleSlice::from_encoded(&extension)
                     .0
                     .merge_encoded(&child_extension, false)
                     .into_vec()
-                    .into_boxed_slice();
+                    .into();
                 let node = GenericUpdatedTrieNode::Extension {
                     extension: merged_extension,
                     child: inner_child,
@@ -169,6 +210,199 @@ where
         }
         Ok(())
     }
+
+    /// Removes all keys in `[start, end)` from the subtree of `root_id`,
+    /// squashing the visited nodes in post-order, and returns the number of
+    /// removed values. Only subtrees which may contain keys from the range
+    /// are visited. The traversal uses an explicit stack rather than
+    /// recursion, so that its stack depth doesn't depend on the trie depth.
+    fn generic_delete_range(
+        &mut self,
+        root_id: UpdatedNodeId,
+        start: &NibbleSlice,
+        end: &NibbleSlice,
+    ) -> Result<u64, StorageError> {
+        let start = start.iter().collect_vec();
+        let end = end.iter().collect_vec();
+        let mut num_deleted = 0;
+        let mut stack = vec![DeleteRangeStep::Visit { node_id: root_id, key_nibbles: vec![] }];
+        while let Some(step) = stack.pop() {
+            match step {
+                DeleteRangeStep::Visit { node_id, key_nibbles } => {
+                    num_deleted += self.visit_for_delete_range(
+                        node_id,
+                        key_nibbles,
+                        &start,
+                        &end,
+                        &mut stack,
+                    )?;
+                }
+                DeleteRangeStep::Finish { node_id, children } => {
+                    // Add back the memory usages of the children, which were
+                    // subtracted when descending into them.
+                    let GenericUpdatedTrieNodeWithSize { node, mut memory_usage } =
+                        self.take_node(node_id)?;
+                    for child_id in children {
+                        memory_usage += self.get_node_ref(child_id).memory_usage;
+                    }
+                    self.place_node_at(
+                        node_id,
+                        GenericUpdatedTrieNodeWithSize { node, memory_usage },
+                    );
+                    self.squash_node(node_id)?;
+                }
+            }
+        }
+        Ok(num_deleted)
+    }
+
+    /// Handles a single node for `generic_delete_range`: deletes its value if
+    /// it is in the range, and schedules visiting the children which may
+    /// contain keys from the range, followed by finishing the node itself.
+    /// Returns the number of deleted values, i.e. 0 or 1.
+    fn visit_for_delete_range(
+        &mut self,
+        node_id: UpdatedNodeId,
+        key_nibbles: Vec<u8>,
+        start: &[u8],
+        end: &[u8],
+        stack: &mut Vec<DeleteRangeStep>,
+    ) -> Result<u64, StorageError> {
+        let in_range = |key: &[u8]| start <= key && key < end;
+        let GenericUpdatedTrieNodeWithSize { node, memory_usage } = self.take_node(node_id)?;
+        let mut children_memory_usage = memory_usage.saturating_sub(node.memory_usage_direct());
+        let mut touched_children = vec![];
+        let mut num_deleted = 0;
+        let node = match node {
+            GenericUpdatedTrieNode::Empty => GenericUpdatedTrieNode::Empty,
+            GenericUpdatedTrieNode::Leaf {
+                extension,
+                value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value,
+            } => {
+                let full_key_nibbles =
+                    [key_nibbles, NibbleSlice::from_encoded(&extension).0.iter().collect_vec()]
+                        .concat();
+                if in_range(&full_key_nibbles) {
+                    self.delete_value(value)?;
+                    self.place_node_at(node_id, GenericUpdatedTrieNodeWithSize::empty());
+                    return Ok(1);
+                }
+                GenericUpdatedTrieNode::Leaf {
+                    extension,
+                    value,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value,
+                }
+            }
+            GenericUpdatedTrieNode::Branch {
+                mut children,
+                mut value,
+                #[cfg(feature = "memtrie_shadow_values")]
+                mut shadow_value,
+            } => {
+                let value_deleted = value.is_some() && in_range(&key_nibbles);
+                if value_deleted {
+                    self.delete_value(value.take().unwrap())?;
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    let _ = shadow_value.take();
+                    num_deleted = 1;
+                }
+                for (i, child) in children.iter_mut().enumerate() {
+                    let child_key_nibbles = [key_nibbles.clone(), vec![i as u8]].concat();
+                    if !may_intersect_range(&child_key_nibbles, start, end) {
+                        continue;
+                    }
+                    let Some(old_child_id) = child.take() else {
+                        continue;
+                    };
+                    let new_child_id = self.ensure_updated(old_child_id)?;
+                    *child = Some(GenericNodeOrIndex::Updated(new_child_id));
+                    children_memory_usage = children_memory_usage
+                        .saturating_sub(self.get_node_ref(new_child_id).memory_usage);
+                    touched_children.push((new_child_id, child_key_nibbles));
+                }
+                let node = GenericUpdatedTrieNode::Branch {
+                    children,
+                    value,
+                    #[cfg(feature = "memtrie_shadow_values")]
+                    shadow_value,
+                };
+                if !value_deleted && touched_children.is_empty() {
+                    self.place_node_at(
+                        node_id,
+                        GenericUpdatedTrieNodeWithSize { node, memory_usage },
+                    );
+                    return Ok(0);
+                }
+                node
+            }
+            GenericUpdatedTrieNode::Extension { extension, child } => {
+                let child_key_nibbles =
+                    [key_nibbles, NibbleSlice::from_encoded(&extension).0.iter().collect_vec()]
+                        .concat();
+                if !may_intersect_range(&child_key_nibbles, start, end) {
+                    let node = GenericUpdatedTrieNode::Extension { extension, child };
+                    self.place_node_at(
+                        node_id,
+                        GenericUpdatedTrieNodeWithSize { node, memory_usage },
+                    );
+                    return Ok(0);
+                }
+                let new_child_id = self.ensure_updated(child)?;
+                children_memory_usage = children_memory_usage
+                    .saturating_sub(self.get_node_ref(new_child_id).memory_usage);
+                touched_children.push((new_child_id, child_key_nibbles));
+                GenericUpdatedTrieNode::Extension {
+                    extension,
+                    child: GenericNodeOrIndex::Updated(new_child_id),
+                }
+            }
+        };
+
+        let memory_usage = children_memory_usage + node.memory_usage_direct();
+        let needs_finish =
+            !matches!(node, GenericUpdatedTrieNode::Empty | GenericUpdatedTrieNode::Leaf { .. });
+        // Branches are squashed when finished.
+        self.place_unsquashed_node_at(
+            node_id,
+            GenericUpdatedTrieNodeWithSize { node, memory_usage },
+        );
+        if needs_finish {
+            // The node is finished only after all its children, which are
+            // pushed after it.
+            stack.push(DeleteRangeStep::Finish {
+                node_id,
+                children: touched_children.iter().map(|(child_id, _)| *child_id).collect(),
+            });
+        }
+        for (child_id, child_key_nibbles) in touched_children {
+            stack
+                .push(DeleteRangeStep::Visit { node_id: child_id, key_nibbles: child_key_nibbles });
+        }
+        Ok(num_deleted)
+    }
+}
+
+/// Step of the traversal in `generic_delete_range`.
+pub(crate) enum DeleteRangeStep {
+    /// Delete the node's value if needed and schedule its children.
+    Visit { node_id: UpdatedNodeId, key_nibbles: Vec<u8> },
+    /// Recompute memory usage of the node after its children were processed,
+    /// and squash it.
+    Finish { node_id: UpdatedNodeId, children: Vec<UpdatedNodeId> },
+}
+
+/// Whether the subtree with keys starting with `key` may contain keys from
+/// `[start, end)`. All the keys in the subtree are at least `key`, so they
+/// are outside the range if `key >= end`. They are all smaller than `start`
+/// if `key < start` and `start` can't be obtained by extending `key`.
+fn may_intersect_range(key: &[u8], start: &[u8], end: &[u8]) -> bool {
+    if key >= end {
+        return false;
+    }
+    !(key < start && !start.starts_with(key))
 }
 
 impl<'a, N, V, T> GenericTrieUpdateSquash<'a, N, V> for T
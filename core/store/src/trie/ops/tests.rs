@@ -1,4 +1,5 @@
 use near_primitives::hash::CryptoHash;
+use near_primitives::state::FlatStateValue;
 use rand::rngs::StdRng;
 use rand::seq::SliceRandom;
 use rand::{Rng, SeedableRng};
@@ -19,8 +20,11 @@ use crate::trie::trie_recording::TrieRecorder;
 use crate::trie::trie_storage::TrieMemoryPartialStorage;
 use crate::trie::trie_storage_update::TrieStorageUpdate;
 use crate::trie::Trie;
+use crate::NibbleSlice;
 
+use super::interface::HasValueLength;
 use super::resharding::retain_split_shard_custom_ranges;
+use super::squash::GenericTrieUpdateSquash;
 
 // Given a set of initial entries and a set of ranges, generates the set of retained entries
 fn generate_native_result(
@@ -66,12 +70,12 @@ fn setup_tries(initial_entries: Vec<(Vec<u8>, Vec<u8>)>) -> (Trie, MemTries) {
 
     // Setup memtrie
     let mut memtries = MemTries::new(ShardUId::single_shard());
-    let mut update = memtries.update(Trie::EMPTY_ROOT, TrackingMode::None).unwrap();
+    let mut update = memtries.update_memtrie_only(Trie::EMPTY_ROOT).unwrap();
     for (key, value) in initial_entries {
         update.insert(&key, value).unwrap();
     }
-    let memtrie_changes = update.to_memtrie_changes_only();
-    let memtrie_state_root = memtries.apply_memtrie_changes(0, &memtrie_changes);
+    let memtrie_changes = update.to_memtrie_changes_only().unwrap();
+    let memtrie_state_root = memtries.apply_memtrie_changes(0, &memtrie_changes).unwrap();
 
     assert_eq!(trie_state_root, memtrie_state_root);
     (trie, memtries)
@@ -108,9 +112,9 @@ fn run(initial_entries: Vec<(Vec<u8>, Vec<u8>)>, retain_multi_ranges: Vec<Range<
     let mode = TrackingMode::RefcountsAndAccesses(&mut trie_recorder);
     let mut update = memtries.update(initial_state_root, mode).unwrap();
     retain_split_shard_custom_ranges(&mut update, &retain_multi_ranges);
-    let mut trie_changes = update.to_trie_changes();
+    let mut trie_changes = update.to_trie_changes().unwrap();
     let memtrie_changes = trie_changes.memtrie_changes.take().unwrap();
-    let mem_state_root = memtries.apply_memtrie_changes(1, &memtrie_changes);
+    let mem_state_root = memtries.apply_memtrie_changes(1, &memtrie_changes).unwrap();
     let proof = trie_recorder.recorded_storage();
 
     // Use proof to verify split
@@ -376,3 +380,90 @@ fn test_rand_long_long_keys() {
 fn test_rand_large_data() {
     check_random(32, 100000, 1);
 }
+
+// Deletes the keys in `range` from a trie and a memtrie created from the
+// initial entries, and compares the results with filtering the entries.
+fn run_delete_range(initial_entries: Vec<(Vec<u8>, Vec<u8>)>, range: Range<Vec<u8>>) {
+    let mut expected_entries =
+        initial_entries.iter().filter(|&(key, _)| !range.contains(key)).cloned().collect_vec();
+    expected_entries.sort();
+    let expected_state_root = crate::test_utils::test_populate_trie(
+        &TestTriesBuilder::new().build(),
+        &Trie::EMPTY_ROOT,
+        ShardUId::single_shard(),
+        expected_entries.iter().map(|(key, value)| (key.clone(), Some(value.clone()))).collect(),
+    );
+
+    let (trie, mut memtries) = setup_tries(initial_entries);
+    let start = NibbleSlice::new(&range.start);
+    let end = NibbleSlice::new(&range.end);
+
+    let mut trie_update = TrieStorageUpdate::new(&trie);
+    let root_node = trie.move_node_to_mutable(&mut trie_update, &trie.root).unwrap();
+    trie_update.generic_delete_range(root_node.0, &start, &end).unwrap();
+    let disk_state_root = trie_update.flatten_nodes(&trie.root, root_node.0).unwrap().new_root;
+
+    let mut update = memtries.update(trie.root, TrackingMode::Refcounts).unwrap();
+    update.generic_delete_range(0, &start, &end).unwrap();
+    let mut trie_changes = update.to_trie_changes().unwrap();
+    let memtrie_changes = trie_changes.memtrie_changes.take().unwrap();
+    let mem_state_root = memtries.apply_memtrie_changes(1, &memtrie_changes).unwrap();
+
+    let entries = if mem_state_root != StateRoot::default() {
+        let state_root_ptr = memtries.get_root(&mem_state_root).unwrap();
+        let trie = Trie::new(Arc::new(TrieMemoryPartialStorage::default()), mem_state_root, None);
+        MemTrieIterator::new(Some(state_root_ptr), &trie).map(|e| e.unwrap()).collect_vec()
+    } else {
+        vec![]
+    };
+    assert_eq!(entries, expected_entries);
+    assert_eq!(mem_state_root, expected_state_root);
+    assert_eq!(trie_changes.new_root, expected_state_root);
+    assert_eq!(disk_state_root, expected_state_root);
+}
+
+#[test]
+fn test_delete_range() {
+    let initial_entries = vec![
+        (b"alice".to_vec(), vec![1]),
+        (b"alice.near".to_vec(), vec![2]),
+        (b"bob".to_vec(), vec![3]),
+        (b"bob.near".to_vec(), vec![4]),
+        (b"bobby".to_vec(), vec![5]),
+        (b"charlie".to_vec(), vec![6]),
+    ];
+    run_delete_range(initial_entries.clone(), b"alice.near".to_vec()..b"bobby".to_vec());
+    run_delete_range(initial_entries.clone(), b"a".to_vec()..b"d".to_vec());
+    run_delete_range(initial_entries.clone(), b"bob".to_vec()..b"bob".to_vec());
+    run_delete_range(initial_entries.clone(), b"bo".to_vec()..b"bob.near".to_vec());
+    run_delete_range(initial_entries, b"d".to_vec()..b"e".to_vec());
+}
+
+#[test]
+fn test_delete_range_random() {
+    let mut rng = StdRng::seed_from_u64(442);
+    for _ in 0..20 {
+        let key_cnt = rng.gen_range(1..=100);
+        let mut keys = (0..key_cnt).map(|_| random_key(5, &mut rng)).collect_vec();
+        keys.sort();
+        keys.dedup();
+        let mut boundary_left = random_key(5, &mut rng);
+        let mut boundary_right = random_key(5, &mut rng);
+        if boundary_left > boundary_right {
+            std::mem::swap(&mut boundary_left, &mut boundary_right);
+        }
+        let initial_entries = keys.into_iter().map(|key| (key, vec![1])).collect_vec();
+        run_delete_range(initial_entries, boundary_left..boundary_right);
+    }
+}
+
+#[test]
+fn test_value_length_of_reference() {
+    // Generic code holding a reference to the value.
+    fn length_and_hash<V: HasValueLength>(value: V) -> (u64, Option<CryptoHash>) {
+        (value.len(), value.value_hash())
+    }
+    let value = FlatStateValue::Inlined(vec![1, 2, 3]);
+    assert_eq!(length_and_hash(&value), (3, Some(value.to_value_ref().hash)));
+    assert_eq!(length_and_hash(&value), length_and_hash(value));
+}
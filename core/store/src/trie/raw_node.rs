@@ -7,7 +7,7 @@ use near_schema_checker_lib::ProtocolSchema;
 /// Trie node with memory cost of its subtree.
 ///
 /// memory_usage is serialized, stored and contributes to hash.
-#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq, ProtocolSchema)]
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq, Hash, ProtocolSchema)]
 pub struct RawTrieNodeWithSize {
     pub node: RawTrieNode,
     pub memory_usage: u64,
@@ -20,7 +20,7 @@ impl RawTrieNodeWithSize {
 }
 
 /// Trie node.
-#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq, ProtocolSchema)]
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq, Hash, ProtocolSchema)]
 #[allow(clippy::large_enum_variant)]
 pub enum RawTrieNode {
     /// Leaf(key, value_length, value_hash)
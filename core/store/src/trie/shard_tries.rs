@@ -1,3 +1,4 @@
+use super::mem::memtrie_update::SharedValueRefcount;
 use super::mem::memtries::MemTries;
 use super::state_snapshot::{StateSnapshot, StateSnapshotConfig};
 use super::TrieRefcountSubtraction;
@@ -15,12 +16,14 @@ use itertools::Itertools;
 use near_primitives::errors::StorageError;
 use near_primitives::hash::CryptoHash;
 use near_primitives::shard_layout::ShardUId;
+use near_primitives::state::ValueRef;
 use near_primitives::trie_key::TrieKey;
 use near_primitives::types::{
     BlockHeight, RawStateChange, RawStateChangesWithTrieKey, StateChangeCause, StateRoot,
 };
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::num::NonZeroU32;
 use std::sync::{Arc, Mutex, RwLock};
 
 struct ShardTriesInner {
@@ -365,6 +368,25 @@ impl ShardTries {
         self.apply_deletions_inner(&trie_changes.deletions, shard_uid, store_update)
     }
 
+    /// Persists the references added by `MemTrieUpdate::insert_shared_value`
+    /// against the shards owning the values. The value bytes are read from
+    /// the owning shard, which must already store them, so they are never
+    /// part of the trie changes of the referencing shard.
+    pub fn apply_shared_value_refcounts(
+        &self,
+        shared_value_refcounts: &BTreeMap<CryptoHash, SharedValueRefcount>,
+        store_update: &mut TrieStoreUpdateAdapter,
+    ) -> Result<(), StorageError> {
+        for (value_hash, refcount) in shared_value_refcounts {
+            let Some(rc) = NonZeroU32::new(refcount.rc) else {
+                continue;
+            };
+            let value = self.0.store.get(refcount.origin_shard, value_hash)?;
+            store_update.increment_refcount_by(refcount.origin_shard, value_hash, &value, rc);
+        }
+        Ok(())
+    }
+
     pub fn revert_insertions(
         &self,
         trie_changes: &TrieChanges,
@@ -413,7 +435,11 @@ impl ShardTries {
             // shares the base with parent, in which case we skip writing the changes.
             if children_shard_uid.contains(&shard_uid) {
                 let memtrie = self.get_memtries(*shard_uid).expect("Memtrie must exist");
-                memtrie.write().unwrap().apply_memtrie_changes(block_height, memtrie_changes);
+                memtrie
+                    .write()
+                    .unwrap()
+                    .apply_memtrie_changes(block_height, memtrie_changes)
+                    .expect("Failed to apply children memtrie changes");
             }
         }
 
@@ -422,7 +448,13 @@ impl ShardTries {
                 .memtrie_changes
                 .as_ref()
                 .expect("Memtrie changes must be present if memtrie is loaded");
-            Some(memtries.write().unwrap().apply_memtrie_changes(block_height, changes))
+            Some(
+                memtries
+                    .write()
+                    .unwrap()
+                    .apply_memtrie_changes(block_height, changes)
+                    .expect("Failed to apply memtrie changes"),
+            )
         } else {
             assert!(
                 trie_changes.memtrie_changes.is_none(),
@@ -604,6 +636,83 @@ impl ShardTries {
         tracing::info!(target: "memtrie", "Memtries freezing complete");
         Ok(())
     }
+
+    /// Walks the memtrie and the on-disk trie of the shard for the given
+    /// root, checking that they contain exactly the same keys and values.
+    /// Returns the first divergence in key order. Only the key-value
+    /// entries are compared: the node structure and the node hashes of the
+    /// memtrie are not checked.
+    ///
+    /// This is an integrity check operators can run to self-verify a shard.
+    /// It iterates over the whole state, so it must not be used in block
+    /// processing.
+    pub fn verify_memtrie_matches_disk(
+        &self,
+        shard_uid: ShardUId,
+        root: StateRoot,
+    ) -> Result<(), MemTrieMismatch> {
+        let memtries = self.get_memtries(shard_uid).ok_or(MemTrieMismatch::MemTrieNotLoaded)?;
+        let memtries = memtries.read().unwrap();
+        let update = memtries.update_memtrie_only(root).map_err(MemTrieMismatch::Storage)?;
+        let mut memtrie_entries =
+            update.iter_prefix(&[]).map(|(key, value)| (key, value.to_value_ref()));
+        let trie = self.get_trie_for_shard(shard_uid, root);
+        let mut disk_entries = trie
+            .disk_iter()
+            .map_err(MemTrieMismatch::Storage)?
+            .map(|item| item.map(|(key, value)| (key, ValueRef::new(&value))));
+
+        let mut memtrie_entry = memtrie_entries.next();
+        let mut disk_entry = disk_entries.next().transpose().map_err(MemTrieMismatch::Storage)?;
+        loop {
+            let key = match (&memtrie_entry, &disk_entry) {
+                (None, None) => return Ok(()),
+                (Some((key, _)), None) | (None, Some((key, _))) => key.clone(),
+                (Some((memtrie_key, _)), Some((disk_key, _))) => memtrie_key.min(disk_key).clone(),
+            };
+            let memtrie_value = match memtrie_entry.take() {
+                Some((memtrie_key, value)) if memtrie_key == key => {
+                    memtrie_entry = memtrie_entries.next();
+                    Some(value)
+                }
+                entry => {
+                    memtrie_entry = entry;
+                    None
+                }
+            };
+            let disk_value = match disk_entry.take() {
+                Some((disk_key, value)) if disk_key == key => {
+                    disk_entry =
+                        disk_entries.next().transpose().map_err(MemTrieMismatch::Storage)?;
+                    Some(value)
+                }
+                entry => {
+                    disk_entry = entry;
+                    None
+                }
+            };
+            if memtrie_value != disk_value {
+                return Err(MemTrieMismatch::Value {
+                    key,
+                    memtrie: memtrie_value,
+                    disk: disk_value,
+                });
+            }
+        }
+    }
+}
+
+/// Divergence between the memtrie and the on-disk trie, found by
+/// `ShardTries::verify_memtrie_matches_disk`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MemTrieMismatch {
+    /// The memtrie of the shard is not loaded.
+    MemTrieNotLoaded,
+    /// The key has different values in the memtrie and on disk. None means
+    /// that the key is absent from the corresponding trie.
+    Value { key: Vec<u8>, memtrie: Option<ValueRef>, disk: Option<ValueRef> },
+    /// Failed to read one of the tries.
+    Storage(StorageError),
 }
 
 pub struct WrappedTrieChanges {
@@ -841,6 +950,7 @@ impl KeyForStateChanges {
 #[cfg(test)]
 mod test {
     use crate::adapter::StoreAdapter;
+    use crate::test_utils::{test_populate_trie, TestTriesBuilder};
     use crate::{
         config::TrieCacheConfig, test_utils::create_test_store,
         trie::DEFAULT_SHARD_CACHE_TOTAL_SIZE_LIMIT, TrieConfig,
@@ -964,6 +1074,35 @@ mod test {
         assert!(trie_caches.lock().unwrap().get(&shard_uid).unwrap().get(&key).is_none());
     }
 
+    #[test]
+    fn test_verify_memtrie_matches_disk() {
+        let tries =
+            TestTriesBuilder::new().with_flat_storage(true).with_in_memory_tries(true).build();
+        let shard_uid = ShardUId::single_shard();
+        let changes = vec![
+            (b"alice".to_vec(), Some(vec![1, 2, 3])),
+            (b"bob".to_vec(), Some(vec![4, 5])),
+            (b"carol".to_vec(), Some(vec![6])),
+        ];
+        let root = test_populate_trie(&tries, &Trie::EMPTY_ROOT, shard_uid, changes);
+        assert_eq!(tries.verify_memtrie_matches_disk(shard_uid, root), Ok(()));
+        assert_eq!(
+            tries.verify_memtrie_matches_disk(ShardUId { version: 10, shard_id: 5 }, root),
+            Err(MemTrieMismatch::MemTrieNotLoaded)
+        );
+
+        let memtries = tries.get_memtries(shard_uid).unwrap();
+        memtries.write().unwrap().corrupt_value_for_test(&root, b"bob", &[7]);
+        assert_eq!(
+            tries.verify_memtrie_matches_disk(shard_uid, root),
+            Err(MemTrieMismatch::Value {
+                key: b"bob".to_vec(),
+                memtrie: Some(ValueRef::new(&[7])),
+                disk: Some(ValueRef::new(&[4, 5])),
+            })
+        );
+    }
+
     #[test]
     fn test_shard_cache_max_value() {
         let store = create_test_store();
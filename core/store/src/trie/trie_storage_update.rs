@@ -22,22 +22,29 @@ impl TrieStorageNode {
     fn new_branch(children: Children, value: Option<ValueRef>) -> Self {
         let children = Box::new(children.0);
         let value = value.map(ValueHandle::HashAndSize);
-        Self::Branch { children, value }
+        Self::Branch {
+            children,
+            value,
+            #[cfg(feature = "memtrie_shadow_values")]
+            shadow_value: None,
+        }
     }
 
     /// Conversion from the node just read from trie storage.
     pub fn from_raw_trie_node(node: RawTrieNode) -> Self {
         match node {
             RawTrieNode::Leaf(extension, value) => Self::Leaf {
-                extension: extension.into_boxed_slice(),
+                extension: extension.into(),
                 value: ValueHandle::HashAndSize(value),
+                #[cfg(feature = "memtrie_shadow_values")]
+                shadow_value: None,
             },
             RawTrieNode::BranchNoValue(children) => Self::new_branch(children, None),
             RawTrieNode::BranchWithValue(value, children) => {
                 Self::new_branch(children, Some(value))
             }
             RawTrieNode::Extension(extension, child) => {
-                Self::Extension { extension: extension.into_boxed_slice(), child }
+                Self::Extension { extension: extension.into(), child }
             }
         }
     }
@@ -102,12 +109,16 @@ impl<'a> GenericTrieUpdate<'a, TrieStorageNodePtr, ValueHandle> for TrieStorageU
         }
     }
 
-    fn take_node(&mut self, index: UpdatedNodeId) -> UpdatedTrieStorageNodeWithSize {
-        self.nodes
+    fn take_node(
+        &mut self,
+        index: UpdatedNodeId,
+    ) -> Result<UpdatedTrieStorageNodeWithSize, StorageError> {
+        Ok(self
+            .nodes
             .get_mut(index)
             .expect(INVALID_STORAGE_HANDLE)
             .take()
-            .expect(INVALID_STORAGE_HANDLE)
+            .expect(INVALID_STORAGE_HANDLE))
     }
 
     fn place_node_at(&mut self, index: UpdatedNodeId, node: UpdatedTrieStorageNodeWithSize) {
@@ -178,7 +189,7 @@ impl TrieStorageUpdate<'_> {
                     last_hash = Trie::EMPTY_ROOT;
                     continue;
                 }
-                GenericUpdatedTrieNode::Branch { children, value } => match position {
+                GenericUpdatedTrieNode::Branch { children, value, .. } => match position {
                     FlattenNodesCrumb::Entering => {
                         stack.push((node, FlattenNodesCrumb::AtChild(Default::default(), 0)));
                         continue;
@@ -225,7 +236,7 @@ impl TrieStorageUpdate<'_> {
                     }
                     _ => unreachable!(),
                 },
-                GenericUpdatedTrieNode::Leaf { extension, value } => {
+                GenericUpdatedTrieNode::Leaf { extension, value, .. } => {
                     let key = extension.to_vec();
                     let value = *value;
                     let value = self.flatten_value(value);
@@ -0,0 +1,8 @@
+/// Checks that misuses of the store API are rejected at compile time. The
+/// `.stderr` files pin the diagnostics of the toolchain in rust-toolchain.toml,
+/// so they must be regenerated with `TRYBUILD=overwrite` when it is bumped.
+#[test]
+fn compile_fail() {
+    let t = trybuild::TestCases::new();
+    t.compile_fail("tests/compile_fail/*.rs");
+}
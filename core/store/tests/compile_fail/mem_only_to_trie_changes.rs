@@ -0,0 +1,9 @@
+use near_primitives::hash::CryptoHash;
+use near_store::trie::mem::memtries::MemTries;
+use near_store::ShardUId;
+
+fn main() {
+    let tries = MemTries::new(ShardUId::single_shard());
+    let update = tries.update_memtrie_only(CryptoHash::default()).unwrap();
+    let _ = update.to_trie_changes();
+}
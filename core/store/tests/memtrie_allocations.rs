@@ -0,0 +1,124 @@
+//! Tests asserting the number of heap allocations made by memtrie updates.
+//! Counting allocations requires replacing the global allocator, so these
+//! tests have their own binary rather than affecting all tests of the crate.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use near_primitives::hash::CryptoHash;
+use near_primitives::state::FlatStateValue;
+use near_store::trie::mem::memtrie_update::TrackingMode;
+use near_store::trie::mem::memtries::MemTries;
+use near_store::ShardUId;
+
+/// Counts heap allocations per thread, so that tests running in parallel
+/// don't affect each other's counts.
+struct CountingAllocator;
+
+thread_local! {
+    static NUM_ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = NUM_ALLOCATIONS.try_with(|num| num.set(num.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn num_allocations() -> usize {
+    NUM_ALLOCATIONS.with(|num| num.get())
+}
+
+/// Creates memtries holding a single root with the given entries.
+fn memtries_with(entries: &[(Vec<u8>, Vec<u8>)]) -> (MemTries, CryptoHash) {
+    let mut tries = MemTries::new(ShardUId::single_shard());
+    let mut update = tries.update(CryptoHash::default(), TrackingMode::Refcounts).unwrap();
+    for (key, value) in entries {
+        update.insert(key, value.clone()).unwrap();
+    }
+    let changes = update.to_trie_changes().unwrap();
+    let root = tries.apply_memtrie_changes(0, changes.memtrie_changes.as_ref().unwrap()).unwrap();
+    (tries, root)
+}
+
+#[test]
+fn test_to_trie_changes_moves_serialized_nodes() {
+    let (tries, root) =
+        memtries_with(&[(vec![0x00], vec![0x00, 0x00]), (vec![0x01], vec![0x00, 0x01])]);
+    // All keys share a value, so that the insertions are almost all nodes.
+    let keys: Vec<Vec<u8>> = (0..=255u8).map(|i| vec![i, i]).collect();
+    let value = vec![0x07];
+    // Initializes metrics, which allocate on first use.
+    tries.update(root, TrackingMode::Refcounts).unwrap().to_trie_changes().unwrap();
+
+    let mut update = tries.update(root, TrackingMode::Refcounts).unwrap();
+    for key in &keys {
+        update.insert(key, value.clone()).unwrap();
+    }
+    update.delete(&[0x01]).unwrap();
+    let allocations_before = num_allocations();
+    let changes = update.to_trie_changes().unwrap();
+    let tracking_allocations = num_allocations() - allocations_before;
+
+    let mut update = tries.update_memtrie_only(root).unwrap();
+    for key in &keys {
+        update.insert_memtrie_only(key, FlatStateValue::on_disk(&value)).unwrap();
+    }
+    update.delete(&[0x01]).unwrap();
+    let allocations_before = num_allocations();
+    let memtrie_changes = update.to_memtrie_changes_only().unwrap();
+    let mem_only_allocations = num_allocations() - allocations_before;
+
+    assert_eq!(changes.memtrie_changes, Some(memtrie_changes));
+    // Besides the refcount map, tracking costs no allocation per new node:
+    // the serialized nodes are moved into the changes rather than copied.
+    let num_insertions = changes.insertions().len();
+    assert!(
+        tracking_allocations < mem_only_allocations + num_insertions,
+        "{tracking_allocations} allocations with tracking, {mem_only_allocations} without, \
+         {num_insertions} insertions"
+    );
+}
+
+/// Inserts 20 into a trie whose root is an extension with the given entries
+/// below it, splitting the extension into a branch, and returns the
+/// allocations made by the insert.
+fn split_allocations(entries: &[(Vec<u8>, Vec<u8>)]) -> usize {
+    let (tries, root) = memtries_with(entries);
+    let mut update = tries.update(root, TrackingMode::Refcounts).unwrap();
+    // Keeps the growth of `updated_nodes` out of the count.
+    update.updated_nodes.reserve(16);
+    let allocations_before = num_allocations();
+    update.insert(&[0x20], vec![0x02]).unwrap();
+    let allocations = num_allocations() - allocations_before;
+    let new_root = update.to_trie_changes().unwrap().new_root;
+    // The split produces the same root as inserting all entries at once.
+    let mut all_entries = entries.to_vec();
+    all_entries.push((vec![0x20], vec![0x02]));
+    assert_eq!(new_root, memtries_with(&all_entries).1);
+    allocations
+}
+
+#[test]
+fn test_insert_extension_split_allocations() {
+    let one_nibble_entries = [(vec![0x10], vec![0x00]), (vec![0x11], vec![0x01])];
+    let two_nibble_entries = [(vec![0x10, 0x00], vec![0x00]), (vec![0x10, 0x10], vec![0x01])];
+    // Initializes metrics, which allocate on first use.
+    split_allocations(&one_nibble_entries);
+
+    // A one nibble extension is replaced by the branch, while a longer one
+    // leaves an inner extension below it. Everything else is the same, so
+    // the difference is the cost of the inner extension, which was two
+    // allocations when going through a `Vec`.
+    let without_inner_extension = split_allocations(&one_nibble_entries);
+    let with_inner_extension = split_allocations(&two_nibble_entries);
+    assert_eq!(with_inner_extension, without_inner_extension + 1);
+}